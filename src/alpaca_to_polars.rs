@@ -1,132 +1,235 @@
+//Typed conversion between Alpaca bars and Polars, in both directions.
+//Columns: symbol, time (UTC, ms), open, high, low, close, volume (u64), trade_count, vwap
 use apca::data::v2::bars::Bar;
-use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use chrono::{DateTime, Utc};
 use num_decimal::Num;
 use polars::{
-    df, frame::DataFrame, functions::concat_df_horizontal, prelude::NamedFrom, series::Series,
+    df,
+    frame::DataFrame,
+    prelude::{DataType, TimeUnit},
 };
-use serde::Deserialize;
-use struct_iterable::Iterable;
+use serde_json::json;
 
 use crate::error::CLIError;
 
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Iterable)]
-pub struct Sss {
+pub const TIME: &str = "time";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BarRecord {
+    pub symbol: String,
     /// The beginning time of this bar.
-    #[serde(rename = "t")]
     pub time: DateTime<Utc>,
-    /// The open price.
-    #[serde(rename = "o")]
-    pub open: Num,
-    /// The close price.
-    #[serde(rename = "c")]
-    pub close: Num,
-    /// The highest price.
-    #[serde(rename = "h")]
-    pub high: Num,
-    /// The lowest price.
-    #[serde(rename = "l")]
-    pub low: Num,
-    /// The trading volume.
-    #[serde(rename = "v")]
-    pub volume: usize,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: u64,
+    /// Not every feed reports the number of trades.
+    pub trade_count: Option<u64>,
+    /// Volume weighted average price.
+    pub vwap: Option<f64>,
 }
 
-impl From<Bar> for Sss {
-    fn from(a: Bar) -> Self {
-        Self {
-            time: a.time,
-            open: a.open,
-            close: a.close,
-            high: a.high,
-            low: a.low,
-            volume: a.volume,
-        }
+impl BarRecord {
+    pub fn from_bar(symbol: &str, index: usize, b: &Bar) -> Result<Self, CLIError> {
+        Ok(BarRecord {
+            symbol: symbol.to_string(),
+            time: b.time,
+            open: num_to_f64("open", index, &b.open)?,
+            high: num_to_f64("high", index, &b.high)?,
+            low: num_to_f64("low", index, &b.low)?,
+            close: num_to_f64("close", index, &b.close)?,
+            volume: b.volume as u64,
+            //apca does not deserialize the trade count of a bar
+            trade_count: None,
+            vwap: Some(num_to_f64("vwap", index, &b.weighted_average)?),
+        })
+    }
+
+    //apca bars can't be built field by field, they are deserialized like the API response
+    pub fn to_bar(&self) -> Result<Bar, CLIError> {
+        let value = json!({
+            "t": self.time.to_rfc3339(),
+            "o": self.open,
+            "h": self.high,
+            "l": self.low,
+            "c": self.close,
+            "v": self.volume,
+            "vw": self.vwap.unwrap_or(self.close),
+        });
+        serde_json::from_value(value).map_err(|e| CLIError::BarConversion {
+            field: String::from("bar"),
+            index: 0,
+            value: e.to_string(),
+        })
     }
 }
 
-pub struct S {
-    pub v: DataFrame,
+fn num_to_f64(field: &str, index: usize, n: &Num) -> Result<f64, CLIError> {
+    n.to_f64().ok_or_else(|| CLIError::BarConversion {
+        field: field.to_string(),
+        index,
+        value: n.to_string(),
+    })
 }
 
-impl S {
-    /* pub fn loads(v: Vec<Sss>) -> Self {
-        let ii: Series = Series::from_iter(v.iter().map(|b| b.close.to_f64().unwrap_or_default()));
-        println!("{}", ii);
-        todo!()
-    } */
+pub fn bars_to_records(symbol: &str, bars: &[Bar]) -> Result<Vec<BarRecord>, CLIError> {
+    bars.iter()
+        .enumerate()
+        .map(|(i, b)| BarRecord::from_bar(symbol, i, b))
+        .collect()
 }
 
-impl From<Vec<Bar>> for S {
-    fn from(v: Vec<Bar>) -> Self {
-        let dt: NaiveDateTime = NaiveDate::from_ymd_opt(2016, 7, 8)
-            .unwrap()
-            .and_hms_opt(9, 10, 11)
-            .unwrap();
-
-        //let dft = df!("time" => v.iter().map(|b| dt).collect::<Vec<NaiveDateTime>>()).unwrap();
-
-        let oo = v
-            .clone()
-            .iter()
-            .map(|x| x.time.naive_utc())
-            .collect::<Vec<NaiveDateTime>>();
-
-        //polars dataframe syntax
-        let df = df! {
-            //"time" => struct_to_slice("time",v.clone()),
-            "open"=> struct_to_slice("open",v.clone()).unwrap(),
-            "close" => struct_to_slice("close",v.clone()).unwrap(),
-            "high" => struct_to_slice("high",v.clone()).unwrap(),
-            "low" => struct_to_slice("low",v.clone()).unwrap(),
-            "volume" => struct_to_slice("volume",v).unwrap(),
-        };
-        let dfts = df!("time" => oo).unwrap();
-
-        let df_append = concat_df_horizontal(&[dfts, df.unwrap()], false);
-
-        S {
-            v: df_append.unwrap(),
-        }
-    }
-    /* ... */
+pub fn bars_to_df(symbol: &str, bars: &[Bar]) -> Result<DataFrame, CLIError> {
+    records_to_df(&bars_to_records(symbol, bars)?)
+}
+
+pub fn records_to_df(records: &[BarRecord]) -> Result<DataFrame, CLIError> {
+    let mut df = df! {
+        "symbol" => records.iter().map(|r| r.symbol.clone()).collect::<Vec<String>>(),
+        TIME => records.iter().map(|r| r.time.timestamp_millis()).collect::<Vec<i64>>(),
+        "open" => records.iter().map(|r| r.open).collect::<Vec<f64>>(),
+        "high" => records.iter().map(|r| r.high).collect::<Vec<f64>>(),
+        "low" => records.iter().map(|r| r.low).collect::<Vec<f64>>(),
+        "close" => records.iter().map(|r| r.close).collect::<Vec<f64>>(),
+        "volume" => records.iter().map(|r| r.volume).collect::<Vec<u64>>(),
+        "trade_count" => records.iter().map(|r| r.trade_count).collect::<Vec<Option<u64>>>(),
+        "vwap" => records.iter().map(|r| r.vwap).collect::<Vec<Option<f64>>>(),
+    }?;
+    let time = df.column(TIME)?.cast(&DataType::Datetime(
+        TimeUnit::Milliseconds,
+        Some("UTC".into()),
+    ))?;
+    df.with_column(time)?;
+    Ok(df)
 }
 
-fn struct_to_slice(s: &str, v: Vec<Bar>) -> Result<Series, CLIError> {
-    let i = match s {
-        "open" | "close" | "high" | "low" => {
-            Series::from_iter(
-                v.iter()
-                    .map(|b| {
-                        match s {
-                            //"time" => b.time,
-                            "open" => b.open.to_f64().unwrap_or_default(),
-                            "close" => b.close.to_f64().unwrap_or_default(),
-                            "high" => b.high.to_f64().unwrap_or_default(),
-                            "low" => b.low.to_f64().unwrap_or_default(),
-                            _ => 1.0,
-                            //"volume" => b.volume,
-                        }
-                    })
-                    .collect::<Vec<f64>>(),
-            )
-        }
-        "volume" => Series::from_iter(
-            v.iter()
-                .map(|b| match s {
-                    "volume" => b.volume as i32,
-                    _ => 0,
-                })
-                .collect::<Vec<i32>>(),
-        ),
-        _ => Series::new("".into(), Vec::<f64>::new()),
+//nulls in the required columns are reported, not zeroed
+pub fn df_to_records(df: &DataFrame) -> Result<Vec<BarRecord>, CLIError> {
+    let symbol = df.column("symbol")?.cast(&DataType::String)?;
+    let time = df.column(TIME)?.cast(&DataType::Datetime(
+        TimeUnit::Milliseconds,
+        Some("UTC".into()),
+    ))?;
+    let open = df.column("open")?.cast(&DataType::Float64)?;
+    let high = df.column("high")?.cast(&DataType::Float64)?;
+    let low = df.column("low")?.cast(&DataType::Float64)?;
+    let close = df.column("close")?.cast(&DataType::Float64)?;
+    let volume = df.column("volume")?.cast(&DataType::UInt64)?;
+    let trade_count = match df.column("trade_count") {
+        Ok(c) => Some(c.cast(&DataType::UInt64)?),
+        Err(_) => None,
     };
-    let dt = NaiveDate::from_ymd_opt(2016, 7, 8)
+    let vwap = match df.column("vwap") {
+        Ok(c) => Some(c.cast(&DataType::Float64)?),
+        Err(_) => None,
+    };
+
+    let (symbol, time) = (symbol.str()?, time.datetime()?);
+    let (open, high, low, close) = (open.f64()?, high.f64()?, low.f64()?, close.f64()?);
+    let volume = volume.u64()?;
+    let required = |field: &str, index: usize| CLIError::BarConversion {
+        field: field.to_string(),
+        index,
+        value: String::from("null"),
+    };
+
+    (0..df.height())
+        .map(|i| {
+            let ms = time.get(i).ok_or_else(|| required(TIME, i))?;
+            Ok(BarRecord {
+                symbol: symbol
+                    .get(i)
+                    .ok_or_else(|| required("symbol", i))?
+                    .to_string(),
+                time: DateTime::from_timestamp_millis(ms).ok_or_else(|| {
+                    CLIError::BarConversion {
+                        field: TIME.to_string(),
+                        index: i,
+                        value: ms.to_string(),
+                    }
+                })?,
+                open: open.get(i).ok_or_else(|| required("open", i))?,
+                high: high.get(i).ok_or_else(|| required("high", i))?,
+                low: low.get(i).ok_or_else(|| required("low", i))?,
+                close: close.get(i).ok_or_else(|| required("close", i))?,
+                volume: volume.get(i).ok_or_else(|| required("volume", i))?,
+                trade_count: match &trade_count {
+                    Some(c) => c.u64()?.get(i),
+                    None => None,
+                },
+                vwap: match &vwap {
+                    Some(c) => c.f64()?.get(i),
+                    None => None,
+                },
+            })
+        })
+        .collect()
+}
+
+pub fn df_to_bars(df: &DataFrame) -> Result<Vec<Bar>, CLIError> {
+    df_to_records(df)?
+        .iter()
+        .enumerate()
+        .map(|(i, r)| {
+            r.to_bar().map_err(|e| match e {
+                CLIError::BarConversion { field, value, .. } => CLIError::BarConversion {
+                    field,
+                    index: i,
+                    value,
+                },
+                e => e,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polars::prelude::{Column, NamedFrom};
+
+    fn bar(t: &str, close: f64, volume: u64) -> Bar {
+        serde_json::from_value(json!({
+            "t": t, "o": 1.5, "h": close + 1.0, "l": 1.0, "c": close, "v": volume, "vw": 2.25,
+        }))
         .unwrap()
-        .and_hms_opt(9, 10, 11)
-        .ok_or_else(|| CLIError::Converting)?;
-    let bc = v.iter().map(|_| dt).collect::<Vec<NaiveDateTime>>();
-    let df = df!("time" => bc).unwrap();
+    }
 
-    //Series::from_iter(i)
-    Ok(i)
+    #[test]
+    fn bars_to_df_roundtrip_test() -> Result<(), Box<dyn std::error::Error>> {
+        //more volume than fits into an i32
+        let bars = vec![
+            bar("2024-01-02T14:30:00Z", 2.0, 5_000_000_000),
+            bar("2024-01-03T14:30:00Z", 3.0, 10),
+        ];
+        let df = bars_to_df("SPY", &bars)?;
+        assert_eq!(df.height(), 2);
+        assert_eq!(
+            df.column(TIME)?.dtype(),
+            &DataType::Datetime(TimeUnit::Milliseconds, Some("UTC".into()))
+        );
+
+        let records = df_to_records(&df)?;
+        assert_eq!(records[0].symbol, "SPY");
+        assert_eq!(records[0].volume, 5_000_000_000);
+        assert_eq!(records[0].vwap, Some(2.25));
+        assert_eq!(records[0].trade_count, None);
+        assert_eq!(records[1].time, bars[1].time);
+
+        let back = df_to_bars(&df)?;
+        assert_eq!(back, bars);
+        Ok(())
+    }
+
+    #[test]
+    fn df_to_records_null_test() -> Result<(), Box<dyn std::error::Error>> {
+        let mut df = bars_to_df("SPY", &[bar("2024-01-02T14:30:00Z", 2.0, 10)])?;
+        df.with_column(Column::new("close".into(), [None::<f64>]))?;
+        let err = df_to_records(&df).unwrap_err();
+        assert!(
+            matches!(err, CLIError::BarConversion { ref field, index: 0, .. } if field == "close")
+        );
+        Ok(())
+    }
 }
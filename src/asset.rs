@@ -0,0 +1,72 @@
+//Equities and crypto pairs share the trading pipeline, the symbol tells them apart:
+//crypto pairs are written with a slash (BTC/USD), equities as plain tickers (ORCL, BRK.B).
+//Crypto trades around the clock and in fractions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AssetClass {
+    Equity,
+    Crypto,
+}
+
+impl AssetClass {
+    pub fn of(symbol: &str) -> Self {
+        if symbol.contains('/') {
+            AssetClass::Crypto
+        } else {
+            AssetClass::Equity
+        }
+    }
+}
+
+pub fn is_crypto(symbol: &str) -> bool {
+    AssetClass::of(symbol) == AssetClass::Crypto
+}
+
+//ORCL, BRK.B or BTC/USD
+pub fn valid_symbol(symbol: &str) -> bool {
+    let ticker = |s: &str| {
+        !s.is_empty()
+            && s.chars()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '.')
+    };
+    match symbol.split_once('/') {
+        Some((base, quote)) => ticker(base) && ticker(quote),
+        None => ticker(symbol),
+    }
+}
+
+//name part of cache and CSV files, BTC/USD -> btc-usd
+pub fn file_stem(symbol: &str) -> String {
+    symbol.replace('/', "-").to_lowercase()
+}
+
+//positions are addressed without the slash, BTC/USD -> BTCUSD
+pub fn position_symbol(symbol: &str) -> String {
+    symbol.replace('/', "")
+}
+
+//whole shares for equities, crypto down to 1e-9
+pub fn round_qty(symbol: &str, qty: f64) -> f64 {
+    match AssetClass::of(symbol) {
+        AssetClass::Equity => qty.trunc(),
+        AssetClass::Crypto => (qty * 1e9).trunc() / 1e9,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbol_test() {
+        assert!(is_crypto("BTC/USD"));
+        assert!(!is_crypto("ORCL"));
+        assert!(valid_symbol("BRK.B"));
+        assert!(valid_symbol("ETH/USDT"));
+        assert!(!valid_symbol("btc/usd"));
+        assert!(!valid_symbol("BTC/"));
+        assert_eq!(file_stem("BTC/USD"), "btc-usd");
+        assert_eq!(position_symbol("BTC/USD"), "BTCUSD");
+        assert_eq!(round_qty("ORCL", 2.7), 2.0);
+        assert_eq!(round_qty("BTC/USD", 0.0123456789123), 0.012345678);
+    }
+}
@@ -0,0 +1,334 @@
+//Backtest reports and helpers shared by the backtest and optimize commands
+use std::{collections::HashMap, fs::File};
+
+use chrono::{DateTime, NaiveDate};
+use polars::{
+    df,
+    frame::DataFrame,
+    prelude::{BooleanChunked, CsvWriter, DataType, SerWriter, TimeUnit},
+};
+use tracing::warn;
+
+use crate::{
+    asset::file_stem,
+    data::{
+        cache::CachedBar,
+        csv_file::data_csv,
+        panel::{panel_from_frames, PanelOptions},
+        quality::{bars_from_df, repair_bars},
+    },
+    error::{CLIError, PersistenceError},
+    order::Side,
+    portfolio::{rebalance::RebalanceConf, types::Portfolio},
+    types::PriceField,
+};
+
+pub const START_CASH: f64 = 1000.0;
+
+//outcome of one TraderConf variant over one symbol
+#[derive(Clone, Debug, PartialEq)]
+pub struct BacktestResult {
+    pub symbol: String,
+    pub variant: String,
+    pub bars: usize,
+    pub trades: usize,
+    pub start_cash: f64,
+    pub end_cash: f64,
+    pub end_shares: f64,
+    pub last_close: f64,
+}
+
+impl BacktestResult {
+    pub fn equity(&self) -> f64 {
+        self.end_cash + self.end_shares * self.last_close
+    }
+
+    pub fn return_pct(&self) -> f64 {
+        if self.start_cash == 0.0 {
+            return 0.0;
+        }
+        (self.equity() / self.start_cash - 1.0) * 100.0
+    }
+}
+
+//keeps the rows whose Date lies in [start, end]
+pub fn filter_dates(
+    df: DataFrame,
+    start: Option<NaiveDate>,
+    end: Option<NaiveDate>,
+) -> Result<DataFrame, CLIError> {
+    if start.is_none() && end.is_none() {
+        return Ok(df);
+    }
+    let mask: BooleanChunked = df
+        .column("Date")?
+        .datetime()?
+        .into_iter()
+        .map(|d| {
+            let Some(d) = d.and_then(DateTime::from_timestamp_millis) else {
+                return false;
+            };
+            let d = d.date_naive();
+            start.map_or(true, |s| d >= s) && end.map_or(true, |e| d <= e)
+        })
+        .collect();
+    Ok(df.filter(&mask)?)
+}
+
+//{data_dir}/{symbol}.csv in [start, end] as repaired bars, see repair_bars.
+//Prices are split/dividend adjusted by Adj Close / Close like a Panel, Adj Close is
+//the close afterwards
+pub fn load_bars(
+    data_dir: &str,
+    symbol: &str,
+    start: Option<NaiveDate>,
+    end: Option<NaiveDate>,
+) -> Result<DataFrame, CLIError> {
+    let df = data_csv(format!("{data_dir}/{}.csv", file_stem(symbol)))?;
+    let df = filter_dates(df, start, end)?;
+    let bars = bars_from_df(&df)?;
+    let repaired = repair_bars(&bars);
+    if repaired.len() != df.height() {
+        warn!(
+            "{symbol}: {} of {} bars dropped by the repair",
+            df.height() - repaired.len(),
+            df.height()
+        );
+    }
+
+    //the repair keeps close, the last row of a time wins like in repair_bars
+    let factors: HashMap<i64, f64> = match df.column("Adj Close") {
+        Ok(adj) => {
+            let adj = adj.cast(&DataType::Float64)?;
+            let close = df.column("Close")?.cast(&DataType::Float64)?;
+            let date = df.column("Date")?.cast(&DataType::Int64)?;
+            date.i64()?
+                .into_iter()
+                .zip(adj.f64()?.into_iter().zip(close.f64()?.into_iter()))
+                .filter_map(|(d, (a, c))| match (d, a, c) {
+                    (Some(d), Some(a), Some(c)) if c != 0.0 => Some((d, a / c)),
+                    _ => None,
+                })
+                .collect()
+        }
+        Err(_) => HashMap::new(),
+    };
+    let adjusted: Vec<CachedBar> = repaired
+        .into_iter()
+        .map(|mut b| {
+            let factor = factors
+                .get(&b.time.timestamp_millis())
+                .copied()
+                .unwrap_or(1.0);
+            b.open *= factor;
+            b.high *= factor;
+            b.low *= factor;
+            b.close *= factor;
+            b.volume /= factor;
+            b
+        })
+        .collect();
+    let close: Vec<f64> = adjusted.iter().map(|b| b.close).collect();
+    let mut out = df! {
+        "Date" => adjusted.iter().map(|b| b.time.timestamp_millis()).collect::<Vec<i64>>(),
+        "Open" => adjusted.iter().map(|b| b.open).collect::<Vec<f64>>(),
+        "High" => adjusted.iter().map(|b| b.high).collect::<Vec<f64>>(),
+        "Low" => adjusted.iter().map(|b| b.low).collect::<Vec<f64>>(),
+        "Close" => close.clone(),
+        "Adj Close" => close,
+        "Volume" => adjusted.iter().map(|b| b.volume).collect::<Vec<f64>>(),
+    }?;
+    let date = out
+        .column("Date")?
+        .cast(&DataType::Datetime(TimeUnit::Milliseconds, None))?;
+    out.with_column(date)?;
+    Ok(out)
+}
+
+//the [rebalance] basket over a Panel of {data_dir}/{symbol}.csv, rebalanced once
+//every target has a price and every_days after. One row, the positions are marked
+//to cash at the last close.
+pub fn rebalance_backtest(
+    conf: &RebalanceConf,
+    data_dir: &str,
+    start: Option<NaiveDate>,
+    end: Option<NaiveDate>,
+) -> Result<BacktestResult, CLIError> {
+    let mut symbols: Vec<String> = conf.targets.keys().cloned().collect();
+    symbols.sort();
+    let frames = symbols
+        .iter()
+        .map(|symbol| Ok((symbol.clone(), load_bars(data_dir, symbol, start, end)?)))
+        .collect::<Result<Vec<_>, CLIError>>()?;
+    //the basket is priced on every trading day, missing closes are forward filled.
+    //load_bars already adjusted the prices
+    let panel = panel_from_frames(
+        frames,
+        &PanelOptions {
+            start,
+            end,
+            adjust: false,
+            ..Default::default()
+        },
+    )?;
+    let closes: Vec<(&String, Vec<Option<f64>>)> = symbols
+        .iter()
+        .map(|symbol| (symbol, panel.series(symbol, PriceField::Close)))
+        .collect();
+
+    let mut port = Portfolio {
+        name: String::from("basket"),
+        cash: Some(conf.capital),
+        stocks: Some(HashMap::new()),
+    };
+    let mut prices = HashMap::new();
+    let mut last_run: Option<NaiveDate> = None;
+    let mut trades = 0;
+    for (i, day) in panel.dates.iter().copied().enumerate() {
+        prices.extend(
+            closes
+                .iter()
+                .filter_map(|(symbol, c)| c[i].map(|c| ((*symbol).clone(), c))),
+        );
+        let due = last_run.map_or(true, |last| {
+            conf.every_days > 0 && (day - last).num_days() >= i64::from(conf.every_days)
+        });
+        if !due || prices.len() < conf.targets.len() {
+            continue;
+        }
+        for trade in conf.plan(&port, &prices)? {
+            match trade.side {
+                Side::Buy => port.buy(&trade.symbol, trade.qty, trade.price)?,
+                Side::Sell => port.sell(&trade.symbol, trade.qty, trade.price)?,
+            }
+            trades += 1;
+        }
+        last_run = Some(day);
+    }
+
+    let held: f64 = port
+        .stocks
+        .iter()
+        .flatten()
+        .map(|(symbol, shares)| shares * prices.get(symbol).copied().unwrap_or(0.0))
+        .sum();
+    Ok(BacktestResult {
+        symbol: String::from("basket"),
+        variant: String::from("rebalance"),
+        bars: panel.dates.len(),
+        trades,
+        start_cash: conf.capital,
+        end_cash: port.cash.unwrap_or(0.0) + held,
+        end_shares: 0.0,
+        last_close: 0.0,
+    })
+}
+
+pub fn report_df(results: &[BacktestResult]) -> Result<DataFrame, CLIError> {
+    let df = df! {
+        "symbol" => results.iter().map(|r| r.symbol.clone()).collect::<Vec<String>>(),
+        "variant" => results.iter().map(|r| r.variant.clone()).collect::<Vec<String>>(),
+        "bars" => results.iter().map(|r| r.bars as u64).collect::<Vec<u64>>(),
+        "trades" => results.iter().map(|r| r.trades as u64).collect::<Vec<u64>>(),
+        "start_cash" => results.iter().map(|r| r.start_cash).collect::<Vec<f64>>(),
+        "end_cash" => results.iter().map(|r| r.end_cash).collect::<Vec<f64>>(),
+        "end_shares" => results.iter().map(|r| r.end_shares).collect::<Vec<f64>>(),
+        "last_close" => results.iter().map(|r| r.last_close).collect::<Vec<f64>>(),
+        "equity" => results.iter().map(|r| r.equity()).collect::<Vec<f64>>(),
+        "return_pct" => results.iter().map(|r| r.return_pct()).collect::<Vec<f64>>(),
+    }?;
+    Ok(df)
+}
+
+pub fn write_report(results: &[BacktestResult], path: &str) -> Result<(), CLIError> {
+    let mut df = report_df(results)?;
+    let mut file = File::create(path).map_err(|e| PersistenceError::Io {
+        path: path.to_string(),
+        source: e,
+    })?;
+    CsvWriter::new(&mut file).finish(&mut df)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{calendar::TradingCalendar, data::csv_file::data_csv};
+
+    #[test]
+    fn filter_dates_test() -> Result<(), Box<dyn std::error::Error>> {
+        let df = data_csv(String::from("files/orcl.csv"))?;
+        let start = NaiveDate::from_ymd_opt(1995, 1, 4);
+        let end = NaiveDate::from_ymd_opt(1995, 1, 5);
+        let df = filter_dates(df, start, end)?;
+        assert_eq!(df.height(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn load_bars_test() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("load_bars_{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(
+            dir.join("ORCL.csv"),
+            "Date,Open,High,Low,Close,Adj Close,Volume\n\
+             2024-01-03,10,9,11,10.5,5.25,100\n\
+             2024-01-02,10,11,9,10,5,100\n\
+             2024-01-04,0,11,9,10,5,100\n\
+             2024-01-03,10,11,9,10,5,100\n",
+        )?;
+        let df = load_bars(&dir.to_string_lossy(), "ORCL", None, None)?;
+        //sorted, the second 01-03 row wins, the zero open is dropped
+        assert_eq!(df.height(), 2);
+        //adjusted by Adj Close / Close
+        let close: Vec<Option<f64>> = df.column("Close")?.f64()?.into_iter().collect();
+        assert_eq!(close, vec![Some(5.0), Some(5.0)]);
+        let volume: Vec<Option<f64>> = df.column("Volume")?.f64()?.into_iter().collect();
+        assert_eq!(volume, vec![Some(200.0), Some(200.0)]);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn rebalance_backtest_test() -> Result<(), Box<dyn std::error::Error>> {
+        let conf = RebalanceConf {
+            targets: HashMap::from([(String::from("ORCL"), 0.5)]),
+            min_trade: 10.0,
+            every_days: 30,
+            ..Default::default()
+        };
+        let start = NaiveDate::from_ymd_opt(1995, 1, 1);
+        let end = NaiveDate::from_ymd_opt(1995, 12, 31);
+        let res = rebalance_backtest(&conf, "files", start, end)?;
+        //one bar per trading day of the window
+        assert_eq!(
+            res.bars,
+            TradingCalendar::nyse()
+                .trading_days(start.unwrap(), end.unwrap())
+                .len()
+        );
+        //the first run buys half of the capital
+        assert!(res.trades >= 1);
+        assert!(res.end_cash > 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn report_df_test() -> Result<(), Box<dyn std::error::Error>> {
+        let res = BacktestResult {
+            symbol: String::from("ORCL"),
+            variant: String::from("type1"),
+            bars: 3,
+            trades: 1,
+            start_cash: 1000.0,
+            end_cash: 500.0,
+            end_shares: 10.0,
+            last_close: 60.0,
+        };
+        assert_eq!(res.equity(), 1100.0);
+        assert!((res.return_pct() - 10.0).abs() < 1e-9);
+        let df = report_df(&[res])?;
+        assert_eq!(df.shape(), (1, 10));
+        Ok(())
+    }
+}
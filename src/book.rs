@@ -0,0 +1,164 @@
+//Top of book per symbol from stream quotes, used for spread filters,
+//mid price signals and marketable limit prices
+use apca::data::v2::stream::Quote;
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+
+use crate::types::Action;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TopOfBook {
+    pub bid: f64,
+    pub ask: f64,
+    pub bid_size: f64,
+    pub ask_size: f64,
+    pub time: DateTime<Utc>,
+}
+
+//quote handling of a TraderConf, [Stockconfig.SYM.quote]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct QuoteConf {
+    //no orders while the spread is wider, in basis points of the mid
+    pub max_spread_bps: Option<f64>,
+    //limit price this far through the touch, in basis points
+    #[serde(default = "default_limit_offset_bps")]
+    pub limit_offset_bps: f64,
+    //feed the mid price instead of the bar close into the strategy
+    #[serde(default)]
+    pub use_mid: bool,
+    //an older book counts as missing, seconds before the bar
+    #[serde(default = "default_max_age_secs")]
+    pub max_age_secs: u64,
+}
+
+fn default_limit_offset_bps() -> f64 {
+    5.0
+}
+
+//one minute bar
+pub const MAX_QUOTE_AGE_SECS: u64 = 60;
+
+fn default_max_age_secs() -> u64 {
+    MAX_QUOTE_AGE_SECS
+}
+
+impl Default for QuoteConf {
+    fn default() -> Self {
+        QuoteConf {
+            max_spread_bps: None,
+            limit_offset_bps: default_limit_offset_bps(),
+            use_mid: false,
+            max_age_secs: default_max_age_secs(),
+        }
+    }
+}
+
+impl TopOfBook {
+    //None for one-sided or crossed quotes
+    pub fn from_quote(q: &Quote) -> Option<Self> {
+        let book = TopOfBook {
+            bid: q.bid_price.to_f64()?,
+            ask: q.ask_price.to_f64()?,
+            bid_size: q.bid_size.to_f64().unwrap_or_default(),
+            ask_size: q.ask_size.to_f64().unwrap_or_default(),
+            time: q.timestamp,
+        };
+        (book.bid > 0.0 && book.ask >= book.bid).then_some(book)
+    }
+
+    //quoted at most max_age_secs before now
+    pub fn is_fresh(&self, now: DateTime<Utc>, max_age_secs: u64) -> bool {
+        now - self.time <= Duration::seconds(max_age_secs as i64)
+    }
+
+    pub fn mid(&self) -> f64 {
+        (self.bid + self.ask) / 2.0
+    }
+
+    pub fn spread(&self) -> f64 {
+        self.ask - self.bid
+    }
+
+    pub fn spread_bps(&self) -> f64 {
+        self.spread() / self.mid() * 10_000.0
+    }
+
+    //buys pay up from the ask, sells give down from the bid, rounded to the cent
+    //away from the touch so the order stays marketable
+    pub fn marketable_limit(&self, action: &Action, offset_bps: f64) -> Option<f64> {
+        let offset = offset_bps / 10_000.0;
+        match action {
+            Action::Buy => Some((self.ask * (1.0 + offset) * 100.0).ceil() / 100.0),
+            Action::Sell => Some((self.bid * (1.0 - offset) * 100.0).floor() / 100.0),
+            Action::Hold => None,
+        }
+    }
+}
+
+impl QuoteConf {
+    //the book if it is recent enough for the bar at now
+    pub fn fresh(&self, book: Option<TopOfBook>, now: DateTime<Utc>) -> Option<TopOfBook> {
+        book.filter(|b| b.is_fresh(now, self.max_age_secs))
+    }
+
+    //false if a spread filter is set and the book is missing or too wide
+    pub fn spread_ok(&self, book: Option<&TopOfBook>) -> bool {
+        match (self.max_spread_bps, book) {
+            (None, _) => true,
+            (Some(max), Some(book)) => book.spread_bps() <= max,
+            (Some(_), None) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book(bid: f64, ask: f64) -> TopOfBook {
+        TopOfBook {
+            bid,
+            ask,
+            bid_size: 100.0,
+            ask_size: 100.0,
+            time: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn top_of_book_test() {
+        let b = book(99.95, 100.05);
+        assert!((b.mid() - 100.0).abs() < 1e-9);
+        assert!((b.spread_bps() - 10.0).abs() < 1e-6);
+        assert_eq!(b.marketable_limit(&Action::Buy, 5.0), Some(100.11));
+        assert_eq!(b.marketable_limit(&Action::Sell, 5.0), Some(99.9));
+        assert_eq!(b.marketable_limit(&Action::Hold, 5.0), None);
+    }
+
+    #[test]
+    fn spread_filter_test() {
+        let conf = QuoteConf {
+            max_spread_bps: Some(20.0),
+            ..Default::default()
+        };
+        assert!(conf.spread_ok(Some(&book(99.95, 100.05))));
+        assert!(!conf.spread_ok(Some(&book(99.0, 101.0))));
+        assert!(!conf.spread_ok(None));
+        assert!(QuoteConf::default().spread_ok(None));
+    }
+
+    #[test]
+    fn quote_age_test() {
+        let conf = QuoteConf::default();
+        let now = Utc::now();
+        let fresh = book(99.95, 100.05);
+        assert_eq!(conf.fresh(Some(fresh), now), Some(fresh));
+        //hours old, treated as no book
+        let old = TopOfBook {
+            time: now - Duration::hours(3),
+            ..fresh
+        };
+        assert_eq!(conf.fresh(Some(old), now), None);
+    }
+}
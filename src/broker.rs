@@ -0,0 +1,303 @@
+//Paper broker, orders rest in a SimBook and fill at the marked prices of the symbol
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard},
+};
+
+use chrono::Utc;
+use tracing::{info, warn};
+
+use crate::{
+    error::{BrokerError, CLIError},
+    notify::{notify, Event},
+    order::{Fill, Filled, OrderSpec, Side, SimBar, SimBook},
+    portfolio::types::Portfolio,
+    telemetry::metrics,
+    trade::StockActions,
+    types::ActionValuator,
+};
+
+//locks are taken in the order book, portfolio, prices
+#[derive(Debug)]
+pub struct PaperBroker {
+    book: Mutex<SimBook>,
+    portfolio: Mutex<Portfolio>,
+    prices: Mutex<HashMap<String, f64>>,
+    //P&L baseline
+    start_cash: f64,
+}
+
+impl PaperBroker {
+    pub fn new(cash: f64) -> Self {
+        PaperBroker {
+            book: Mutex::new(SimBook::default()),
+            portfolio: Mutex::new(Portfolio {
+                name: String::from("Paper Portfolio"),
+                cash: Some(cash),
+                stocks: Some(HashMap::new()),
+            }),
+            prices: Mutex::new(HashMap::new()),
+            start_cash: cash,
+        }
+    }
+
+    pub fn portfolio(&self) -> Portfolio {
+        match self.portfolio.lock() {
+            Ok(port) => port.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    fn lock_portfolio(&self) -> Result<MutexGuard<'_, Portfolio>, CLIError> {
+        self.portfolio
+            .lock()
+            .map_err(|_| CLIError::Lock("paper portfolio"))
+    }
+
+    fn lock_book(&self) -> Result<MutexGuard<'_, SimBook>, CLIError> {
+        self.book.lock().map_err(|_| CLIError::Lock("paper orders"))
+    }
+
+    //resting orders of the symbol in the book
+    pub fn open_orders(&self, symbol: &str) -> usize {
+        self.lock_book().map_or(0, |book| book.open(symbol))
+    }
+
+    //fills the resting orders of symbol at price, a fill the portfolio can't
+    //book cancels its order and is the error
+    fn cross(&self, symbol: &str, price: f64) -> Result<Vec<Fill>, CLIError> {
+        let mut book = self.lock_book()?;
+        let mut port = self.lock_portfolio()?;
+        let mut booked = vec![];
+        let mut rejected = None;
+        book.on_bar(
+            symbol,
+            &SimBar::flat(Utc::now(), price),
+            |fill| match port.fill(fill) {
+                Ok(qty) if qty > 0.0 => {
+                    booked.push(Fill {
+                        qty,
+                        ..fill.clone()
+                    });
+                    true
+                }
+                Ok(_) => false,
+                Err(e) => {
+                    rejected.get_or_insert(e);
+                    false
+                }
+            },
+        );
+        for fill in &booked {
+            info!(
+                "paper {:?} {} {} @ {}",
+                fill.side, fill.qty, fill.symbol, fill.price
+            );
+            let side = format!("{:?}", fill.side).to_lowercase();
+            self.filled(&port, &fill.symbol, &side, fill.qty, fill.price);
+        }
+        match rejected {
+            Some(e) => Err(e.into()),
+            None => Ok(booked),
+        }
+    }
+
+    fn price(&self, symbol: &str) -> Result<f64, CLIError> {
+        let price = self
+            .prices
+            .lock()
+            .map_err(|_| CLIError::Lock("paper prices"))?
+            .get(symbol)
+            .copied();
+        price.ok_or_else(|| BrokerError::NoPrice(symbol.to_string()).into())
+    }
+
+    //exposure and P&L at the marked prices
+    fn publish(&self, port: &Portfolio) {
+        if let Ok(prices) = self.prices.lock() {
+            metrics().portfolio(port, &prices, self.start_cash);
+        }
+    }
+
+    fn filled(&self, port: &Portfolio, symbol: &str, side: &str, qty: f64, price: f64) {
+        metrics().order(symbol, "filled");
+        self.publish(port);
+        notify(Event::Fill {
+            symbol: symbol.to_string(),
+            side: side.to_string(),
+            qty,
+            price,
+        });
+    }
+}
+
+impl StockActions for PaperBroker {
+    async fn stock_buy(&self, av: ActionValuator) -> Result<(), CLIError> {
+        self.submit(OrderSpec::from_av(&av, Side::Buy)).await?;
+        Ok(())
+    }
+
+    //open orders of the symbol, e.g. the exits of an earlier bracket, are canceled first
+    async fn stock_sell(&self, av: ActionValuator) -> Result<(), CLIError> {
+        self.lock_book()?.cancel(&av.symbol);
+        self.submit(OrderSpec::from_av(&av, Side::Sell)).await?;
+        Ok(())
+    }
+
+    //marketable orders fill right away, the others rest until mark_price reaches them
+    async fn submit(&self, spec: OrderSpec) -> Result<Filled, CLIError> {
+        let price = self.price(&spec.symbol)?;
+        self.lock_book()?.submit(&spec, Utc::now())?;
+        let mut filled = Filled::default();
+        //other resting orders of the symbol may cross as well
+        for fill in self.cross(&spec.symbol, price)? {
+            if fill.side == spec.side {
+                filled.add(fill.qty, fill.price);
+            }
+        }
+        Ok(filled)
+    }
+
+    async fn cancel_orders(&self, symbol: &str) -> Result<(), CLIError> {
+        self.lock_book()?.cancel(symbol);
+        Ok(())
+    }
+
+    async fn holdings(&self) -> Result<Portfolio, CLIError> {
+        Ok(self.portfolio())
+    }
+
+    async fn liquidate_all(&self, av: ActionValuator) -> Result<(), CLIError> {
+        *self.lock_book()? = SimBook::default();
+        let mut port = self.lock_portfolio()?;
+        let held: Vec<(String, f64)> = port
+            .stocks
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(_, shares)| *shares > 0.0)
+            .collect();
+        for (symbol, shares) in held {
+            let price = self.price(&symbol)?;
+            port.sell(&symbol, shares, price)?;
+            self.filled(&port, &symbol, "sell", shares, price);
+        }
+        Ok(())
+    }
+
+    async fn close_position(&self, symbol: &str) -> Result<(), CLIError> {
+        let price = self.price(symbol)?;
+        self.lock_book()?.cancel(symbol);
+        let mut port = self.lock_portfolio()?;
+        let owned = port
+            .stocks
+            .as_ref()
+            .and_then(|s| s.get(symbol))
+            .copied()
+            .unwrap_or(0.0);
+        if owned > 0.0 {
+            port.sell(symbol, owned, price)?;
+            info!("paper close {} {} @ {}", owned, symbol, price);
+            self.filled(&port, symbol, "sell", owned, price);
+        }
+        Ok(())
+    }
+
+    fn mark_price(&self, symbol: &str, price: f64) {
+        if let Ok(mut prices) = self.prices.lock() {
+            prices.insert(symbol.to_string(), price);
+        }
+        if let Err(e) = self.cross(symbol, price) {
+            warn!("{symbol}: resting order canceled: {e}");
+        }
+        if let Ok(port) = self.portfolio.lock() {
+            self.publish(&port);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::RiskError, order::Exit, types::Action};
+
+    fn av(action: Action, strength: f64) -> ActionValuator {
+        ActionValuator {
+            symbol: String::from("ORCL"),
+            strength,
+            action,
+            limit_price: None,
+            exit: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn paper_broker_test() -> Result<(), Box<dyn std::error::Error>> {
+        let broker = PaperBroker::new(1000.0);
+        let err = broker.stock_buy(av(Action::Buy, 1.0)).await.unwrap_err();
+        assert!(matches!(err, CLIError::Broker(BrokerError::NoPrice(_))));
+
+        broker.mark_price("ORCL", 50.0);
+        broker.stock_buy(av(Action::Buy, 1.0)).await?;
+        let port = broker.portfolio();
+        assert_eq!(port.cash, Some(500.0));
+        assert_eq!(port.stocks.unwrap()["ORCL"], 10.0);
+
+        broker.mark_price("ORCL", 60.0);
+        broker.stock_sell(av(Action::Sell, 0.5)).await?;
+        assert_eq!(broker.portfolio().cash, Some(800.0));
+
+        //limit below the market, not filled
+        let mut limited = av(Action::Buy, 1.0);
+        limited.limit_price = Some(59.0);
+        broker.stock_buy(limited).await?;
+        assert_eq!(broker.portfolio().cash, Some(800.0));
+
+        broker.liquidate_all(av(Action::Sell, 1.0)).await?;
+        let port = broker.portfolio();
+        assert_eq!(port.cash, Some(1100.0));
+        assert_eq!(port.stocks.unwrap()["ORCL"], 0.0);
+
+        //20 shares at 60 are more than the cash
+        let err = broker.stock_buy(av(Action::Buy, 2.0)).await.unwrap_err();
+        assert!(matches!(
+            err,
+            CLIError::Risk(RiskError::InsufficientCash { .. })
+        ));
+        assert_eq!(broker.portfolio().cash, Some(1100.0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn paper_bracket_test() -> Result<(), Box<dyn std::error::Error>> {
+        let broker = PaperBroker::new(1000.0);
+        broker.mark_price("ORCL", 50.0);
+        let mut entry = av(Action::Buy, 1.0);
+        entry.exit = Some(Exit {
+            take_profit: Some(55.0),
+            stop_loss: Some(45.0),
+            stop_limit: None,
+        });
+        broker.stock_buy(entry).await?;
+        assert_eq!(broker.portfolio().cash, Some(500.0));
+        assert_eq!(broker.open_orders("ORCL"), 2);
+
+        //the take-profit fills and cancels the stop
+        broker.mark_price("ORCL", 52.0);
+        assert_eq!(broker.portfolio().cash, Some(500.0));
+        broker.mark_price("ORCL", 56.0);
+        let port = broker.portfolio();
+        assert_eq!(port.cash, Some(1060.0));
+        assert_eq!(port.stocks.unwrap()["ORCL"], 0.0);
+        assert_eq!(broker.open_orders("ORCL"), 0);
+
+        //a resting limit fills once the price comes down
+        let mut limited = av(Action::Buy, 1.0);
+        limited.limit_price = Some(50.0);
+        broker.stock_buy(limited).await?;
+        assert_eq!(broker.open_orders("ORCL"), 1);
+        broker.mark_price("ORCL", 49.0);
+        assert_eq!(broker.portfolio().cash, Some(570.0));
+        Ok(())
+    }
+}
@@ -0,0 +1,458 @@
+//Exchange trading calendar and sessions. A day is a trading day if it is a
+//weekday and no holiday. TradingCalendar::nyse() knows the NYSE holiday and
+//early close rules, more days can come from a local file or from Alpaca.
+//Session times are exchange time (America/New_York).
+use std::collections::{BTreeMap, BTreeSet};
+
+use apca::{
+    api::v2::{calendar, clock},
+    Client,
+};
+use chrono::{
+    DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc, Weekday,
+};
+use chrono_tz::{America::New_York, Tz};
+use serde::Deserialize;
+
+use crate::error::{CLIError, PersistenceError};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Session {
+    PreMarket,
+    Regular,
+    AfterHours,
+    Closed,
+}
+
+//where the calendar comes from, [calendar] in the config
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CalendarConf {
+    //extra holidays and early closes, see TradingCalendar::from_file
+    pub file: Option<String>,
+    //ask Alpaca's calendar endpoint for the days around today
+    #[serde(default)]
+    pub alpaca: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TradingCalendar {
+    nyse: bool,
+    holidays: BTreeSet<NaiveDate>,
+    early_closes: BTreeMap<NaiveDate, NaiveTime>,
+}
+
+fn time(h: u32, m: u32) -> NaiveTime {
+    NaiveTime::from_hms_opt(h, m, 0).unwrap()
+}
+
+//layout of the calendar file
+#[derive(Debug, Default, Deserialize)]
+struct CalendarFile {
+    #[serde(default)]
+    holidays: Vec<NaiveDate>,
+    //date = "HH:MM"
+    #[serde(default)]
+    early_closes: BTreeMap<NaiveDate, String>,
+}
+
+impl TradingCalendar {
+    //every weekday is a trading day
+    pub fn weekdays() -> Self {
+        TradingCalendar::default()
+    }
+
+    pub fn nyse() -> Self {
+        TradingCalendar {
+            nyse: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_holidays(mut self, holidays: impl IntoIterator<Item = NaiveDate>) -> Self {
+        self.holidays.extend(holidays);
+        self
+    }
+
+    pub fn with_early_close(mut self, day: NaiveDate, close: NaiveTime) -> Self {
+        self.early_closes.insert(day, close);
+        self
+    }
+
+    //toml with holidays = ["2025-01-09"] and [early_closes] 2025-12-24 = "13:00"
+    pub fn from_file(mut self, path: &str) -> Result<Self, CLIError> {
+        let content = std::fs::read_to_string(path).map_err(|e| PersistenceError::Io {
+            path: path.into(),
+            source: e,
+        })?;
+        let file: CalendarFile = toml::from_str(&content)
+            .map_err(|e| CLIError::InvalidConfig(format!("{path}: {e}")))?;
+        self.holidays.extend(file.holidays);
+        for (day, close) in file.early_closes {
+            let close = NaiveTime::parse_from_str(&close, "%H:%M")
+                .map_err(|e| CLIError::InvalidConfig(format!("{path}: early close {day}: {e}")))?;
+            self.early_closes.insert(day, close);
+        }
+        Ok(self)
+    }
+
+    //days in [start, end] Alpaca lists are trading days, other weekdays holidays
+    pub async fn from_alpaca(
+        mut self,
+        client: &Client,
+        start: NaiveDate,
+        end: NaiveDate,
+    ) -> Result<Self, CLIError> {
+        let request = calendar::ListReq { start, end };
+        let days = client
+            .issue::<calendar::List>(&request)
+            .await
+            .map_err(|e| CLIError::Alpaca(e.to_string()))?;
+        let open: BTreeMap<NaiveDate, NaiveTime> = days.iter().map(|d| (d.date, d.close)).collect();
+        for day in start.iter_days().take_while(|d| *d <= end) {
+            match open.get(&day) {
+                Some(close) if *close < time(16, 0) => {
+                    self.early_closes.insert(day, *close);
+                }
+                Some(_) => {}
+                None if !is_weekend(day) => {
+                    self.holidays.insert(day);
+                }
+                None => {}
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn from_conf(conf: &CalendarConf) -> Result<Self, CLIError> {
+        match &conf.file {
+            Some(file) => TradingCalendar::nyse().from_file(file),
+            None => Ok(TradingCalendar::nyse()),
+        }
+    }
+
+    pub fn is_holiday(&self, day: NaiveDate) -> bool {
+        self.holidays.contains(&day) || (self.nyse && nyse_holiday(day))
+    }
+
+    pub fn is_trading_day(&self, day: NaiveDate) -> bool {
+        !is_weekend(day) && !self.is_holiday(day)
+    }
+
+    //trading days in [start, end]
+    pub fn trading_days(&self, start: NaiveDate, end: NaiveDate) -> Vec<NaiveDate> {
+        start
+            .iter_days()
+            .take_while(|d| *d <= end)
+            .filter(|d| self.is_trading_day(*d))
+            .collect()
+    }
+
+    pub fn next_trading_day(&self, day: NaiveDate) -> NaiveDate {
+        let mut next = day + Duration::days(1);
+        while !self.is_trading_day(next) {
+            next += Duration::days(1);
+        }
+        next
+    }
+
+    //close in exchange time, None if the market is closed that day
+    pub fn close_time(&self, day: NaiveDate) -> Option<NaiveTime> {
+        if !self.is_trading_day(day) {
+            return None;
+        }
+        if let Some(close) = self.early_closes.get(&day) {
+            return Some(*close);
+        }
+        if self.nyse && nyse_early_close(day) {
+            return Some(time(13, 0));
+        }
+        Some(time(16, 0))
+    }
+
+    //regular open, None if the market is closed that day
+    pub fn open_at(&self, day: NaiveDate) -> Option<DateTime<Utc>> {
+        if !self.is_trading_day(day) {
+            return None;
+        }
+        exchange_to_utc(day.and_time(time(9, 30)))
+    }
+
+    pub fn close_at(&self, day: NaiveDate) -> Option<DateTime<Utc>> {
+        let close = self.close_time(day)?;
+        exchange_to_utc(day.and_time(close))
+    }
+
+    pub fn session_at(&self, t: DateTime<Utc>) -> Session {
+        let local = t.with_timezone(&New_York);
+        let (day, now) = (local.date_naive(), local.time());
+        let Some(close) = self.close_time(day) else {
+            return Session::Closed;
+        };
+        let after_end = if close < time(16, 0) {
+            time(17, 0)
+        } else {
+            time(20, 0)
+        };
+        if now < time(4, 0) {
+            Session::Closed
+        } else if now < time(9, 30) {
+            Session::PreMarket
+        } else if now < close {
+            Session::Regular
+        } else if now < after_end {
+            Session::AfterHours
+        } else {
+            Session::Closed
+        }
+    }
+
+    //minutes left in the regular session, None outside of it
+    pub fn minutes_to_close(&self, t: DateTime<Utc>) -> Option<i64> {
+        if self.session_at(t) != Session::Regular {
+            return None;
+        }
+        let day = t.with_timezone(&New_York).date_naive();
+        Some((self.close_at(day)? - t).num_minutes())
+    }
+
+    //first regular close after t
+    pub fn next_close(&self, t: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let day = t.with_timezone(&New_York).date_naive();
+        (0..15)
+            .filter_map(|i| self.close_at(day + Duration::days(i)))
+            .find(|close| *close > t)
+    }
+
+    //how the calendar differs from the broker clock at now, None if it agrees
+    pub fn check_clock(
+        &self,
+        now: DateTime<Utc>,
+        open: bool,
+        next_close: DateTime<Utc>,
+    ) -> Option<String> {
+        let ours = self.session_at(now) == Session::Regular;
+        if ours != open {
+            let state = |open| if open { "open" } else { "closed" };
+            return Some(format!(
+                "the broker clock says the market is {}, the calendar {}",
+                state(open),
+                state(ours)
+            ));
+        }
+        match self.next_close(now) {
+            Some(close) if close == next_close => None,
+            close => Some(format!(
+                "the broker clock closes next at {next_close}, the calendar at {close:?}"
+            )),
+        }
+    }
+}
+
+//is the market open right now and when does it close, from Alpaca's clock
+pub async fn alpaca_clock(client: &Client) -> Result<(bool, DateTime<Utc>), CLIError> {
+    let clock = client
+        .issue::<clock::Get>(&())
+        .await
+        .map_err(|e| CLIError::Alpaca(e.to_string()))?;
+    Ok((clock.open, clock.next_close))
+}
+
+pub fn exchange_to_utc(t: NaiveDateTime) -> Option<DateTime<Utc>> {
+    let t: DateTime<Tz> = New_York.from_local_datetime(&t).single()?;
+    Some(t.with_timezone(&Utc))
+}
+
+fn is_weekend(day: NaiveDate) -> bool {
+    matches!(day.weekday(), Weekday::Sat | Weekday::Sun)
+}
+
+//n-th weekday of the month, n = 1 is the first
+fn nth_weekday(year: i32, month: u32, weekday: Weekday, n: u8) -> NaiveDate {
+    NaiveDate::from_weekday_of_month_opt(year, month, weekday, n).unwrap()
+}
+
+fn last_weekday(year: i32, month: u32, weekday: Weekday) -> NaiveDate {
+    NaiveDate::from_weekday_of_month_opt(year, month, weekday, 5)
+        .unwrap_or_else(|| nth_weekday(year, month, weekday, 4))
+}
+
+//Saturday holidays move to Friday, Sunday holidays to Monday
+fn observed(day: NaiveDate) -> NaiveDate {
+    match day.weekday() {
+        Weekday::Sat => day - Duration::days(1),
+        Weekday::Sun => day + Duration::days(1),
+        _ => day,
+    }
+}
+
+//anonymous gregorian algorithm
+fn easter(year: i32) -> NaiveDate {
+    let a = year % 19;
+    let b = year / 100;
+    let c = year % 100;
+    let d = b / 4;
+    let e = b % 4;
+    let f = (b + 8) / 25;
+    let g = (b - f + 1) / 3;
+    let h = (19 * a + b - d - g + 15) % 30;
+    let i = c / 4;
+    let k = c % 4;
+    let l = (32 + 2 * e + 2 * i - h - k) % 7;
+    let m = (a + 11 * h + 22 * l) / 451;
+    let month = (h + l - 7 * m + 114) / 31;
+    let day = (h + l - 7 * m + 114) % 31 + 1;
+    NaiveDate::from_ymd_opt(year, month as u32, day as u32).unwrap()
+}
+
+fn nyse_holidays(year: i32) -> Vec<NaiveDate> {
+    let ymd = |m, d| NaiveDate::from_ymd_opt(year, m, d).unwrap();
+    let mut days = vec![
+        nth_weekday(year, 1, Weekday::Mon, 3),
+        nth_weekday(year, 2, Weekday::Mon, 3),
+        easter(year) - Duration::days(2),
+        last_weekday(year, 5, Weekday::Mon),
+        observed(ymd(7, 4)),
+        nth_weekday(year, 9, Weekday::Mon, 1),
+        nth_weekday(year, 11, Weekday::Thu, 4),
+        observed(ymd(12, 25)),
+    ];
+    //a Saturday New Year is not moved into the old year
+    let new_year = ymd(1, 1);
+    if new_year.weekday() != Weekday::Sat {
+        days.push(observed(new_year));
+    }
+    if year >= 2022 {
+        days.push(observed(ymd(6, 19)));
+    }
+    days
+}
+
+fn nyse_holiday(day: NaiveDate) -> bool {
+    nyse_holidays(day.year()).contains(&day)
+}
+
+//13:00 closes: the day before Independence Day, the day after Thanksgiving, Christmas Eve
+fn nyse_early_close(day: NaiveDate) -> bool {
+    let year = day.year();
+    let ymd = |m, d| NaiveDate::from_ymd_opt(year, m, d).unwrap();
+    let candidates = [
+        ymd(7, 3),
+        nth_weekday(year, 11, Weekday::Thu, 4) + Duration::days(1),
+        ymd(12, 24),
+    ];
+    candidates.contains(&day) && !is_weekend(day) && !nyse_holiday(day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn trading_days_test() {
+        let cal = TradingCalendar::weekdays().with_holidays([day("2024-01-01")]);
+        //Mon holiday, Sat and Sun off
+        let days = cal.trading_days(day("2023-12-30"), day("2024-01-03"));
+        assert_eq!(days, vec![day("2024-01-02"), day("2024-01-03")]);
+        assert_eq!(cal.next_trading_day(day("2023-12-29")), day("2024-01-02"));
+    }
+
+    #[test]
+    fn nyse_holidays_test() {
+        let cal = TradingCalendar::nyse();
+        for d in [
+            "2024-01-01",
+            "2024-01-15",
+            "2024-02-19",
+            "2024-03-29",
+            "2024-05-27",
+            "2024-06-19",
+            "2024-07-04",
+            "2024-09-02",
+            "2024-11-28",
+            "2024-12-25",
+            //Independence Day on a Saturday
+            "2020-07-03",
+        ] {
+            assert!(cal.is_holiday(day(d)), "{d}");
+        }
+        //New Year 2022 was a Saturday, 2021-12-31 was traded
+        assert!(cal.is_trading_day(day("2021-12-31")));
+        assert_eq!(cal.close_time(day("2024-11-29")), Some(time(13, 0)));
+        assert_eq!(cal.close_time(day("2024-12-24")), Some(time(13, 0)));
+        assert_eq!(cal.close_time(day("2024-12-23")), Some(time(16, 0)));
+        assert_eq!(cal.close_time(day("2024-12-25")), None);
+    }
+
+    #[test]
+    fn session_test() {
+        let cal = TradingCalendar::nyse();
+        //2024-07-01 is EDT, UTC-4
+        assert_eq!(cal.session_at(utc("2024-07-01T07:00:00Z")), Session::Closed);
+        assert_eq!(
+            cal.session_at(utc("2024-07-01T12:00:00Z")),
+            Session::PreMarket
+        );
+        assert_eq!(
+            cal.session_at(utc("2024-07-01T13:30:00Z")),
+            Session::Regular
+        );
+        assert_eq!(
+            cal.session_at(utc("2024-07-01T20:30:00Z")),
+            Session::AfterHours
+        );
+        assert_eq!(cal.minutes_to_close(utc("2024-07-01T19:45:00Z")), Some(15));
+        //early close at 13:00
+        assert_eq!(
+            cal.session_at(utc("2024-07-03T17:30:00Z")),
+            Session::AfterHours
+        );
+        assert_eq!(cal.session_at(utc("2024-07-04T15:00:00Z")), Session::Closed);
+    }
+
+    #[test]
+    fn check_clock_test() {
+        let cal = TradingCalendar::nyse();
+        //11:00 EDT before the early close at 13:00
+        let now = utc("2024-07-03T15:00:00Z");
+        assert_eq!(
+            cal.check_clock(now, true, utc("2024-07-03T17:00:00Z")),
+            None
+        );
+        assert!(cal
+            .check_clock(now, true, utc("2024-07-03T20:00:00Z"))
+            .is_some());
+        assert!(cal
+            .check_clock(now, false, utc("2024-07-03T17:00:00Z"))
+            .is_some());
+        //closed over Independence Day, the next close is Friday's
+        let now = utc("2024-07-04T15:00:00Z");
+        assert_eq!(
+            cal.check_clock(now, false, utc("2024-07-05T20:00:00Z")),
+            None
+        );
+    }
+
+    #[test]
+    fn calendar_file_test() -> Result<(), Box<dyn std::error::Error>> {
+        let path = std::env::temp_dir().join(format!("calendar_{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "holidays = [\"2025-01-09\"]\n[early_closes]\n2025-12-26 = \"12:00\"\n",
+        )?;
+        let cal = TradingCalendar::nyse().from_file(&path.to_string_lossy())?;
+        std::fs::remove_file(&path)?;
+        assert!(cal.is_holiday(day("2025-01-09")));
+        assert_eq!(cal.close_time(day("2025-12-26")), Some(time(12, 0)));
+        Ok(())
+    }
+}
@@ -0,0 +1,1068 @@
+//Command line: trader <command> [--config-dir config] [--run-mode development]
+//[--symbols ORCL,AAPL] [--start 2024-01-01] [--end 2024-12-31]
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use apca::data::v2::stream::{Bar, Data};
+use chrono::{Duration, NaiveDate, Utc};
+use clap::{Args, Parser, Subcommand};
+use futures::{future::try_join_all, stream::FuturesUnordered, StreamExt as _};
+use tokio::sync::mpsc;
+use tracing::{error, info, info_span, warn, Instrument as _};
+
+use crate::{
+    asset::{file_stem, AssetClass},
+    backtest::{rebalance_backtest, report_df, write_report, BacktestResult, START_CASH},
+    broker::PaperBroker,
+    calendar::{alpaca_clock, TradingCalendar},
+    config2::Settings,
+    control::{serve_control, Control},
+    data::{
+        cache::{write_csv, BarCache, BarTimeframe, CachedBar},
+        csv_file::data_csv,
+        download::{to_cached, Downloader},
+        quality::{bars_from_df, check_bars, FeedGuard, QualityConfig},
+        supervisor::{cached_stream_bar, symbol_channel, Router, StreamSupervisor, SymbolChange},
+    },
+    error::{CLIError, PersistenceError},
+    execution::{step, Algo, ExecConf, Execution, VolumeProfile},
+    grpc_client::{IndicatorSender, IndicatorService},
+    notify::{self, notify, DailySummary, Event},
+    order::{OrderSpec, Side, SimBar},
+    portfolio::{manager::net_actions, rebalance::RebalanceConf},
+    reload::{ConfigWatcher, ReloadRequest},
+    retry::RetryPolicy,
+    scheduler::{append_snapshot, Job, ScheduleConf, Scheduler, Snapshot, When},
+    telemetry::{metrics, serve_metrics},
+    trade::{alpaca_client, execute, order_qty, watch_fills, StockActions},
+    trader::TraderConfigs,
+    types::Action,
+    validate::load_settings,
+};
+
+#[derive(Parser, Debug)]
+#[command(name = "trader", version, about = "Trading bot for Alpaca")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Commands,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct CommonOpts {
+    /// Directory with default.toml, {run_mode}.toml and local.toml
+    #[arg(long, default_value = "config")]
+    pub config_dir: String,
+    /// Config layer to load on top of default.toml
+    #[arg(long, env = "RUN_MODE", default_value = "development")]
+    pub run_mode: String,
+    /// Only use these symbols of the Stockconfig, comma separated
+    #[arg(long, value_delimiter = ',')]
+    pub symbols: Vec<String>,
+    /// First day to use, YYYY-MM-DD
+    #[arg(long)]
+    pub start: Option<NaiveDate>,
+    /// Last day to use, YYYY-MM-DD
+    #[arg(long)]
+    pub end: Option<NaiveDate>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// Run the configured variants over CSV data and report the results
+    Backtest {
+        #[command(flatten)]
+        common: CommonOpts,
+        /// Directory with one {symbol}.csv per symbol
+        #[arg(long, default_value = "files")]
+        data_dir: String,
+        /// Write the report as CSV to this file
+        #[arg(long)]
+        report: Option<String>,
+        /// Fill the bar cache from Alpaca and export {data_dir}/{symbol}.csv before the run
+        #[arg(long)]
+        download: bool,
+        #[arg(long, default_value = "cache")]
+        cache_dir: String,
+    },
+    /// Trade live bars against a simulated broker
+    Paper {
+        #[command(flatten)]
+        common: CommonOpts,
+    },
+    /// Trade live bars with orders sent to Alpaca
+    Live {
+        #[command(flatten)]
+        common: CommonOpts,
+    },
+    /// Check the config files and exit
+    ValidateConfig {
+        #[command(flatten)]
+        common: CommonOpts,
+    },
+    /// Download bars from Alpaca into the cache and export {out_dir}/{symbol}.csv
+    FetchData {
+        #[command(flatten)]
+        common: CommonOpts,
+        #[arg(long, default_value = "files")]
+        out_dir: String,
+        #[arg(long, default_value = "cache")]
+        cache_dir: String,
+        #[arg(long, value_enum, default_value = "day")]
+        timeframe: BarTimeframe,
+    },
+    /// Trade the Alpaca account to the [rebalance] targets once
+    Rebalance {
+        #[command(flatten)]
+        common: CommonOpts,
+        /// Only print the planned trades
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Backtest every variant with different buffer capacities, best first
+    Optimize {
+        #[command(flatten)]
+        common: CommonOpts,
+        #[arg(long, default_value = "files")]
+        data_dir: String,
+        /// Buffer capacities to try, comma separated
+        #[arg(long, value_delimiter = ',', default_value = "3,5,10,20")]
+        capacities: Vec<usize>,
+    },
+}
+
+impl Commands {
+    pub fn common(&self) -> &CommonOpts {
+        match self {
+            Commands::Backtest { common, .. }
+            | Commands::Paper { common }
+            | Commands::Live { common }
+            | Commands::ValidateConfig { common }
+            | Commands::FetchData { common, .. }
+            | Commands::Rebalance { common, .. }
+            | Commands::Optimize { common, .. } => common,
+        }
+    }
+}
+
+pub async fn run(cli: Cli) -> Result<(), CLIError> {
+    match cli.command {
+        Commands::Backtest {
+            common,
+            data_dir,
+            report,
+            download,
+            cache_dir,
+        } => {
+            let settings = settings(&common, download)?;
+            if download {
+                let symbols: Vec<String> = settings.Stockconfig.keys().cloned().collect();
+                fetch_data(&common, &symbols, &cache_dir, &data_dir, BarTimeframe::Day).await?;
+            }
+            quality_report(&settings, &data_dir)?;
+            let basket = settings.rebalance.clone();
+            let mut results = backtest(settings, &data_dir, &common).await?;
+            if !basket.is_empty() {
+                results.push(rebalance_backtest(
+                    &basket,
+                    &data_dir,
+                    common.start,
+                    common.end,
+                )?);
+            }
+            println!("{}", report_df(&results)?);
+            if let Some(path) = report {
+                write_report(&results, &path)?;
+                info!("report written to {path}");
+            }
+            Ok(())
+        }
+        Commands::Paper { common } => {
+            let broker = PaperBroker::new(START_CASH);
+            let res = trade_stream(&common, &broker).await;
+            info!("paper portfolio: {:?}", broker.portfolio());
+            res
+        }
+        Commands::Live { common } => {
+            let settings = settings(&common, true)?;
+            let broker = TraderConfigs::new(settings, "", None, "").await?;
+            //orders fill on the broker's side, often long after they were sent
+            tokio::spawn(watch_fills(alpaca_client()?));
+            trade_stream(&common, &broker).await
+        }
+        Commands::ValidateConfig { common } => {
+            let settings = settings(&common, false)?;
+            println!(
+                "{}/{}: ok, {} symbols",
+                common.config_dir,
+                common.run_mode,
+                settings.Stockconfig.len()
+            );
+            Ok(())
+        }
+        Commands::FetchData {
+            common,
+            out_dir,
+            cache_dir,
+            timeframe,
+        } => {
+            let settings = settings(&common, true)?;
+            let symbols: Vec<String> = settings.Stockconfig.keys().cloned().collect();
+            fetch_data(&common, &symbols, &cache_dir, &out_dir, timeframe).await
+        }
+        Commands::Rebalance { common, dry_run } => {
+            let settings = settings(&common, true)?;
+            let conf = settings.rebalance.clone();
+            if conf.is_empty() {
+                return Err(CLIError::InvalidConfig(String::from(
+                    "no [rebalance] targets",
+                )));
+            }
+            let broker = TraderConfigs::new(settings, "", None, "").await?;
+            let port = broker.holdings().await?;
+            let mut symbols: Vec<String> = conf.targets.keys().cloned().collect();
+            symbols.extend(port.stocks.iter().flatten().map(|(s, _)| s.clone()));
+            let prices = latest_prices(&symbols).await?;
+            if dry_run {
+                for trade in conf.plan(&port, &prices)? {
+                    println!(
+                        "{:?} {} {} @ {}",
+                        trade.side, trade.qty, trade.symbol, trade.price
+                    );
+                }
+                return Ok(());
+            }
+            let sent = rebalance(&broker, &conf, &prices).await?;
+            info!("rebalanced with {sent} orders");
+            Ok(())
+        }
+        Commands::Optimize {
+            common,
+            data_dir,
+            capacities,
+        } => {
+            let base = settings(&common, false)?;
+            let mut results = vec![];
+            for capacity in capacities {
+                let mut settings = base.clone();
+                for conf in settings.Stockconfig.values_mut().flatten() {
+                    conf.buff.capacity = capacity;
+                    conf.variant = format!("{}-buf{capacity}", conf.variant);
+                }
+                results.extend(backtest(settings, &data_dir, &common).await?);
+            }
+            results.sort_by(|a, b| b.equity().total_cmp(&a.equity()));
+            println!("{}", report_df(&results)?);
+            Ok(())
+        }
+    }
+}
+
+//validated settings, restricted to --symbols if given
+fn settings(common: &CommonOpts, require_credentials: bool) -> Result<Settings, CLIError> {
+    let mut settings = load_settings(&common.config_dir, &common.run_mode, require_credentials)?;
+    if !common.symbols.is_empty() {
+        for symbol in &common.symbols {
+            if !settings.Stockconfig.contains_key(symbol) {
+                return Err(CLIError::InvalidConfig(format!(
+                    "{symbol} is not in the Stockconfig"
+                )));
+            }
+        }
+        settings
+            .Stockconfig
+            .retain(|symbol, _| common.symbols.contains(symbol));
+    }
+    Ok(settings)
+}
+
+//data quality of every {data_dir}/{symbol}.csv, issues are logged and the backtest
+//runs on the repaired bars, see backtest::load_bars
+fn quality_report(settings: &Settings, data_dir: &str) -> Result<(), CLIError> {
+    for symbol in settings.Stockconfig.keys() {
+        let df = data_csv(format!("{data_dir}/{}.csv", file_stem(symbol)))?;
+        let report = check_bars(
+            symbol,
+            &bars_from_df(&df)?,
+            BarTimeframe::Day,
+            &QualityConfig::for_symbol(symbol),
+        );
+        if report.issues.is_empty() {
+            info!("{symbol}: {} bars, no data issues", report.bars);
+        } else {
+            warn!("data quality {report}");
+        }
+    }
+    Ok(())
+}
+
+async fn backtest(
+    settings: Settings,
+    data_dir: &str,
+    common: &CommonOpts,
+) -> Result<Vec<BacktestResult>, CLIError> {
+    let mut tr = TraderConfigs::new(settings, "", None, "").await?;
+    tr.data_from_csv(data_dir, common.start, common.end).await
+}
+
+//close of the latest minute bar of each symbol
+async fn latest_prices(symbols: &[String]) -> Result<HashMap<String, f64>, CLIError> {
+    let downloader = Downloader::new(alpaca_client()?, BarCache::new("cache"));
+    let end = Utc::now();
+    let mut prices = HashMap::new();
+    for symbol in symbols {
+        //a missing price only matters if the plan needs it
+        match downloader
+            .fetch_range(symbol, BarTimeframe::Minute, end - Duration::days(5), end)
+            .await
+        {
+            Ok(bars) => {
+                if let Some(close) = bars.last().and_then(|b| b.close.to_f64()) {
+                    prices.insert(symbol.clone(), close);
+                }
+            }
+            Err(e) => warn!("{symbol}: no price: {e}"),
+        }
+    }
+    Ok(prices)
+}
+
+//sends the trades that bring the account to the targets, number of orders
+async fn rebalance<B: StockActions>(
+    broker: &B,
+    conf: &RebalanceConf,
+    prices: &HashMap<String, f64>,
+) -> Result<usize, CLIError> {
+    let port = broker.holdings().await?;
+    let trades = conf.plan(&port, prices)?;
+    for trade in &trades {
+        info!(
+            "rebalance {:?} {} {} @ {}",
+            trade.side, trade.qty, trade.symbol, trade.price
+        );
+        broker.submit(trade.order()).await?;
+    }
+    Ok(trades.len())
+}
+
+//jobs of [schedule] and the rebalance of the basket, until the process ends
+async fn run_schedule<B: StockActions>(
+    broker: &B,
+    common: &CommonOpts,
+    tr_config: &Mutex<TraderConfigs>,
+    summary: &Mutex<DailySummary>,
+    calendar: TradingCalendar,
+    conf: ScheduleConf,
+    basket: RebalanceConf,
+) -> Result<(), CLIError> {
+    let mut scheduler = Scheduler::new(calendar, &conf, Utc::now());
+    //half an hour into the session the stream has prices of the basket
+    if !basket.is_empty() && basket.every_days > 0 {
+        scheduler.add(Job::Rebalance, When::AfterOpen(30));
+    }
+    let mut rebalanced: Option<NaiveDate> = None;
+    while let Some(job) = scheduler.next().await {
+        let today = Utc::now().date_naive();
+        let res = match job {
+            //the indicator service kept the bars of the day before
+            Job::Warmup => warm_up(tr_config, conf.warmup_days, None).await,
+            Job::Rebalance => {
+                let every = i64::from(basket.every_days);
+                if rebalanced.is_some_and(|day| (today - day).num_days() < every) {
+                    continue;
+                }
+                let prices = tr_config
+                    .lock()
+                    .map_err(|_| CLIError::Lock("trader"))?
+                    .closes()
+                    .clone();
+                rebalance(broker, &basket, &prices).await.map(|sent| {
+                    info!("rebalanced with {sent} orders");
+                    rebalanced = Some(today);
+                })
+            }
+            Job::Flatten => flatten(broker, tr_config).await,
+            Job::Report => report(broker, tr_config, summary, &conf, today).await,
+            Job::RefreshCache => {
+                let mut symbols = tr_config
+                    .lock()
+                    .map_err(|_| CLIError::Lock("trader"))?
+                    .symbols();
+                symbols.extend(basket.targets.keys().cloned());
+                let range = CommonOpts {
+                    start: Some(today - Duration::days(i64::from(conf.refresh_days))),
+                    end: Some(today),
+                    ..common.clone()
+                };
+                fetch_data(&range, &symbols, "cache", "files", BarTimeframe::Day).await
+            }
+        };
+        match res {
+            Ok(()) => info!("{job:?} done"),
+            Err(e) => warn!("{job:?} failed: {e}"),
+        }
+    }
+    std::future::pending().await
+}
+
+//minute bars of the last days from the cache, downloaded where missing, in front of
+//the buffers of every symbol. The indicator stream gets the history too
+async fn warm_up(
+    tr_config: &Mutex<TraderConfigs>,
+    days: u32,
+    mut indicator_tx: Option<IndicatorSender>,
+) -> Result<(), CLIError> {
+    let symbols = tr_config
+        .lock()
+        .map_err(|_| CLIError::Lock("trader"))?
+        .symbols();
+    let downloader = Downloader::new(alpaca_client()?, BarCache::new("cache"));
+    let end = Utc::now().date_naive();
+    let start = end - Duration::days(i64::from(days));
+    for symbol in symbols {
+        let bars = match downloader
+            .bars(&symbol, BarTimeframe::Minute, start, end)
+            .await
+        {
+            Ok(bars) => bars,
+            Err(e) => {
+                warn!("{symbol}: no warm-up, the stream fills the buffers: {e}");
+                continue;
+            }
+        };
+        let history: Vec<Bar> = bars
+            .iter()
+            .filter_map(|b| cached_stream_bar(&symbol, b))
+            .collect();
+        let (seeded, specs) = {
+            let mut tr = tr_config.lock().map_err(|_| CLIError::Lock("trader"))?;
+            (tr.warm_up(&symbol, &history), tr.indicator_specs(&symbol))
+        };
+        info!(
+            "{symbol}: buffers warmed up with {seeded} of {} bars",
+            history.len()
+        );
+        if let Some(tx) = indicator_tx.as_mut() {
+            for bar in &bars {
+                if !tx
+                    .send(&symbol, bar.time.timestamp_millis(), bar.close, &specs)
+                    .await
+                {
+                    warn!("indicator stream closed, no indicator warm-up");
+                    indicator_tx = None;
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+//closes the stock positions of the Stockconfig, the basket is left alone
+async fn flatten<B: StockActions>(
+    broker: &B,
+    tr_config: &Mutex<TraderConfigs>,
+) -> Result<(), CLIError> {
+    let symbols = tr_config
+        .lock()
+        .map_err(|_| CLIError::Lock("trader"))?
+        .stock_symbols();
+    let port = broker.holdings().await?;
+    let retry = RetryPolicy::default();
+    for symbol in symbols {
+        let shares = port
+            .stocks
+            .as_ref()
+            .and_then(|s| s.get(&symbol))
+            .copied()
+            .unwrap_or(0.0);
+        if shares == 0.0 {
+            continue;
+        }
+        info!("{symbol}: flattening {shares} before the close");
+        retry
+            .run(&format!("flatten {symbol}"), || {
+                broker.close_position(&symbol)
+            })
+            .await?;
+    }
+    Ok(())
+}
+
+//snapshot of the account at the closes and the summary of the day
+async fn report<B: StockActions>(
+    broker: &B,
+    tr_config: &Mutex<TraderConfigs>,
+    summary: &Mutex<DailySummary>,
+    conf: &ScheduleConf,
+    today: NaiveDate,
+) -> Result<(), CLIError> {
+    let port = broker.holdings().await?;
+    let closes = tr_config
+        .lock()
+        .map_err(|_| CLIError::Lock("trader"))?
+        .closes()
+        .clone();
+    let snap = Snapshot::new(today, &port, &closes);
+    let path = std::path::Path::new(&conf.report_dir).join("pnl.csv");
+    let pnl = append_snapshot(&path, &snap)?;
+    info!(
+        "{today}: equity {:.2}, P&L {pnl:.2}, written to {}",
+        snap.equity,
+        path.display()
+    );
+    let event = summary
+        .lock()
+        .map_err(|_| CLIError::Lock("summary"))?
+        .close(pnl);
+    if let Some(event) = event {
+        notify(event);
+    }
+    Ok(())
+}
+
+//fills the cache for the date range, a year up to today by default
+async fn fetch_data(
+    common: &CommonOpts,
+    symbols: &[String],
+    cache_dir: &str,
+    out_dir: &str,
+    tf: BarTimeframe,
+) -> Result<(), CLIError> {
+    let end = common.end.unwrap_or_else(|| Utc::now().date_naive());
+    let start = common.start.unwrap_or(end - Duration::days(365));
+    let downloader = Downloader::from_env(cache_dir)?;
+    std::fs::create_dir_all(out_dir).map_err(|e| PersistenceError::Io {
+        path: out_dir.to_string(),
+        source: e,
+    })?;
+    for symbol in symbols {
+        let bars = downloader.bars(symbol, tf, start, end).await?;
+        let path = format!("{out_dir}/{}.csv", file_stem(symbol));
+        write_csv(&bars, std::path::Path::new(&path), tf)?;
+        info!("{symbol}: {} bars written to {path}", bars.len());
+    }
+    Ok(())
+}
+
+//streams the bars of all configured symbols and trades them through the broker,
+//each symbol in its own loop fed by the stream supervisor of its asset class
+async fn trade_stream<B: StockActions>(common: &CommonOpts, broker: &B) -> Result<(), CLIError> {
+    let settings = settings(common, true)?;
+    //the basket needs prices of its symbols too
+    let mut symbols: Vec<String> = settings.Stockconfig.keys().cloned().collect();
+    for symbol in settings.rebalance.targets.keys() {
+        if !symbols.contains(symbol) {
+            symbols.push(symbol.clone());
+        }
+    }
+    let basket = settings.rebalance.clone();
+    let schedule = settings.schedule.clone();
+    let control_conf = settings.control.clone();
+    let calendar_conf = settings.calendar.clone();
+    let stream_conf = settings.stream.clone();
+    let exec_conf = settings.execution.clone();
+    if let Some(listen) = settings.metrics.listen.clone() {
+        tokio::spawn(serve_metrics(listen));
+    }
+    notify::install(settings.notify.clone());
+    //a down indicator service is not fatal, the health check reconnects
+    let indicators = IndicatorService::from_conf(&settings.grpc);
+    if let Err(e) = indicators.connect().await {
+        warn!("{e}, fallback {:?}", indicators.conf().fallback);
+    }
+    tokio::spawn(indicators.clone().run_health());
+    let mut tr = TraderConfigs::new(settings, "", Some(indicators.clone()), "").await?;
+
+    let client = alpaca_client()?;
+    if calendar_conf.alpaca {
+        let today = Utc::now().date_naive();
+        let calendar = TradingCalendar::from_conf(&calendar_conf)?
+            .from_alpaca(
+                &client,
+                today - Duration::days(7),
+                today + Duration::days(30),
+            )
+            .await?;
+        tr.set_calendar(calendar);
+    }
+    //a calendar that disagrees with the broker trades the wrong sessions
+    match alpaca_clock(&client).await {
+        Ok((open, next_close)) => {
+            if let Some(diff) = tr.calendar().check_clock(Utc::now(), open, next_close) {
+                warn!("calendar: {diff}, check [calendar]");
+            }
+        }
+        Err(e) => warn!("broker clock: {e}"),
+    }
+    let calendar = tr.calendar().clone();
+    let tr_config = Arc::new(Mutex::new(tr));
+
+    //incremental indicator values per bar, without the stream the traders
+    //fall back to batch requests over their buffers
+    let indicator_tx = match indicators.open_stream().await {
+        Ok((tx, mut inbound)) => {
+            let tr_config = tr_config.clone();
+            tokio::spawn(async move {
+                loop {
+                    match inbound.message().await {
+                        Ok(Some(update)) => match tr_config.lock() {
+                            Ok(mut tr) => tr.on_indicators(&update),
+                            Err(_) => break,
+                        },
+                        Ok(None) => break,
+                        Err(status) => {
+                            warn!("indicator stream: {}", status.message());
+                            break;
+                        }
+                    }
+                }
+                warn!("indicator stream closed");
+                if let Ok(mut tr) = tr_config.lock() {
+                    tr.clear_indicators();
+                }
+                notify(Event::Disconnect {
+                    service: String::from("indicator stream"),
+                    msg: String::from("closed, batch requests from now on"),
+                });
+            });
+            Some(tx)
+        }
+        Err(e) => {
+            warn!("no indicator stream: {e}");
+            None
+        }
+    };
+
+    //buffers start from history, signals wait until a variant has its lookback
+    if schedule.warmup_days > 0 {
+        if let Err(e) = warm_up(&tr_config, schedule.warmup_days, indicator_tx.clone()).await {
+            warn!("warm-up failed, the stream fills the buffers: {e}");
+        }
+    }
+
+    //watch config files, a reload can also be requested by POST /reload or SIGHUP;
+    //symbols added or removed by a reload are (un)subscribed and get a trader of
+    //their own
+    let (diff_tx, mut diffs) = mpsc::unbounded_channel();
+    let (watcher, reload_tx) = ConfigWatcher::new(&common.config_dir, &common.run_mode);
+    tokio::spawn(watcher.with_diffs(diff_tx).run(tr_config.clone()));
+    tokio::spawn(reload_on_hangup(reload_tx.clone()));
+    if let Some(listen) = control_conf.listen.clone() {
+        let control = Control {
+            trader: tr_config.clone(),
+            reload: reload_tx.clone(),
+        };
+        tokio::spawn(serve_control(listen, control));
+    }
+
+    //equities and crypto pairs come over different websockets, a supervisor
+    //without symbols waits for a reload to add one
+    let mut streamed: HashSet<String> = symbols.iter().cloned().collect();
+    let mut receivers = HashMap::new();
+    let mut supervisors = vec![];
+    let mut changes = HashMap::new();
+    for class in [AssetClass::Equity, AssetClass::Crypto] {
+        let group: Vec<String> = symbols
+            .iter()
+            .filter(|symbol| AssetClass::of(symbol) == class)
+            .cloned()
+            .collect();
+        let (router, rx) = Router::new(&group, stream_conf.buffer);
+        receivers.extend(rx);
+        let (change_tx, change_rx) = mpsc::unbounded_channel();
+        changes.insert(class, change_tx);
+        let downloader = Downloader::new(alpaca_client()?, BarCache::new("cache"));
+        let supervisor = StreamSupervisor::new(
+            alpaca_client()?,
+            downloader,
+            class,
+            stream_conf.clone(),
+            calendar.clone(),
+        )
+        .with_changes(change_rx);
+        supervisors.push(async move { supervisor.run(router).await });
+    }
+    let summary = Mutex::new(DailySummary::default());
+    let mut traders: FuturesUnordered<_> = receivers
+        .into_iter()
+        .map(|(symbol, rx)| {
+            trade_symbol(
+                symbol,
+                rx,
+                broker,
+                &tr_config,
+                &summary,
+                indicator_tx.clone(),
+                &exec_conf,
+            )
+        })
+        .collect();
+    let supervisors = try_join_all(supervisors);
+    let schedule = run_schedule(
+        broker,
+        common,
+        &tr_config,
+        &summary,
+        calendar.clone(),
+        schedule,
+        basket.clone(),
+    );
+    tokio::pin!(supervisors, schedule);
+    loop {
+        tokio::select! {
+            res = &mut supervisors => return res.map(|_| ()),
+            res = &mut schedule => return res,
+            Some(diff) = diffs.recv() => {
+                for symbol in diff.added {
+                    if !streamed.insert(symbol.clone()) {
+                        continue;
+                    }
+                    info!("{symbol}: added by a reload");
+                    let (tx, rx) = symbol_channel(stream_conf.buffer);
+                    if let Some(changes) = changes.get(&AssetClass::of(&symbol)) {
+                        let _ = changes.send(SymbolChange::Add(symbol.clone(), tx));
+                    }
+                    traders.push(trade_symbol(
+                        symbol,
+                        rx,
+                        broker,
+                        &tr_config,
+                        &summary,
+                        indicator_tx.clone(),
+                        &exec_conf,
+                    ));
+                }
+                //the basket still needs the prices of its symbols
+                for symbol in diff.removed {
+                    if basket.targets.contains_key(&symbol) || !streamed.remove(&symbol) {
+                        continue;
+                    }
+                    info!("{symbol}: removed by a reload");
+                    if let Some(changes) = changes.get(&AssetClass::of(&symbol)) {
+                        let _ = changes.send(SymbolChange::Remove(symbol));
+                    }
+                }
+            }
+            Some(res) = traders.next() => {
+                res?;
+                if traders.is_empty() {
+                    return Err(CLIError::Stream(String::from("every symbol is halted")));
+                }
+            }
+        }
+    }
+}
+
+//SIGHUP asks the ConfigWatcher for a reload, the outcome is logged by the watcher
+#[cfg(unix)]
+async fn reload_on_hangup(reload_tx: mpsc::Sender<ReloadRequest>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("no reload on SIGHUP: {e}");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        info!("SIGHUP, reloading the config");
+        if reload_tx.send(ReloadRequest { reply: None }).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn reload_on_hangup(_reload_tx: mpsc::Sender<ReloadRequest>) {}
+
+//minute volumes of the last profile_days, an empty profile (vwap works like twap)
+//if they can't be loaded
+async fn volume_profile(symbol: &str, conf: &ExecConf) -> VolumeProfile {
+    let end = Utc::now();
+    let start = end - Duration::days(conf.profile_days as i64);
+    let bars = match alpaca_client() {
+        Ok(client) => {
+            Downloader::new(client, BarCache::new("cache"))
+                .fetch_range(symbol, BarTimeframe::Minute, start, end)
+                .await
+        }
+        Err(e) => Err(e),
+    };
+    match bars {
+        Ok(bars) => {
+            let bars = to_cached(symbol, &bars);
+            info!("{symbol}: volume profile of {} minute bars", bars.len());
+            VolumeProfile::from_bars(&bars)
+        }
+        Err(e) => {
+            warn!("{symbol}: no volume profile, vwap slices evenly: {e}");
+            VolumeProfile::default()
+        }
+    }
+}
+
+//report of a finished execution, the exits of its bracket go out for what was bought
+async fn finish_execution<B: StockActions>(broker: &B, exec: Execution) {
+    let report = exec.report();
+    info!("worked order {report}");
+    if report.filled <= 0.0 {
+        return;
+    }
+    let algo = format!("{:?}", report.algo).to_lowercase();
+    metrics()
+        .slippage
+        .with_label_values(&[&algo])
+        .observe(report.slippage_bps);
+    if let (Side::Buy, Some(exit)) = (exec.side, exec.exit) {
+        let spec = OrderSpec::protect(&exec.symbol, report.filled, exit);
+        if let Err(e) = broker.submit(spec).await {
+            error!("{}: exits of the worked order failed: {e}", exec.symbol);
+        }
+    }
+}
+
+//trades the items of one symbol until the stream ends or the symbol is halted
+async fn trade_symbol<B: StockActions>(
+    symbol: String,
+    mut rx: mpsc::Receiver<Data>,
+    broker: &B,
+    tr_config: &Mutex<TraderConfigs>,
+    summary: &Mutex<DailySummary>,
+    mut indicator_tx: Option<IndicatorSender>,
+    exec_conf: &ExecConf,
+) -> Result<(), CLIError> {
+    //vwap slices by the minute volumes of the symbol
+    let profile = if exec_conf.algo == Algo::Vwap {
+        Some(volume_profile(&symbol, exec_conf).await)
+    } else {
+        None
+    };
+    let mut guard = FeedGuard::new(QualityConfig::for_symbol(&symbol));
+    let mut flattened: Option<NaiveDate> = None;
+    let retry = RetryPolicy::default();
+    //larger orders are worked one child per bar
+    let mut working: Option<Execution> = None;
+
+    while let Some(item) = rx.recv().await {
+        let bar = match item {
+            Data::Bar(bar) => bar,
+            Data::Quote(quote) => {
+                let mut tr = tr_config.lock().map_err(|_| CLIError::Lock("trader"))?;
+                tr.on_quote(&quote);
+                continue;
+            }
+            _ => continue,
+        };
+        let span = info_span!("bar", symbol = %bar.symbol, time = %bar.timestamp);
+        metrics().bars.with_label_values(&[&bar.symbol]).inc();
+        let day_over = summary
+            .lock()
+            .map_err(|_| CLIError::Lock("summary"))?
+            .bar(bar.timestamp.date_naive(), metrics().pnl.get());
+        if let Some(event) = day_over {
+            notify(event);
+        }
+        //bars of a corrupted feed still fill the buffers but are not traded
+        let cached = CachedBar::from(&bar);
+        let tradable = span.in_scope(|| guard.accept(&bar.symbol, cached.clone()));
+        let close = bar.close_price.to_f64();
+        if let Some(price) = close {
+            broker.mark_price(&bar.symbol, price);
+        }
+        let timestamp = bar.timestamp;
+        let (actions, flatten, specs, book, exec_conf) = {
+            let mut tr = tr_config.lock().map_err(|_| CLIError::Lock("trader"))?;
+            (
+                span.in_scope(|| tr.on_bar(bar)),
+                tr.flatten_due(&symbol, timestamp),
+                tr.indicator_specs(&symbol),
+                tr.book(&symbol, timestamp).copied(),
+                tr.execution.clone(),
+            )
+        };
+        if let (Some(tx), Some(close)) = (indicator_tx.as_mut(), close) {
+            if !tx.try_send(&symbol, timestamp.timestamp_millis(), close, &specs) {
+                warn!("indicator stream closed, batch requests from now on");
+                indicator_tx = None;
+                tr_config
+                    .lock()
+                    .map_err(|_| CLIError::Lock("trader"))?
+                    .clear_indicators();
+            }
+        }
+        //once per day, no new positions until the close
+        if flatten {
+            let today = timestamp.date_naive();
+            if flattened != Some(today) {
+                info!("{symbol}: flattening before the close");
+                if let Some(exec) = working.take() {
+                    finish_execution(broker, exec)
+                        .instrument(span.clone())
+                        .await;
+                }
+                let what = format!("flatten {symbol}");
+                let res = retry
+                    .run(&what, || broker.close_position(&symbol))
+                    .instrument(span.clone())
+                    .await;
+                if let Err(e) = res {
+                    error!("{symbol}: flatten failed: {e}");
+                    if halt_on_fatal(&symbol, &e) {
+                        return Ok(());
+                    }
+                }
+                flattened = Some(today);
+            }
+            continue;
+        }
+        if !tradable {
+            continue;
+        }
+        if let Some(exec) = working.as_mut() {
+            let res = step(
+                broker,
+                exec,
+                &SimBar::from(&cached),
+                cached.volume,
+                book.as_ref(),
+            )
+            .instrument(span.clone())
+            .await;
+            if let Err(e) = res {
+                error!("{symbol}: child order failed: {e}");
+                if halt_on_fatal(&symbol, &e) {
+                    return Ok(());
+                }
+            }
+        }
+        if let Some(exec) = working.take_if(|exec| exec.is_done(timestamp)) {
+            finish_execution(broker, exec)
+                .instrument(span.clone())
+                .await;
+        }
+        //opposing orders of the variants go out as one
+        for av in net_actions(actions) {
+            //arrival at the mid of the quote, the close without one
+            let arrival = book.map(|b| (b.bid + b.ask) / 2.0).or(close);
+            let side = match av.action {
+                Action::Buy => Some(Side::Buy),
+                Action::Sell => Some(Side::Sell),
+                Action::Hold => None,
+            };
+            if let (Some(side), Some(arrival)) = (side, arrival) {
+                let qty = order_qty(&av);
+                if exec_conf.works(qty) {
+                    if let Some(exec) = working.take() {
+                        finish_execution(broker, exec)
+                            .instrument(span.clone())
+                            .await;
+                    }
+                    //exits of an earlier bracket would hold the shares to sell
+                    if side == Side::Sell {
+                        if let Err(e) = broker.cancel_orders(&symbol).await {
+                            error!("{symbol}: {e}");
+                        }
+                    }
+                    let mut exec = Execution::new(
+                        &symbol,
+                        side,
+                        qty,
+                        arrival,
+                        timestamp,
+                        &exec_conf,
+                        profile.as_ref(),
+                    );
+                    exec.exit = av.exit;
+                    info!("{symbol}: working {side:?} {qty} with {:?}", exec_conf.algo);
+                    working = Some(exec);
+                    continue;
+                }
+            }
+            let res = execute(broker, av, &retry).instrument(span.clone()).await;
+            summary
+                .lock()
+                .map_err(|_| CLIError::Lock("summary"))?
+                .order(res.is_err());
+            if let Err(e) = res {
+                error!("{symbol}: order failed: {e}");
+                if let CLIError::Risk(risk) = &e {
+                    notify(Event::Risk {
+                        symbol: symbol.clone(),
+                        msg: risk.to_string(),
+                    });
+                }
+                if halt_on_fatal(&symbol, &e) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+//true if the symbol has to stop
+fn halt_on_fatal(symbol: &str, e: &CLIError) -> bool {
+    if !e.is_fatal() {
+        return false;
+    }
+    error!("{symbol}: halted until restart");
+    notify(Event::Risk {
+        symbol: symbol.to_string(),
+        msg: format!("halted until restart: {e}"),
+    });
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_backtest_test() {
+        let cli = Cli::try_parse_from([
+            "trader",
+            "backtest",
+            "--symbols",
+            "ORCL,AAPL",
+            "--start",
+            "2024-01-02",
+            "--report",
+            "report.csv",
+        ])
+        .unwrap();
+        let Commands::Backtest { common, report, .. } = cli.command else {
+            panic!("expected backtest");
+        };
+        assert_eq!(common.symbols, vec!["ORCL", "AAPL"]);
+        assert_eq!(common.start, NaiveDate::from_ymd_opt(2024, 1, 2));
+        assert_eq!(common.config_dir, "config");
+        assert_eq!(report.as_deref(), Some("report.csv"));
+    }
+
+    #[test]
+    fn parse_fetch_data_test() {
+        let cli = Cli::try_parse_from(["trader", "fetch-data", "--timeframe", "hour"]).unwrap();
+        let Commands::FetchData {
+            timeframe,
+            cache_dir,
+            ..
+        } = cli.command
+        else {
+            panic!("expected fetch-data");
+        };
+        assert_eq!(timeframe, BarTimeframe::Hour);
+        assert_eq!(cache_dir, "cache");
+    }
+
+    #[test]
+    fn parse_optimize_test() {
+        let cli = Cli::try_parse_from(["trader", "optimize", "--capacities", "2,4"]).unwrap();
+        let Commands::Optimize { capacities, .. } = cli.command else {
+            panic!("expected optimize");
+        };
+        assert_eq!(capacities, vec![2, 4]);
+        assert!(Cli::try_parse_from(["trader", "backtest", "--start", "yesterday"]).is_err());
+    }
+}
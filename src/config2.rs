@@ -1,6 +1,15 @@
 use crate::{
+    calendar::CalendarConf,
+    control::ControlConf,
+    data::supervisor::StreamConf,
+    execution::ExecConf,
+    grpc_client::ClientConf,
+    notify::NotifyConf,
     portfolio::types::TraderConf,
+    portfolio::{manager::AllocConf, rebalance::RebalanceConf},
     proto::{self},
+    scheduler::ScheduleConf,
+    telemetry::{LoggingConf, MetricsConf},
 };
 use apca::data::v2::stream::Bar;
 use config::{Config, ConfigError, Environment, File};
@@ -28,12 +37,17 @@ pub struct TraderConf {
     pub buff: Buffer,
 }
  */
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
 #[allow(unused)]
 pub struct AppConfig {
     pub grpcport: String,
     pub username: String,
     pub password: String,
+    pub baseurl: Option<String>,
+    //deadlines, retries and fallback of the indicator client
+    #[serde(default)]
+    pub client: ClientConf,
 }
 
 /* #[derive(Debug, Deserialize, Clone)]
@@ -52,27 +66,54 @@ pub enum IndicatorType {
     StandardDeviation = 10,
 } */
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 #[allow(unused)]
 pub(crate) struct Settings {
     pub Stockconfig: HashMap<String, Vec<TraderConf>>,
     pub grpc: AppConfig,
+    #[serde(default)]
+    pub calendar: CalendarConf,
+    #[serde(default)]
+    pub logging: LoggingConf,
+    #[serde(default)]
+    pub metrics: MetricsConf,
+    #[serde(default)]
+    pub notify: NotifyConf,
+    #[serde(default)]
+    pub stream: StreamConf,
+    #[serde(default)]
+    pub execution: ExecConf,
+    //shared capital of the variants in backtests
+    #[serde(default)]
+    pub allocation: Option<AllocConf>,
+    //long-term basket kept at target weights
+    #[serde(default)]
+    pub rebalance: RebalanceConf,
+    //jobs around the trading session
+    #[serde(default)]
+    pub schedule: ScheduleConf,
+    //HTTP control API, off without listen
+    #[serde(default)]
+    pub control: ControlConf,
 }
 
 impl Settings {
     pub(crate) fn new() -> Result<Self, ConfigError> {
         let run_mode = env::var("RUN_MODE").unwrap_or_else(|_| "development".into());
+        Self::from_dir("config", &run_mode)
+    }
 
+    pub(crate) fn from_dir(dir: &str, run_mode: &str) -> Result<Self, ConfigError> {
         let s = Config::builder()
             // Start off by merging in the "default" configuration file
-            .add_source(File::with_name("config/default"))
+            .add_source(File::with_name(&format!("{dir}/default")))
             // Add in the current environment file
             // Default to 'development' env
             // Note that this file is _optional_
-            .add_source(File::with_name(&format!("config/{run_mode}")).required(false))
+            .add_source(File::with_name(&format!("{dir}/{run_mode}")).required(false))
             // Add in a local configuration file
             // This file shouldn't be checked in to git
-            .add_source(File::with_name("config/local").required(false))
+            .add_source(File::with_name(&format!("{dir}/local")).required(false))
             // Add in settings from the environment (with a prefix of APP)
             // Eg.. `APP_DEBUG=1 ./target/app` would set the `debug` key
             .add_source(Environment::with_prefix("app"))
@@ -85,7 +126,13 @@ impl Settings {
         println!("database: {:?}", s.get::<String>("database.url")); */
 
         // You can deserialize (and thus freeze) the entire configuration as
-        s.try_deserialize()
+        let mut settings: Settings = s.try_deserialize()?;
+        //rules are parsed here once, not on every bar
+        for tc in settings.Stockconfig.values_mut().flatten() {
+            tc.compile_rules()
+                .map_err(|e| ConfigError::Message(format!("{}: {e}", tc.variant)))?;
+        }
+        Ok(settings)
     }
 }
 
@@ -100,5 +147,14 @@ fn config_parse_test() -> Result<(), Box<dyn std::error::Error>> {
     println!("{settings:?}");
     //panic!("Test failed, this is a panic to test the error handling in the test framework");
     assert!(settings.is_ok(), "Failed to parse settings");
+    //rules are compiled on load
+    for tc in settings.as_ref().unwrap().Stockconfig.values().flatten() {
+        assert_eq!(
+            tc.rule_set.is_empty(),
+            tc.rules.is_empty(),
+            "{}",
+            tc.variant
+        );
+    }
     Ok(())
 }
@@ -0,0 +1,179 @@
+//HTTP control API of a running trader: GET /warmup lists the warm-up of every
+//variant, GET /warmup/{symbol} the variants of one symbol, POST /reload reloads
+//the config files and answers with what changed or why nothing was applied.
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    extract::{Path, State},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info};
+
+use crate::{
+    error::CLIError,
+    reload::{ConfigDiff, ReloadRequest},
+    trader::{TraderConfigs, WarmupState},
+};
+
+//[control]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ControlConf {
+    //e.g. "127.0.0.1:9185", off without
+    pub listen: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct WarmupReport {
+    //every variant takes signals
+    pub ready: bool,
+    pub variants: Vec<WarmupState>,
+}
+
+impl WarmupReport {
+    fn new(variants: Vec<WarmupState>) -> Self {
+        WarmupReport {
+            ready: variants.iter().all(|v| v.ready),
+            variants,
+        }
+    }
+}
+
+type Trader = Arc<Mutex<TraderConfigs>>;
+
+#[derive(Clone)]
+pub struct Control {
+    pub trader: Trader,
+    //requests to the ConfigWatcher
+    pub reload: mpsc::Sender<ReloadRequest>,
+}
+
+async fn warmup(State(control): State<Control>) -> Result<Json<WarmupReport>, CLIError> {
+    let states = control
+        .trader
+        .lock()
+        .map_err(|_| CLIError::Lock("trader"))?
+        .warmup_state();
+    Ok(Json(WarmupReport::new(states)))
+}
+
+async fn warmup_symbol(
+    State(control): State<Control>,
+    Path(symbol): Path<String>,
+) -> Result<Json<WarmupReport>, CLIError> {
+    let states: Vec<WarmupState> = control
+        .trader
+        .lock()
+        .map_err(|_| CLIError::Lock("trader"))?
+        .warmup_state()
+        .into_iter()
+        .filter(|s| s.symbol == symbol)
+        .collect();
+    if states.is_empty() {
+        return Err(CLIError::NotFound(format!("no variant trades {symbol}")));
+    }
+    Ok(Json(WarmupReport::new(states)))
+}
+
+//a config that fails validation is answered with 422 and the report
+async fn reload(State(control): State<Control>) -> Result<Json<ConfigDiff>, CLIError> {
+    let (reply, applied) = oneshot::channel();
+    control
+        .reload
+        .send(ReloadRequest { reply: Some(reply) })
+        .await
+        .map_err(|_| CLIError::Reload(String::from("config watcher stopped")))?;
+    let diff = applied
+        .await
+        .map_err(|_| CLIError::Reload(String::from("config watcher dropped the request")))??;
+    Ok(Json(diff))
+}
+
+pub fn router(control: Control) -> Router {
+    Router::new()
+        .route("/warmup", get(warmup))
+        .route("/warmup/{symbol}", get(warmup_symbol))
+        .route("/reload", post(reload))
+        .with_state(control)
+}
+
+pub async fn serve_control(listen: String, control: Control) {
+    let listener = match tokio::net::TcpListener::bind(&listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("control API on {listen}: {e}");
+            return;
+        }
+    };
+    info!("control API on http://{listen}/warmup");
+    if let Err(e) = axum::serve(listener, router(control)).await {
+        error!("control API: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config2::Settings;
+    use axum::{http::StatusCode, response::IntoResponse as _};
+
+    async fn control() -> Result<Control, Box<dyn std::error::Error>> {
+        let tr = TraderConfigs::new(Settings::new()?, "", None, "").await?;
+        let (reload, _) = mpsc::channel(1);
+        Ok(Control {
+            trader: Arc::new(Mutex::new(tr)),
+            reload,
+        })
+    }
+
+    #[tokio::test]
+    async fn warmup_test() -> Result<(), Box<dyn std::error::Error>> {
+        let control = control().await?;
+        //nothing buffered yet
+        let Json(report) = warmup(State(control.clone())).await?;
+        assert!(!report.ready);
+        assert!(report
+            .variants
+            .iter()
+            .all(|v| v.bars == 0 && v.seeded.is_none()));
+
+        let Json(orcl) = warmup_symbol(State(control.clone()), Path(String::from("ORCL"))).await?;
+        assert!(orcl.variants.iter().all(|v| v.symbol == "ORCL"));
+        let unknown = warmup_symbol(State(control), Path(String::from("MSFT")))
+            .await
+            .unwrap_err()
+            .into_response();
+        assert_eq!(unknown.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reload_test() -> Result<(), Box<dyn std::error::Error>> {
+        let mut control = control().await?;
+        let (tx, mut requests) = mpsc::channel::<ReloadRequest>(1);
+        control.reload = tx;
+        //a watcher that answers with one added symbol, then a rejected config
+        tokio::spawn(async move {
+            let mut replies = vec![
+                Err(CLIError::InvalidConfig(String::from("capacity is 0"))),
+                Ok(ConfigDiff {
+                    added: vec![String::from("TSLA")],
+                    ..Default::default()
+                }),
+            ];
+            while let Some(req) = requests.recv().await {
+                if let (Some(reply), Some(res)) = (req.reply, replies.pop()) {
+                    let _ = reply.send(res);
+                }
+            }
+        });
+        let Json(diff) = reload(State(control.clone())).await?;
+        assert_eq!(diff.added, vec![String::from("TSLA")]);
+        let rejected = reload(State(control)).await.unwrap_err().into_response();
+        assert_eq!(rejected.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        Ok(())
+    }
+}
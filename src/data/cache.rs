@@ -0,0 +1,394 @@
+//Local bar cache, one CSV per symbol and timeframe in the layout of files/orcl.csv
+//plus a {key}.ranges file with the date ranges that were already downloaded.
+//Bars only exist on trading days, so gaps in the CSV alone don't tell what is missing.
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use apca::data::v2::bars::TimeFrame;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use polars::{
+    df,
+    io::SerReader,
+    prelude::{CsvReadOptions, CsvWriter, DataType, SerWriter},
+};
+
+use crate::{
+    asset::file_stem,
+    data::download::RestBar,
+    error::{CLIError, PersistenceError},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub enum BarTimeframe {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl BarTimeframe {
+    pub fn key(&self) -> &'static str {
+        match self {
+            BarTimeframe::Minute => "1min",
+            BarTimeframe::Hour => "1hour",
+            BarTimeframe::Day => "1day",
+        }
+    }
+
+    pub fn to_apca(self) -> TimeFrame {
+        match self {
+            BarTimeframe::Minute => TimeFrame::OneMinute,
+            BarTimeframe::Hour => TimeFrame::OneHour,
+            BarTimeframe::Day => TimeFrame::OneDay,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CachedBar {
+    pub time: DateTime<Utc>,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: f64,
+}
+
+impl CachedBar {
+    //None if a price doesn't fit an f64
+    pub fn from_rest(b: &RestBar) -> Option<Self> {
+        Some(CachedBar {
+            time: b.time,
+            open: b.open.to_f64()?,
+            high: b.high.to_f64()?,
+            low: b.low.to_f64()?,
+            close: b.close.to_f64()?,
+            volume: b.volume.to_f64()?,
+        })
+    }
+}
+
+impl From<&apca::data::v2::stream::Bar> for CachedBar {
+    fn from(b: &apca::data::v2::stream::Bar) -> Self {
+        CachedBar {
+            time: b.timestamp,
+            open: b.open_price.to_f64().unwrap_or(f64::NAN),
+            high: b.high_price.to_f64().unwrap_or(f64::NAN),
+            low: b.low_price.to_f64().unwrap_or(f64::NAN),
+            close: b.close_price.to_f64().unwrap_or(f64::NAN),
+            volume: b.volume.to_f64().unwrap_or(f64::NAN),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BarCache {
+    dir: PathBuf,
+}
+
+impl BarCache {
+    pub fn new(dir: &str) -> Self {
+        BarCache {
+            dir: PathBuf::from(dir),
+        }
+    }
+
+    pub fn path(&self, symbol: &str, tf: BarTimeframe) -> PathBuf {
+        self.dir
+            .join(format!("{}_{}.csv", file_stem(symbol), tf.key()))
+    }
+
+    fn ranges_path(&self, symbol: &str, tf: BarTimeframe) -> PathBuf {
+        self.dir
+            .join(format!("{}_{}.ranges", file_stem(symbol), tf.key()))
+    }
+
+    //all cached bars, oldest first
+    pub fn load(&self, symbol: &str, tf: BarTimeframe) -> Result<Vec<CachedBar>, CLIError> {
+        let path = self.path(symbol, tf);
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+        read_csv(&path)
+    }
+
+    //merges bars into the cache, a bar with the same time replaces the cached one
+    pub fn store(
+        &self,
+        symbol: &str,
+        tf: BarTimeframe,
+        bars: Vec<CachedBar>,
+    ) -> Result<Vec<CachedBar>, CLIError> {
+        let mut merged: BTreeMap<DateTime<Utc>, CachedBar> = self
+            .load(symbol, tf)?
+            .into_iter()
+            .map(|b| (b.time, b))
+            .collect();
+        merged.extend(bars.into_iter().map(|b| (b.time, b)));
+        let bars: Vec<CachedBar> = merged.into_values().collect();
+        std::fs::create_dir_all(&self.dir).map_err(|e| PersistenceError::Io {
+            path: self.dir.to_string_lossy().to_string(),
+            source: e,
+        })?;
+        write_csv(&bars, &self.path(symbol, tf), tf)?;
+        Ok(bars)
+    }
+
+    pub fn covered(
+        &self,
+        symbol: &str,
+        tf: BarTimeframe,
+    ) -> Result<Vec<(NaiveDate, NaiveDate)>, CLIError> {
+        let path = self.ranges_path(symbol, tf);
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+        let content = std::fs::read_to_string(&path).map_err(|e| PersistenceError::Io {
+            path: path.to_string_lossy().to_string(),
+            source: e,
+        })?;
+        content
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| {
+                let (start, end) = l.split_once(',').unwrap_or((l, l));
+                match (start.trim().parse(), end.trim().parse()) {
+                    (Ok(s), Ok(e)) => Ok((s, e)),
+                    _ => Err(corrupt(&path, format!("bad range {l}"))),
+                }
+            })
+            .collect()
+    }
+
+    pub fn mark_covered(
+        &self,
+        symbol: &str,
+        tf: BarTimeframe,
+        start: NaiveDate,
+        end: NaiveDate,
+    ) -> Result<(), CLIError> {
+        let mut ranges = self.covered(symbol, tf)?;
+        ranges.push((start, end));
+        let content: String = merge_ranges(ranges)
+            .iter()
+            .map(|(s, e)| format!("{s},{e}\n"))
+            .collect();
+        let path = self.ranges_path(symbol, tf);
+        std::fs::write(&path, content).map_err(|e| PersistenceError::Io {
+            path: path.to_string_lossy().to_string(),
+            source: e,
+        })?;
+        Ok(())
+    }
+
+    //the parts of [start, end] that were never downloaded
+    pub fn missing(
+        &self,
+        symbol: &str,
+        tf: BarTimeframe,
+        start: NaiveDate,
+        end: NaiveDate,
+    ) -> Result<Vec<(NaiveDate, NaiveDate)>, CLIError> {
+        Ok(missing_ranges(&self.covered(symbol, tf)?, start, end))
+    }
+}
+
+//sorts and joins overlapping or touching ranges
+pub fn merge_ranges(mut ranges: Vec<(NaiveDate, NaiveDate)>) -> Vec<(NaiveDate, NaiveDate)> {
+    ranges.sort();
+    let mut out: Vec<(NaiveDate, NaiveDate)> = vec![];
+    for (s, e) in ranges {
+        match out.last_mut() {
+            Some(last) if s <= last.1 + Duration::days(1) => last.1 = last.1.max(e),
+            _ => out.push((s, e)),
+        }
+    }
+    out
+}
+
+pub fn missing_ranges(
+    covered: &[(NaiveDate, NaiveDate)],
+    start: NaiveDate,
+    end: NaiveDate,
+) -> Vec<(NaiveDate, NaiveDate)> {
+    let mut out = vec![];
+    let mut from = start;
+    for (s, e) in merge_ranges(covered.to_vec()) {
+        if from > end {
+            break;
+        }
+        if e < from {
+            continue;
+        }
+        if s > from {
+            out.push((from, (s - Duration::days(1)).min(end)));
+        }
+        from = e + Duration::days(1);
+    }
+    if from <= end {
+        out.push((from, end));
+    }
+    out
+}
+
+//daily bars keep the plain date of files/orcl.csv, shorter timeframes RFC 3339
+//the Downloader fetches adjusted bars, their Adj Close is the close
+pub fn write_csv(bars: &[CachedBar], path: &Path, tf: BarTimeframe) -> Result<(), CLIError> {
+    let date = |b: &CachedBar| match tf {
+        BarTimeframe::Day => b.time.format("%Y-%m-%d").to_string(),
+        _ => b.time.to_rfc3339(),
+    };
+    let close: Vec<f64> = bars.iter().map(|b| b.close).collect();
+    let mut df = df! {
+        "Date" => bars.iter().map(date).collect::<Vec<String>>(),
+        "Open" => bars.iter().map(|b| b.open).collect::<Vec<f64>>(),
+        "High" => bars.iter().map(|b| b.high).collect::<Vec<f64>>(),
+        "Low" => bars.iter().map(|b| b.low).collect::<Vec<f64>>(),
+        "Close" => close.clone(),
+        "Adj Close" => close,
+        "Volume" => bars.iter().map(|b| b.volume as u64).collect::<Vec<u64>>(),
+    }?;
+    let mut file = File::create(path).map_err(|e| PersistenceError::Io {
+        path: path.to_string_lossy().to_string(),
+        source: e,
+    })?;
+    CsvWriter::new(&mut file).finish(&mut df)?;
+    Ok(())
+}
+
+fn read_csv(path: &Path) -> Result<Vec<CachedBar>, CLIError> {
+    let df = CsvReadOptions::default()
+        .try_into_reader_with_file_path(Some(path.to_path_buf()))?
+        .finish()?;
+    let date = df.column("Date")?.cast(&DataType::String)?;
+    let open = df.column("Open")?.cast(&DataType::Float64)?;
+    let high = df.column("High")?.cast(&DataType::Float64)?;
+    let low = df.column("Low")?.cast(&DataType::Float64)?;
+    let close = df.column("Close")?.cast(&DataType::Float64)?;
+    let volume = df.column("Volume")?.cast(&DataType::Float64)?;
+
+    let mut bars = Vec::with_capacity(df.height());
+    for i in 0..df.height() {
+        let raw = date.str()?.get(i).unwrap_or_default();
+        let Some(time) = parse_time(raw) else {
+            return Err(corrupt(path, format!("bad Date {raw}")));
+        };
+        bars.push(CachedBar {
+            time,
+            open: open.f64()?.get(i).unwrap_or_default(),
+            high: high.f64()?.get(i).unwrap_or_default(),
+            low: low.f64()?.get(i).unwrap_or_default(),
+            close: close.f64()?.get(i).unwrap_or_default(),
+            volume: volume.f64()?.get(i).unwrap_or_default(),
+        });
+    }
+    Ok(bars)
+}
+
+//a cache file that can't be read back, not retried
+fn corrupt(path: &Path, msg: String) -> CLIError {
+    PersistenceError::Io {
+        path: path.to_string_lossy().to_string(),
+        source: std::io::Error::new(std::io::ErrorKind::InvalidData, msg),
+    }
+    .into()
+}
+
+fn parse_time(raw: &str) -> Option<DateTime<Utc>> {
+    if let Ok(d) = raw.parse::<NaiveDate>() {
+        return Some(d.and_hms_opt(0, 0, 0)?.and_utc());
+    }
+    DateTime::parse_from_rfc3339(raw)
+        .ok()
+        .map(|d| d.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    fn bar(date: &str, close: f64) -> CachedBar {
+        CachedBar {
+            time: parse_time(date).unwrap(),
+            open: close,
+            high: close,
+            low: close,
+            close,
+            volume: 100.0,
+        }
+    }
+
+    #[test]
+    fn missing_ranges_test() {
+        let covered = vec![
+            (day("2024-01-10"), day("2024-01-20")),
+            (day("2024-01-01"), day("2024-01-05")),
+        ];
+        let res = missing_ranges(&covered, day("2024-01-03"), day("2024-01-31"));
+        assert_eq!(
+            res,
+            vec![
+                (day("2024-01-06"), day("2024-01-09")),
+                (day("2024-01-21"), day("2024-01-31")),
+            ]
+        );
+        assert!(missing_ranges(&covered, day("2024-01-11"), day("2024-01-12")).is_empty());
+        assert_eq!(
+            merge_ranges(vec![
+                (day("2024-01-06"), day("2024-01-09")),
+                (day("2024-01-01"), day("2024-01-05")),
+            ]),
+            vec![(day("2024-01-01"), day("2024-01-09"))]
+        );
+    }
+
+    #[test]
+    fn cache_store_test() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("bar_cache_test_{}", std::process::id()));
+        let cache = BarCache::new(&dir.to_string_lossy());
+        cache.store(
+            "ORCL",
+            BarTimeframe::Day,
+            vec![bar("2024-01-02", 1.0), bar("2024-01-03", 2.0)],
+        )?;
+        let bars = cache.store(
+            "ORCL",
+            BarTimeframe::Day,
+            vec![bar("2024-01-03", 3.0), bar("2024-01-01", 0.5)],
+        )?;
+        assert_eq!(bars.len(), 3);
+        assert_eq!(cache.load("ORCL", BarTimeframe::Day)?, bars);
+        assert_eq!(bars[2].close, 3.0);
+
+        cache.mark_covered(
+            "ORCL",
+            BarTimeframe::Day,
+            day("2024-01-01"),
+            day("2024-01-03"),
+        )?;
+        let missing = cache.missing(
+            "ORCL",
+            BarTimeframe::Day,
+            day("2024-01-01"),
+            day("2024-01-05"),
+        )?;
+        assert_eq!(missing, vec![(day("2024-01-04"), day("2024-01-05"))]);
+
+        //a corrupt ranges file is a persistence error, not a config error
+        std::fs::write(
+            cache.ranges_path("ORCL", BarTimeframe::Day),
+            "2024-01-01,soon\n",
+        )?;
+        let err = cache.covered("ORCL", BarTimeframe::Day).unwrap_err();
+        assert!(matches!(err, CLIError::Persistence(_)));
+        assert!(!err.is_retryable() && !err.is_fatal());
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -22,6 +22,7 @@ use polars::{
     },
     series::{IntoSeries, Series},
 };
+use tracing::debug;
 
 use crate::error::CLIError;
 //Data<Bar, Quote, Trade>
@@ -41,7 +42,7 @@ pub fn data_csv(filename: String) -> Result<DataFrame, CLIError> {
 
 fn testtt(i: &mut f64) -> f64 {
     //*i = *i + 1;
-    println!("{:?}", i);
+    debug!("{:?}", i);
     2.0
 }
 
@@ -51,10 +52,10 @@ pub fn make_adder() -> impl FnMut(DateTime<Utc>, f64, f64, f64, f64) -> f64 {
     //Data<Bar, Quote, Trade>
     let mut count = 0;
     move |d: DateTime<Utc>, o: f64, c: f64, h: f64, l: f64| {
-        println!("o:{o}");
-        println!("c:{c}");
-        println!("l:{l}");
-        println!("h:{h}");
+        debug!("o:{o}");
+        debug!("c:{c}");
+        debug!("l:{l}");
+        debug!("h:{h}");
         o + l + testtt(&mut c.clone())
     } // 'move' captures n by value
 }
@@ -77,7 +78,7 @@ pub fn trader(d: DateTime<Utc>, o: f64, c: f64, h: f64, l: f64) -> f64 {
     }
     // Add the new value to the back of the buffer.
     buffer.push_back(bar_new);
-    println!("Buffer length: {}", buffer.len());
+    debug!("Buffer length: {}", buffer.len());
     2.0
 }
 
@@ -92,7 +93,7 @@ pub fn data_stream(filename: String) -> Data<Bar, Quote, Trade> {
     let date = df.column("Date").unwrap().datetime().unwrap();
 
     //.naive_utc()
-    println!("{:?}", df);
+    debug!("{:?}", df);
     //panic!("test)");
 
     let open = df.column("Open").unwrap().f64().unwrap();
@@ -121,7 +122,7 @@ pub fn data_stream(filename: String) -> Data<Bar, Quote, Trade> {
     // Add the new column to the DataFrame
     df.with_column(new_series).unwrap(); */
 
-    println!("{:?}", df);
+    debug!("{:?}", df);
 
     let d: Data<Bar, Quote, Trade> = Data::from(todo!());
 
@@ -0,0 +1,343 @@
+//Pages through bars::List (stocks) or the crypto bars endpoint and fills the BarCache,
+//only ranges that were never downloaded are requested. Rate limited requests (HTTP 429)
+//are retried with backoff. Stock bars are requested split and dividend adjusted.
+use std::{collections::HashMap, time::Duration};
+
+use apca::{
+    data::v2::bars::{Adjustment, Bar, List, ListReqInit},
+    ApiError, Client, RequestError,
+};
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use http_endpoint::{Endpoint, Str};
+use num_decimal::Num;
+use serde::Deserialize;
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+use crate::{
+    asset::AssetClass,
+    data::cache::{BarCache, BarTimeframe, CachedBar},
+    error::{is_rate_limited, CLIError, HttpStatus},
+    trade::alpaca_client,
+};
+
+const DATA_BASE_URL: &str = "https://data.alpaca.markets";
+
+//bar of either bars endpoint, crypto volume is fractional so it stays a Num
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct RestBar {
+    #[serde(rename = "t")]
+    pub time: DateTime<Utc>,
+    #[serde(rename = "o")]
+    pub open: Num,
+    #[serde(rename = "h")]
+    pub high: Num,
+    #[serde(rename = "l")]
+    pub low: Num,
+    #[serde(rename = "c")]
+    pub close: Num,
+    #[serde(rename = "v")]
+    pub volume: Num,
+}
+
+impl From<Bar> for RestBar {
+    fn from(bar: Bar) -> Self {
+        RestBar {
+            time: bar.time,
+            open: bar.open,
+            high: bar.high,
+            low: bar.low,
+            close: bar.close,
+            volume: Num::from(bar.volume),
+        }
+    }
+}
+
+//one page of crypto bars of a pair, apca only knows the stock bars endpoint
+#[derive(Clone, Debug, PartialEq)]
+pub struct CryptoBarsReq {
+    pub symbol: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub timeframe: BarTimeframe,
+    pub page_token: Option<String>,
+}
+
+impl CryptoBarsReq {
+    fn query(&self) -> String {
+        let timeframe = match self.timeframe {
+            BarTimeframe::Minute => "1Min",
+            BarTimeframe::Hour => "1Hour",
+            BarTimeframe::Day => "1Day",
+        };
+        let mut query = url::form_urlencoded::Serializer::new(String::new());
+        query
+            .append_pair("symbols", &self.symbol)
+            .append_pair("timeframe", timeframe)
+            .append_pair(
+                "start",
+                &self.start.to_rfc3339_opts(SecondsFormat::Secs, true),
+            )
+            .append_pair("end", &self.end.to_rfc3339_opts(SecondsFormat::Secs, true))
+            .append_pair("limit", "10000");
+        if let Some(token) = &self.page_token {
+            query.append_pair("page_token", token);
+        }
+        query.finish()
+    }
+}
+
+//bars by pair, a pair without bars in the range is left out
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct CryptoBars {
+    #[serde(default)]
+    pub bars: HashMap<String, Vec<RestBar>>,
+    pub next_page_token: Option<String>,
+}
+
+//429 is no variant of its own, it ends up in UnexpectedStatus for is_rate_limited
+http_endpoint::EndpointDef! {
+    pub CryptoBarsList(CryptoBarsReq),
+    Ok => CryptoBars, [OK,],
+    Err => CryptoBarsError, [BAD_REQUEST => InvalidInput,],
+    ConversionErr => serde_json::Error,
+    ApiErr => ApiError,
+
+    fn base_url() -> Option<Str> {
+        Some(DATA_BASE_URL.into())
+    }
+
+    fn path(_input: &Self::Input) -> Str {
+        "/v1beta3/crypto/us/bars".into()
+    }
+
+    fn query(input: &Self::Input) -> Result<Option<Str>, Self::ConversionError> {
+        Ok(Some(input.query().into()))
+    }
+
+    fn parse(body: &[u8]) -> Result<Self::Output, Self::ConversionError> {
+        serde_json::from_slice(body)
+    }
+
+    fn parse_err(body: &[u8]) -> Result<Self::ApiError, Vec<u8>> {
+        serde_json::from_slice(body).map_err(|_| body.to_vec())
+    }
+}
+
+pub struct Downloader {
+    client: Client,
+    cache: BarCache,
+    max_retries: u32,
+    backoff: Duration,
+}
+
+impl Downloader {
+    pub fn new(client: Client, cache: BarCache) -> Self {
+        Downloader {
+            client,
+            cache,
+            max_retries: 5,
+            backoff: Duration::from_secs(1),
+        }
+    }
+
+    //client from APCA_API_KEY_ID and APCA_API_SECRET_KEY
+    pub fn from_env(cache_dir: &str) -> Result<Self, CLIError> {
+        Ok(Self::new(alpaca_client()?, BarCache::new(cache_dir)))
+    }
+
+    pub fn cache(&self) -> &BarCache {
+        &self.cache
+    }
+
+    //cached bars for [start, end], downloads the missing ranges first
+    pub async fn bars(
+        &self,
+        symbol: &str,
+        tf: BarTimeframe,
+        start: NaiveDate,
+        end: NaiveDate,
+    ) -> Result<Vec<CachedBar>, CLIError> {
+        let mut bars = self.cache.load(symbol, tf)?;
+        for (from, to) in self.cache.missing(symbol, tf, start, end)? {
+            let fetched = self.fetch(symbol, tf, from, to).await?;
+            info!(
+                "{symbol} {}: {} bars for {from}..{to}",
+                tf.key(),
+                fetched.len()
+            );
+            bars = self.cache.store(symbol, tf, fetched)?;
+            //today is not over yet, it has to be fetched again next time
+            let today = Utc::now().date_naive();
+            let complete = to.min(today - chrono::Duration::days(1));
+            if complete >= from {
+                self.cache.mark_covered(symbol, tf, from, complete)?;
+            }
+        }
+        Ok(bars
+            .into_iter()
+            .filter(|b| {
+                let d = b.time.date_naive();
+                d >= start && d <= end
+            })
+            .collect())
+    }
+
+    //every page of bars for [start, end]
+    pub async fn fetch(
+        &self,
+        symbol: &str,
+        tf: BarTimeframe,
+        start: NaiveDate,
+        end: NaiveDate,
+    ) -> Result<Vec<CachedBar>, CLIError> {
+        let start = start.and_hms_opt(0, 0, 0).unwrap().and_utc();
+        let end = end.and_hms_opt(23, 59, 59).unwrap().and_utc();
+        let bars = self.fetch_range(symbol, tf, start, end).await?;
+        Ok(to_cached(symbol, &bars))
+    }
+
+    //every page of bars between two points in time, not cached
+    pub async fn fetch_range(
+        &self,
+        symbol: &str,
+        tf: BarTimeframe,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<RestBar>, CLIError> {
+        match AssetClass::of(symbol) {
+            AssetClass::Equity => self.stock_range(symbol, tf, start, end).await,
+            AssetClass::Crypto => self.crypto_range(symbol, tf, start, end).await,
+        }
+    }
+
+    async fn stock_range(
+        &self,
+        symbol: &str,
+        tf: BarTimeframe,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<RestBar>, CLIError> {
+        let mut bars = vec![];
+        let mut page_token = None;
+        loop {
+            let request = ListReqInit {
+                adjustment: Some(Adjustment::All),
+                page_token: page_token.clone(),
+                ..Default::default()
+            }
+            .init(symbol, start, end, tf.to_apca());
+            let res = self.issue::<List>(&request).await?;
+            bars.extend(res.bars.into_iter().map(RestBar::from));
+            page_token = res.next_page_token;
+            if page_token.is_none() {
+                break;
+            }
+        }
+        Ok(bars)
+    }
+
+    async fn crypto_range(
+        &self,
+        symbol: &str,
+        tf: BarTimeframe,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<RestBar>, CLIError> {
+        let mut bars = vec![];
+        let mut request = CryptoBarsReq {
+            symbol: symbol.to_string(),
+            start,
+            end,
+            timeframe: tf,
+            page_token: None,
+        };
+        loop {
+            let mut res = self.issue::<CryptoBarsList>(&request).await?;
+            bars.extend(res.bars.remove(symbol).unwrap_or_default());
+            request.page_token = res.next_page_token;
+            if request.page_token.is_none() {
+                break;
+            }
+        }
+        Ok(bars)
+    }
+
+    async fn issue<E>(&self, request: &E::Input) -> Result<E::Output, CLIError>
+    where
+        E: Endpoint,
+        E::Error: HttpStatus,
+        CLIError: From<RequestError<E::Error>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match self.client.issue::<E>(request).await {
+                Err(e) if is_rate_limited(&e) && attempt < self.max_retries => {
+                    let wait = self.backoff * 2u32.pow(attempt);
+                    warn!("rate limited, retrying in {:?}", wait);
+                    sleep(wait).await;
+                    attempt += 1;
+                }
+                res => return Ok(res?),
+            }
+        }
+    }
+}
+
+//bars with a price that is no f64 are left out, a 0 would poison backtests
+pub fn to_cached(symbol: &str, bars: &[RestBar]) -> Vec<CachedBar> {
+    bars.iter()
+        .filter_map(|bar| {
+            let cached = CachedBar::from_rest(bar);
+            if cached.is_none() {
+                warn!(
+                    "{symbol}: bar of {} has an invalid price, skipped",
+                    bar.time
+                );
+            }
+            cached
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn crypto_bars_test() -> Result<(), serde_json::Error> {
+        let body = r#"{"bars":{"BTC/USD":[{"t":"2024-03-01T14:30:00Z","o":61000.5,"h":61050,
+            "l":60990,"c":61020.25,"v":0.123456789,"n":12,"vw":61010.1}]},
+            "next_page_token":null}"#;
+        let res = CryptoBarsList::parse(body.as_bytes())?;
+        assert_eq!(res.next_page_token, None);
+        let bars = &res.bars["BTC/USD"];
+        assert_eq!(bars.len(), 1);
+        //the volume keeps its fraction
+        assert_eq!(bars[0].volume.to_string(), "0.123456789");
+        let cached = to_cached("BTC/USD", bars);
+        assert_eq!(cached[0].close, 61020.25);
+        assert!((cached[0].volume - 0.123456789).abs() < 1e-12);
+        //a range without bars has no key for the pair
+        let empty = CryptoBarsList::parse(br#"{"bars":{},"next_page_token":null}"#)?;
+        assert!(empty.bars.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn crypto_query_test() {
+        let request = CryptoBarsReq {
+            symbol: String::from("BTC/USD"),
+            start: Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap(),
+            end: Utc.with_ymd_and_hms(2024, 3, 1, 23, 59, 59).unwrap(),
+            timeframe: BarTimeframe::Minute,
+            page_token: Some(String::from("abc")),
+        };
+        assert_eq!(
+            request.query(),
+            "symbols=BTC%2FUSD&timeframe=1Min&start=2024-03-01T00%3A00%3A00Z\
+             &end=2024-03-01T23%3A59%3A59Z&limit=10000&page_token=abc"
+        );
+    }
+}
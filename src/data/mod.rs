@@ -1,2 +1,7 @@
+pub mod cache;
 pub mod csv_file;
 pub mod datasource;
+pub mod download;
+pub mod panel;
+pub mod quality;
+pub mod supervisor;
@@ -0,0 +1,367 @@
+//Bars of many symbols on one time index, the trading days of a TradingCalendar.
+//Prices are split/dividend adjusted with Adj Close / Close, missing bars are
+//forward filled or left empty, either way they are flagged.
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, NaiveDate};
+use polars::{
+    df,
+    frame::DataFrame,
+    prelude::{Column, DataType, NamedFrom, TimeUnit},
+};
+
+use crate::{
+    asset::file_stem, calendar::TradingCalendar, data::csv_file::data_csv, error::CLIError,
+    types::PriceField,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FillMode {
+    //repeat the last bar, volume 0
+    #[default]
+    ForwardFill,
+    //leave the bar empty
+    Flag,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PanelBar {
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: f64,
+    //true if the bar is not in the data
+    pub filled: bool,
+}
+
+impl PanelBar {
+    //None for vwap, it runs over the series, see Panel::series
+    pub fn field(&self, field: PriceField) -> Option<f64> {
+        Some(match field {
+            PriceField::Open => self.open,
+            PriceField::High => self.high,
+            PriceField::Low => self.low,
+            PriceField::Close => self.close,
+            PriceField::Volume => self.volume,
+            PriceField::Typical => (self.high + self.low + self.close) / 3.0,
+            PriceField::Vwap => return None,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Panel {
+    pub dates: Vec<NaiveDate>,
+    pub symbols: Vec<String>,
+    //per symbol, one entry per date, None if missing and not filled
+    pub bars: HashMap<String, Vec<Option<PanelBar>>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PanelOptions {
+    pub calendar: TradingCalendar,
+    pub start: Option<NaiveDate>,
+    pub end: Option<NaiveDate>,
+    pub fill: FillMode,
+    pub adjust: bool,
+}
+
+impl Default for PanelOptions {
+    fn default() -> Self {
+        PanelOptions {
+            calendar: TradingCalendar::nyse(),
+            start: None,
+            end: None,
+            fill: FillMode::default(),
+            adjust: true,
+        }
+    }
+}
+
+//loads {data_dir}/{symbol}.csv for every symbol
+pub fn load_panel(
+    data_dir: &str,
+    symbols: &[String],
+    opts: &PanelOptions,
+) -> Result<Panel, CLIError> {
+    let mut frames = vec![];
+    for symbol in symbols {
+        let df = data_csv(format!("{data_dir}/{}.csv", file_stem(symbol)))?;
+        frames.push((symbol.clone(), df));
+    }
+    panel_from_frames(frames, opts)
+}
+
+pub fn panel_from_frames(
+    frames: Vec<(String, DataFrame)>,
+    opts: &PanelOptions,
+) -> Result<Panel, CLIError> {
+    let mut raw: Vec<(String, BTreeMap<NaiveDate, PanelBar>)> = vec![];
+    for (symbol, df) in frames {
+        raw.push((symbol, read_bars(&df, opts.adjust)?));
+    }
+
+    //without bounds the index spans all data
+    let first = raw
+        .iter()
+        .filter_map(|(_, b)| b.keys().next())
+        .min()
+        .copied();
+    let last = raw
+        .iter()
+        .filter_map(|(_, b)| b.keys().last())
+        .max()
+        .copied();
+    let (Some(start), Some(end)) = (opts.start.or(first), opts.end.or(last)) else {
+        return Ok(Panel::default());
+    };
+    let dates = opts.calendar.trading_days(start, end);
+
+    let mut panel = Panel {
+        dates: dates.clone(),
+        ..Default::default()
+    };
+    for (symbol, bars) in raw {
+        //last bar before the window seeds the forward fill
+        let mut prev = bars.range(..start).next_back().map(|(_, b)| *b);
+        let aligned = dates
+            .iter()
+            .map(|d| match bars.get(d) {
+                Some(bar) => {
+                    prev = Some(*bar);
+                    Some(*bar)
+                }
+                None => match (opts.fill, prev) {
+                    (FillMode::ForwardFill, Some(p)) => Some(PanelBar {
+                        open: p.close,
+                        high: p.close,
+                        low: p.close,
+                        close: p.close,
+                        volume: 0.0,
+                        filled: true,
+                    }),
+                    _ => None,
+                },
+            })
+            .collect();
+        panel.symbols.push(symbol.clone());
+        panel.bars.insert(symbol, aligned);
+    }
+    Ok(panel)
+}
+
+fn read_bars(df: &DataFrame, adjust: bool) -> Result<BTreeMap<NaiveDate, PanelBar>, CLIError> {
+    let date = df
+        .column("Date")?
+        .cast(&DataType::Datetime(TimeUnit::Milliseconds, None))?;
+    let cols = ["Open", "High", "Low", "Close", "Volume"]
+        .map(|name| df.column(name).and_then(|c| c.cast(&DataType::Float64)));
+    let [open, high, low, close, volume] = cols;
+    let (open, high, low, close, volume) = (open?, high?, low?, close?, volume?);
+    let adj = match df.column("Adj Close") {
+        Ok(c) if adjust => Some(c.cast(&DataType::Float64)?),
+        _ => None,
+    };
+
+    let mut out = BTreeMap::new();
+    let date = date.datetime()?;
+    for i in 0..df.height() {
+        let Some(day) = date
+            .get(i)
+            .and_then(DateTime::from_timestamp_millis)
+            .map(|d| d.date_naive())
+        else {
+            continue;
+        };
+        let (Some(o), Some(h), Some(l), Some(c), Some(v)) = (
+            open.f64()?.get(i),
+            high.f64()?.get(i),
+            low.f64()?.get(i),
+            close.f64()?.get(i),
+            volume.f64()?.get(i),
+        ) else {
+            continue;
+        };
+        let factor = match &adj {
+            Some(adj) => match adj.f64()?.get(i) {
+                Some(a) if c != 0.0 => a / c,
+                _ => 1.0,
+            },
+            None => 1.0,
+        };
+        out.insert(
+            day,
+            PanelBar {
+                open: o * factor,
+                high: h * factor,
+                low: l * factor,
+                close: c * factor,
+                volume: v / factor,
+                filled: false,
+            },
+        );
+    }
+    Ok(out)
+}
+
+impl Panel {
+    //one value per date, None if the bar is missing. Vwap is volume weighted since
+    //the first date, the typical price while no volume has traded
+    pub fn series(&self, symbol: &str, field: PriceField) -> Vec<Option<f64>> {
+        let Some(bars) = self.bars.get(symbol) else {
+            return vec![];
+        };
+        if field != PriceField::Vwap {
+            return bars
+                .iter()
+                .map(|b| b.and_then(|b| b.field(field)))
+                .collect();
+        }
+        let (mut value, mut volume) = (0.0, 0.0);
+        bars.iter()
+            .map(|b| {
+                let b = b.as_ref()?;
+                let price = b.field(PriceField::Typical)?;
+                value += price * b.volume;
+                volume += b.volume;
+                Some(if volume > 0.0 { value / volume } else { price })
+            })
+            .collect()
+    }
+
+    fn date_column(&self, dates: &[NaiveDate]) -> Vec<i64> {
+        dates
+            .iter()
+            .map(|d| d.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp_millis())
+            .collect()
+    }
+
+    //Date, symbol, Open, High, Low, Close, Volume, filled
+    pub fn long_df(&self) -> Result<DataFrame, CLIError> {
+        let mut dates = vec![];
+        let mut symbols = vec![];
+        let mut values: [Vec<Option<f64>>; 5] = Default::default();
+        let mut filled = vec![];
+        for symbol in &self.symbols {
+            for (i, date) in self.dates.iter().enumerate() {
+                let bar = self.bars[symbol][i];
+                dates.push(*date);
+                symbols.push(symbol.clone());
+                for (v, field) in values.iter_mut().zip(FIELDS) {
+                    v.push(bar.and_then(|b| b.field(field)));
+                }
+                filled.push(bar.map_or(true, |b| b.filled));
+            }
+        }
+        let [open, high, low, close, volume] = values;
+        let mut df = df! {
+            "Date" => self.date_column(&dates),
+            "symbol" => symbols,
+            "Open" => open,
+            "High" => high,
+            "Low" => low,
+            "Close" => close,
+            "Volume" => volume,
+            "filled" => filled,
+        }?;
+        cast_date(&mut df)?;
+        Ok(df)
+    }
+
+    //Date plus one column per symbol holding field
+    pub fn wide_df(&self, field: PriceField) -> Result<DataFrame, CLIError> {
+        let mut df = df! { "Date" => self.date_column(&self.dates) }?;
+        for symbol in &self.symbols {
+            df.with_column(Column::new(
+                symbol.as_str().into(),
+                self.series(symbol, field),
+            ))?;
+        }
+        cast_date(&mut df)?;
+        Ok(df)
+    }
+}
+
+const FIELDS: [PriceField; 5] = [
+    PriceField::Open,
+    PriceField::High,
+    PriceField::Low,
+    PriceField::Close,
+    PriceField::Volume,
+];
+
+//same Date type as data_csv
+fn cast_date(df: &mut DataFrame) -> Result<(), CLIError> {
+    let date = df
+        .column("Date")?
+        .cast(&DataType::Datetime(TimeUnit::Milliseconds, None))?;
+    df.with_column(date)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    fn frame(dates: &[&str], close: &[f64], adj: &[f64]) -> DataFrame {
+        let mut df = df! {
+            "Date" => dates.iter().map(|d| day(d).and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp_millis()).collect::<Vec<i64>>(),
+            "Open" => close.to_vec(),
+            "High" => close.to_vec(),
+            "Low" => close.to_vec(),
+            "Close" => close.to_vec(),
+            "Adj Close" => adj.to_vec(),
+            "Volume" => vec![100.0; close.len()],
+        }
+        .unwrap();
+        cast_date(&mut df).unwrap();
+        df
+    }
+
+    #[test]
+    fn panel_align_test() -> Result<(), Box<dyn std::error::Error>> {
+        //2024-01-05 is a Friday, 01-08 the Monday after
+        let a = frame(
+            &["2024-01-04", "2024-01-05", "2024-01-08"],
+            &[10.0, 11.0, 12.0],
+            &[5.0, 5.5, 6.0],
+        );
+        let b = frame(&["2024-01-04", "2024-01-08"], &[20.0, 22.0], &[20.0, 22.0]);
+        let frames = vec![(String::from("A"), a), (String::from("B"), b)];
+
+        let panel = panel_from_frames(frames.clone(), &PanelOptions::default())?;
+        assert_eq!(
+            panel.dates,
+            vec![day("2024-01-04"), day("2024-01-05"), day("2024-01-08")]
+        );
+        //adjusted by Adj Close / Close
+        assert_eq!(
+            panel.series("A", PriceField::Close),
+            vec![Some(5.0), Some(5.5), Some(6.0)]
+        );
+        assert_eq!(panel.bars["A"][0].unwrap().volume, 200.0);
+        //equal volumes, the running mean of the typical prices
+        assert_eq!(
+            panel.series("A", PriceField::Vwap),
+            vec![Some(5.0), Some(5.25), Some(5.5)]
+        );
+        let filled = panel.bars["B"][1].unwrap();
+        assert!(filled.filled);
+        assert_eq!(filled.close, 20.0);
+
+        let opts = PanelOptions {
+            fill: FillMode::Flag,
+            ..Default::default()
+        };
+        let panel = panel_from_frames(frames, &opts)?;
+        assert_eq!(panel.bars["B"][1], None);
+        assert_eq!(panel.long_df()?.shape(), (6, 8));
+        assert_eq!(panel.wide_df(PriceField::Close)?.shape(), (3, 3));
+        Ok(())
+    }
+}
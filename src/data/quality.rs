@@ -0,0 +1,491 @@
+//Data quality checks for ingested bars. check_bars builds a report for a series,
+//repair_bars fixes what can be fixed and FeedGuard blocks live trading of a
+//symbol while its stream looks corrupted.
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+};
+
+use chrono::{DateTime, Utc};
+use polars::{frame::DataFrame, prelude::DataType};
+use tracing::warn;
+
+use crate::{
+    asset::is_crypto,
+    calendar::TradingCalendar,
+    data::cache::{BarTimeframe, CachedBar},
+    error::CLIError,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    //the bar can't be trusted, live trading of the symbol stops
+    Error,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IssueKind {
+    Duplicate,
+    OutOfOrder,
+    Gap,
+    Ohlc,
+    NonPositivePrice,
+    VolumeSpike,
+    Stale,
+}
+
+impl IssueKind {
+    pub fn severity(&self) -> Severity {
+        match self {
+            IssueKind::Duplicate
+            | IssueKind::OutOfOrder
+            | IssueKind::Ohlc
+            | IssueKind::NonPositivePrice => Severity::Error,
+            //quiet minutes of illiquid symbols repeat the same bar
+            IssueKind::Gap | IssueKind::VolumeSpike | IssueKind::Stale => Severity::Warning,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QualityIssue {
+    pub time: DateTime<Utc>,
+    pub kind: IssueKind,
+    pub detail: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QualityReport {
+    pub symbol: String,
+    pub bars: usize,
+    pub issues: Vec<QualityIssue>,
+}
+
+impl QualityReport {
+    pub fn count(&self, kind: IssueKind) -> usize {
+        self.issues.iter().filter(|i| i.kind == kind).count()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.issues
+            .iter()
+            .any(|i| i.kind.severity() == Severity::Error)
+    }
+}
+
+impl fmt::Display for QualityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}: {} bars, {} issues",
+            self.symbol,
+            self.bars,
+            self.issues.len()
+        )?;
+        for i in &self.issues {
+            writeln!(f, "  {} {:?}: {}", i.time, i.kind, i.detail)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct QualityConfig {
+    //bars compared for a volume spike
+    pub volume_window: usize,
+    //volume above factor * median of the window is a spike
+    pub volume_spike_factor: f64,
+    //this many identical bars in a row are stale
+    pub stale_bars: usize,
+    //only daily bars are checked for gaps
+    pub calendar: Option<TradingCalendar>,
+}
+
+impl Default for QualityConfig {
+    fn default() -> Self {
+        QualityConfig {
+            volume_window: 20,
+            volume_spike_factor: 10.0,
+            stale_bars: 5,
+            calendar: Some(TradingCalendar::nyse()),
+        }
+    }
+}
+
+impl QualityConfig {
+    //crypto trades every day, no gaps to check against a calendar
+    pub fn for_symbol(symbol: &str) -> Self {
+        QualityConfig {
+            calendar: (!is_crypto(symbol)).then(TradingCalendar::nyse),
+            ..QualityConfig::default()
+        }
+    }
+}
+
+//bars of a data_csv frame, rows with nulls are skipped
+pub fn bars_from_df(df: &DataFrame) -> Result<Vec<CachedBar>, CLIError> {
+    let date = df.column("Date")?.cast(&DataType::Int64)?;
+    let cols = ["Open", "High", "Low", "Close", "Volume"]
+        .map(|name| df.column(name).and_then(|c| c.cast(&DataType::Float64)));
+    let [open, high, low, close, volume] = cols;
+    let (open, high, low, close, volume) = (open?, high?, low?, close?, volume?);
+    let mut bars = vec![];
+    for i in 0..df.height() {
+        if let (Some(t), Some(o), Some(h), Some(l), Some(c), Some(v)) = (
+            date.i64()?.get(i).and_then(DateTime::from_timestamp_millis),
+            open.f64()?.get(i),
+            high.f64()?.get(i),
+            low.f64()?.get(i),
+            close.f64()?.get(i),
+            volume.f64()?.get(i),
+        ) {
+            bars.push(CachedBar {
+                time: t,
+                open: o,
+                high: h,
+                low: l,
+                close: c,
+                volume: v,
+            });
+        }
+    }
+    Ok(bars)
+}
+
+//checks of a single bar
+fn check_bar(bar: &CachedBar, issues: &mut Vec<QualityIssue>) {
+    let mut push = |kind, detail: String| {
+        issues.push(QualityIssue {
+            time: bar.time,
+            kind,
+            detail,
+        })
+    };
+    if [bar.open, bar.high, bar.low, bar.close]
+        .iter()
+        .any(|p| *p <= 0.0 || !p.is_finite())
+    {
+        push(
+            IssueKind::NonPositivePrice,
+            format!(
+                "o {} h {} l {} c {}",
+                bar.open, bar.high, bar.low, bar.close
+            ),
+        );
+        return;
+    }
+    if bar.high < bar.low {
+        push(
+            IssueKind::Ohlc,
+            format!("high {} below low {}", bar.high, bar.low),
+        );
+    } else {
+        for (name, p) in [("open", bar.open), ("close", bar.close)] {
+            if p < bar.low || p > bar.high {
+                push(
+                    IssueKind::Ohlc,
+                    format!("{name} {p} outside [{}, {}]", bar.low, bar.high),
+                );
+            }
+        }
+    }
+}
+
+fn same_prices(a: &CachedBar, b: &CachedBar) -> bool {
+    a.open == b.open && a.high == b.high && a.low == b.low && a.close == b.close
+}
+
+fn median(values: &mut [f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    Some(values[values.len() / 2])
+}
+
+pub fn check_bars(
+    symbol: &str,
+    bars: &[CachedBar],
+    tf: BarTimeframe,
+    conf: &QualityConfig,
+) -> QualityReport {
+    let mut issues = vec![];
+    let mut seen = HashSet::new();
+    let mut stale_run = 1;
+    for (i, bar) in bars.iter().enumerate() {
+        if !seen.insert(bar.time) {
+            issues.push(QualityIssue {
+                time: bar.time,
+                kind: IssueKind::Duplicate,
+                detail: String::from("timestamp seen before"),
+            });
+        }
+        check_bar(bar, &mut issues);
+        let Some(prev) = i.checked_sub(1).map(|p| &bars[p]) else {
+            continue;
+        };
+        if bar.time < prev.time {
+            issues.push(QualityIssue {
+                time: bar.time,
+                kind: IssueKind::OutOfOrder,
+                detail: format!("after {}", prev.time),
+            });
+        }
+
+        stale_run = if same_prices(bar, prev) {
+            stale_run + 1
+        } else {
+            1
+        };
+        if stale_run >= conf.stale_bars {
+            issues.push(QualityIssue {
+                time: bar.time,
+                kind: IssueKind::Stale,
+                detail: format!("{} identical bars", conf.stale_bars),
+            });
+        }
+
+        let window = &bars[i.saturating_sub(conf.volume_window)..i];
+        let mut volumes: Vec<f64> = window.iter().map(|b| b.volume).collect();
+        if let Some(m) = median(&mut volumes) {
+            if window.len() == conf.volume_window
+                && m > 0.0
+                && bar.volume > m * conf.volume_spike_factor
+            {
+                issues.push(QualityIssue {
+                    time: bar.time,
+                    kind: IssueKind::VolumeSpike,
+                    detail: format!("volume {} vs median {m}", bar.volume),
+                });
+            }
+        }
+    }
+
+    if let (BarTimeframe::Day, Some(cal), Some(first), Some(last)) =
+        (tf, &conf.calendar, bars.first(), bars.last())
+    {
+        let days: HashSet<_> = bars.iter().map(|b| b.time.date_naive()).collect();
+        for day in cal.trading_days(first.time.date_naive(), last.time.date_naive()) {
+            if !days.contains(&day) {
+                issues.push(QualityIssue {
+                    time: day.and_hms_opt(0, 0, 0).unwrap().and_utc(),
+                    kind: IssueKind::Gap,
+                    detail: String::from("no bar on trading day"),
+                });
+            }
+        }
+    }
+
+    QualityReport {
+        symbol: symbol.to_string(),
+        bars: bars.len(),
+        issues,
+    }
+}
+
+//sorted, one bar per timestamp (the last one wins), bars with bad prices dropped,
+//high and low widened to contain open and close
+pub fn repair_bars(bars: &[CachedBar]) -> Vec<CachedBar> {
+    let mut by_time = std::collections::BTreeMap::new();
+    for bar in bars {
+        by_time.insert(bar.time, bar.clone());
+    }
+    by_time
+        .into_values()
+        .filter(|b| {
+            [b.open, b.high, b.low, b.close]
+                .iter()
+                .all(|p| *p > 0.0 && p.is_finite())
+        })
+        .map(|mut b| {
+            let (lo, hi) = (b.low.min(b.high), b.low.max(b.high));
+            b.low = lo.min(b.open).min(b.close);
+            b.high = hi.max(b.open).max(b.close);
+            b
+        })
+        .collect()
+}
+
+//Checks live bars as they arrive. A symbol with an error is blocked until
+//recover_after clean bars in a row were seen.
+#[derive(Debug)]
+pub struct FeedGuard {
+    conf: QualityConfig,
+    recover_after: usize,
+    recent: HashMap<String, VecDeque<CachedBar>>,
+    blocked: HashMap<String, usize>,
+}
+
+impl FeedGuard {
+    pub fn new(conf: QualityConfig) -> Self {
+        FeedGuard {
+            conf,
+            recover_after: 5,
+            recent: HashMap::new(),
+            blocked: HashMap::new(),
+        }
+    }
+
+    pub fn is_blocked(&self, symbol: &str) -> bool {
+        self.blocked.contains_key(symbol)
+    }
+
+    pub fn blocked(&self) -> Vec<String> {
+        let mut out: Vec<String> = self.blocked.keys().cloned().collect();
+        out.sort();
+        out
+    }
+
+    //true if the symbol may be traded on this bar
+    pub fn accept(&mut self, symbol: &str, bar: CachedBar) -> bool {
+        let window = self.conf.volume_window.max(self.conf.stale_bars);
+        let recent = self.recent.entry(symbol.to_string()).or_default();
+        recent.push_back(bar);
+        if recent.len() > window + 1 {
+            recent.pop_front();
+        }
+        let bars: Vec<CachedBar> = recent.iter().cloned().collect();
+        //gaps don't matter intraday, only the newest bar is judged
+        let conf = QualityConfig {
+            calendar: None,
+            ..self.conf.clone()
+        };
+        let last = bars.last().map(|b| b.time);
+        let report = check_bars(symbol, &bars, BarTimeframe::Minute, &conf);
+        let errors: Vec<&QualityIssue> = report
+            .issues
+            .iter()
+            .filter(|i| Some(i.time) == last && i.kind.severity() == Severity::Error)
+            .collect();
+
+        if !errors.is_empty() {
+            if !self.blocked.contains_key(symbol) {
+                warn!(
+                    "{symbol}: feed looks corrupted, trading blocked: {:?}",
+                    errors
+                );
+            }
+            self.blocked.insert(symbol.to_string(), 0);
+            //a bad bar must not poison the next checks
+            if let Some(recent) = self.recent.get_mut(symbol) {
+                recent.pop_back();
+            }
+            return false;
+        }
+        match self.blocked.get_mut(symbol) {
+            None => true,
+            Some(clean) => {
+                *clean += 1;
+                if *clean >= self.recover_after {
+                    self.blocked.remove(symbol);
+                    warn!("{symbol}: feed recovered, trading resumed");
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::csv_file::data_csv;
+
+    fn bar(day: u32, o: f64, h: f64, l: f64, c: f64, v: f64) -> CachedBar {
+        CachedBar {
+            time: chrono::NaiveDate::from_ymd_opt(2024, 1, day)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap()
+                .and_utc(),
+            open: o,
+            high: h,
+            low: l,
+            close: c,
+            volume: v,
+        }
+    }
+
+    #[test]
+    fn check_bars_test() {
+        //2024-01-02 Tue .. 2024-01-08 Mon, 01-05 missing
+        let bars = vec![
+            bar(2, 10.0, 11.0, 9.0, 10.5, 100.0),
+            bar(3, 10.0, 9.0, 11.0, 10.5, 100.0),
+            bar(3, 10.0, 11.0, 9.0, 12.0, 100.0),
+            bar(4, 0.0, 11.0, 9.0, 10.0, 100.0),
+            bar(8, 10.0, 11.0, 9.0, 10.5, 100.0),
+        ];
+        let conf = QualityConfig::default();
+        let report = check_bars("ORCL", &bars, BarTimeframe::Day, &conf);
+        assert_eq!(report.count(IssueKind::Duplicate), 1);
+        assert_eq!(report.count(IssueKind::Ohlc), 2);
+        assert_eq!(report.count(IssueKind::NonPositivePrice), 1);
+        assert_eq!(report.count(IssueKind::Gap), 1);
+        assert!(report.has_errors());
+
+        let repaired = repair_bars(&bars);
+        assert_eq!(repaired.len(), 3);
+        let report = check_bars("ORCL", &repaired, BarTimeframe::Day, &conf);
+        assert!(!report.has_errors(), "{report}");
+
+        //crypto trades on weekends, missing days are not checked
+        let conf = QualityConfig::for_symbol("BTC/USD");
+        let report = check_bars("BTC/USD", &bars, BarTimeframe::Day, &conf);
+        assert_eq!(report.count(IssueKind::Gap), 0);
+    }
+
+    #[test]
+    fn volume_spike_and_stale_test() {
+        let conf = QualityConfig {
+            volume_window: 3,
+            stale_bars: 3,
+            ..Default::default()
+        };
+        let bars = vec![
+            bar(2, 10.0, 11.0, 9.0, 10.0, 100.0),
+            bar(3, 10.0, 11.0, 9.0, 10.5, 100.0),
+            bar(4, 10.0, 11.0, 9.0, 10.0, 100.0),
+            bar(5, 10.0, 11.0, 9.0, 10.5, 5000.0),
+            bar(8, 10.0, 11.0, 9.0, 10.5, 100.0),
+            bar(9, 10.0, 11.0, 9.0, 10.5, 100.0),
+        ];
+        let report = check_bars("ORCL", &bars, BarTimeframe::Day, &conf);
+        assert_eq!(report.count(IssueKind::VolumeSpike), 1);
+        assert_eq!(report.count(IssueKind::Stale), 1);
+    }
+
+    #[test]
+    fn feed_guard_test() {
+        let mut guard = FeedGuard::new(QualityConfig::default());
+        assert!(guard.accept("ORCL", bar(2, 10.0, 11.0, 9.0, 10.5, 100.0)));
+        assert!(!guard.accept("ORCL", bar(3, 10.0, 9.0, 11.0, 10.5, 100.0)));
+        assert_eq!(guard.blocked(), vec![String::from("ORCL")]);
+        for day in 4..8 {
+            assert!(!guard.accept(
+                "ORCL",
+                bar(day, 10.0, 11.0, 9.0, 10.0 + day as f64 / 10.0, 100.0)
+            ));
+        }
+        assert!(guard.accept("ORCL", bar(8, 10.0, 11.0, 9.0, 10.9, 100.0)));
+        assert!(!guard.is_blocked("ORCL"));
+
+        //a quiet symbol repeating its bar keeps trading
+        for day in 2..10 {
+            assert!(guard.accept("MSFT", bar(day, 10.0, 11.0, 9.0, 10.5, 100.0)));
+        }
+    }
+
+    #[test]
+    fn orcl_csv_quality_test() -> Result<(), Box<dyn std::error::Error>> {
+        let bars = bars_from_df(&data_csv(String::from("files/orcl.csv"))?)?;
+        let report = check_bars("ORCL", &bars, BarTimeframe::Day, &QualityConfig::default());
+        assert_eq!(report.bars, bars.len());
+        assert_eq!(report.count(IssueKind::Duplicate), 0);
+        assert_eq!(report.count(IssueKind::OutOfOrder), 0);
+        Ok(())
+    }
+}
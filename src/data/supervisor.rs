@@ -0,0 +1,608 @@
+//Keeps the Alpaca market data websocket alive: reconnects with backoff, subscribes
+//the bars and quotes of every symbol again, treats a silent stream during the
+//regular session as lost and backfills the bars missed in between over REST.
+//Items go to one channel per symbol so a slow symbol never holds up the others.
+//Equities come from the IEX, SIP or a custom feed, crypto pairs from the crypto feed.
+//Symbols added or removed by a config reload are subscribed on the live connection.
+use std::{collections::HashMap, fmt, str::FromStr, sync::OnceLock, time::Duration};
+
+use apca::{
+    data::v2::stream::{drive, Bar, CustomUrl, Data, MarketData, RealtimeData, Source, IEX, SIP},
+    Client,
+};
+use chrono::{DateTime, Utc};
+use futures::{FutureExt as _, StreamExt as _};
+use num_decimal::Num;
+use serde::Deserialize;
+use tokio::{
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    asset::AssetClass,
+    calendar::{Session, TradingCalendar},
+    data::{
+        cache::{BarTimeframe, CachedBar},
+        download::{Downloader, RestBar},
+    },
+    error::CLIError,
+    notify::{notify, Event},
+    retry::RetryPolicy,
+};
+
+const SERVICE: &str = "market data";
+
+//equity feed, crypto pairs always use crypto_url
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Feed {
+    //free, one exchange
+    #[default]
+    Iex,
+    //all exchanges, needs a paid subscription
+    Sip,
+    //url of [stream]
+    Custom,
+}
+
+//[stream]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StreamConf {
+    //reconnect when nothing arrived for this long during the regular session
+    pub stale_secs: u64,
+    //first wait after a lost connection, doubled up to max_backoff_secs
+    pub backoff_ms: u64,
+    pub max_backoff_secs: u64,
+    //fetch the bars missed while disconnected
+    pub backfill: bool,
+    //items queued per symbol before the stream waits for its trader
+    pub buffer: usize,
+    pub feed: Feed,
+    //websocket of feed = "custom", e.g. a proxy in front of the Alpaca stream
+    pub url: Option<String>,
+    pub crypto_url: String,
+}
+
+impl Default for StreamConf {
+    fn default() -> Self {
+        StreamConf {
+            stale_secs: 120,
+            backoff_ms: 1000,
+            max_backoff_secs: 60,
+            backfill: true,
+            buffer: 256,
+            feed: Feed::default(),
+            url: None,
+            crypto_url: String::from("wss://stream.data.alpaca.markets/v1beta3/crypto/us"),
+        }
+    }
+}
+
+//apca takes custom stream urls as types, these read the configured ones
+static FEED_URL: OnceLock<String> = OnceLock::new();
+static CRYPTO_URL: OnceLock<String> = OnceLock::new();
+
+#[derive(Default)]
+struct FeedUrl;
+
+impl fmt::Display for FeedUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(FEED_URL.get().map_or("", String::as_str))
+    }
+}
+
+#[derive(Default)]
+struct CryptoUrl;
+
+impl fmt::Display for CryptoUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(CRYPTO_URL.get().map_or("", String::as_str))
+    }
+}
+
+fn symbol_of(item: &Data) -> Option<&str> {
+    match item {
+        Data::Bar(bar) => Some(&bar.symbol),
+        Data::Quote(quote) => Some(&quote.symbol),
+        Data::Trade(trade) => Some(&trade.symbol),
+        _ => None,
+    }
+}
+
+//channel of one symbol, buffer items deep
+pub fn symbol_channel(buffer: usize) -> (mpsc::Sender<Data>, mpsc::Receiver<Data>) {
+    mpsc::channel(buffer.max(1))
+}
+
+//symbol added or removed while streaming, see StreamSupervisor::with_changes
+#[derive(Clone, Debug)]
+pub enum SymbolChange {
+    //items of the symbol go to the sender
+    Add(String, mpsc::Sender<Data>),
+    //the trader of the symbol sees its channel close
+    Remove(String),
+}
+
+//fans items out to the channel of their symbol, bars at or before the last
+//routed bar of the symbol are dropped so a backfill never repeats a bar
+pub struct Router {
+    channels: HashMap<String, mpsc::Sender<Data>>,
+    last_bar: HashMap<String, DateTime<Utc>>,
+}
+
+impl Router {
+    pub fn new(symbols: &[String], buffer: usize) -> (Self, HashMap<String, mpsc::Receiver<Data>>) {
+        let mut channels = HashMap::new();
+        let mut receivers = HashMap::new();
+        for symbol in symbols {
+            let (tx, rx) = symbol_channel(buffer);
+            channels.insert(symbol.clone(), tx);
+            receivers.insert(symbol.clone(), rx);
+        }
+        let router = Router {
+            channels,
+            last_bar: HashMap::new(),
+        };
+        (router, receivers)
+    }
+
+    pub fn last_bar(&self, symbol: &str) -> Option<DateTime<Utc>> {
+        self.last_bar.get(symbol).copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty()
+    }
+
+    //routed symbols, sorted
+    pub fn symbols(&self) -> Vec<String> {
+        let mut symbols: Vec<String> = self.channels.keys().cloned().collect();
+        symbols.sort();
+        symbols
+    }
+
+    //an added symbol replaces the channel it had, a removed one forgets its last bar
+    pub fn apply(&mut self, change: SymbolChange) {
+        match change {
+            SymbolChange::Add(symbol, tx) => {
+                self.channels.insert(symbol, tx);
+            }
+            SymbolChange::Remove(symbol) => {
+                self.channels.remove(&symbol);
+                self.last_bar.remove(&symbol);
+            }
+        }
+    }
+
+    //false once no trader is left
+    pub async fn route(&mut self, item: Data) -> bool {
+        let Some(symbol) = symbol_of(&item).map(str::to_string) else {
+            return !self.channels.is_empty();
+        };
+        if let Data::Bar(bar) = &item {
+            if self
+                .last_bar(&symbol)
+                .is_some_and(|last| bar.timestamp <= last)
+            {
+                return true;
+            }
+            self.last_bar.insert(symbol.clone(), bar.timestamp);
+        }
+        if let Some(tx) = self.channels.get(&symbol) {
+            //the trader of a halted symbol dropped its receiver
+            if tx.send(item).await.is_err() {
+                info!("{symbol}: trader stopped, no more data");
+                self.channels.remove(&symbol);
+            }
+        }
+        !self.channels.is_empty()
+    }
+}
+
+//REST bar in the shape of a stream bar
+pub fn stream_bar(symbol: &str, bar: &RestBar) -> Bar {
+    Bar {
+        symbol: symbol.to_string(),
+        open_price: bar.open.clone(),
+        high_price: bar.high.clone(),
+        low_price: bar.low.clone(),
+        close_price: bar.close.clone(),
+        volume: bar.volume.clone(),
+        timestamp: bar.time,
+    }
+}
+
+//cached bar in the shape of a stream bar, None if a price is no number
+pub fn cached_stream_bar(symbol: &str, bar: &CachedBar) -> Option<Bar> {
+    let num = |v: f64| Num::from_str(&v.to_string()).ok();
+    Some(Bar {
+        symbol: symbol.to_string(),
+        open_price: num(bar.open)?,
+        high_price: num(bar.high)?,
+        low_price: num(bar.low)?,
+        close_price: num(bar.close)?,
+        volume: num(bar.volume)?,
+        timestamp: bar.time,
+    })
+}
+
+//bars and quotes of the symbols
+fn market_data(symbols: &[String]) -> MarketData {
+    let mut data = MarketData::default();
+    data.set_bars(symbols.to_vec());
+    data.set_quotes(symbols.to_vec());
+    data
+}
+
+//why a connection ended
+enum Ended {
+    Lost(String),
+    //every trader stopped, nothing to stream for
+    Done,
+}
+
+//The symbols are the ones of the Router, all of them equities or all crypto pairs
+pub struct StreamSupervisor {
+    client: Client,
+    downloader: Downloader,
+    conf: StreamConf,
+    calendar: TradingCalendar,
+    //one supervisor per asset class
+    class: AssetClass,
+    changes: Option<mpsc::UnboundedReceiver<SymbolChange>>,
+}
+
+impl StreamSupervisor {
+    pub fn new(
+        client: Client,
+        downloader: Downloader,
+        class: AssetClass,
+        conf: StreamConf,
+        calendar: TradingCalendar,
+    ) -> Self {
+        if let Some(url) = &conf.url {
+            let _ = FEED_URL.set(url.clone());
+        }
+        let _ = CRYPTO_URL.set(conf.crypto_url.clone());
+        StreamSupervisor {
+            client,
+            downloader,
+            conf,
+            calendar,
+            class,
+            changes: None,
+        }
+    }
+
+    //symbols to add or remove while running, without the set is fixed
+    pub fn with_changes(mut self, changes: mpsc::UnboundedReceiver<SymbolChange>) -> Self {
+        self.changes = Some(changes);
+        self
+    }
+
+    //pending until a change arrives, forever once the sender is gone
+    async fn next_change(&mut self) -> SymbolChange {
+        if let Some(changes) = self.changes.as_mut() {
+            if let Some(change) = changes.recv().await {
+                return change;
+            }
+            self.changes = None;
+        }
+        std::future::pending().await
+    }
+
+    //false once the router is empty and no change can fill it
+    async fn wait_for_symbols(&mut self, router: &mut Router) -> bool {
+        while router.is_empty() {
+            let Some(changes) = self.changes.as_mut() else {
+                return false;
+            };
+            match changes.recv().await {
+                Some(change) => router.apply(change),
+                None => return false,
+            }
+        }
+        true
+    }
+
+    fn backoff(&self) -> RetryPolicy {
+        RetryPolicy {
+            attempts: u32::MAX,
+            base: Duration::from_millis(self.conf.backoff_ms),
+            max: Duration::from_secs(self.conf.max_backoff_secs),
+        }
+    }
+
+    //outside the regular session a quiet stream is normal, crypto never closes
+    pub fn stale_matters(&self, now: DateTime<Utc>) -> bool {
+        self.class == AssetClass::Crypto || self.calendar.session_at(now) == Session::Regular
+    }
+
+    //runs until every trader stopped and no symbol can be added, lost connections
+    //are retried forever
+    pub async fn run(mut self, mut router: Router) -> Result<(), CLIError> {
+        let mut attempt = 0;
+        let mut connected = false;
+        loop {
+            if !self.wait_for_symbols(&mut router).await {
+                return Ok(());
+            }
+            match self.session(&mut router, connected).await {
+                //no trader left, idle until a reload adds a symbol
+                Ok(Ended::Done) => {
+                    info!("{SERVICE}: no symbols left to stream");
+                    continue;
+                }
+                Ok(Ended::Lost(msg)) => {
+                    warn!("{SERVICE} lost: {msg}");
+                    notify(Event::Disconnect {
+                        service: SERVICE.to_string(),
+                        msg,
+                    });
+                    connected = true;
+                    attempt = 0;
+                }
+                Err(e) => warn!("{SERVICE}: {e}"),
+            }
+            let wait = self.backoff().delay(attempt);
+            attempt = attempt.saturating_add(1);
+            info!("{SERVICE}: reconnecting in {wait:?}");
+            sleep(wait).await;
+        }
+    }
+
+    async fn session(&mut self, router: &mut Router, reconnect: bool) -> Result<Ended, CLIError> {
+        match (self.class, self.conf.feed) {
+            (AssetClass::Crypto, _) => {
+                self.session_on::<CustomUrl<CryptoUrl>>(router, reconnect)
+                    .await
+            }
+            (AssetClass::Equity, Feed::Iex) => self.session_on::<IEX>(router, reconnect).await,
+            (AssetClass::Equity, Feed::Sip) => self.session_on::<SIP>(router, reconnect).await,
+            (AssetClass::Equity, Feed::Custom) => {
+                self.session_on::<CustomUrl<FeedUrl>>(router, reconnect)
+                    .await
+            }
+        }
+    }
+
+    //one connection: subscribe, backfill after a reconnect, then route until it ends
+    async fn session_on<S: Source>(
+        &mut self,
+        router: &mut Router,
+        reconnect: bool,
+    ) -> Result<Ended, CLIError> {
+        let (mut stream, mut subscription) = self
+            .client
+            .subscribe::<RealtimeData<S>>()
+            .await
+            .map_err(|e| CLIError::Stream(e.to_string()))?;
+        let symbols = router.symbols();
+        let subscribe = subscription.subscribe(&market_data(&symbols)).boxed();
+        let () = drive(subscribe, &mut stream)
+            .await
+            .map_err(|_| CLIError::Stream(String::from("stream ended while subscribing")))?
+            .map_err(|e| CLIError::Stream(format!("{e:?}")))?
+            .map_err(|e| CLIError::Stream(format!("{e:?}")))?;
+        info!("streaming bars and quotes for {symbols:?}");
+        if reconnect {
+            notify(Event::Reconnect {
+                service: SERVICE.to_string(),
+            });
+            if self.conf.backfill && !self.backfill(router).await {
+                return Ok(Ended::Done);
+            }
+        }
+
+        let stale = Duration::from_secs(self.conf.stale_secs.max(1));
+        loop {
+            let next = tokio::select! {
+                change = self.next_change() => Err(change),
+                item = timeout(stale, stream.next()) => Ok(item),
+            };
+            let item = match next {
+                Ok(item) => item,
+                //the connection stays, only the subscription changes
+                Err(change) => {
+                    router.apply(change.clone());
+                    match change {
+                        SymbolChange::Add(symbol, _) => {
+                            let data = market_data(&[symbol.clone()]);
+                            let subscribe = subscription.subscribe(&data).boxed();
+                            let () = drive(subscribe, &mut stream)
+                                .await
+                                .map_err(|_| {
+                                    CLIError::Stream(String::from("stream ended while subscribing"))
+                                })?
+                                .map_err(|e| CLIError::Stream(format!("{e:?}")))?
+                                .map_err(|e| CLIError::Stream(format!("{e:?}")))?;
+                            info!("{symbol}: streaming bars and quotes");
+                        }
+                        SymbolChange::Remove(symbol) => {
+                            let data = market_data(&[symbol.clone()]);
+                            let unsubscribe = subscription.unsubscribe(&data).boxed();
+                            let () = drive(unsubscribe, &mut stream)
+                                .await
+                                .map_err(|_| {
+                                    CLIError::Stream(String::from(
+                                        "stream ended while unsubscribing",
+                                    ))
+                                })?
+                                .map_err(|e| CLIError::Stream(format!("{e:?}")))?
+                                .map_err(|e| CLIError::Stream(format!("{e:?}")))?;
+                            info!("{symbol}: no longer streamed");
+                        }
+                    }
+                    if router.is_empty() {
+                        return Ok(Ended::Done);
+                    }
+                    continue;
+                }
+            };
+            let item = match item {
+                Err(_) if self.stale_matters(Utc::now()) => {
+                    return Ok(Ended::Lost(format!("no data for {stale:?}")))
+                }
+                Err(_) => continue,
+                Ok(None) => return Ok(Ended::Lost(String::from("stream closed"))),
+                Ok(Some(Err(e))) => return Ok(Ended::Lost(e.to_string())),
+                Ok(Some(Ok(Err(e)))) => {
+                    error!("invalid message: {e}");
+                    continue;
+                }
+                Ok(Some(Ok(Ok(item)))) => item,
+            };
+            if !router.route(item).await {
+                return Ok(Ended::Done);
+            }
+        }
+    }
+
+    //minute bars since the last routed bar of each symbol, false once no trader is left
+    async fn backfill(&self, router: &mut Router) -> bool {
+        let now = Utc::now();
+        for symbol in &router.symbols() {
+            let Some(last) = router.last_bar(symbol) else {
+                continue;
+            };
+            let start = last + chrono::Duration::minutes(1);
+            if start >= now {
+                continue;
+            }
+            let bars = match self
+                .downloader
+                .fetch_range(symbol, BarTimeframe::Minute, start, now)
+                .await
+            {
+                Ok(bars) => bars,
+                Err(e) => {
+                    warn!("{symbol}: backfill failed: {e}");
+                    continue;
+                }
+            };
+            info!("{symbol}: {} bars backfilled since {last}", bars.len());
+            for bar in &bars {
+                if !router.route(Data::Bar(stream_bar(symbol, bar))).await {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn bar(symbol: &str, minute: u32, close: i32) -> Data {
+        Data::Bar(Bar {
+            symbol: symbol.to_string(),
+            open_price: Num::from(close),
+            high_price: Num::from(close),
+            low_price: Num::from(close),
+            close_price: Num::from(close),
+            volume: Num::from(100),
+            timestamp: Utc.with_ymd_and_hms(2024, 3, 4, 15, minute, 0).unwrap(),
+        })
+    }
+
+    fn close(item: Data) -> Num {
+        match item {
+            Data::Bar(bar) => bar.close_price,
+            _ => panic!("expected a bar"),
+        }
+    }
+
+    #[tokio::test]
+    async fn router_test() {
+        let symbols = vec![String::from("ORCL"), String::from("MSFT")];
+        let (mut router, mut receivers) = Router::new(&symbols, 8);
+        assert!(router.route(bar("ORCL", 0, 1)).await);
+        assert!(router.route(bar("MSFT", 0, 2)).await);
+        //a backfilled bar the stream already delivered
+        assert!(router.route(bar("ORCL", 0, 3)).await);
+        assert!(router.route(bar("ORCL", 1, 4)).await);
+        //not configured, dropped
+        assert!(router.route(bar("AAPL", 2, 5)).await);
+
+        let orcl = receivers.get_mut("ORCL").unwrap();
+        assert_eq!(close(orcl.recv().await.unwrap()), Num::from(1));
+        assert_eq!(close(orcl.recv().await.unwrap()), Num::from(4));
+        assert!(orcl.try_recv().is_err());
+        let msft = receivers.get_mut("MSFT").unwrap();
+        assert_eq!(close(msft.recv().await.unwrap()), Num::from(2));
+        assert_eq!(
+            router.last_bar("ORCL"),
+            Some(Utc.with_ymd_and_hms(2024, 3, 4, 15, 1, 0).unwrap())
+        );
+
+        //halted symbols drop their receiver, the last one ends the stream
+        receivers.remove("ORCL");
+        assert!(router.route(bar("ORCL", 2, 6)).await);
+        receivers.remove("MSFT");
+        assert!(!router.route(bar("MSFT", 2, 7)).await);
+    }
+
+    #[tokio::test]
+    async fn router_change_test() {
+        let (mut router, mut receivers) = Router::new(&[String::from("ORCL")], 8);
+        //a symbol added by a reload gets its own channel
+        let (tx, mut tsla) = symbol_channel(8);
+        router.apply(SymbolChange::Add(String::from("TSLA"), tx));
+        assert_eq!(
+            router.symbols(),
+            vec![String::from("ORCL"), String::from("TSLA")]
+        );
+        assert!(router.route(bar("TSLA", 0, 1)).await);
+        assert_eq!(close(tsla.recv().await.unwrap()), Num::from(1));
+
+        //a removed symbol closes the channel of its trader
+        assert!(router.route(bar("ORCL", 0, 2)).await);
+        router.apply(SymbolChange::Remove(String::from("ORCL")));
+        assert_eq!(router.last_bar("ORCL"), None);
+        let orcl = receivers.get_mut("ORCL").unwrap();
+        assert_eq!(close(orcl.recv().await.unwrap()), Num::from(2));
+        assert!(orcl.recv().await.is_none());
+    }
+
+    #[test]
+    fn stream_bar_test() {
+        let time = Utc.with_ymd_and_hms(2024, 3, 4, 15, 0, 0).unwrap();
+        let volume = Num::from_str("0.25").unwrap();
+        let rest = RestBar {
+            time,
+            open: Num::from(2),
+            high: Num::from(3),
+            low: Num::from(1),
+            close: Num::from(2),
+            volume: volume.clone(),
+        };
+        //crypto volume keeps its fraction
+        assert_eq!(stream_bar("BTC/USD", &rest).volume, volume);
+        let cached = CachedBar::from_rest(&rest).unwrap();
+        assert_eq!(
+            cached_stream_bar("BTC/USD", &cached).unwrap().volume,
+            volume
+        );
+    }
+
+    #[test]
+    fn stream_conf_test() {
+        let conf: StreamConf = toml::from_str("stale_secs = 30").unwrap();
+        assert_eq!(conf.stale_secs, 30);
+        assert!(conf.backfill);
+        assert_eq!(conf.buffer, 256);
+        assert_eq!(conf.feed, Feed::Iex);
+
+        let conf: StreamConf =
+            toml::from_str("feed = \"custom\"\nurl = \"wss://localhost:9000/iex\"").unwrap();
+        assert_eq!(conf.feed, Feed::Custom);
+        assert_eq!(conf.url.as_deref(), Some("wss://localhost:9000/iex"));
+        assert!(toml::from_str::<StreamConf>("feed = \"otc\"").is_err());
+    }
+}
@@ -4,7 +4,10 @@ use polars::{
     time::DynamicGroupOptions,
 };
 
-use crate::{alpaca_to_polars::S, error::CLIError};
+use crate::{
+    alpaca_to_polars::{bars_to_df, TIME},
+    error::CLIError,
+};
 
 pub fn data_select_column(column: &str, df: DataFrame) -> Result<DataFrame, CLIError> {
     //df.with_column(column("Close").cast::<Float64>())
@@ -19,28 +22,21 @@ pub fn data_select_column(column: &str, df: DataFrame) -> Result<DataFrame, CLIE
 }
 
 fn data(res: apca::data::v2::bars::Bars, span: DynamicGroupOptions) -> Result<DataFrame, CLIError> {
-    let bars = res.bars;
-    let mut s: S = bars.into();
-    s.v = s.v.with_row_index("index".into(), None).unwrap();
-    let n =
-        s.v.clone()
-            .lazy()
-            .select([col("*")])
-            .group_by_dynamic(col("time"), [], span)
-            .agg([col("close").mean().alias("name")])
-            .collect()
-            .unwrap()
-            .with_row_index("index".into(), None)
-            .unwrap();
-    let joined =
-        s.v.join(&n, ["index"], ["index"], JoinType::Left.into(), None)
-            .unwrap();
-    let oo = joined
+    let df = bars_to_df(&res.symbol, &res.bars)?;
+    let df = df.with_row_index("index".into(), None)?;
+    let n = df
         .clone()
+        .lazy()
+        .select([col("*")])
+        .group_by_dynamic(col(TIME), [], span)
+        .agg([col("close").mean().alias("name")])
+        .collect()?
+        .with_row_index("index".into(), None)?;
+    let joined = df.join(&n, ["index"], ["index"], JoinType::Left.into(), None)?;
+    let oo = joined
         .lazy()
         .select([col("*").exclude(["time_right"])])
-        .collect()
-        .unwrap();
+        .collect()?;
     Ok(oo)
 }
 
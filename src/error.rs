@@ -1,14 +1,25 @@
-use crate::config::ConfigError;
-use apca::{api::v2::order::CreateError, RequestError};
+use crate::{
+    config::ConfigError, data::download::CryptoBarsError, strategy::RuleError,
+    validate::ValidationReport,
+};
+use apca::{
+    api::v2::{
+        order::{CreateError, DeleteError},
+        orders::ListError as OrdersListError,
+    },
+    data::v2::bars::ListError,
+    RequestError,
+};
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
 };
 use polars::error::PolarsError;
+use tonic::Code;
 
 #[derive(thiserror::Error, Debug)]
 pub enum CLIError {
-    #[error("Database error")]
+    #[error("Conversion error")]
     Converting,
 
     #[error("Config error")]
@@ -17,14 +28,263 @@ pub enum CLIError {
     #[error("Failed to get data from Alpaca API")]
     DB(#[from] apca::RequestError<apca::data::v2::bars::ListError>),
 
-    #[error("Config error")]
-    Consfig(#[from] RequestError<CreateError>),
+    #[error("Failed to get crypto bars from Alpaca API")]
+    CryptoDB(#[from] apca::RequestError<CryptoBarsError>),
 
     #[error("Tonic error")]
     Tonic(#[from] tonic::transport::Error),
 
     #[error("Polars error")]
     Polars(#[from] PolarsError),
+
+    #[error("Rule error: {0}")]
+    Rule(#[from] RuleError),
+
+    #[error("Settings error: {0}")]
+    Settings(#[from] ::config::ConfigError),
+
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+
+    #[error("Invalid config:\n{0}")]
+    Validation(ValidationReport),
+
+    #[error("Stream error: {0}")]
+    Stream(String),
+
+    #[error("Alpaca error: {0}")]
+    Alpaca(String),
+
+    #[error("Cannot convert {field} of bar {index}: {value}")]
+    BarConversion {
+        field: String,
+        index: usize,
+        value: String,
+    },
+
+    #[error("Data error: {0}")]
+    Data(#[from] DataError),
+
+    #[error("Indicator error: {0}")]
+    Indicator(#[from] IndicatorError),
+
+    #[error("Broker error: {0}")]
+    Broker(#[from] BrokerError),
+
+    #[error("Risk check failed: {0}")]
+    Risk(#[from] RiskError),
+
+    #[error("Persistence error: {0}")]
+    Persistence(#[from] PersistenceError),
+
+    #[error("Poisoned lock on {0}")]
+    Lock(&'static str),
+
+    #[error("Config reload failed: {0}")]
+    Reload(String),
+
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    #[error("Notification via {sink} failed: {msg}")]
+    Notify { sink: &'static str, msg: String },
+}
+
+impl CLIError {
+    //worth another attempt, e.g. a dropped connection or a rate limit
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            CLIError::Stream(_) | CLIError::Tonic(_) => true,
+            CLIError::DB(e) => !matches!(e, RequestError::Endpoint(_)) || is_rate_limited(e),
+            CLIError::CryptoDB(e) => !matches!(e, RequestError::Endpoint(_)) || is_rate_limited(e),
+            CLIError::Indicator(e) => e.is_retryable(),
+            CLIError::Broker(e) => e.is_retryable(),
+            CLIError::Persistence(PersistenceError::Io { source, .. }) => matches!(
+                source.kind(),
+                std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::WouldBlock
+            ),
+            _ => false,
+        }
+    }
+
+    //retrying won't help, the symbol (or the process) has to stop until it is fixed.
+    //Errors that are neither retryable nor fatal only cost the current bar or order.
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            CLIError::Config(_)
+            | CLIError::Settings(_)
+            | CLIError::InvalidConfig(_)
+            | CLIError::Validation(_)
+            | CLIError::Rule(_)
+            | CLIError::Lock(_) => true,
+            CLIError::Broker(e) => e.is_fatal(),
+            _ => false,
+        }
+    }
+}
+
+//HTTP status of an endpoint error, apca only keeps the ones the endpoint doesn't expect
+pub trait HttpStatus {
+    fn status(&self) -> Option<StatusCode>;
+}
+
+macro_rules! http_status {
+    ($($err:ident),*) => {
+        $(impl HttpStatus for $err {
+            fn status(&self) -> Option<StatusCode> {
+                match self {
+                    $err::UnexpectedStatus(status, _) => Some(*status),
+                    _ => None,
+                }
+            }
+        })*
+    };
+}
+
+http_status!(
+    ListError,
+    OrdersListError,
+    CreateError,
+    DeleteError,
+    CryptoBarsError
+);
+
+pub fn is_rate_limited<E: HttpStatus>(e: &RequestError<E>) -> bool {
+    matches!(e, RequestError::Endpoint(e) if e.status() == Some(StatusCode::TOO_MANY_REQUESTS))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum DataError {
+    #[error("{symbol}: no {what}")]
+    Missing { symbol: String, what: String },
+
+    #[error("{symbol}: invalid {what}: {value}")]
+    Invalid {
+        symbol: String,
+        what: String,
+        value: String,
+    },
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum IndicatorError {
+    #[error("indicator service not connected")]
+    NotConnected,
+
+    #[error("cannot connect to indicator service at {addr}: {source}")]
+    Connect {
+        addr: String,
+        source: tonic::transport::Error,
+    },
+
+    #[error("indicator request for {symbol} failed: {status}")]
+    Request {
+        symbol: String,
+        status: tonic::Status,
+    },
+
+    #[error("indicator service at {0} is failing, circuit open")]
+    CircuitOpen(String),
+
+    #[error(transparent)]
+    Ta(#[from] TaError),
+}
+
+impl IndicatorError {
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            IndicatorError::NotConnected | IndicatorError::Connect { .. } => true,
+            IndicatorError::Request { status, .. } => matches!(
+                status.code(),
+                Code::Unavailable
+                    | Code::DeadlineExceeded
+                    | Code::ResourceExhausted
+                    | Code::Aborted
+            ),
+            IndicatorError::CircuitOpen(_) | IndicatorError::Ta(_) => false,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum BrokerError {
+    #[error("no Alpaca credentials: {0}")]
+    Credentials(String),
+
+    #[error("order for {symbol} failed: {msg}")]
+    Order {
+        symbol: String,
+        msg: String,
+        retryable: bool,
+    },
+
+    #[error("invalid order for {symbol}: {msg}")]
+    InvalidOrder { symbol: String, msg: String },
+
+    #[error("position of {symbol}: {msg}")]
+    Position { symbol: String, msg: String },
+
+    #[error("no price for {0} yet")]
+    NoPrice(String),
+}
+
+impl BrokerError {
+    //transport errors and rate limits are retried, rejected orders are not
+    pub fn order<E: std::fmt::Display + HttpStatus>(symbol: &str, e: &RequestError<E>) -> Self {
+        BrokerError::Order {
+            symbol: symbol.to_string(),
+            msg: e.to_string(),
+            retryable: !matches!(e, RequestError::Endpoint(_)) || is_rate_limited(e),
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            BrokerError::Order {
+                retryable: true,
+                ..
+            }
+        )
+    }
+
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, BrokerError::Credentials(_))
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum RiskError {
+    #[error("no portfolio")]
+    NoPortfolio,
+
+    #[error("{symbol}: {needed:.2} cash needed, {available:.2} available")]
+    InsufficientCash {
+        symbol: String,
+        needed: f64,
+        available: f64,
+    },
+
+    #[error("{symbol}: selling {wanted} shares, {owned} held")]
+    InsufficientShares {
+        symbol: String,
+        wanted: f64,
+        owned: f64,
+    },
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum PersistenceError {
+    #[error("IO error on {path}: {source}")]
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("cannot write {what}: {msg}")]
+    Serialize { what: String, msg: String },
 }
 
 /* impl From<ConfigError> for CLIError {
@@ -33,37 +293,64 @@ pub enum CLIError {
     }
 }
  */
+//a rejected config is the caller's to fix, it gets the report
 impl IntoResponse for CLIError {
     fn into_response(self) -> Response {
-        (StatusCode::INTERNAL_SERVER_ERROR, "").into_response()
+        match self {
+            CLIError::Config(_)
+            | CLIError::Settings(_)
+            | CLIError::InvalidConfig(_)
+            | CLIError::Validation(_)
+            | CLIError::Rule(_) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, self.to_string()).into_response()
+            }
+            CLIError::NotFound(_) => (StatusCode::NOT_FOUND, self.to_string()).into_response(),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, "").into_response(),
+        }
     }
 }
 
 pub type Result<T> = std::result::Result<T, TaError>;
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(thiserror::Error, Debug, PartialEq, Eq, Clone)]
 pub enum TaError {
-    /* InvalidParameter,
+    #[error("invalid parameter")]
+    InvalidParameter,
+    #[error("data item is incomplete")]
     DataItemIncomplete,
-    DataItemInvalid, */
+    #[error("data item is invalid")]
+    DataItemInvalid,
 }
 
-/* impl Display for TaError {
-    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        match *self {
-            TaError::InvalidParameter => write!(f, "invalid parameter"),
-            TaError::DataItemIncomplete => write!(f, "data item is incomplete"),
-            TaError::DataItemInvalid => write!(f, "data item is invalid"),
-        }
-    }
-} */
-
-/* impl Error for TaError {
-    fn source(&self) -> Option<&(dyn Error + 'static)> {
-        match *self {
-            TaError::InvalidParameter => None,
-            TaError::DataItemIncomplete => None,
-            TaError::DataItemInvalid => None,
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_test() {
+        assert!(CLIError::Stream(String::from("closed")).is_retryable());
+        let unavailable = IndicatorError::Request {
+            symbol: String::from("ORCL"),
+            status: tonic::Status::unavailable("down"),
+        };
+        assert!(CLIError::from(unavailable).is_retryable());
+        let invalid = IndicatorError::Request {
+            symbol: String::from("ORCL"),
+            status: tonic::Status::invalid_argument("period"),
+        };
+        assert!(!CLIError::from(invalid).is_retryable());
+
+        let rejected = BrokerError::Order {
+            symbol: String::from("ORCL"),
+            msg: String::from("insufficient buying power"),
+            retryable: false,
+        };
+        let err = CLIError::from(rejected);
+        assert!(!err.is_retryable() && !err.is_fatal());
+        assert!(CLIError::from(BrokerError::Credentials(String::from("unset"))).is_fatal());
+        assert!(CLIError::Lock("portfolio").is_fatal());
+
+        let risk = CLIError::from(RiskError::NoPortfolio);
+        assert!(!risk.is_retryable() && !risk.is_fatal());
     }
-} */
+}
@@ -0,0 +1,501 @@
+//Works larger orders over time instead of sending them in one shot: TWAP slices
+//evenly, VWAP along the intraday volume profile, and a chase that starts passive
+//at the near touch and steps to the far touch. One child per bar, never more than
+//the participation cap of the bar volume. Children are IOC limits so every fill is
+//known once the child returns, the same in live trading, the paper broker and the
+//backtest simulation.
+use std::{collections::HashMap, fmt};
+
+use chrono::{DateTime, Duration, Timelike, Utc};
+use serde::Deserialize;
+
+use crate::{
+    asset::round_qty,
+    book::TopOfBook,
+    data::cache::CachedBar,
+    error::CLIError,
+    order::{Exit, Filled, OrderSpec, OrderType, Side, SimBar, SimBook, TimeInForce},
+    trade::StockActions,
+};
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Algo {
+    //every order in one shot
+    #[default]
+    None,
+    Twap,
+    Vwap,
+    Chase,
+}
+
+//[execution]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExecConf {
+    pub algo: Algo,
+    //orders of at least this many units are worked, smaller ones go out at once
+    pub min_qty: f64,
+    pub duration_secs: u64,
+    //share of the bar volume the children may take, 0 for no cap
+    pub participation: f64,
+    //children are priced this far through the far touch (or the last price)
+    pub limit_offset_bps: f64,
+    //chase: bars from the near to the far touch
+    pub chase_steps: u32,
+    //vwap: days of minute bars the volume profile is built from
+    pub profile_days: u32,
+}
+
+impl Default for ExecConf {
+    fn default() -> Self {
+        ExecConf {
+            algo: Algo::None,
+            min_qty: 100.0,
+            duration_secs: 600,
+            participation: 0.1,
+            limit_offset_bps: 5.0,
+            chase_steps: 5,
+            profile_days: 20,
+        }
+    }
+}
+
+impl ExecConf {
+    pub fn works(&self, qty: f64) -> bool {
+        self.algo != Algo::None && qty >= self.min_qty
+    }
+
+    fn minutes(&self) -> usize {
+        (self.duration_secs / 60).max(1) as usize
+    }
+}
+
+//share of the daily volume per minute of the day (UTC)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VolumeProfile {
+    minutes: HashMap<u32, f64>,
+}
+
+impl VolumeProfile {
+    pub fn from_bars(bars: &[CachedBar]) -> Self {
+        let mut minutes: HashMap<u32, f64> = HashMap::new();
+        for bar in bars.iter().filter(|b| b.volume.is_finite()) {
+            let minute = bar.time.hour() * 60 + bar.time.minute();
+            *minutes.entry(minute).or_default() += bar.volume;
+        }
+        let total: f64 = minutes.values().sum();
+        if total > 0.0 {
+            minutes.values_mut().for_each(|v| *v /= total);
+        }
+        VolumeProfile { minutes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.minutes.is_empty()
+    }
+
+    fn weight(&self, time: DateTime<Utc>) -> f64 {
+        let minute = time.hour() * 60 + time.minute();
+        self.minutes.get(&minute).copied().unwrap_or(0.0)
+    }
+}
+
+//fill statistics of a worked order
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExecReport {
+    pub symbol: String,
+    pub side: Side,
+    pub algo: Algo,
+    pub target: f64,
+    pub filled: f64,
+    pub avg_price: f64,
+    //price when the order was decided
+    pub arrival: f64,
+    //cost against the arrival price, positive is worse
+    pub slippage_bps: f64,
+    pub children: usize,
+}
+
+impl fmt::Display for ExecReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:?} {:?}: {}/{} @ {:.4}, arrival {:.4}, slippage {:.1} bps, {} children",
+            self.symbol,
+            self.algo,
+            self.side,
+            self.filled,
+            self.target,
+            self.avg_price,
+            self.arrival,
+            self.slippage_bps,
+            self.children
+        )
+    }
+}
+
+//one worked order
+#[derive(Clone, Debug)]
+pub struct Execution {
+    pub symbol: String,
+    pub side: Side,
+    pub target: f64,
+    conf: ExecConf,
+    start: DateTime<Utc>,
+    arrival: f64,
+    //share of the target due by the end of each minute
+    schedule: Vec<f64>,
+    filled: Filled,
+    children: usize,
+    //a child was sent after the duration, with everything due
+    late: bool,
+    //exits of a bracket, sent for the filled quantity once done
+    pub exit: Option<Exit>,
+}
+
+impl Execution {
+    pub fn new(
+        symbol: &str,
+        side: Side,
+        target: f64,
+        arrival: f64,
+        start: DateTime<Utc>,
+        conf: &ExecConf,
+        profile: Option<&VolumeProfile>,
+    ) -> Self {
+        let minutes = conf.minutes();
+        //vwap without a profile is twap
+        let weights: Vec<f64> = match (conf.algo, profile.filter(|p| !p.is_empty())) {
+            (Algo::Vwap, Some(profile)) => (0..minutes)
+                .map(|m| profile.weight(start + Duration::minutes(m as i64)))
+                .collect(),
+            _ => vec![1.0; minutes],
+        };
+        let total: f64 = weights.iter().sum();
+        let mut due = 0.0;
+        let schedule = weights
+            .iter()
+            .map(|w| {
+                due += if total > 0.0 {
+                    w / total
+                } else {
+                    1.0 / minutes as f64
+                };
+                due
+            })
+            .collect();
+        Execution {
+            symbol: symbol.to_string(),
+            side,
+            target,
+            conf: conf.clone(),
+            start,
+            arrival,
+            schedule,
+            filled: Filled::default(),
+            children: 0,
+            late: false,
+            exit: None,
+        }
+    }
+
+    fn elapsed(&self, now: DateTime<Utc>) -> usize {
+        (now - self.start).num_minutes().max(0) as usize
+    }
+
+    fn remaining(&self) -> f64 {
+        round_qty(&self.symbol, self.target - self.filled.qty)
+    }
+
+    //filled, or twice the duration is over and the rest is given up. At least one
+    //child is tried after the duration, bars longer than it (daily backtests) get one
+    pub fn is_done(&self, now: DateTime<Utc>) -> bool {
+        self.remaining() <= 0.0 || (self.late && self.elapsed(now) >= 2 * self.conf.minutes())
+    }
+
+    //units due by now, everything once the duration is over
+    fn due(&self, now: DateTime<Utc>) -> f64 {
+        let frac = match self.conf.algo {
+            Algo::Chase => 1.0,
+            _ => self.schedule.get(self.elapsed(now)).copied().unwrap_or(1.0),
+        };
+        self.target * frac.min(1.0)
+    }
+
+    //IOC limit, passive at the near touch up to aggressive through the far touch
+    fn limit(&self, last: f64, book: Option<&TopOfBook>) -> f64 {
+        let offset = self.conf.limit_offset_bps / 10_000.0;
+        let (near, far) = match (self.side, book) {
+            (Side::Buy, Some(b)) => (b.bid, b.ask * (1.0 + offset)),
+            (Side::Sell, Some(b)) => (b.ask, b.bid * (1.0 - offset)),
+            (Side::Buy, None) => (last * (1.0 - offset), last * (1.0 + offset)),
+            (Side::Sell, None) => (last * (1.0 + offset), last * (1.0 - offset)),
+        };
+        match self.conf.algo {
+            Algo::Chase => {
+                let steps = self.conf.chase_steps.max(1) as f64;
+                let step = (self.children as f64).min(steps);
+                near + (far - near) * step / steps
+            }
+            _ => far,
+        }
+    }
+
+    //child order for the bar, None when nothing is due yet
+    pub fn next_order(
+        &mut self,
+        now: DateTime<Utc>,
+        last: f64,
+        volume: f64,
+        book: Option<&TopOfBook>,
+    ) -> Option<OrderSpec> {
+        if self.is_done(now) {
+            return None;
+        }
+        let mut qty = self.due(now) - self.filled.qty;
+        if self.conf.participation > 0.0 && volume > 0.0 {
+            qty = qty.min(volume * self.conf.participation);
+        }
+        let qty = round_qty(&self.symbol, qty.min(self.remaining()));
+        if qty <= 0.0 {
+            return None;
+        }
+        let limit = self.limit(last, book);
+        self.children += 1;
+        self.late = self.late || self.elapsed(now) >= self.conf.minutes();
+        Some(
+            OrderSpec::new(&self.symbol, self.side, qty)
+                .with_type(OrderType::Limit(limit))
+                .with_time_in_force(TimeInForce::Ioc),
+        )
+    }
+
+    pub fn on_fill(&mut self, filled: &Filled) {
+        self.filled.add(filled.qty, filled.price);
+    }
+
+    //cash the worked order cost over filling the target at the arrival price,
+    //the unfilled rest completed at last
+    pub fn cost(&self, last: f64) -> f64 {
+        let sign = match self.side {
+            Side::Buy => 1.0,
+            Side::Sell => -1.0,
+        };
+        let rest = self.target - self.filled.qty;
+        sign * ((self.filled.price - self.arrival) * self.filled.qty + (last - self.arrival) * rest)
+    }
+
+    pub fn report(&self) -> ExecReport {
+        let sign = match self.side {
+            Side::Buy => 1.0,
+            Side::Sell => -1.0,
+        };
+        let slippage_bps = if self.filled.qty > 0.0 && self.arrival > 0.0 {
+            sign * (self.filled.price - self.arrival) / self.arrival * 10_000.0
+        } else {
+            0.0
+        };
+        ExecReport {
+            symbol: self.symbol.clone(),
+            side: self.side,
+            algo: self.conf.algo,
+            target: self.target,
+            filled: self.filled.qty,
+            avg_price: self.filled.price,
+            arrival: self.arrival,
+            slippage_bps,
+            children: self.children,
+        }
+    }
+}
+
+//one bar of a running execution: at most one child, its fill booked
+pub async fn step<B: StockActions>(
+    broker: &B,
+    exec: &mut Execution,
+    bar: &SimBar,
+    volume: f64,
+    book: Option<&TopOfBook>,
+) -> Result<(), CLIError> {
+    if let Some(child) = exec.next_order(bar.time, bar.close, volume, book) {
+        let filled = broker.submit(child).await?;
+        exec.on_fill(&filled);
+    }
+    Ok(())
+}
+
+//the execution over bars with their volume, children sent at the open and
+//filled against the range of the bar, for the backtester
+pub fn simulate(exec: &mut Execution, bars: &[(SimBar, f64)]) -> ExecReport {
+    let mut book = SimBook::default();
+    for (bar, volume) in bars {
+        if exec.is_done(bar.time) {
+            break;
+        }
+        let Some(child) = exec.next_order(bar.time, bar.open, *volume, None) else {
+            continue;
+        };
+        if book.submit(&child, bar.time).is_err() {
+            continue;
+        }
+        for fill in book.on_bar(&exec.symbol, bar, |_| true) {
+            exec.on_fill(&Filled {
+                qty: fill.qty,
+                price: fill.price,
+            });
+        }
+    }
+    exec.report()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, 4, 15, minute, 0).unwrap()
+    }
+
+    fn bar(minute: u32, price: f64) -> SimBar {
+        SimBar {
+            time: at(minute),
+            open: price,
+            high: price + 0.5,
+            low: price - 0.5,
+            close: price,
+        }
+    }
+
+    fn conf(algo: Algo) -> ExecConf {
+        ExecConf {
+            algo,
+            duration_secs: 300,
+            participation: 0.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn twap_test() {
+        let mut exec = Execution::new(
+            "ORCL",
+            Side::Buy,
+            100.0,
+            50.0,
+            at(0),
+            &conf(Algo::Twap),
+            None,
+        );
+        for minute in 0..5 {
+            let child = exec.next_order(at(minute), 50.0, 0.0, None).unwrap();
+            assert_eq!(child.qty, 20.0);
+            assert_eq!(child.time_in_force, TimeInForce::Ioc);
+            exec.on_fill(&Filled {
+                qty: child.qty,
+                price: 50.0 + minute as f64 * 0.1,
+            });
+        }
+        assert!(exec.is_done(at(5)));
+        let report = exec.report();
+        assert_eq!(report.filled, 100.0);
+        assert!((report.avg_price - 50.2).abs() < 1e-9);
+        assert!((report.slippage_bps - 40.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn vwap_and_participation_test() {
+        //three times the volume in the first minute
+        let history: Vec<CachedBar> = [(0, 300.0), (1, 100.0), (2, 100.0)]
+            .iter()
+            .map(|(minute, volume)| CachedBar {
+                time: at(*minute) - Duration::days(1),
+                open: 1.0,
+                high: 1.0,
+                low: 1.0,
+                close: 1.0,
+                volume: *volume,
+            })
+            .collect();
+        let profile = VolumeProfile::from_bars(&history);
+        let conf = ExecConf {
+            duration_secs: 180,
+            ..conf(Algo::Vwap)
+        };
+        let mut exec = Execution::new("ORCL", Side::Sell, 50.0, 10.0, at(0), &conf, Some(&profile));
+        assert_eq!(exec.next_order(at(0), 10.0, 0.0, None).unwrap().qty, 30.0);
+
+        //at most 10% of the bar volume
+        let conf = ExecConf {
+            participation: 0.1,
+            ..conf
+        };
+        let mut exec = Execution::new("ORCL", Side::Sell, 50.0, 10.0, at(0), &conf, Some(&profile));
+        let child = exec.next_order(at(0), 10.0, 120.0, None).unwrap();
+        assert_eq!(child.qty, 12.0);
+        assert_eq!(child.type_, OrderType::Limit(10.0 * (1.0 - 0.0005)));
+    }
+
+    #[test]
+    fn chase_test() {
+        let book = TopOfBook {
+            bid: 99.0,
+            ask: 101.0,
+            bid_size: 100.0,
+            ask_size: 100.0,
+            time: at(0),
+        };
+        let conf = ExecConf {
+            chase_steps: 2,
+            limit_offset_bps: 0.0,
+            ..conf(Algo::Chase)
+        };
+        let mut exec = Execution::new("ORCL", Side::Buy, 10.0, 100.0, at(0), &conf, None);
+        let limits: Vec<OrderType> = (0..3)
+            .map(|m| {
+                exec.next_order(at(m), 100.0, 0.0, Some(&book))
+                    .unwrap()
+                    .type_
+            })
+            .collect();
+        assert_eq!(
+            limits,
+            vec![
+                OrderType::Limit(99.0),
+                OrderType::Limit(100.0),
+                OrderType::Limit(101.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn simulate_test() {
+        let bars: Vec<(SimBar, f64)> = (0..5).map(|m| (bar(m, 50.0 + m as f64), 1000.0)).collect();
+        let conf = ExecConf {
+            participation: 0.01,
+            ..conf(Algo::Twap)
+        };
+        let mut exec = Execution::new("ORCL", Side::Buy, 50.0, 50.0, at(0), &conf, None);
+        let report = simulate(&mut exec, &bars);
+        //10 per bar at most, the open of each bar
+        assert_eq!(report.children, 5);
+        assert_eq!(report.filled, 50.0);
+        assert!((report.avg_price - 52.0).abs() < 1e-9);
+        assert!(report.slippage_bps > 0.0);
+
+        //daily bars: one capped child after the duration, then the rest is given up
+        let daily: Vec<(SimBar, f64)> = (1..4)
+            .map(|d| {
+                let mut b = bar(0, 51.0);
+                b.time += Duration::days(d);
+                (b, 1000.0)
+            })
+            .collect();
+        let mut exec = Execution::new("ORCL", Side::Buy, 50.0, 50.0, at(0), &conf, None);
+        let report = simulate(&mut exec, &daily);
+        assert_eq!((report.children, report.filled), (1, 10.0));
+        //10 filled 1 above the arrival, 40 completed 2 above it
+        assert!((exec.cost(52.0) - 90.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,849 @@
+//Managed channel to the calculate service: per call deadlines, retries with backoff on
+//transient status codes, a circuit breaker and a health check that reconnects.
+//While the service is down indicators are computed locally or the trader holds.
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tonic::{
+    transport::{Channel, Endpoint},
+    Code, Streaming,
+};
+use tracing::{info, warn};
+
+use crate::{
+    config2::AppConfig,
+    error::{CLIError, IndicatorError},
+    notify::{notify, Event},
+    portfolio::types::{IndicatorConf, TraderConf},
+    proto::{
+        self, indicator_batch_client::IndicatorBatchClient, indicator_client::IndicatorClient,
+        BarUpdate, BatchRequest, IndicatorSeries, IndicatorSpec, IndicatorUpdate,
+        ListNumbersRequest2,
+    },
+    retry::RetryPolicy,
+    strategy::indicators::{bollinger, ema, rsi, sma, std_dev},
+    telemetry::metrics,
+    types::PriceField,
+};
+
+//bars waiting to be sent on the live stream
+const STREAM_BUFFER: usize = 64;
+
+//[grpc.client]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConf {
+    //deadline of a single call
+    pub deadline_ms: u64,
+    pub connect_timeout_ms: u64,
+    //retries after the first attempt, only for transient status codes
+    pub retries: u32,
+    pub backoff_ms: u64,
+    //consecutive failed calls that open the circuit
+    pub breaker_failures: u32,
+    //no calls while the circuit is open
+    pub breaker_cooldown_secs: u64,
+    pub health_interval_secs: u64,
+    pub fallback: Fallback,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Fallback {
+    //strategy::indicators, Hold for indicators without a local version
+    #[default]
+    Local,
+    Hold,
+}
+
+impl Default for ClientConf {
+    fn default() -> Self {
+        ClientConf {
+            deadline_ms: 2000,
+            connect_timeout_ms: 3000,
+            retries: 2,
+            backoff_ms: 200,
+            breaker_failures: 5,
+            breaker_cooldown_secs: 30,
+            health_interval_secs: 10,
+            fallback: Fallback::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold: threshold.max(1),
+            cooldown,
+            failures: 0,
+            open_until: None,
+        }
+    }
+
+    //closed, or open with the cooldown over, then one trial call decides
+    pub fn allow(&self, now: Instant) -> bool {
+        !self.is_open(now)
+    }
+
+    pub fn is_open(&self, now: Instant) -> bool {
+        self.open_until.is_some_and(|until| now < until)
+    }
+
+    pub fn success(&mut self) {
+        self.failures = 0;
+        self.open_until = None;
+    }
+
+    pub fn failure(&mut self, now: Instant) {
+        self.failures += 1;
+        if self.failures >= self.threshold {
+            self.open_until = Some(now + self.cooldown);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    channel: Option<Channel>,
+    breaker: CircuitBreaker,
+    //false once the service answered GenBatch with Unimplemented
+    batch: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct IndicatorService {
+    addr: String,
+    conf: ClientConf,
+    state: Arc<Mutex<State>>,
+}
+
+impl IndicatorService {
+    //not connected yet, the first call or health check connects
+    pub fn new(addr: &str, conf: ClientConf) -> Self {
+        let breaker = CircuitBreaker::new(
+            conf.breaker_failures,
+            Duration::from_secs(conf.breaker_cooldown_secs),
+        );
+        IndicatorService {
+            addr: addr.to_string(),
+            conf,
+            state: Arc::new(Mutex::new(State {
+                channel: None,
+                breaker,
+                batch: true,
+            })),
+        }
+    }
+
+    //grpcport is the full address, e.g. http://[::1]:50051
+    pub fn from_conf(conf: &AppConfig) -> Self {
+        Self::new(&conf.grpcport, conf.client.clone())
+    }
+
+    pub fn conf(&self) -> &ClientConf {
+        &self.conf
+    }
+
+    fn state(&self) -> Result<std::sync::MutexGuard<'_, State>, CLIError> {
+        self.state
+            .lock()
+            .map_err(|_| CLIError::Lock("indicator service"))
+    }
+
+    //deadlines are set per call, a channel wide timeout would end the live stream
+    pub async fn connect(&self) -> Result<Channel, CLIError> {
+        let connect_err = |source| {
+            metrics().grpc_errors.with_label_values(&["Connect"]).inc();
+            IndicatorError::Connect {
+                addr: self.addr.clone(),
+                source,
+            }
+        };
+        let channel = Endpoint::from_shared(self.addr.clone())
+            .map_err(connect_err)?
+            .connect_timeout(Duration::from_millis(self.conf.connect_timeout_ms))
+            .connect()
+            .await
+            .map_err(connect_err)?;
+        self.state()?.channel = Some(channel.clone());
+        info!("connected to indicator service at {}", self.addr);
+        Ok(channel)
+    }
+
+    async fn channel(&self) -> Result<Channel, CLIError> {
+        let channel = self.state()?.channel.clone();
+        match channel {
+            Some(channel) => Ok(channel),
+            None => self.connect().await,
+        }
+    }
+
+    fn disconnect(&self) {
+        if let Ok(mut state) = self.state() {
+            state.channel = None;
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.state()
+            .map(|s| s.breaker.is_open(Instant::now()))
+            .unwrap_or(true)
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            attempts: self.conf.retries + 1,
+            base: Duration::from_millis(self.conf.backoff_ms),
+            ..Default::default()
+        }
+    }
+
+    fn request<T>(&self, msg: T) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(msg);
+        request.set_timeout(Duration::from_millis(self.conf.deadline_ms));
+        request
+    }
+
+    fn status_err(&self, symbol: &str, status: tonic::Status) -> CLIError {
+        metrics()
+            .grpc_errors
+            .with_label_values(&[&format!("{:?}", status.code())])
+            .inc();
+        let e = CLIError::from(IndicatorError::Request {
+            symbol: symbol.to_string(),
+            status,
+        });
+        //a fresh channel for the next attempt
+        if e.is_retryable() {
+            self.disconnect();
+        }
+        e
+    }
+
+    async fn call(&self, symbol: &str, req: ListNumbersRequest2) -> Result<Vec<f64>, CLIError> {
+        let mut client = IndicatorClient::new(self.channel().await?);
+        let _timer = metrics()
+            .indicator_latency
+            .with_label_values(&["gen_liste"])
+            .start_timer();
+        match client.gen_liste(self.request(req)).await {
+            Ok(res) => Ok(res.into_inner().result),
+            Err(status) => Err(self.status_err(symbol, status)),
+        }
+    }
+
+    async fn call_batch(&self, req: BatchRequest) -> Result<Vec<IndicatorSeries>, CLIError> {
+        let mut client = IndicatorBatchClient::new(self.channel().await?);
+        let symbol = req.symbol.clone();
+        let _timer = metrics()
+            .indicator_latency
+            .with_label_values(&["gen_batch"])
+            .start_timer();
+        match client.gen_batch(self.request(req)).await {
+            Ok(res) => Ok(res.into_inner().series),
+            Err(status) => Err(self.status_err(&symbol, status)),
+        }
+    }
+
+    //one indicator series from the service
+    pub async fn gen_liste(
+        &self,
+        symbol: &str,
+        req: ListNumbersRequest2,
+    ) -> Result<Vec<f64>, CLIError> {
+        if self.is_open() {
+            return Err(IndicatorError::CircuitOpen(self.addr.clone()).into());
+        }
+        let what = format!("indicator {} for {symbol}", req.id);
+        let res = self
+            .retry_policy()
+            .run(&what, || self.call(symbol, req.clone()))
+            .await;
+        self.record(&res);
+        res
+    }
+
+    //only an unreachable service counts against the breaker
+    fn record<T>(&self, res: &Result<T, CLIError>) {
+        let Ok(mut state) = self.state() else {
+            return;
+        };
+        match res {
+            Err(e) if e.is_retryable() => state.breaker.failure(Instant::now()),
+            _ => state.breaker.success(),
+        }
+    }
+
+    //all indicators over one series in one request, services without GenBatch
+    //get one GenListe per indicator
+    pub async fn gen_batch(
+        &self,
+        symbol: &str,
+        specs: &[IndicatorSpec],
+        list: Vec<f64>,
+    ) -> Result<Vec<IndicatorSeries>, CLIError> {
+        if self.is_open() {
+            return Err(IndicatorError::CircuitOpen(self.addr.clone()).into());
+        }
+        if self.batch_supported() {
+            let req = BatchRequest {
+                symbol: symbol.to_string(),
+                indicators: specs.to_vec(),
+                list: list.clone(),
+            };
+            let what = format!("indicator batch for {symbol}");
+            let res = self
+                .retry_policy()
+                .run(&what, || self.call_batch(req.clone()))
+                .await;
+            match res {
+                Err(CLIError::Indicator(IndicatorError::Request { status, .. }))
+                    if status.code() == Code::Unimplemented =>
+                {
+                    warn!("{}: no GenBatch, one request per indicator", self.addr);
+                    if let Ok(mut state) = self.state() {
+                        state.batch = false;
+                    }
+                }
+                res => {
+                    self.record(&res);
+                    return res;
+                }
+            }
+        }
+        let mut series = vec![];
+        for spec in specs {
+            let req = ListNumbersRequest2 {
+                id: spec.id,
+                opt: spec.opt.clone(),
+                list: list.clone(),
+            };
+            series.push(IndicatorSeries {
+                id: spec.id,
+                result: self.gen_liste(symbol, req).await?,
+            });
+        }
+        Ok(series)
+    }
+
+    fn batch_supported(&self) -> bool {
+        self.state().map(|s| s.batch).unwrap_or(false)
+    }
+
+    //gen_batch with the fallback per indicator, missing ids mean Hold
+    pub async fn values_batch(
+        &self,
+        symbol: &str,
+        specs: &[IndicatorSpec],
+        list: Vec<f64>,
+    ) -> HashMap<i32, Vec<f64>> {
+        match self.gen_batch(symbol, specs, list.clone()).await {
+            Ok(series) => series.into_iter().map(|s| (s.id, s.result)).collect(),
+            Err(e) => {
+                warn!("{symbol}: {e}, fallback {:?}", self.conf.fallback);
+                if self.conf.fallback == Fallback::Hold {
+                    return HashMap::new();
+                }
+                specs
+                    .iter()
+                    .filter_map(|spec| {
+                        let req = ListNumbersRequest2 {
+                            id: spec.id,
+                            opt: spec.opt.clone(),
+                            list: list.clone(),
+                        };
+                        Some((spec.id, local_indicator(&req)?))
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    //live stream, one BarUpdate per bar in, IndicatorUpdates out
+    pub async fn open_stream(
+        &self,
+    ) -> Result<(IndicatorSender, Streaming<IndicatorUpdate>), CLIError> {
+        if self.is_open() {
+            return Err(IndicatorError::CircuitOpen(self.addr.clone()).into());
+        }
+        let mut client = IndicatorBatchClient::new(self.channel().await?);
+        let (tx, rx) = mpsc::channel::<BarUpdate>(STREAM_BUFFER);
+        let outbound =
+            futures::stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|u| (u, rx)) });
+        let res = client
+            .gen_stream(outbound)
+            .await
+            .map(|res| res.into_inner())
+            .map_err(|status| self.status_err("stream", status));
+        self.record(&res);
+        let sender = IndicatorSender {
+            tx,
+            opened: HashSet::new(),
+        };
+        Ok((sender, res?))
+    }
+
+    //the series from the service, else the fallback, None means Hold
+    pub async fn values(&self, symbol: &str, req: ListNumbersRequest2) -> Option<Vec<f64>> {
+        match self.gen_liste(symbol, req.clone()).await {
+            Ok(values) => Some(values),
+            Err(e) => {
+                warn!("{symbol}: {e}, fallback {:?}", self.conf.fallback);
+                match self.conf.fallback {
+                    Fallback::Local => local_indicator(&req),
+                    Fallback::Hold => None,
+                }
+            }
+        }
+    }
+
+    //probe with a tiny request, reconnects if the service is back
+    pub async fn health_check(&self) -> bool {
+        let probe = ListNumbersRequest2 {
+            id: proto::IndicatorType::SimpleMovingAverage.into(),
+            opt: Some(proto::Opt {
+                multiplier: 1.0,
+                period: 1,
+            }),
+            list: vec![1.0],
+        };
+        let res = self.call("health", probe).await;
+        if res.is_err() {
+            self.disconnect();
+        }
+        self.record(&res);
+        res.is_ok()
+    }
+
+    //runs until the task is dropped
+    pub async fn run_health(self) {
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(self.conf.health_interval_secs.max(1)));
+        let mut healthy = true;
+        loop {
+            interval.tick().await;
+            let ok = self.health_check().await;
+            if ok != healthy {
+                let service = format!("indicator service at {}", self.addr);
+                match ok {
+                    true => {
+                        info!("{service} is back");
+                        notify(Event::Reconnect { service });
+                    }
+                    false => {
+                        warn!("{service} is down");
+                        notify(Event::Disconnect {
+                            service,
+                            msg: format!("fallback {:?}", self.conf.fallback),
+                        });
+                    }
+                }
+                healthy = ok;
+            }
+        }
+    }
+}
+
+//sending half of GenStream, the indicators of a symbol go out with its first bar
+#[derive(Clone, Debug)]
+pub struct IndicatorSender {
+    tx: mpsc::Sender<BarUpdate>,
+    opened: HashSet<String>,
+}
+
+impl IndicatorSender {
+    //the first update of a symbol carries its indicators
+    fn update(
+        &self,
+        symbol: &str,
+        timestamp: i64,
+        close: f64,
+        specs: &[IndicatorSpec],
+    ) -> BarUpdate {
+        let first = !self.opened.contains(symbol);
+        BarUpdate {
+            symbol: symbol.to_string(),
+            timestamp,
+            close,
+            indicators: if first { specs.to_vec() } else { vec![] },
+        }
+    }
+
+    //never waits for the service, a full buffer drops the update,
+    //false once the stream is closed
+    pub fn try_send(
+        &mut self,
+        symbol: &str,
+        timestamp: i64,
+        close: f64,
+        specs: &[IndicatorSpec],
+    ) -> bool {
+        let update = self.update(symbol, timestamp, close, specs);
+        match self.tx.try_send(update) {
+            Ok(()) => {
+                self.opened.insert(symbol.to_string());
+                true
+            }
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                warn!("{symbol}: indicator stream busy, bar dropped");
+                true
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        }
+    }
+
+    //waits while the buffer is full, for history that must not be dropped.
+    //False once the stream is closed
+    pub async fn send(
+        &mut self,
+        symbol: &str,
+        timestamp: i64,
+        close: f64,
+        specs: &[IndicatorSpec],
+    ) -> bool {
+        let update = self.update(symbol, timestamp, close, specs);
+        if self.tx.send(update).await.is_err() {
+            return false;
+        }
+        self.opened.insert(symbol.to_string());
+        true
+    }
+}
+
+//an indicator with its period, the service default without. The series is sent
+//along, so it goes over all of it
+pub fn spec(ind: &IndicatorConf) -> IndicatorSpec {
+    IndicatorSpec {
+        id: ind.kind.clone() as i32,
+        opt: ind.period.map(|period| proto::Opt {
+            multiplier: 2.0,
+            period: period as _,
+        }),
+        lookback: 0,
+    }
+}
+
+//the indicators of a TraderConf
+pub fn specs(tc: &TraderConf) -> Vec<IndicatorSpec> {
+    tc.indicator.iter().map(spec).collect()
+}
+
+//the spec of an indicator on the live stream, which only carries closes. None for
+//other fields and time windows, their values are computed locally
+pub fn stream_spec(ind: &IndicatorConf, capacity: usize) -> Option<IndicatorSpec> {
+    if ind.field != PriceField::Close || ind.window_mins.is_some() {
+        return None;
+    }
+    Some(IndicatorSpec {
+        lookback: ind.lookback.unwrap_or(capacity) as u32,
+        ..spec(ind)
+    })
+}
+
+//the indicators of a TraderConf the live stream can compute
+pub fn stream_specs(tc: &TraderConf) -> Vec<IndicatorSpec> {
+    tc.indicator
+        .iter()
+        .filter_map(|ind| stream_spec(ind, tc.buff.capacity))
+        .collect()
+}
+
+//same request computed with strategy::indicators, warm-up values are left out
+//so the last value lines up with the last input
+pub fn local_indicator(req: &ListNumbersRequest2) -> Option<Vec<f64>> {
+    let kind = proto::IndicatorType::try_from(req.id).ok()?;
+    let (period, multiplier) = match &req.opt {
+        Some(opt) => (usize::try_from(opt.period).ok()?, f64::from(opt.multiplier)),
+        None => (14, 2.0),
+    };
+    let values = match kind {
+        proto::IndicatorType::SimpleMovingAverage => sma(&req.list, period),
+        proto::IndicatorType::ExponentialMovingAverage => ema(&req.list, period),
+        proto::IndicatorType::RelativeStrengthIndex => rsi(&req.list, period),
+        proto::IndicatorType::StandardDeviation => std_dev(&req.list, period),
+        //upper band
+        proto::IndicatorType::BollingerBands => bollinger(&req.list, period, multiplier).0,
+        _ => return None,
+    };
+    Some(values.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{
+        indicator_batch_server::{IndicatorBatch, IndicatorBatchServer},
+        BatchResponse, IndicatorValue,
+    };
+    use tonic::{Request, Response, Status};
+
+    //in-process calculate service, same values as local_indicator
+    struct Stub;
+
+    fn stub_values(spec: &IndicatorSpec, list: &[f64]) -> Option<Vec<f64>> {
+        local_indicator(&ListNumbersRequest2 {
+            id: spec.id,
+            opt: spec.opt.clone(),
+            list: list.to_vec(),
+        })
+    }
+
+    #[tonic::async_trait]
+    impl IndicatorBatch for Stub {
+        async fn gen_batch(
+            &self,
+            request: Request<BatchRequest>,
+        ) -> Result<Response<BatchResponse>, Status> {
+            let req = request.into_inner();
+            let series = req
+                .indicators
+                .iter()
+                .filter_map(|spec| {
+                    Some(IndicatorSeries {
+                        id: spec.id,
+                        result: stub_values(spec, &req.list)?,
+                    })
+                })
+                .collect();
+            Ok(Response::new(BatchResponse { series }))
+        }
+
+        type GenStreamStream = futures::stream::BoxStream<'static, Result<IndicatorUpdate, Status>>;
+
+        //the newest value of every indicator of the symbol over its closes so far
+        async fn gen_stream(
+            &self,
+            request: Request<Streaming<BarUpdate>>,
+        ) -> Result<Response<Self::GenStreamStream>, Status> {
+            let symbols: HashMap<String, (Vec<IndicatorSpec>, Vec<f64>)> = HashMap::new();
+            let outbound = futures::stream::unfold(
+                (request.into_inner(), symbols),
+                |(mut inbound, mut symbols)| async move {
+                    let bar = inbound.message().await.ok()??;
+                    let (specs, closes) = symbols.entry(bar.symbol.clone()).or_default();
+                    if !bar.indicators.is_empty() {
+                        *specs = bar.indicators;
+                    }
+                    closes.push(bar.close);
+                    let values = specs
+                        .iter()
+                        .filter_map(|spec| {
+                            Some(IndicatorValue {
+                                id: spec.id,
+                                value: stub_values(spec, closes)?.last().copied()?,
+                                spec: Some(spec.clone()),
+                            })
+                        })
+                        .collect();
+                    let update = IndicatorUpdate {
+                        symbol: bar.symbol,
+                        timestamp: bar.timestamp,
+                        values,
+                    };
+                    Some((Ok(update), (inbound, symbols)))
+                },
+            );
+            Ok(Response::new(Box::pin(outbound)))
+        }
+    }
+
+    async fn serve_stub() -> Result<IndicatorService, Box<dyn std::error::Error>> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let incoming = Box::pin(futures::stream::unfold(listener, |listener| async move {
+            let conn = listener.accept().await.map(|(stream, _)| stream);
+            Some((conn, listener))
+        }));
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(IndicatorBatchServer::new(Stub))
+                .serve_with_incoming(incoming),
+        );
+        Ok(IndicatorService::new(
+            &format!("http://{addr}"),
+            ClientConf::default(),
+        ))
+    }
+
+    fn sma_spec(period: u32, lookback: u32) -> IndicatorSpec {
+        IndicatorSpec {
+            id: proto::IndicatorType::SimpleMovingAverage.into(),
+            opt: Some(proto::Opt {
+                multiplier: 2.0,
+                period: period as _,
+            }),
+            lookback,
+        }
+    }
+
+    fn req(kind: proto::IndicatorType, period: u32) -> ListNumbersRequest2 {
+        ListNumbersRequest2 {
+            id: kind.into(),
+            opt: Some(proto::Opt {
+                multiplier: 2.0,
+                period: period as _,
+            }),
+            list: vec![1.0, 2.0, 3.0, 4.0],
+        }
+    }
+
+    #[test]
+    fn circuit_breaker_test() {
+        let now = Instant::now();
+        let mut breaker = CircuitBreaker::new(2, Duration::from_secs(30));
+        breaker.failure(now);
+        assert!(breaker.allow(now));
+        breaker.failure(now);
+        assert!(!breaker.allow(now));
+        //half open after the cooldown, a success closes it
+        let later = now + Duration::from_secs(31);
+        assert!(breaker.allow(later));
+        breaker.failure(later);
+        assert!(!breaker.allow(later));
+        breaker.success();
+        assert!(breaker.allow(later));
+    }
+
+    #[test]
+    fn local_indicator_test() {
+        let sma = local_indicator(&req(proto::IndicatorType::SimpleMovingAverage, 2));
+        assert_eq!(sma, Some(vec![1.5, 2.5, 3.5]));
+        assert_eq!(
+            local_indicator(&req(proto::IndicatorType::MaxDrawdown, 2)),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn service_down_test() {
+        //nothing listens on port 1
+        let conf = ClientConf {
+            connect_timeout_ms: 200,
+            retries: 0,
+            breaker_failures: 2,
+            ..Default::default()
+        };
+        let service = IndicatorService::new("http://127.0.0.1:1", conf.clone());
+        let req = req(proto::IndicatorType::SimpleMovingAverage, 2);
+        assert_eq!(
+            service.values("ORCL", req.clone()).await,
+            Some(vec![1.5, 2.5, 3.5])
+        );
+        assert!(service.gen_liste("ORCL", req.clone()).await.is_err());
+        //two failures open the circuit, no more connection attempts
+        assert!(service.is_open());
+        let err = service.gen_liste("ORCL", req.clone()).await.unwrap_err();
+        assert!(matches!(
+            err,
+            CLIError::Indicator(IndicatorError::CircuitOpen(_))
+        ));
+        assert!(!service.health_check().await);
+
+        let hold = IndicatorService::new(
+            "http://127.0.0.1:1",
+            ClientConf {
+                fallback: Fallback::Hold,
+                ..conf
+            },
+        );
+        assert_eq!(hold.values("ORCL", req).await, None);
+    }
+
+    #[tokio::test]
+    async fn batch_down_test() {
+        let conf = ClientConf {
+            connect_timeout_ms: 200,
+            retries: 0,
+            ..Default::default()
+        };
+        let service = IndicatorService::new("http://127.0.0.1:1", conf.clone());
+        let sma = req(proto::IndicatorType::SimpleMovingAverage, 2);
+        let specs = vec![
+            IndicatorSpec {
+                id: sma.id,
+                opt: sma.opt.clone(),
+                lookback: 0,
+            },
+            IndicatorSpec {
+                id: proto::IndicatorType::MaxDrawdown.into(),
+                opt: None,
+                lookback: 0,
+            },
+        ];
+        //local values for what strategy::indicators knows, nothing for the rest
+        let values = service.values_batch("ORCL", &specs, sma.list.clone()).await;
+        assert_eq!(values.get(&sma.id), Some(&vec![1.5, 2.5, 3.5]));
+        assert_eq!(values.len(), 1);
+        assert!(service.open_stream().await.is_err());
+
+        let hold = IndicatorService::new(
+            "http://127.0.0.1:1",
+            ClientConf {
+                fallback: Fallback::Hold,
+                ..conf
+            },
+        );
+        assert!(hold.values_batch("ORCL", &specs, sma.list).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn batch_test() -> Result<(), Box<dyn std::error::Error>> {
+        let service = serve_stub().await?;
+        let ema = IndicatorSpec {
+            id: proto::IndicatorType::ExponentialMovingAverage.into(),
+            ..sma_spec(2, 0)
+        };
+        let specs = vec![sma_spec(2, 0), ema.clone()];
+        let list = vec![1.0, 2.0, 3.0, 4.0];
+        let series = service.gen_batch("ORCL", &specs, list.clone()).await?;
+        assert_eq!(series.len(), 2);
+        assert_eq!(series[0].result, vec![1.5, 2.5, 3.5]);
+        assert_eq!(Some(series[1].result.clone()), stub_values(&ema, &list));
+        let values = service.values_batch("ORCL", &specs, list).await;
+        assert_eq!(values.get(&specs[0].id), Some(&vec![1.5, 2.5, 3.5]));
+        assert_eq!(values.len(), 2);
+        assert!(!service.is_open());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stream_test() -> Result<(), Box<dyn std::error::Error>> {
+        let service = serve_stub().await?;
+        let (mut sender, mut inbound) = service.open_stream().await?;
+        let specs = vec![sma_spec(2, 10)];
+        //only the first update carries the indicators, the service keeps them
+        for (minute, close) in [1.0, 2.0, 3.0].into_iter().enumerate() {
+            assert!(
+                sender
+                    .send("ORCL", minute as i64 * 60_000, close, &specs)
+                    .await
+            );
+        }
+        //no value before the period is covered
+        let first = inbound.message().await?.unwrap();
+        assert!(first.values.is_empty());
+        let second = inbound.message().await?.unwrap();
+        assert_eq!(second.values[0].value, 1.5);
+        let third = inbound.message().await?.unwrap();
+        assert_eq!(third.timestamp, 120_000);
+        assert_eq!(third.values[0].value, 2.5);
+        assert_eq!(third.values[0].spec, Some(specs[0].clone()));
+        assert!(sender.try_send("ORCL", 180_000, 4.0, &specs));
+        assert_eq!(inbound.message().await?.unwrap().values[0].value, 3.5);
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 
 use crate::{
+    indicator_decision::Signal,
     proto,
     types::{Action, Indi, IndiValidate},
 };
@@ -14,10 +15,10 @@ pub fn desision_maker(
     let mut action = vec![];
 
     for i in indicator_values.indicator.iter() {
-        match indicator_values.indicator.get(i.0) {
-            Some(x) => {
-                let o = indicator_eval.get(i.0).unwrap();
-                if *x > *o {
+        //no threshold, no opinion
+        match indicator_eval.get(i.0) {
+            Some(o) => {
+                if *i.1 > *o {
                     action.push(Action::Buy)
                 } else {
                     action.push(Action::Sell)
@@ -30,6 +31,30 @@ pub fn desision_maker(
     action
 }
 
+//one Signal per indicator for indicator_decision::combine_signals,
+//indicators without a threshold are skipped
+pub fn indicator_signals(
+    indicator_values: &Indi,
+    indicator_eval: &HashMap<proto::IndicatorType, f64>,
+) -> Vec<Signal> {
+    indicator_values
+        .indicator
+        .iter()
+        .filter_map(|(indicator, value)| {
+            let threshold = indicator_eval.get(indicator)?;
+            let action = if value > threshold {
+                Action::Buy
+            } else {
+                Action::Sell
+            };
+            Some(Signal {
+                indicator: *indicator,
+                action,
+            })
+        })
+        .collect()
+}
+
 fn amount(funds: f64, fraction: f64, price: f64) -> i64 {
     ((funds * fraction) / price) as i64
 }
@@ -51,9 +76,33 @@ mod tests {
         let handles = desision_maker(hm.clone(), indicator_selected.clone());
         assert_eq!(handles, vec![Action::Sell]);
 
-        let handles = desision_maker(hm, indicator_selected);
+        let handles = desision_maker(hm.clone(), indicator_selected);
         assert_eq!(handles, vec![Action::Sell]);
 
+        //no threshold for the indicator
+        let handles = desision_maker(hm, HashMap::new());
+        assert_eq!(handles, vec![Action::Hold]);
+
         Ok(())
     }
+
+    #[test]
+    fn indicator_signals_test() {
+        let hm = Indi {
+            symbol: String::from("ORCL"),
+            indicator: HashMap::from([
+                (proto::IndicatorType::BollingerBands, 0.3),
+                (proto::IndicatorType::SimpleMovingAverage, 0.3),
+            ]),
+        };
+        let eval = HashMap::from([(proto::IndicatorType::BollingerBands, 0.1)]);
+        let res = indicator_signals(&hm, &eval);
+        assert_eq!(
+            res,
+            vec![Signal {
+                indicator: proto::IndicatorType::BollingerBands,
+                action: Action::Buy,
+            }]
+        );
+    }
 }
@@ -1,4 +1,10 @@
-use crate::types::{Action, ActionEval, ActionValidate, ActionValuator};
+use serde::Deserialize;
+
+use crate::{
+    error::{CLIError, DataError},
+    proto,
+    types::{Action, ActionEval, ActionValidate, ActionValuator, IndiValidate},
+};
 
 /* fn decision_bollinger_bands(upperlower: Vec<(f64, f64, f64)>) -> Vec<u32> {
     let actions_vec: Vec<u32> = upperlower
@@ -14,33 +20,201 @@ use crate::types::{Action, ActionEval, ActionValidate, ActionValuator};
 
     actions_vec
 } */
-pub fn action_evaluator(symbol: String, eval: ActionValidate, av: Vec<Action>) -> ActionValuator {
+//ratio without dividing by zero, no opposing votes counts as the plain vote count
+fn vote_ratio(votes: f32, against: f32) -> f32 {
+    if against == 0.0 {
+        votes
+    } else {
+        votes / against
+    }
+}
+
+pub fn action_evaluator(
+    symbol: String,
+    eval: ActionValidate,
+    av: Vec<Action>,
+) -> Result<ActionValuator, CLIError> {
     let buy_count = av.iter().filter(|x| **x == Action::Buy).count() as f32;
     let sell_count = av.iter().filter(|x| **x == Action::Sell).count() as f32;
-    let eval1 = eval.validate.get(&symbol).unwrap();
+    let eval1 = eval
+        .validate
+        .get(&symbol)
+        .ok_or_else(|| DataError::Missing {
+            symbol: symbol.clone(),
+            what: String::from("action_validate threshold"),
+        })?;
 
     let strength = match eval1 {
         ActionEval::Buy(t) => *t,
         ActionEval::Sell(t) => *t,
         ActionEval::Hold(t) => *t,
     };
-    if (buy_count / sell_count) > strength {
+    let buy_ratio = vote_ratio(buy_count, sell_count);
+    let sell_ratio = vote_ratio(sell_count, buy_count);
+    let av = if buy_ratio > strength && buy_count > sell_count {
         ActionValuator {
             symbol,
-            strength: (buy_count / sell_count) as f64,
+            strength: buy_ratio as f64,
             action: Action::Buy,
+            limit_price: None,
+            exit: None,
         }
-    } else if (sell_count / buy_count) > strength {
+    } else if sell_ratio > strength && sell_count > buy_count {
         ActionValuator {
             symbol,
-            strength: (sell_count / buy_count) as f64,
+            strength: sell_ratio as f64,
             action: Action::Sell,
+            limit_price: None,
+            exit: None,
         }
     } else {
         ActionValuator {
             symbol,
             strength: 0.0,
             action: Action::Hold,
+            limit_price: None,
+            exit: None,
+        }
+    };
+    Ok(av)
+}
+
+//signal of a single indicator
+#[derive(Clone, Debug, PartialEq)]
+pub struct Signal {
+    pub indicator: proto::IndicatorType,
+    pub action: Action,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CombineMode {
+    //more than half of the signals agree
+    #[default]
+    Majority,
+    //weighted net vote must reach the threshold
+    WeightedSum,
+    //every weighted signal agrees
+    Unanimous,
+    //any signal acts as long as no signal opposes it
+    AnyOf,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CombinerConf {
+    #[serde(default)]
+    pub mode: CombineMode,
+    //minimum confidence before Buy or Sell is returned
+    #[serde(default)]
+    pub threshold: f64,
+}
+
+impl Default for CombinerConf {
+    fn default() -> Self {
+        Self {
+            mode: CombineMode::Majority,
+            threshold: 0.0,
+        }
+    }
+}
+
+//Combines per indicator signals into one ActionValuator.
+//Weights come from IndiValidate, missing weights count as 1.0 and negative as 0.0.
+//strength is the confidence in [0, 1] for the returned Buy or Sell, Hold always has 0.0.
+pub fn combine_signals(
+    symbol: String,
+    signals: &[Signal],
+    weights: &IndiValidate,
+    conf: &CombinerConf,
+) -> ActionValuator {
+    let symbol_weights = weights.validate.get(&symbol);
+    let weighted: Vec<(f64, &Action)> = signals
+        .iter()
+        .map(|s| {
+            let w = symbol_weights
+                .and_then(|w| w.get(&s.indicator))
+                .copied()
+                .unwrap_or(1.0)
+                .max(0.0);
+            (w, &s.action)
+        })
+        .filter(|(w, _)| *w > 0.0)
+        .collect();
+
+    let total: f64 = weighted.iter().map(|(w, _)| w).sum();
+    let sum_of = |action: Action| -> f64 {
+        weighted
+            .iter()
+            .filter(|(_, a)| **a == action)
+            .map(|(w, _)| w)
+            .sum()
+    };
+    let count_of = |action: Action| weighted.iter().filter(|(_, a)| **a == action).count();
+    let (buy_w, sell_w) = (sum_of(Action::Buy), sum_of(Action::Sell));
+    let n = weighted.len() as f64;
+
+    let (action, confidence) = if weighted.is_empty() || total == 0.0 {
+        (Action::Hold, 0.0)
+    } else {
+        match conf.mode {
+            CombineMode::Majority => {
+                let (buy, sell) = (count_of(Action::Buy) as f64, count_of(Action::Sell) as f64);
+                if buy > n / 2.0 {
+                    (Action::Buy, buy / n)
+                } else if sell > n / 2.0 {
+                    (Action::Sell, sell / n)
+                } else {
+                    (Action::Hold, 0.0)
+                }
+            }
+            CombineMode::WeightedSum => {
+                let score = (buy_w - sell_w) / total;
+                if score > 0.0 {
+                    (Action::Buy, score)
+                } else if score < 0.0 {
+                    (Action::Sell, -score)
+                } else {
+                    (Action::Hold, 0.0)
+                }
+            }
+            CombineMode::Unanimous => {
+                if buy_w == total {
+                    (Action::Buy, 1.0)
+                } else if sell_w == total {
+                    (Action::Sell, 1.0)
+                } else {
+                    (Action::Hold, 0.0)
+                }
+            }
+            CombineMode::AnyOf => {
+                if buy_w > 0.0 && sell_w == 0.0 {
+                    (Action::Buy, buy_w / total)
+                } else if sell_w > 0.0 && buy_w == 0.0 {
+                    (Action::Sell, sell_w / total)
+                } else {
+                    (Action::Hold, 0.0)
+                }
+            }
+        }
+    };
+
+    let confidence = confidence.clamp(0.0, 1.0);
+    if action == Action::Hold || confidence < conf.threshold {
+        ActionValuator {
+            symbol,
+            strength: 0.0,
+            action: Action::Hold,
+            limit_price: None,
+            exit: None,
+        }
+    } else {
+        ActionValuator {
+            symbol,
+            strength: confidence,
+            action,
+            limit_price: None,
+            exit: None,
         }
     }
 }
@@ -62,8 +236,97 @@ mod tests {
                 (sym.clone(), ActionEval::Hold(0.3)),
             ]),
         };
-        let action = action_evaluator(sym, i, gg);
+        let action = action_evaluator(sym, i.clone(), gg)?;
         assert_eq!(action.strength, 3.0);
+        //no threshold for the symbol
+        let err = action_evaluator(String::from("AAPL"), i, vec![Action::Buy]).unwrap_err();
+        assert!(matches!(err, CLIError::Data(DataError::Missing { .. })));
+        Ok(())
+    }
+
+    fn signals(actions: &[(proto::IndicatorType, Action)]) -> Vec<Signal> {
+        actions
+            .iter()
+            .map(|(i, a)| Signal {
+                indicator: *i,
+                action: a.clone(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn action_evaluator_no_sells_test() -> Result<(), Box<dyn std::error::Error>> {
+        let sym = String::from("ORCL");
+        let i = ActionValidate {
+            validate: HashMap::from([(sym.clone(), ActionEval::Buy(0.1))]),
+        };
+        let action = action_evaluator(sym.clone(), i.clone(), vec![Action::Buy, Action::Buy])?;
+        assert_eq!(action.action, Action::Buy);
+        assert_eq!(action.strength, 2.0);
+        let action = action_evaluator(sym, i, vec![])?;
+        assert_eq!(action.action, Action::Hold);
+        Ok(())
+    }
+
+    #[test]
+    fn combine_signals_test() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::proto::IndicatorType::{
+            BollingerBands, ExponentialMovingAverage, RelativeStrengthIndex,
+        };
+        let sym = String::from("ORCL");
+        let weights = IndiValidate {
+            validate: HashMap::from([(
+                sym.clone(),
+                HashMap::from([
+                    (BollingerBands, 3.0),
+                    (ExponentialMovingAverage, 1.0),
+                    (RelativeStrengthIndex, 1.0),
+                ]),
+            )]),
+        };
+        let s = signals(&[
+            (BollingerBands, Action::Sell),
+            (ExponentialMovingAverage, Action::Buy),
+            (RelativeStrengthIndex, Action::Buy),
+        ]);
+        let conf = |mode| CombinerConf {
+            mode,
+            threshold: 0.0,
+        };
+
+        let res = combine_signals(sym.clone(), &s, &weights, &conf(CombineMode::Majority));
+        assert_eq!(res.action, Action::Buy);
+        assert!((res.strength - 2.0 / 3.0).abs() < 1e-9);
+
+        let res = combine_signals(sym.clone(), &s, &weights, &conf(CombineMode::WeightedSum));
+        assert_eq!(res.action, Action::Sell);
+        assert!((res.strength - 0.2).abs() < 1e-9);
+
+        let res = combine_signals(sym.clone(), &s, &weights, &conf(CombineMode::Unanimous));
+        assert_eq!(res.action, Action::Hold);
+
+        let res = combine_signals(sym.clone(), &s, &weights, &conf(CombineMode::AnyOf));
+        assert_eq!(res.action, Action::Hold);
+
+        let res = combine_signals(
+            sym.clone(),
+            &s[1..],
+            &weights,
+            &conf(CombineMode::Unanimous),
+        );
+        assert_eq!(res.action, Action::Buy);
+        assert_eq!(res.strength, 1.0);
+
+        let strict = CombinerConf {
+            mode: CombineMode::WeightedSum,
+            threshold: 0.5,
+        };
+        let res = combine_signals(sym.clone(), &s, &weights, &strict);
+        assert_eq!(res.action, Action::Hold);
+
+        let res = combine_signals(sym, &[], &weights, &conf(CombineMode::AnyOf));
+        assert_eq!(res.action, Action::Hold);
+        assert_eq!(res.strength, 0.0);
         Ok(())
     }
 }
@@ -1,28 +1,41 @@
 //no warnings
 #![allow(warnings)]
 
-use apca::data::v2::stream::Data;
+use clap::Parser;
 use error::CLIError;
-use std::sync::Arc;
-use std::sync::Mutex;
-use trader::TraderConfigs;
 
 mod alpaca_to_polars;
+mod asset;
+mod backtest;
+mod book;
+mod broker;
+mod calendar;
+mod cli;
 mod client;
 mod config;
 mod config2;
+mod control;
 mod data;
 mod dataframe;
 mod error;
+mod execution;
+mod grpc_client;
 mod helper;
 mod indicator_decision;
+mod notify;
+mod order;
+mod portfolio;
+mod reload;
+mod retry;
 mod runner;
+mod scheduler;
+mod strategy;
+mod telemetry;
 mod test_helper;
 mod trade;
 mod trader;
 mod types;
-use proto::indicator_client::IndicatorClient;
-mod portfolio;
+mod validate;
 
 pub mod proto {
     tonic::include_proto!("calculate");
@@ -33,127 +46,28 @@ mod settings_delete;
 //use settings::Settings;
 use config2::Settings;
 
-use apca::data::v2::stream::drive;
-use apca::data::v2::stream::Bar;
-use apca::data::v2::stream::MarketData;
-use apca::data::v2::stream::Quote;
-use apca::data::v2::stream::RealtimeData;
-use apca::data::v2::stream::IEX;
-use apca::ApiInfo;
-use apca::Client;
-use apca::Error;
-
-use chrono::DateTime;
-use chrono::Utc;
-
-use futures::FutureExt as _;
-use futures::StreamExt as _;
-use futures::TryStreamExt as _;
-
-use num_decimal::Num;
-
-use serde::Deserialize;
-use serde::Serialize;
-
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
-pub struct Trade {
-    /// The trade's symbol.
-    #[serde(rename = "S")]
-    pub symbol: String,
-    /// The trade's ID.
-    #[serde(rename = "i")]
-    pub trade_id: u64,
-    /// The trade's price.
-    #[serde(rename = "p")]
-    pub trade_price: Num,
-    /// The trade's size.
-    #[serde(rename = "s")]
-    pub trade_size: u64,
-    /// The trade's conditions.
-    #[serde(rename = "c")]
-    pub conditions: Vec<String>,
-    /// The trade's time stamp.
-    #[serde(rename = "t")]
-    pub timestamp: DateTime<Utc>,
-    /// The trade's exchange.
-    #[serde(rename = "x")]
-    pub exchange: String,
-    /// The trade's tape.
-    #[serde(rename = "z")]
-    pub tape: String,
-    /// The trade's update, may be "canceled", "corrected", or
-    /// "incorrect".
-    #[serde(rename = "u", default)]
-    pub update: Option<String>,
-}
-
-fn te(data: Data<Bar, Quote, Trade>) -> () {}
-
 #[tokio::main]
 async fn main() -> Result<(), CLIError> {
-    // Requires the following environment variables to be present:
+    // Trading commands require the following environment variables:
     // - APCA_API_KEY_ID -> your API key
     // - APCA_API_SECRET_KEY -> your secret key
     //
     // Optionally, the following variable is honored:
     // - APCA_API_BASE_URL -> the API base URL to use (set to
     //   https://api.alpaca.markets for live trading)
-    let api_info = ApiInfo::from_env().unwrap();
-    let client = Client::new(api_info);
-
-    let (mut stream, mut subscription) = client
-        .subscribe::<RealtimeData<IEX, Bar, Quote, Trade>>()
-        .await
-        .unwrap();
-
-    let mut data = MarketData::default();
-    // Subscribe to minute aggregate bars for SPY and XLK...
-    data.set_bars(["SPY", "XLK"]);
-    // ... and realtime quotes for AAPL...
-    data.set_quotes(["AAPL"]);
-    // ... and realtime trades for TSLA.
-    data.set_trades(["TSLA"]);
-
-    let subscribe = subscription.subscribe(&data).boxed();
-    // Actually subscribe with the websocket server.
-    let () = drive(subscribe, &mut stream)
-        .await
-        .unwrap()
-        .unwrap()
-        .unwrap();
-
-    let () = stream
-        // Stop after receiving and printing 50 updates.
-        .take(50)
-        .map_err(Error::WebSocket)
-        .try_for_each(|result| async { result.map(|data| te(data)).map_err(Error::Json) })
-        .await
-        .unwrap();
-
-    //let settings = Settings::new();
-
-    // Print out our settings
-    //println!("{settings:?}");
-
-    // construct a subscriber that prints formatted traces to stdout
-    let subscriber = tracing_subscriber::fmt().compact().finish();
-
-    // use that subscriber to process traces emitted after this point
-    tracing::subscriber::set_global_default(subscriber).unwrap();
-    tracing::info!("Hello, world!");
-
-    let settings = Settings::new().unwrap();
-
-    let client = IndicatorClient::connect("http://[::1]:50051").await?;
-    tracing::info!("Hello, world!");
-    let tr = TraderConfigs::new(settings, "Config.toml", Some(client), "ORCL").await?;
-    let tr_config = Arc::new(Mutex::new(tr.clone()));
-
-    //spawn trader
-    let handles = tr.trader_spawn(tr_config).await;
-
-    for i in handles {
-        i.await.unwrap();
+    let cli = cli::Cli::parse();
+
+    // [logging] of the config, the defaults while the config is broken,
+    // the validation of the command reports why
+    let common = cli.command.common();
+    let logging = Settings::from_dir(&common.config_dir, &common.run_mode)
+        .map(|s| s.logging)
+        .unwrap_or_default();
+    telemetry::init_logging(&logging)?;
+
+    let res = cli::run(cli).await;
+    if let Err(e) = &res {
+        tracing::error!("{e}");
     }
-    Ok(())
+    res
 }
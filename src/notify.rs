@@ -0,0 +1,590 @@
+//Notifications on fills, risk breaches, lost connections and daily summaries.
+//Events are queued without waiting, a worker task throttles them per kind and
+//subject, renders the template and hands the text to every sink of [notify].
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use chrono::{NaiveDate, Utc};
+use http_body_util::Full;
+use hyper::{body::Bytes, header::CONTENT_TYPE, Request};
+use hyper_tls::HttpsConnector;
+use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+use tokio::{io::AsyncWriteExt, sync::mpsc};
+use tracing::{error, info};
+
+use crate::error::CLIError;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Fill,
+    Risk,
+    Disconnect,
+    Reconnect,
+    Summary,
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Event {
+    Fill {
+        symbol: String,
+        side: String,
+        qty: f64,
+        price: f64,
+    },
+    Risk {
+        symbol: String,
+        msg: String,
+    },
+    Disconnect {
+        service: String,
+        msg: String,
+    },
+    Reconnect {
+        service: String,
+    },
+    Summary {
+        date: NaiveDate,
+        bars: u64,
+        orders: u64,
+        rejected: u64,
+        pnl: f64,
+    },
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::Fill { .. } => EventKind::Fill,
+            Event::Risk { .. } => EventKind::Risk,
+            Event::Disconnect { .. } => EventKind::Disconnect,
+            Event::Reconnect { .. } => EventKind::Reconnect,
+            Event::Summary { .. } => EventKind::Summary,
+        }
+    }
+
+    //what the throttle tells apart, a symbol or a service
+    pub fn subject(&self) -> &str {
+        match self {
+            Event::Fill { symbol, .. } | Event::Risk { symbol, .. } => symbol,
+            Event::Disconnect { service, .. } | Event::Reconnect { service } => service,
+            Event::Summary { .. } => "summary",
+        }
+    }
+
+    //{field} of the template is replaced with the field of the event
+    pub fn render(&self, template: &str) -> String {
+        let Ok(serde_json::Value::Object(fields)) = serde_json::to_value(self) else {
+            return template.to_string();
+        };
+        fields.iter().fold(template.to_string(), |text, (k, v)| {
+            let v = match v {
+                serde_json::Value::String(s) => s.clone(),
+                v => v.to_string(),
+            };
+            text.replace(&format!("{{{k}}}"), &v)
+        })
+    }
+}
+
+fn default_template(kind: EventKind) -> &'static str {
+    match kind {
+        EventKind::Fill => "{symbol}: filled {side} {qty} @ {price}",
+        EventKind::Risk => "{symbol}: {msg}",
+        EventKind::Disconnect => "{service} disconnected: {msg}",
+        EventKind::Reconnect => "{service} reconnected",
+        EventKind::Summary => {
+            "{date}: {bars} bars, {orders} orders, {rejected} rejected, P&L {pnl}"
+        }
+    }
+}
+
+//fills and summaries always go out, repeated outages every 5 minutes at most
+fn default_throttle_secs(kind: EventKind) -> u64 {
+    match kind {
+        EventKind::Fill | EventKind::Summary => 0,
+        EventKind::Risk | EventKind::Disconnect | EventKind::Reconnect => 300,
+    }
+}
+
+//[notify]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifyConf {
+    pub sinks: Vec<SinkConf>,
+    //seconds between two events of the same kind and subject
+    pub throttle_secs: HashMap<EventKind, u64>,
+    pub templates: HashMap<EventKind, String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SinkConf {
+    pub sink: Sink,
+    //all events if empty
+    pub events: Vec<EventKind>,
+}
+
+//events next to the keys of the sink, split by hand as flatten would let unknown keys through
+impl<'de> Deserialize<'de> for SinkConf {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut table = toml::Table::deserialize(deserializer)?;
+        let events = table
+            .remove("events")
+            .map(Vec::<EventKind>::deserialize)
+            .transpose()
+            .map_err(|e| D::Error::custom(e.message()))?
+            .unwrap_or_default();
+        let sink = Sink::deserialize(toml::Value::Table(table))
+            .map_err(|e| D::Error::custom(e.message()))?;
+        Ok(SinkConf { sink, events })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Sink {
+    //POST of the event as JSON with the rendered text
+    Webhook {
+        url: String,
+    },
+    Smtp {
+        host: String,
+        #[serde(default = "default_smtp_port")]
+        port: u16,
+        //STARTTLS relay, plain SMTP for a local relay otherwise
+        #[serde(default)]
+        tls: bool,
+        username: Option<String>,
+        //SMTP_PASSWORD if not set
+        password: Option<String>,
+        from: String,
+        to: Vec<String>,
+    },
+    //one line per event appended to the file
+    File {
+        path: String,
+    },
+    //program runs with NOTIFY_KIND, NOTIFY_TEXT and NOTIFY_EVENT (JSON) set
+    Command {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+}
+
+fn default_smtp_port() -> u16 {
+    25
+}
+
+fn notify_err(sink: &'static str) -> impl Fn(&dyn Display) -> CLIError {
+    move |e| CLIError::Notify {
+        sink,
+        msg: e.to_string(),
+    }
+}
+
+impl Sink {
+    pub async fn send(&self, event: &Event, text: &str) -> Result<(), CLIError> {
+        match self {
+            Sink::Webhook { url } => {
+                let err = notify_err("webhook");
+                let body = serde_json::json!({ "text": text, "event": event }).to_string();
+                let req = Request::post(url)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Full::new(Bytes::from(body)))
+                    .map_err(|e| err(&e))?;
+                let client = Client::builder(TokioExecutor::new()).build(HttpsConnector::new());
+                let res = client.request(req).await.map_err(|e| err(&e))?;
+                if !res.status().is_success() {
+                    return Err(err(&format!("{url} answered {}", res.status())));
+                }
+            }
+            Sink::Smtp {
+                host,
+                port,
+                tls,
+                username,
+                password,
+                from,
+                to,
+            } => {
+                let err = notify_err("smtp");
+                let mut builder = if *tls {
+                    AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)
+                        .map_err(|e| err(&e))?
+                } else {
+                    AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+                }
+                .port(*port);
+                if let Some(username) = username {
+                    let password = password
+                        .clone()
+                        .or_else(|| std::env::var("SMTP_PASSWORD").ok())
+                        .unwrap_or_default();
+                    builder = builder.credentials(Credentials::new(username.clone(), password));
+                }
+                let mut message = Message::builder()
+                    .from(from.parse::<Mailbox>().map_err(|e| err(&e))?)
+                    .subject(format!("trader: {:?} {}", event.kind(), event.subject()));
+                for to in to {
+                    message = message.to(to.parse::<Mailbox>().map_err(|e| err(&e))?);
+                }
+                let message = message.body(text.to_string()).map_err(|e| err(&e))?;
+                builder.build().send(message).await.map_err(|e| err(&e))?;
+            }
+            Sink::File { path } => {
+                let err = notify_err("file");
+                let mut file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await
+                    .map_err(|e| err(&e))?;
+                let line = format!("{} {text}\n", Utc::now().to_rfc3339());
+                file.write_all(line.as_bytes()).await.map_err(|e| err(&e))?;
+            }
+            Sink::Command { program, args } => {
+                let err = notify_err("command");
+                let status = tokio::process::Command::new(program)
+                    .args(args)
+                    .env("NOTIFY_KIND", format!("{:?}", event.kind()).to_lowercase())
+                    .env("NOTIFY_TEXT", text)
+                    .env(
+                        "NOTIFY_EVENT",
+                        serde_json::to_string(event).unwrap_or_default(),
+                    )
+                    .status()
+                    .await
+                    .map_err(|e| err(&e))?;
+                if !status.success() {
+                    return Err(err(&format!("{program} exited with {status}")));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct Notifier {
+    conf: NotifyConf,
+    //last event sent per kind and subject
+    sent: HashMap<(EventKind, String), Instant>,
+}
+
+impl Notifier {
+    pub fn new(conf: NotifyConf) -> Self {
+        Notifier {
+            conf,
+            sent: HashMap::new(),
+        }
+    }
+
+    //true if the same kind and subject went out within the throttle window
+    pub fn throttled(&mut self, event: &Event, now: Instant) -> bool {
+        let kind = event.kind();
+        let secs = self
+            .conf
+            .throttle_secs
+            .get(&kind)
+            .copied()
+            .unwrap_or_else(|| default_throttle_secs(kind));
+        let key = (kind, event.subject().to_string());
+        if let Some(last) = self.sent.get(&key) {
+            if now.duration_since(*last) < Duration::from_secs(secs) {
+                return true;
+            }
+        }
+        self.sent.insert(key, now);
+        false
+    }
+
+    pub fn text(&self, event: &Event) -> String {
+        let kind = event.kind();
+        match self.conf.templates.get(&kind) {
+            Some(template) => event.render(template),
+            None => event.render(default_template(kind)),
+        }
+    }
+
+    //a failing sink is logged, the others still get the event
+    pub async fn dispatch(&mut self, event: Event) {
+        if self.throttled(&event, Instant::now()) {
+            return;
+        }
+        let text = self.text(&event);
+        info!("notify: {text}");
+        let kind = event.kind();
+        for sink in &self.conf.sinks {
+            if !sink.events.is_empty() && !sink.events.contains(&kind) {
+                continue;
+            }
+            if let Err(e) = sink.sink.send(&event, &text).await {
+                error!("{e}");
+            }
+        }
+    }
+
+    pub async fn run(mut self, mut rx: mpsc::UnboundedReceiver<Event>) {
+        while let Some(event) = rx.recv().await {
+            self.dispatch(event).await;
+        }
+    }
+}
+
+//counts of the trading day, the summary goes out with the first bar of the next one
+#[derive(Debug, Default)]
+pub struct DailySummary {
+    date: Option<NaiveDate>,
+    bars: u64,
+    orders: u64,
+    rejected: u64,
+    //reported at the close, the next day doesn't report it again
+    closed: Option<NaiveDate>,
+}
+
+impl DailySummary {
+    //the summary of the previous day if date starts a new one
+    pub fn bar(&mut self, date: NaiveDate, pnl: f64) -> Option<Event> {
+        let summary = match self.date {
+            Some(day) if day != date && self.closed != Some(day) => Some(Event::Summary {
+                date: day,
+                bars: self.bars,
+                orders: self.orders,
+                rejected: self.rejected,
+                pnl,
+            }),
+            _ => None,
+        };
+        if self.date != Some(date) {
+            *self = DailySummary {
+                date: Some(date),
+                ..Default::default()
+            };
+        }
+        self.bars += 1;
+        summary
+    }
+
+    pub fn order(&mut self, rejected: bool) {
+        self.orders += 1;
+        self.rejected += rejected as u64;
+    }
+
+    //summary of the current day at the close, None before the first bar
+    pub fn close(&mut self, pnl: f64) -> Option<Event> {
+        let date = self.date?;
+        if self.closed == Some(date) {
+            return None;
+        }
+        self.closed = Some(date);
+        Some(Event::Summary {
+            date,
+            bars: self.bars,
+            orders: self.orders,
+            rejected: self.rejected,
+            pnl,
+        })
+    }
+}
+
+static EVENTS: OnceLock<mpsc::UnboundedSender<Event>> = OnceLock::new();
+
+//starts the worker once, events before that or without sinks are dropped
+pub fn install(conf: NotifyConf) {
+    if conf.sinks.is_empty() || EVENTS.get().is_some() {
+        return;
+    }
+    let (tx, rx) = mpsc::unbounded_channel();
+    if EVENTS.set(tx).is_ok() {
+        tokio::spawn(Notifier::new(conf).run(rx));
+    }
+}
+
+//never waits, the trading path calls this
+pub fn notify(event: Event) {
+    if let Some(tx) = EVENTS.get() {
+        let _ = tx.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, BufReader},
+        net::TcpListener,
+    };
+
+    fn fill() -> Event {
+        Event::Fill {
+            symbol: String::from("ORCL"),
+            side: String::from("Buy"),
+            qty: 10.0,
+            price: 101.5,
+        }
+    }
+
+    fn outage() -> Event {
+        Event::Disconnect {
+            service: String::from("indicator service"),
+            msg: String::from("connection refused"),
+        }
+    }
+
+    #[test]
+    fn template_test() {
+        let mut conf = NotifyConf::default();
+        conf.templates
+            .insert(EventKind::Fill, String::from("{side} {symbol} {qty}"));
+        let notifier = Notifier::new(conf);
+        assert_eq!(notifier.text(&fill()), "Buy ORCL 10.0");
+        assert_eq!(
+            notifier.text(&outage()),
+            "indicator service disconnected: connection refused"
+        );
+    }
+
+    #[test]
+    fn throttle_test() {
+        let mut notifier = Notifier::new(NotifyConf::default());
+        let now = Instant::now();
+        assert!(!notifier.throttled(&outage(), now));
+        assert!(notifier.throttled(&outage(), now + Duration::from_secs(60)));
+        assert!(!notifier.throttled(&outage(), now + Duration::from_secs(301)));
+        //fills are not throttled by default
+        assert!(!notifier.throttled(&fill(), now));
+        assert!(!notifier.throttled(&fill(), now));
+    }
+
+    #[test]
+    fn daily_summary_test() {
+        let day = |d| NaiveDate::from_ymd_opt(2024, 3, d).unwrap();
+        let mut summary = DailySummary::default();
+        assert_eq!(summary.bar(day(4), 0.0), None);
+        summary.order(false);
+        summary.order(true);
+        assert_eq!(summary.bar(day(4), 0.0), None);
+        assert_eq!(
+            summary.bar(day(5), 12.5),
+            Some(Event::Summary {
+                date: day(4),
+                bars: 2,
+                orders: 2,
+                rejected: 1,
+                pnl: 12.5,
+            })
+        );
+        assert_eq!(summary.bar(day(5), 0.0), None);
+
+        //reported at the close, not again with the first bar of the next day
+        assert!(summary.close(3.0).is_some());
+        assert_eq!(summary.close(3.0), None);
+        assert_eq!(summary.bar(day(6), 0.0), None);
+    }
+
+    #[tokio::test]
+    async fn webhook_test() {
+        //answers one request and hands back what it got
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 4096];
+            let mut received = String::new();
+            //headers and the JSON body, which ends the request
+            while !(received.contains("\"text\"") && received.ends_with('}')) {
+                let n = socket.read(&mut buf).await.unwrap();
+                received.push_str(&String::from_utf8_lossy(&buf[..n]));
+            }
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            received
+        });
+        let sink = Sink::Webhook { url };
+        sink.send(&fill(), "ORCL: filled").await.unwrap();
+        let received = server.await.unwrap();
+        assert!(received.starts_with("POST /hook"));
+        assert!(received.contains(r#""text":"ORCL: filled""#));
+        assert!(received.contains(r#""kind":"fill""#));
+    }
+
+    #[tokio::test]
+    async fn smtp_test() {
+        //the few replies lettre needs for one plain mail
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (read, mut write) = socket.into_split();
+            let mut lines = BufReader::new(read).lines();
+            write.write_all(b"220 localhost\r\n").await.unwrap();
+            let mut data = false;
+            let mut mail = String::new();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let reply: &[u8] = if data {
+                    if line != "." {
+                        mail.push_str(&line);
+                        mail.push('\n');
+                        continue;
+                    }
+                    data = false;
+                    b"250 queued\r\n"
+                } else if line.starts_with("EHLO") {
+                    b"250 localhost\r\n"
+                } else if line == "DATA" {
+                    data = true;
+                    b"354 go ahead\r\n"
+                } else if line == "QUIT" {
+                    write.write_all(b"221 bye\r\n").await.unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                write.write_all(reply).await.unwrap();
+            }
+            mail
+        });
+        let sink = Sink::Smtp {
+            host: String::from("127.0.0.1"),
+            port,
+            tls: false,
+            username: None,
+            password: None,
+            from: String::from("bot@localhost"),
+            to: vec![String::from("desk@localhost")],
+        };
+        sink.send(&outage(), "indicator service disconnected")
+            .await
+            .unwrap();
+        let mail = server.await.unwrap();
+        assert!(mail.contains("Subject: trader: Disconnect indicator service"));
+        assert!(mail.contains("indicator service disconnected"));
+    }
+
+    #[tokio::test]
+    async fn file_sink_test() {
+        let path = std::env::temp_dir().join(format!("notify_{}.log", std::process::id()));
+        let sink = Sink::File {
+            path: path.to_string_lossy().to_string(),
+        };
+        sink.send(&fill(), "first").await.unwrap();
+        sink.send(&fill(), "second").await.unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].ends_with(" second"));
+    }
+}
@@ -0,0 +1,670 @@
+//Orders beyond a single limit: market, limit, stop, stop-limit and trailing stop
+//types, time in force and bracket, OCO and OTO classes with exit legs. Alpaca gets
+//them as they are, the paper broker and the backtester fill them in a SimBook.
+use std::collections::HashSet;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Deserialize;
+
+use crate::{
+    asset::is_crypto, data::cache::CachedBar, error::BrokerError, trade::order_qty,
+    types::ActionValuator,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+//distance of a trailing stop from the best price since it was placed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trail {
+    Price(f64),
+    Percent(f64),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OrderType {
+    #[default]
+    Market,
+    Limit(f64),
+    Stop(f64),
+    //a limit order once the stop is reached
+    StopLimit {
+        stop: f64,
+        limit: f64,
+    },
+    TrailingStop(Trail),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeInForce {
+    #[default]
+    Day,
+    //until canceled
+    Gtc,
+    //whatever fills right away, the rest is canceled
+    Ioc,
+    //all right away or nothing
+    Fok,
+    //opening and closing auction, simulated like day orders
+    Opg,
+    Cls,
+}
+
+//exit legs of a long position, prices
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Exit {
+    pub take_profit: Option<f64>,
+    pub stop_loss: Option<f64>,
+    //limit of the stop-loss, a plain stop without
+    pub stop_limit: Option<f64>,
+}
+
+impl Exit {
+    fn legs(&self) -> usize {
+        self.take_profit.is_some() as usize + self.stop_loss.is_some() as usize
+    }
+}
+
+//[[Stockconfig.SYMBOL]] bracket = { take_profit_pct = 4, stop_pct = 2 },
+//exits of every entry of the variant in percent of the entry price
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BracketConf {
+    pub take_profit_pct: Option<f64>,
+    pub stop_pct: Option<f64>,
+    //the stop-loss becomes a stop-limit this far below the stop
+    pub stop_limit_pct: Option<f64>,
+}
+
+impl BracketConf {
+    //None without a take-profit or a stop
+    pub fn exit(&self, entry: f64) -> Option<Exit> {
+        let below = |pct: f64| entry * (1.0 - pct / 100.0);
+        let exit = Exit {
+            take_profit: self.take_profit_pct.map(|pct| entry * (1.0 + pct / 100.0)),
+            stop_loss: self.stop_pct.map(below),
+            stop_limit: self
+                .stop_pct
+                .zip(self.stop_limit_pct)
+                .map(|(stop, limit)| below(stop + limit)),
+        };
+        (exit.legs() > 0).then_some(exit)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OrderClass {
+    #[default]
+    Simple,
+    //entry with take-profit and stop-loss, the exit that fills cancels the other
+    Bracket(Exit),
+    //take-profit and stop-loss of a held position, one cancels the other
+    Oco(Exit),
+    //entry that places one exit once filled
+    Oto(Exit),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OrderSpec {
+    pub symbol: String,
+    pub side: Side,
+    pub qty: f64,
+    pub type_: OrderType,
+    pub time_in_force: TimeInForce,
+    pub class: OrderClass,
+}
+
+impl OrderSpec {
+    //market order for the day, crypto has no day and stays until canceled
+    pub fn new(symbol: &str, side: Side, qty: f64) -> Self {
+        OrderSpec {
+            symbol: symbol.to_string(),
+            side,
+            qty,
+            type_: OrderType::Market,
+            time_in_force: if is_crypto(symbol) {
+                TimeInForce::Gtc
+            } else {
+                TimeInForce::Day
+            },
+            class: OrderClass::Simple,
+        }
+    }
+
+    pub fn with_type(mut self, type_: OrderType) -> Self {
+        self.type_ = type_;
+        self
+    }
+
+    pub fn with_time_in_force(mut self, time_in_force: TimeInForce) -> Self {
+        self.time_in_force = time_in_force;
+        self
+    }
+
+    pub fn with_class(mut self, class: OrderClass) -> Self {
+        self.class = class;
+        self
+    }
+
+    //take-profit limit and stop-loss selling qty of a held position
+    pub fn oco(symbol: &str, qty: f64, exit: Exit) -> Self {
+        let take_profit = exit.take_profit.unwrap_or_default();
+        OrderSpec::new(symbol, Side::Sell, qty)
+            .with_type(OrderType::Limit(take_profit))
+            .with_time_in_force(TimeInForce::Gtc)
+            .with_class(OrderClass::Oco(exit))
+    }
+
+    //sells protecting a held position: OCO with both legs, else the one exit alone
+    pub fn protect(symbol: &str, qty: f64, exit: Exit) -> Self {
+        let sell = OrderSpec::new(symbol, Side::Sell, qty).with_time_in_force(TimeInForce::Gtc);
+        match (exit.take_profit, exit.stop_loss, exit.stop_limit) {
+            (Some(_), Some(_), _) => OrderSpec::oco(symbol, qty, exit),
+            (Some(take_profit), None, _) => sell.with_type(OrderType::Limit(take_profit)),
+            (None, Some(stop), Some(limit)) => sell.with_type(OrderType::StopLimit { stop, limit }),
+            (None, Some(stop), None) => sell.with_type(OrderType::Stop(stop)),
+            (None, None, _) => sell,
+        }
+    }
+
+    //limit or market order of the action, buys carry the exits of the ActionValuator
+    pub fn from_av(av: &ActionValuator, side: Side) -> Self {
+        let mut spec = OrderSpec::new(&av.symbol, side, order_qty(av));
+        if let Some(limit) = av.limit_price {
+            spec.type_ = OrderType::Limit(limit);
+        }
+        if let (Side::Buy, Some(exit)) = (side, av.exit) {
+            spec.class = match exit.legs() {
+                2 => OrderClass::Bracket(exit),
+                _ => OrderClass::Oto(exit),
+            };
+        }
+        spec
+    }
+
+    fn invalid(&self, msg: &str) -> BrokerError {
+        BrokerError::InvalidOrder {
+            symbol: self.symbol.clone(),
+            msg: msg.to_string(),
+        }
+    }
+
+    //what Alpaca would reject anyway, checked before sending or simulating
+    pub fn check(&self) -> Result<(), BrokerError> {
+        if !(self.qty > 0.0 && self.qty.is_finite()) {
+            return Err(self.invalid("quantity must be > 0"));
+        }
+        let prices = match self.type_ {
+            OrderType::Market => vec![],
+            OrderType::Limit(p) | OrderType::Stop(p) => vec![p],
+            OrderType::StopLimit { stop, limit } => vec![stop, limit],
+            OrderType::TrailingStop(Trail::Price(p) | Trail::Percent(p)) => vec![p],
+        };
+        if prices.iter().any(|p| !(*p > 0.0 && p.is_finite())) {
+            return Err(self.invalid("prices must be > 0"));
+        }
+        let exit = match self.class {
+            OrderClass::Simple => return Ok(()),
+            OrderClass::Bracket(exit) | OrderClass::Oco(exit) | OrderClass::Oto(exit) => exit,
+        };
+        match (self.class, exit.legs()) {
+            (OrderClass::Bracket(_) | OrderClass::Oco(_), 2) | (OrderClass::Oto(_), 1) => {}
+            (OrderClass::Oto(_), _) => {
+                return Err(self.invalid("an OTO order takes a take-profit or a stop-loss"))
+            }
+            _ => return Err(self.invalid("needs a take-profit and a stop-loss")),
+        }
+        if let (Some(take_profit), Some(stop_loss)) = (exit.take_profit, exit.stop_loss) {
+            if take_profit <= stop_loss {
+                return Err(self.invalid("take-profit must be above the stop-loss"));
+            }
+        }
+        if !matches!(self.time_in_force, TimeInForce::Day | TimeInForce::Gtc) {
+            return Err(self.invalid("bracket, OCO and OTO orders are day or gtc"));
+        }
+        match self.class {
+            OrderClass::Oco(exit)
+                if self.side != Side::Sell
+                    || self.type_ != OrderType::Limit(exit.take_profit.unwrap_or_default()) =>
+            {
+                Err(self.invalid("an OCO order sells at the take-profit limit"))
+            }
+            OrderClass::Bracket(_) | OrderClass::Oto(_) if self.side != Side::Buy => {
+                Err(self.invalid("exit legs only protect long entries"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+//one bar as seen by resting orders
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SimBar {
+    pub time: DateTime<Utc>,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+}
+
+impl SimBar {
+    //a single price, e.g. the last trade of the paper broker
+    pub fn flat(time: DateTime<Utc>, price: f64) -> Self {
+        SimBar {
+            time,
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+        }
+    }
+}
+
+impl From<&CachedBar> for SimBar {
+    fn from(b: &CachedBar) -> Self {
+        SimBar {
+            time: b.time,
+            open: b.open,
+            high: b.high,
+            low: b.low,
+            close: b.close,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fill {
+    pub symbol: String,
+    pub side: Side,
+    pub qty: f64,
+    pub price: f64,
+}
+
+//what an order filled by the time submit returned, average price
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Filled {
+    pub qty: f64,
+    pub price: f64,
+}
+
+impl Filled {
+    pub fn add(&mut self, qty: f64, price: f64) {
+        if qty <= 0.0 {
+            return;
+        }
+        let total = self.qty + qty;
+        self.price = (self.price * self.qty + price * qty) / total;
+        self.qty = total;
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Resting {
+    symbol: String,
+    side: Side,
+    qty: f64,
+    type_: OrderType,
+    time_in_force: TimeInForce,
+    //the legs of an OCO group cancel each other
+    group: Option<u64>,
+    //placed once this order fills
+    then: Vec<Resting>,
+    placed: NaiveDate,
+    //best price since placed, trailing stops
+    peak: Option<f64>,
+    //stop of a stop-limit reached
+    triggered: bool,
+}
+
+impl Resting {
+    fn new(spec: &OrderSpec, type_: OrderType, placed: NaiveDate) -> Self {
+        Resting {
+            symbol: spec.symbol.clone(),
+            side: spec.side,
+            qty: spec.qty,
+            type_,
+            time_in_force: spec.time_in_force,
+            group: None,
+            then: vec![],
+            placed,
+            peak: None,
+            triggered: false,
+        }
+    }
+
+    //stops go before take-profits when one bar reaches both
+    fn is_stop(&self) -> bool {
+        !matches!(self.type_, OrderType::Market | OrderType::Limit(_))
+    }
+
+    fn expired(&self, day: NaiveDate) -> bool {
+        !matches!(self.time_in_force, TimeInForce::Gtc) && day > self.placed
+    }
+
+    //fill price on the bar, gaps fill at the open
+    fn fill_price(&mut self, bar: &SimBar) -> Option<f64> {
+        let (side, open) = (self.side, bar.open);
+        let reached = |price: f64| match side {
+            Side::Buy => bar.high >= price,
+            Side::Sell => bar.low <= price,
+        };
+        let worse = |price: f64| match side {
+            Side::Buy => open.max(price),
+            Side::Sell => open.min(price),
+        };
+        match self.type_ {
+            OrderType::Market => Some(open),
+            OrderType::Limit(limit) => match side {
+                Side::Buy => (bar.low <= limit).then(|| open.min(limit)),
+                Side::Sell => (bar.high >= limit).then(|| open.max(limit)),
+            },
+            OrderType::Stop(stop) => reached(stop).then(|| worse(stop)),
+            OrderType::StopLimit { stop, limit } => {
+                self.triggered = self.triggered || reached(stop);
+                if !self.triggered {
+                    return None;
+                }
+                match side {
+                    Side::Buy => (bar.low <= limit).then(|| worse(stop).min(limit)),
+                    Side::Sell => (bar.high >= limit).then(|| worse(stop).max(limit)),
+                }
+            }
+            OrderType::TrailingStop(trail) => {
+                //the peak of the earlier bars sets the stop, this bar moves it afterwards
+                let peak = *self.peak.get_or_insert(open);
+                let stop = match (side, trail) {
+                    (Side::Sell, Trail::Price(p)) => peak - p,
+                    (Side::Sell, Trail::Percent(p)) => peak * (1.0 - p / 100.0),
+                    (Side::Buy, Trail::Price(p)) => peak + p,
+                    (Side::Buy, Trail::Percent(p)) => peak * (1.0 + p / 100.0),
+                };
+                if reached(stop) {
+                    return Some(worse(stop));
+                }
+                self.peak = Some(match side {
+                    Side::Sell => peak.max(bar.high),
+                    Side::Buy => peak.min(bar.low),
+                });
+                None
+            }
+        }
+    }
+}
+
+//resting orders of the simulated brokers
+#[derive(Clone, Debug, Default)]
+pub struct SimBook {
+    orders: Vec<Resting>,
+    groups: u64,
+}
+
+impl SimBook {
+    pub fn submit(&mut self, spec: &OrderSpec, now: DateTime<Utc>) -> Result<(), BrokerError> {
+        spec.check()?;
+        let day = now.date_naive();
+        match spec.class {
+            OrderClass::Simple => self.orders.push(Resting::new(spec, spec.type_, day)),
+            OrderClass::Bracket(exit) | OrderClass::Oto(exit) => {
+                let mut entry = Resting::new(spec, spec.type_, day);
+                entry.then = self.legs(spec, exit, day);
+                self.orders.push(entry);
+            }
+            OrderClass::Oco(exit) => {
+                let legs = self.legs(spec, exit, day);
+                self.orders.extend(legs);
+            }
+        }
+        Ok(())
+    }
+
+    //sell orders of the exit, good until canceled
+    fn legs(&mut self, spec: &OrderSpec, exit: Exit, day: NaiveDate) -> Vec<Resting> {
+        let exit_spec =
+            OrderSpec::new(&spec.symbol, Side::Sell, spec.qty).with_time_in_force(TimeInForce::Gtc);
+        let mut legs = vec![];
+        if let Some(take_profit) = exit.take_profit {
+            legs.push(Resting::new(&exit_spec, OrderType::Limit(take_profit), day));
+        }
+        if let Some(stop) = exit.stop_loss {
+            let type_ = match exit.stop_limit {
+                Some(limit) => OrderType::StopLimit { stop, limit },
+                None => OrderType::Stop(stop),
+            };
+            legs.push(Resting::new(&exit_spec, type_, day));
+        }
+        if legs.len() > 1 {
+            self.groups += 1;
+            for leg in &mut legs {
+                leg.group = Some(self.groups);
+            }
+        }
+        legs
+    }
+
+    //number of canceled orders
+    pub fn cancel(&mut self, symbol: &str) -> usize {
+        let before = self.orders.len();
+        self.orders.retain(|o| o.symbol != symbol);
+        before - self.orders.len()
+    }
+
+    pub fn open(&self, symbol: &str) -> usize {
+        self.orders.iter().filter(|o| o.symbol == symbol).count()
+    }
+
+    //fills the resting orders of symbol on the bar. settle books a fill and returns
+    //false if it can't (no cash, nothing to sell), the order is dropped then.
+    //Legs of a filled entry rest from the next bar on.
+    pub fn on_bar(
+        &mut self,
+        symbol: &str,
+        bar: &SimBar,
+        mut settle: impl FnMut(&Fill) -> bool,
+    ) -> Vec<Fill> {
+        let day = bar.time.date_naive();
+        let (mut mine, mut rest): (Vec<Resting>, Vec<Resting>) = std::mem::take(&mut self.orders)
+            .into_iter()
+            .partition(|o| o.symbol == symbol);
+        mine.retain(|o| !o.expired(day));
+        mine.sort_by_key(|o| !o.is_stop());
+
+        let mut fills = vec![];
+        let mut done: HashSet<u64> = HashSet::new();
+        let mut placed = vec![];
+        for mut order in mine {
+            if order.group.is_some_and(|g| done.contains(&g)) {
+                continue;
+            }
+            let Some(price) = order.fill_price(bar) else {
+                if !matches!(order.time_in_force, TimeInForce::Ioc | TimeInForce::Fok) {
+                    rest.push(order);
+                }
+                continue;
+            };
+            let fill = Fill {
+                symbol: order.symbol.clone(),
+                side: order.side,
+                qty: order.qty,
+                price,
+            };
+            if !settle(&fill) {
+                continue;
+            }
+            if let Some(group) = order.group {
+                done.insert(group);
+            }
+            for mut leg in order.then {
+                leg.placed = day;
+                placed.push(leg);
+            }
+            fills.push(fill);
+        }
+        rest.retain(|o| o.group.map_or(true, |g| !done.contains(&g)));
+        rest.extend(placed);
+        self.orders = rest;
+        fills
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn bar(day: u32, open: f64, high: f64, low: f64, close: f64) -> SimBar {
+        SimBar {
+            time: Utc.with_ymd_and_hms(2024, 3, day, 15, 0, 0).unwrap(),
+            open,
+            high,
+            low,
+            close,
+        }
+    }
+
+    #[test]
+    fn bracket_conf_test() {
+        let conf: BracketConf = toml::from_str("take_profit_pct = 10.0\nstop_pct = 5.0").unwrap();
+        let exit = conf.exit(100.0).unwrap();
+        assert!((exit.take_profit.unwrap() - 110.0).abs() < 1e-9);
+        assert!((exit.stop_loss.unwrap() - 95.0).abs() < 1e-9);
+        assert_eq!(exit.stop_limit, None);
+        assert_eq!(BracketConf::default().exit(100.0), None);
+    }
+
+    #[test]
+    fn check_test() {
+        let exit = Exit {
+            take_profit: Some(110.0),
+            stop_loss: Some(95.0),
+            stop_limit: None,
+        };
+        let buy = OrderSpec::new("ORCL", Side::Buy, 10.0);
+        assert!(buy
+            .clone()
+            .with_class(OrderClass::Bracket(exit))
+            .check()
+            .is_ok());
+        assert!(OrderSpec::oco("ORCL", 10.0, exit).check().is_ok());
+        assert!(buy
+            .clone()
+            .with_class(OrderClass::Oto(exit))
+            .check()
+            .is_err());
+        assert!(buy
+            .clone()
+            .with_class(OrderClass::Bracket(exit))
+            .with_time_in_force(TimeInForce::Ioc)
+            .check()
+            .is_err());
+        assert!(buy
+            .clone()
+            .with_type(OrderType::Limit(-1.0))
+            .check()
+            .is_err());
+        assert!(OrderSpec::new("ORCL", Side::Buy, 0.0).check().is_err());
+        assert_eq!(
+            OrderSpec::new("BTC/USD", Side::Buy, 0.5).time_in_force,
+            TimeInForce::Gtc
+        );
+    }
+
+    #[test]
+    fn bracket_fill_test() {
+        let exit = Exit {
+            take_profit: Some(110.0),
+            stop_loss: Some(95.0),
+            stop_limit: None,
+        };
+        let spec = OrderSpec::new("ORCL", Side::Buy, 10.0)
+            .with_type(OrderType::Limit(100.0))
+            .with_class(OrderClass::Bracket(exit));
+        let mut book = SimBook::default();
+        book.submit(&spec, bar(4, 0.0, 0.0, 0.0, 0.0).time).unwrap();
+        assert!(book
+            .on_bar("ORCL", &bar(4, 102.0, 103.0, 101.0, 102.0), |_| true)
+            .is_empty());
+        //the entry fills, the legs rest from the next bar on
+        let fills = book.on_bar("ORCL", &bar(4, 101.0, 112.0, 99.0, 100.0), |_| true);
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].price, 100.0);
+        assert_eq!(book.open("ORCL"), 2);
+        //both exits reachable, the stop wins and cancels the take-profit
+        let fills = book.on_bar("ORCL", &bar(5, 100.0, 111.0, 94.0, 96.0), |_| true);
+        assert_eq!(fills.len(), 1);
+        assert_eq!((fills[0].side, fills[0].price), (Side::Sell, 95.0));
+        assert_eq!(book.open("ORCL"), 0);
+    }
+
+    #[test]
+    fn order_types_test() {
+        let mut book = SimBook::default();
+        let now = bar(4, 0.0, 0.0, 0.0, 0.0).time;
+        let sell = OrderSpec::new("ORCL", Side::Sell, 1.0).with_time_in_force(TimeInForce::Gtc);
+        book.submit(
+            &sell
+                .clone()
+                .with_type(OrderType::TrailingStop(Trail::Price(5.0))),
+            now,
+        )
+        .unwrap();
+        book.submit(
+            &sell.clone().with_type(OrderType::StopLimit {
+                stop: 90.0,
+                limit: 89.0,
+            }),
+            now,
+        )
+        .unwrap();
+        //the trail follows the high to 110
+        assert!(book
+            .on_bar("ORCL", &bar(4, 100.0, 110.0, 99.0, 108.0), |_| true)
+            .is_empty());
+        //stop at 105, the gap fills at the open
+        let fills = book.on_bar("ORCL", &bar(5, 91.0, 92.0, 91.0, 91.0), |_| true);
+        assert_eq!(
+            fills.iter().map(|f| f.price).collect::<Vec<_>>(),
+            vec![91.0]
+        );
+        //the stop-limit triggers but 88 is below the limit
+        assert!(book
+            .on_bar("ORCL", &bar(6, 88.0, 88.5, 87.0, 88.0), |_| true)
+            .is_empty());
+        let fills = book.on_bar("ORCL", &bar(7, 88.0, 89.5, 88.0, 89.0), |_| true);
+        assert_eq!(fills[0].price, 89.0);
+
+        //day orders expire, ioc is canceled unless it fills right away
+        book.submit(
+            &OrderSpec::new("ORCL", Side::Buy, 1.0).with_type(OrderType::Limit(50.0)),
+            now,
+        )
+        .unwrap();
+        book.submit(
+            &OrderSpec::new("MSFT", Side::Buy, 1.0)
+                .with_type(OrderType::Limit(50.0))
+                .with_time_in_force(TimeInForce::Ioc),
+            now,
+        )
+        .unwrap();
+        assert!(book
+            .on_bar("MSFT", &bar(4, 60.0, 60.0, 60.0, 60.0), |_| true)
+            .is_empty());
+        assert_eq!(book.open("MSFT"), 0);
+        assert!(book
+            .on_bar("ORCL", &bar(5, 40.0, 40.0, 40.0, 40.0), |_| true)
+            .is_empty());
+        assert_eq!(book.open("ORCL"), 0);
+
+        //a fill the portfolio can't book drops the order
+        book.submit(&OrderSpec::new("ORCL", Side::Buy, 1.0), now)
+            .unwrap();
+        assert!(book
+            .on_bar("ORCL", &bar(4, 60.0, 60.0, 60.0, 60.0), |_| false)
+            .is_empty());
+        assert_eq!(book.cancel("ORCL"), 0);
+    }
+}
@@ -0,0 +1,396 @@
+//Capital shared by the TraderConf variants: every strategy (symbol and variant) trades
+//out of its own sleeve, a Portfolio holding its budget. Budgets come from fixed weights,
+//risk parity or recent performance and are rebalanced by moving free cash between the
+//sleeves. Opposing orders of a bar are netted, P&L stays with the sleeve that traded.
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use serde::Deserialize;
+
+use crate::{
+    backtest::START_CASH,
+    portfolio::types::Portfolio,
+    types::{Action, ActionValuator},
+};
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AllocMode {
+    #[default]
+    Fixed,
+    //inverse volatility of the sleeve returns
+    RiskParity,
+    //return over the lookback
+    Performance,
+}
+
+//[allocation]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AllocConf {
+    pub mode: AllocMode,
+    pub capital: f64,
+    //by "SYMBOL:variant" or variant, 1 for the others
+    pub weights: HashMap<String, f64>,
+    //bars of sleeve equity risk and performance are measured over
+    pub lookback: usize,
+    pub rebalance_bars: usize,
+}
+
+impl Default for AllocConf {
+    fn default() -> Self {
+        AllocConf {
+            mode: AllocMode::Fixed,
+            capital: START_CASH,
+            weights: HashMap::new(),
+            lookback: 20,
+            rebalance_bars: 20,
+        }
+    }
+}
+
+pub fn strategy_key(symbol: &str, variant: &str) -> String {
+    format!("{symbol}:{variant}")
+}
+
+#[derive(Clone, Debug)]
+pub struct Sleeve {
+    pub symbol: String,
+    pub variant: String,
+    pub portfolio: Portfolio,
+    //budget plus the cash moved in by rebalancing
+    pub funded: f64,
+    pub trades: usize,
+    //equity of the last lookback + 1 bars
+    history: VecDeque<f64>,
+}
+
+impl Sleeve {
+    pub fn shares(&self) -> f64 {
+        self.portfolio
+            .stocks
+            .as_ref()
+            .and_then(|s| s.get(&self.symbol))
+            .copied()
+            .unwrap_or(0.0)
+    }
+
+    pub fn cash(&self) -> f64 {
+        self.portfolio.cash.unwrap_or(0.0)
+    }
+
+    pub fn equity(&self, price: f64) -> f64 {
+        self.cash() + self.shares() * price
+    }
+
+    pub fn pnl(&self, price: f64) -> f64 {
+        self.equity(price) - self.funded
+    }
+
+    //per bar returns of the history
+    fn returns(&self) -> Vec<f64> {
+        self.history
+            .iter()
+            .zip(self.history.iter().skip(1))
+            .filter(|(a, _)| **a > 0.0)
+            .map(|(a, b)| b / a - 1.0)
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PortfolioManager {
+    conf: AllocConf,
+    sleeves: BTreeMap<String, Sleeve>,
+    //order volume asked for by the strategies and left after netting
+    gross: f64,
+    net: f64,
+}
+
+impl PortfolioManager {
+    //budgets from the fixed weights, there is no history to weigh by yet
+    pub fn new(conf: &AllocConf, strategies: &[(String, String)]) -> Self {
+        let mut manager = PortfolioManager {
+            conf: conf.clone(),
+            sleeves: BTreeMap::new(),
+            gross: 0.0,
+            net: 0.0,
+        };
+        for (symbol, variant) in strategies {
+            let sleeve = Sleeve {
+                symbol: symbol.clone(),
+                variant: variant.clone(),
+                portfolio: Portfolio {
+                    name: strategy_key(symbol, variant),
+                    cash: Some(0.0),
+                    stocks: Some(HashMap::from([(symbol.clone(), 0.0)])),
+                },
+                funded: 0.0,
+                trades: 0,
+                history: VecDeque::new(),
+            };
+            manager
+                .sleeves
+                .insert(strategy_key(symbol, variant), sleeve);
+        }
+        let weights = manager.fixed_weights();
+        for (key, sleeve) in manager.sleeves.iter_mut() {
+            let budget = conf.capital * weights[key];
+            sleeve.portfolio.cash = Some(budget);
+            sleeve.funded = budget;
+        }
+        manager
+    }
+
+    pub fn sleeves(&self) -> impl Iterator<Item = &Sleeve> {
+        self.sleeves.values()
+    }
+
+    pub fn sleeve_mut(&mut self, key: &str) -> Option<&mut Sleeve> {
+        self.sleeves.get_mut(key)
+    }
+
+    fn fixed_weight(&self, sleeve: &Sleeve) -> f64 {
+        let weights = &self.conf.weights;
+        weights
+            .get(&strategy_key(&sleeve.symbol, &sleeve.variant))
+            .or_else(|| weights.get(&sleeve.variant))
+            .copied()
+            .unwrap_or(1.0)
+            .max(0.0)
+    }
+
+    fn normalize(raw: BTreeMap<String, f64>) -> Option<BTreeMap<String, f64>> {
+        let total: f64 = raw.values().sum();
+        (total > 0.0 && total.is_finite())
+            .then(|| raw.into_iter().map(|(k, w)| (k, w / total)).collect())
+    }
+
+    fn fixed_weights(&self) -> BTreeMap<String, f64> {
+        let raw = self
+            .sleeves
+            .iter()
+            .map(|(k, s)| (k.clone(), self.fixed_weight(s)))
+            .collect();
+        let n = self.sleeves.len().max(1) as f64;
+        Self::normalize(raw)
+            .unwrap_or_else(|| self.sleeves.keys().map(|k| (k.clone(), 1.0 / n)).collect())
+    }
+
+    //share of the capital each sleeve should have, fixed weights while the
+    //history says nothing yet
+    pub fn target_weights(&self) -> BTreeMap<String, f64> {
+        let raw: BTreeMap<String, Option<f64>> = self
+            .sleeves
+            .iter()
+            .map(|(k, s)| {
+                let returns = s.returns();
+                let w = match self.conf.mode {
+                    AllocMode::Fixed => Some(1.0),
+                    AllocMode::RiskParity => {
+                        let n = returns.len() as f64;
+                        let mean = returns.iter().sum::<f64>() / n;
+                        let var = returns.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / n;
+                        (returns.len() >= 2 && var > 0.0).then(|| 1.0 / var.sqrt())
+                    }
+                    AllocMode::Performance => {
+                        let growth = returns.iter().map(|r| 1.0 + r).product::<f64>();
+                        Some(growth.max(0.0))
+                    }
+                };
+                (k.clone(), w.map(|w| w * self.fixed_weight(s)))
+            })
+            .collect();
+        //a sleeve without risk yet (all cash) gets the mean of the others
+        let known: Vec<f64> = raw.values().flatten().copied().collect();
+        if known.is_empty() {
+            return self.fixed_weights();
+        }
+        let mean = known.iter().sum::<f64>() / known.len() as f64;
+        let raw = raw
+            .into_iter()
+            .map(|(k, w)| (k, w.unwrap_or(mean)))
+            .collect();
+        Self::normalize(raw).unwrap_or_else(|| self.fixed_weights())
+    }
+
+    //equity of every sleeve at the prices, once per bar
+    pub fn mark(&mut self, prices: &HashMap<String, f64>) {
+        let keep = self.conf.lookback.max(2) + 1;
+        for sleeve in self.sleeves.values_mut() {
+            let Some(price) = prices.get(&sleeve.symbol) else {
+                continue;
+            };
+            sleeve.history.push_back(sleeve.equity(*price));
+            while sleeve.history.len() > keep {
+                sleeve.history.pop_front();
+            }
+        }
+    }
+
+    //moves free cash towards the target weights, positions are not touched so a
+    //sleeve above its budget only gives up its cash
+    pub fn rebalance(&mut self, prices: &HashMap<String, f64>) {
+        let weights = self.target_weights();
+        let price = |s: &Sleeve| prices.get(&s.symbol).copied().unwrap_or(0.0);
+        let equity: f64 = self.sleeves.values().map(|s| s.equity(price(s))).sum();
+        let free: f64 = self.sleeves.values().map(Sleeve::cash).sum();
+        let wanted: BTreeMap<String, f64> = self
+            .sleeves
+            .iter()
+            .map(|(k, s)| {
+                let invested = s.shares() * price(s);
+                (k.clone(), (equity * weights[k] - invested).max(0.0))
+            })
+            .collect();
+        let total: f64 = wanted.values().sum();
+        if total <= 0.0 {
+            return;
+        }
+        for (key, sleeve) in self.sleeves.iter_mut() {
+            let cash = free * wanted[key] / total;
+            sleeve.funded += cash - sleeve.cash();
+            sleeve.portfolio.cash = Some(cash);
+        }
+    }
+
+    //the order the market sees for the strategies' share changes of one symbol,
+    //the opposing parts are crossed between the sleeves
+    pub fn net(&mut self, changes: &[f64]) -> f64 {
+        let net: f64 = changes.iter().sum();
+        self.gross += changes.iter().map(|c| c.abs()).sum::<f64>();
+        self.net += net.abs();
+        net
+    }
+
+    //order volume saved by netting
+    pub fn netted(&self) -> f64 {
+        self.gross - self.net
+    }
+
+    pub fn rebalance_due(&self, bar: usize) -> bool {
+        self.conf.rebalance_bars > 0 && bar > 0 && bar % self.conf.rebalance_bars == 0
+    }
+}
+
+//buys and sells of one symbol from different variants in the same bar go out as
+//one order of the difference, same-side orders are left alone
+pub fn net_actions(actions: Vec<ActionValuator>) -> Vec<ActionValuator> {
+    let mut by_symbol: BTreeMap<String, Vec<ActionValuator>> = BTreeMap::new();
+    for av in actions {
+        by_symbol.entry(av.symbol.clone()).or_default().push(av);
+    }
+    by_symbol
+        .into_values()
+        .flat_map(|avs| {
+            let signed = |av: &ActionValuator| match av.action {
+                Action::Buy => av.strength,
+                Action::Sell => -av.strength,
+                Action::Hold => 0.0,
+            };
+            let both = avs.iter().any(|av| av.action == Action::Buy)
+                && avs.iter().any(|av| av.action == Action::Sell);
+            if !both {
+                return avs;
+            }
+            let net: f64 = avs.iter().map(signed).sum();
+            let action = if net > 0.0 { Action::Buy } else { Action::Sell };
+            avs.into_iter()
+                .find(|av| net != 0.0 && av.action == action)
+                .map(|av| ActionValuator {
+                    strength: net.abs(),
+                    ..av
+                })
+                .into_iter()
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strategies() -> Vec<(String, String)> {
+        ["type1", "type2"]
+            .iter()
+            .map(|v| (String::from("ORCL"), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn budgets_test() {
+        let conf = AllocConf {
+            weights: HashMap::from([(String::from("type1"), 3.0)]),
+            ..Default::default()
+        };
+        let manager = PortfolioManager::new(&conf, &strategies());
+        let cash: Vec<f64> = manager.sleeves().map(Sleeve::cash).collect();
+        assert_eq!(cash, vec![750.0, 250.0]);
+    }
+
+    #[test]
+    fn risk_parity_test() {
+        let conf = AllocConf {
+            mode: AllocMode::RiskParity,
+            ..Default::default()
+        };
+        let mut manager = PortfolioManager::new(&conf, &strategies());
+        //type1 holds 10 shares, type2 only cash
+        let type1 = manager.sleeve_mut("ORCL:type1").unwrap();
+        type1.portfolio.buy("ORCL", 10.0, 10.0).unwrap();
+        for price in [10.0, 11.0, 10.0, 11.0] {
+            manager.mark(&HashMap::from([(String::from("ORCL"), price)]));
+        }
+        //no risk to measure for type2, it gets the mean weight
+        let weights = manager.target_weights();
+        assert!((weights["ORCL:type1"] - 0.5).abs() < 1e-9);
+
+        let conf = AllocConf {
+            mode: AllocMode::Performance,
+            ..Default::default()
+        };
+        manager.conf = conf;
+        let weights = manager.target_weights();
+        //type1 went from 500 to 510, type2 stayed at 500
+        assert!(weights["ORCL:type1"] > weights["ORCL:type2"]);
+    }
+
+    #[test]
+    fn rebalance_test() {
+        let mut manager = PortfolioManager::new(&AllocConf::default(), &strategies());
+        let type1 = manager.sleeve_mut("ORCL:type1").unwrap();
+        type1.portfolio.buy("ORCL", 10.0, 10.0).unwrap();
+        //type1 gained 100 on its shares, equity 1100 split evenly
+        let prices = HashMap::from([(String::from("ORCL"), 20.0)]);
+        manager.rebalance(&prices);
+        let cash: Vec<f64> = manager.sleeves().map(Sleeve::cash).collect();
+        assert_eq!(cash, vec![350.0, 550.0]);
+        let total: f64 = manager.sleeves().map(|s| s.equity(20.0)).sum();
+        assert_eq!(total, 1100.0);
+        //the gain stays with type1
+        let pnl: Vec<f64> = manager.sleeves().map(|s| s.pnl(20.0)).collect();
+        assert_eq!(pnl, vec![100.0, 0.0]);
+    }
+
+    #[test]
+    fn net_test() {
+        let mut manager = PortfolioManager::new(&AllocConf::default(), &strategies());
+        assert_eq!(manager.net(&[10.0, -4.0]), 6.0);
+        assert_eq!(manager.netted(), 8.0);
+
+        let av = |action, strength| ActionValuator {
+            symbol: String::from("ORCL"),
+            strength,
+            action,
+            limit_price: None,
+            exit: None,
+        };
+        let netted = net_actions(vec![av(Action::Buy, 1.0), av(Action::Sell, 3.0)]);
+        assert_eq!(netted, vec![av(Action::Sell, 2.0)]);
+        assert!(net_actions(vec![av(Action::Buy, 1.0), av(Action::Sell, 1.0)]).is_empty());
+        assert_eq!(
+            net_actions(vec![av(Action::Buy, 1.0), av(Action::Buy, 1.0)]).len(),
+            2
+        );
+    }
+}
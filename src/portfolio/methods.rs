@@ -1,38 +1,102 @@
+use std::collections::HashMap;
+
 use tracing::{error, info};
 
-use crate::portfolio::types::Portfolio;
+use crate::{
+    error::RiskError,
+    order::{Fill, Side},
+    portfolio::types::{Buffer, Portfolio},
+};
+
+impl Buffer {
+    //changes the capacity, drops the oldest bars if there are too many
+    pub fn resize(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.data.len() > capacity {
+            self.data.pop_front();
+        }
+    }
+}
 
 impl Portfolio {
-    fn buy(&mut self, symbol: &str, share_amount: f64, share_price: f64) {
+    pub(crate) fn buy(
+        &mut self,
+        symbol: &str,
+        share_amount: f64,
+        share_price: f64,
+    ) -> Result<(), RiskError> {
         info!("Buying {} shares of {}", share_amount, symbol);
-        if self.cash.is_none() || self.cash.unwrap() < share_amount * share_price {
-            error!("Not enough cash to buy shares");
-            return;
+        let cash = self.cash.unwrap_or(0.0);
+        let needed = share_amount * share_price;
+        if cash < needed {
+            return Err(RiskError::InsufficientCash {
+                symbol: symbol.to_string(),
+                needed,
+                available: cash,
+            });
         }
-        self.cash = Some(self.cash.unwrap() - share_amount * share_price);
+        self.cash = Some(cash - needed);
         self.stocks
-            .as_mut()
-            .unwrap()
-            .entry("ORCL".to_string())
-            .and_modify(|value| *value += share_amount);
+            .get_or_insert_with(HashMap::new)
+            .entry(symbol.to_string())
+            .and_modify(|value| *value += share_amount)
+            .or_insert(share_amount);
+        Ok(())
     }
 
-    fn sell(&mut self, symbol: &str, share_amount: f64, share_price: f64) {
+    pub(crate) fn sell(
+        &mut self,
+        symbol: &str,
+        share_amount: f64,
+        share_price: f64,
+    ) -> Result<(), RiskError> {
         info!("Selling {} shares of {}", share_amount, symbol);
-        if *self.stocks.clone().unwrap().get(symbol).unwrap() < share_amount {
-            error!("Not enough cash to buy shares");
-            return;
+        let owned = self
+            .stocks
+            .as_ref()
+            .and_then(|s| s.get(symbol))
+            .copied()
+            .unwrap_or(0.0);
+        if owned < share_amount {
+            return Err(RiskError::InsufficientShares {
+                symbol: symbol.to_string(),
+                wanted: share_amount,
+                owned,
+            });
         }
-        self.cash = Some(self.cash.unwrap() + share_amount * share_price);
+        self.cash = Some(self.cash.unwrap_or(0.0) + share_amount * share_price);
         self.stocks
-            .as_mut()
-            .unwrap()
-            .entry("ORCL".to_string())
+            .get_or_insert_with(HashMap::new)
+            .entry(symbol.to_string())
             .and_modify(|value| *value -= share_amount);
+        Ok(())
+    }
+
+    //books a simulated fill, sells never go below zero shares. Quantity booked.
+    pub(crate) fn fill(&mut self, fill: &Fill) -> Result<f64, RiskError> {
+        match fill.side {
+            Side::Buy => self
+                .buy(&fill.symbol, fill.qty, fill.price)
+                .map(|_| fill.qty),
+            Side::Sell => {
+                let owned = self
+                    .stocks
+                    .as_ref()
+                    .and_then(|s| s.get(&fill.symbol))
+                    .copied()
+                    .unwrap_or(0.0);
+                let qty = fill.qty.min(owned);
+                if qty > 0.0 {
+                    self.sell(&fill.symbol, qty, fill.price)?;
+                }
+                Ok(qty.max(0.0))
+            }
+        }
     }
 
     pub fn evaluator(
         self,
+        symbol: &str,
         a: f32,
         port_ref: &mut Portfolio,
         shares_owned: f64,
@@ -40,14 +104,19 @@ impl Portfolio {
         cash: f64,
         c: f64,
     ) -> (f64, f64) {
+        //a rejected trade only skips the bar
         if a >= 1.0 {
-            port_ref.buy("ORCL", shares_to_buy, c);
+            if let Err(e) = port_ref.buy(symbol, shares_to_buy, c) {
+                error!("{e}");
+            }
             return (cash, shares_owned); // Buy
         } else if a <= -1.0 {
-            port_ref.sell("ORCL", shares_owned, c);
+            if let Err(e) = port_ref.sell(symbol, shares_owned, c) {
+                error!("{e}");
+            }
             return (cash, shares_owned); // Sell
         } else {
-            (port_ref.cash.unwrap(), shares_owned)
+            (port_ref.cash.unwrap_or(0.0), shares_owned)
         }
     }
 }
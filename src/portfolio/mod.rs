@@ -1,2 +1,4 @@
+pub mod manager;
 pub mod methods;
+pub mod rebalance;
 pub mod types;
@@ -0,0 +1,265 @@
+//Keeps a long-term basket at target weights of the equity: trades are planned from the
+//positions and prices of a Portfolio, symbols within the drift threshold and trades
+//below the minimum are left alone, a share of the equity stays in cash.
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{
+    asset::{position_symbol, round_qty},
+    backtest::START_CASH,
+    error::DataError,
+    order::{OrderSpec, Side},
+    portfolio::types::Portfolio,
+};
+
+//[rebalance]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RebalanceConf {
+    //share of the equity per symbol, scaled down if they leave less than the cash buffer
+    pub targets: HashMap<String, f64>,
+    //percentage points a weight may drift before it is traded
+    pub drift_pct: f64,
+    //smaller trades are skipped, in cash
+    pub min_trade: f64,
+    //percent of the equity kept in cash
+    pub cash_buffer_pct: f64,
+    //days between scheduled runs, 0 only on demand
+    pub every_days: u32,
+    //start cash of the backtest
+    pub capital: f64,
+}
+
+impl Default for RebalanceConf {
+    fn default() -> Self {
+        RebalanceConf {
+            targets: HashMap::new(),
+            drift_pct: 5.0,
+            min_trade: 100.0,
+            cash_buffer_pct: 2.0,
+            every_days: 30,
+            capital: START_CASH,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RebalanceTrade {
+    pub symbol: String,
+    pub side: Side,
+    pub qty: f64,
+    pub price: f64,
+}
+
+impl RebalanceTrade {
+    pub fn order(&self) -> OrderSpec {
+        OrderSpec::new(&self.symbol, self.side, self.qty)
+    }
+}
+
+//crypto positions are kept without the slash
+fn held(port: &Portfolio, symbol: &str) -> f64 {
+    let stocks = port.stocks.as_ref();
+    stocks
+        .and_then(|s| s.get(symbol).or_else(|| s.get(&position_symbol(symbol))))
+        .copied()
+        .unwrap_or(0.0)
+}
+
+impl RebalanceConf {
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    //target weights, scaled to leave the cash buffer
+    fn weights(&self) -> HashMap<String, f64> {
+        let investable = 1.0 - self.cash_buffer_pct / 100.0;
+        let total: f64 = self.targets.values().sum();
+        let scale = if total > investable && total > 0.0 {
+            investable / total
+        } else {
+            1.0
+        };
+        self.targets
+            .iter()
+            .map(|(symbol, w)| (symbol.clone(), w * scale))
+            .collect()
+    }
+
+    //sells first, their cash pays for the buys. Every target symbol needs a price,
+    //holdings outside the targets count towards the equity and are not traded,
+    //without a price they are left out of it
+    pub fn plan(
+        &self,
+        port: &Portfolio,
+        prices: &HashMap<String, f64>,
+    ) -> Result<Vec<RebalanceTrade>, DataError> {
+        let price = |symbol: &str| {
+            prices
+                .get(symbol)
+                .copied()
+                .filter(|p| *p > 0.0)
+                .ok_or_else(|| DataError::Missing {
+                    symbol: symbol.to_string(),
+                    what: String::from("price to rebalance"),
+                })
+        };
+        let mut equity = port.cash.unwrap_or(0.0);
+        for (symbol, shares) in port.stocks.iter().flatten() {
+            if *shares == 0.0 {
+                continue;
+            }
+            match self.targets.keys().find(|t| position_symbol(t) == *symbol) {
+                Some(target) => equity += shares * price(target)?,
+                None => match price(symbol) {
+                    Ok(price) => equity += shares * price,
+                    Err(e) => warn!("{e}, left out of the equity"),
+                },
+            }
+        }
+        if equity <= 0.0 {
+            return Ok(vec![]);
+        }
+
+        let mut sells = vec![];
+        let mut buys = vec![];
+        let mut symbols: Vec<(String, f64)> = self.weights().into_iter().collect();
+        symbols.sort_by(|a, b| a.0.cmp(&b.0));
+        for (symbol, target) in symbols {
+            let price = price(&symbol)?;
+            let shares = held(port, &symbol);
+            let weight = shares * price / equity;
+            if (weight - target).abs() * 100.0 < self.drift_pct {
+                continue;
+            }
+            let delta = target * equity / price - shares;
+            let side = if delta > 0.0 { Side::Buy } else { Side::Sell };
+            let qty = round_qty(&symbol, delta.abs()).min(match side {
+                Side::Buy => f64::INFINITY,
+                Side::Sell => shares,
+            });
+            if qty <= 0.0 || qty * price < self.min_trade {
+                continue;
+            }
+            let trade = RebalanceTrade {
+                symbol,
+                side,
+                qty,
+                price,
+            };
+            match side {
+                Side::Buy => buys.push(trade),
+                Side::Sell => sells.push(trade),
+            }
+        }
+
+        //the buys get what is left above the cash buffer, scaled down together
+        let proceeds: f64 = sells.iter().map(|t| t.qty * t.price).sum();
+        let available = port.cash.unwrap_or(0.0) + proceeds - equity * self.cash_buffer_pct / 100.0;
+        let needed: f64 = buys.iter().map(|t| t.qty * t.price).sum();
+        if needed > available {
+            let scale = (available / needed).max(0.0);
+            for trade in &mut buys {
+                trade.qty = round_qty(&trade.symbol, trade.qty * scale);
+            }
+            let min_trade = self.min_trade;
+            buys.retain(|t| t.qty > 0.0 && t.qty * t.price >= min_trade);
+        }
+        sells.extend(buys);
+        Ok(sells)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conf() -> RebalanceConf {
+        RebalanceConf {
+            targets: HashMap::from([(String::from("SPY"), 0.6), (String::from("TLT"), 0.4)]),
+            drift_pct: 5.0,
+            min_trade: 50.0,
+            cash_buffer_pct: 0.0,
+            ..Default::default()
+        }
+    }
+
+    fn port(cash: f64, spy: f64, tlt: f64) -> Portfolio {
+        Portfolio {
+            name: String::from("basket"),
+            cash: Some(cash),
+            stocks: Some(HashMap::from([
+                (String::from("SPY"), spy),
+                (String::from("TLT"), tlt),
+            ])),
+        }
+    }
+
+    fn prices() -> HashMap<String, f64> {
+        HashMap::from([(String::from("SPY"), 100.0), (String::from("TLT"), 50.0)])
+    }
+
+    #[test]
+    fn plan_test() -> Result<(), Box<dyn std::error::Error>> {
+        //all cash: 60 SPY and 80 TLT
+        let trades = conf().plan(&port(10_000.0, 0.0, 0.0), &prices())?;
+        let planned: Vec<(&str, Side, f64)> = trades
+            .iter()
+            .map(|t| (t.symbol.as_str(), t.side, t.qty))
+            .collect();
+        assert_eq!(
+            planned,
+            vec![("SPY", Side::Buy, 60.0), ("TLT", Side::Buy, 80.0)]
+        );
+
+        //SPY at 70%: 10 points off, sold first to pay for TLT
+        let trades = conf().plan(&port(0.0, 70.0, 60.0), &prices())?;
+        assert_eq!(trades[0].side, Side::Sell);
+        assert_eq!(trades[0].qty, 10.0);
+        assert_eq!((trades[1].symbol.as_str(), trades[1].qty), ("TLT", 20.0));
+
+        //within the drift threshold nothing trades
+        assert!(conf().plan(&port(0.0, 62.0, 76.0), &prices())?.is_empty());
+
+        //a missing price is an error
+        let mut missing = prices();
+        missing.remove("TLT");
+        assert!(conf().plan(&port(1000.0, 0.0, 0.0), &missing).is_err());
+
+        //a holding outside the targets without a price doesn't count, the rest is planned
+        let mut other = port(10_000.0, 0.0, 0.0);
+        other
+            .stocks
+            .as_mut()
+            .unwrap()
+            .insert(String::from("XYZ"), 10.0);
+        let trades = conf().plan(&other, &prices())?;
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].qty, 60.0);
+        Ok(())
+    }
+
+    #[test]
+    fn cash_buffer_test() -> Result<(), Box<dyn std::error::Error>> {
+        //weights over 100% are scaled to leave 10% cash
+        let conf = RebalanceConf {
+            targets: HashMap::from([(String::from("SPY"), 1.0), (String::from("TLT"), 1.0)]),
+            cash_buffer_pct: 10.0,
+            ..conf()
+        };
+        let trades = conf.plan(&port(10_000.0, 0.0, 0.0), &prices())?;
+        let spent: f64 = trades.iter().map(|t| t.qty * t.price).sum();
+        assert_eq!(spent, 9_000.0);
+
+        //trades below the minimum are skipped
+        let conf = RebalanceConf {
+            min_trade: 5_000.0,
+            ..conf
+        };
+        let trades = conf.plan(&port(10_000.0, 0.0, 0.0), &prices())?;
+        assert!(trades.is_empty());
+        Ok(())
+    }
+}
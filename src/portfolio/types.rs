@@ -4,8 +4,20 @@ use apca::data::v2::stream::Bar;
 use serde::Deserialize;
 use tracing::{error, info};
 
-#[derive(Debug, Deserialize, Clone)]
-#[serde(tag = "type")]
+use crate::{
+    book::QuoteConf,
+    calendar::Session,
+    indicator_decision::CombinerConf,
+    order::BracketConf,
+    proto,
+    strategy::{
+        buffer::{Lookback, Need},
+        RuleConf, RuleError, RuleSet,
+    },
+    types::{IndiValidate, PriceField},
+};
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub enum IndicatorType {
     BollingerBands = 0,
     ExponentialMovingAverage = 1,
@@ -20,22 +32,141 @@ pub enum IndicatorType {
     StandardDeviation = 10,
 }
 
+//one indicator of a variant, e.g.
+//{ type = "SimpleMovingAverage", period = 20, field = "typical" }
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct IndicatorConf {
+    #[serde(rename = "type")]
+    pub kind: IndicatorType,
+    //period sent to the service, its default without
+    #[serde(default)]
+    pub period: Option<usize>,
+    //bars the indicator is computed over, the capacity of buff without
+    #[serde(default)]
+    pub lookback: Option<usize>,
+    //bars of the last minutes instead of a bar count
+    #[serde(default)]
+    pub window_mins: Option<u32>,
+    #[serde(default)]
+    pub field: PriceField,
+    //the indicator votes Buy above and Sell at or below, no vote without
+    #[serde(default)]
+    pub threshold: Option<f64>,
+    //weight of the vote in the combine mode, 1.0 without
+    #[serde(default)]
+    pub weight: Option<f64>,
+}
+
+impl IndicatorConf {
+    pub fn proto_kind(&self) -> Option<proto::IndicatorType> {
+        proto::IndicatorType::try_from(self.kind.clone() as i32).ok()
+    }
+
+    pub fn need(&self, capacity: usize) -> Need {
+        let lookback = match self.window_mins {
+            Some(mins) => Lookback::Minutes(mins),
+            None => Lookback::Bars(self.lookback.unwrap_or(capacity)),
+        };
+        Need {
+            field: self.field,
+            lookback,
+        }
+    }
+}
+
 #[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct Buffer {
     pub capacity: usize,
     pub data: VecDeque<Bar>,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+//the buffered bars are state, two configs are equal with different bars
+impl PartialEq for Buffer {
+    fn eq(&self, other: &Self) -> bool {
+        self.capacity == other.capacity
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 #[allow(unused)]
 pub struct TraderConf {
     pub variant: String,
     pub symbol: String,
     pub price_label: String,
-    pub indicator: Vec<IndicatorType>,
+    pub indicator: Vec<IndicatorConf>,
     pub shares_to_buy: f64,
     //pub buffersize: usize,
     pub buff: Buffer,
+    //declarative entry/exit rules, see strategy::rule
+    #[serde(default)]
+    pub rules: Vec<RuleConf>,
+    //rules compiled once when the config is loaded, see compile_rules
+    #[serde(skip)]
+    pub rule_set: RuleSet,
+    //how indicator signals are merged into one decision
+    #[serde(default)]
+    pub combine: CombinerConf,
+    //sessions the variant trades in, empty means regular hours only
+    #[serde(default)]
+    pub sessions: Vec<Session>,
+    //sell the position this many minutes before the close
+    #[serde(default)]
+    pub flatten_before_close: Option<u32>,
+    //spread filter and limit pricing from the live quote
+    #[serde(default)]
+    pub quote: QuoteConf,
+    //take-profit and stop-loss sent with every buy
+    #[serde(default)]
+    pub bracket: Option<BracketConf>,
+}
+
+impl TraderConf {
+    //bars the buffer needs before a signal of the variant counts
+    pub fn lookback(&self) -> usize {
+        self.buff.capacity
+    }
+
+    pub fn compile_rules(&mut self) -> Result<(), RuleError> {
+        self.rule_set = RuleSet::compile(&self.rules)?;
+        Ok(())
+    }
+
+    //true if the variant trades on the combined votes of its indicators
+    //instead of the rules or the close compare
+    pub fn has_signals(&self) -> bool {
+        self.indicator.iter().any(|ind| ind.threshold.is_some())
+    }
+
+    //thresholds of the voting indicators, see helper::indicator_signals
+    pub fn thresholds(&self) -> HashMap<proto::IndicatorType, f64> {
+        self.indicator
+            .iter()
+            .filter_map(|ind| Some((ind.proto_kind()?, ind.threshold?)))
+            .collect()
+    }
+
+    //vote weights for indicator_decision::combine_signals
+    pub fn weights(&self) -> IndiValidate {
+        let weights = self
+            .indicator
+            .iter()
+            .filter_map(|ind| Some((ind.proto_kind()?, ind.weight?)))
+            .collect();
+        IndiValidate {
+            validate: HashMap::from([(self.symbol.clone(), weights)]),
+        }
+    }
+
+    //buffered series the indicators are computed over
+    pub fn indicator_needs(&self) -> Vec<Need> {
+        self.indicator
+            .iter()
+            .map(|ind| ind.need(self.buff.capacity))
+            .collect()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -0,0 +1,384 @@
+//Hot reload of config/default, config/{RUN_MODE} and config/local.
+//Files are polled for changes, a reload can also be requested over the channel
+//returned by ConfigWatcher::new. A new Settings is only applied when it parses
+//and passes validate::load_settings, the swap of conf_map happens under one lock.
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info, warn};
+
+use crate::{
+    config2::Settings, error::CLIError, portfolio::types::TraderConf, trader::TraderConfigs,
+    validate::load_settings,
+};
+
+//what a reload changed
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct ConfigDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+    //other sections applied by the reload, e.g. execution
+    pub reloaded: Vec<String>,
+    //sections that changed but are only read at startup
+    pub restart: Vec<String>,
+}
+
+impl ConfigDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && self.reloaded.is_empty()
+            && self.restart.is_empty()
+    }
+}
+
+//reload request, the reply carries the applied diff or why nothing was applied
+pub struct ReloadRequest {
+    pub reply: Option<oneshot::Sender<Result<ConfigDiff, CLIError>>>,
+}
+
+pub struct ConfigWatcher {
+    dir: PathBuf,
+    run_mode: String,
+    interval: Duration,
+    stamps: HashMap<PathBuf, Option<SystemTime>>,
+    requests: mpsc::Receiver<ReloadRequest>,
+    //applied diffs that add or remove symbols, for the streams
+    diffs: Option<mpsc::UnboundedSender<ConfigDiff>>,
+}
+
+impl ConfigWatcher {
+    pub fn new(dir: &str, run_mode: &str) -> (Self, mpsc::Sender<ReloadRequest>) {
+        let (tx, rx) = mpsc::channel(8);
+        let mut watcher = ConfigWatcher {
+            dir: PathBuf::from(dir),
+            run_mode: run_mode.to_string(),
+            interval: Duration::from_secs(5),
+            stamps: HashMap::new(),
+            requests: rx,
+            diffs: None,
+        };
+        watcher.changed();
+        (watcher, tx)
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn with_diffs(mut self, diffs: mpsc::UnboundedSender<ConfigDiff>) -> Self {
+        self.diffs = Some(diffs);
+        self
+    }
+
+    fn files(&self) -> Vec<PathBuf> {
+        ["default", self.run_mode.as_str(), "local"]
+            .iter()
+            .map(|name| self.dir.join(format!("{name}.toml")))
+            .collect()
+    }
+
+    //true if any file was modified, created or removed since the last call
+    fn changed(&mut self) -> bool {
+        let mut changed = false;
+        for file in self.files() {
+            let stamp = modified(&file);
+            if self.stamps.get(&file) != Some(&stamp) {
+                changed = true;
+                self.stamps.insert(file, stamp);
+            }
+        }
+        changed
+    }
+
+    fn reload(&self, trader: &Arc<Mutex<TraderConfigs>>) -> Result<ConfigDiff, CLIError> {
+        let dir = self.dir.to_string_lossy();
+        let settings = load_settings(&dir, &self.run_mode, false)?;
+        let mut trader = trader.lock().map_err(|_| CLIError::Lock("trader"))?;
+        Ok(trader.reload_conf(settings))
+    }
+
+    //runs until every ReloadRequest sender is dropped
+    pub async fn run(mut self, trader: Arc<Mutex<TraderConfigs>>) {
+        let mut ticker = tokio::time::interval(self.interval);
+        loop {
+            let reply = tokio::select! {
+                _ = ticker.tick() => {
+                    if !self.changed() {
+                        continue;
+                    }
+                    None
+                }
+                req = self.requests.recv() => match req {
+                    Some(req) => req.reply,
+                    None => break,
+                },
+            };
+            let res = self.reload(&trader);
+            match &res {
+                Ok(diff) => {
+                    info!(
+                        "config reloaded, added: {:?} removed: {:?} changed: {:?} reloaded: {:?}",
+                        diff.added, diff.removed, diff.changed, diff.reloaded
+                    );
+                    if !diff.restart.is_empty() {
+                        warn!("restart required: {} changed", diff.restart.join(", "));
+                    }
+                    if let Some(diffs) = &self.diffs {
+                        if !(diff.added.is_empty() && diff.removed.is_empty()) {
+                            let _ = diffs.send(diff.clone());
+                        }
+                    }
+                }
+                Err(e) => error!("config reload rejected, keeping current config: {e}"),
+            }
+            if let Some(reply) = reply {
+                let _ = reply.send(res);
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+//Builds the conf_map to swap in. Variants that still exist keep their buffered
+//bars, trimmed to the new capacity so the newest bars survive.
+pub fn merge_conf_map(
+    old: &HashMap<String, Vec<TraderConf>>,
+    new: HashMap<String, Vec<TraderConf>>,
+) -> (HashMap<String, Vec<TraderConf>>, ConfigDiff) {
+    let mut diff = ConfigDiff::default();
+    let mut merged: HashMap<String, Vec<TraderConf>> = HashMap::new();
+
+    for (symbol, confs) in new {
+        let previous = old.get(&symbol);
+        match previous {
+            None => diff.added.push(symbol.clone()),
+            Some(previous) => {
+                let unchanged = previous.len() == confs.len()
+                    && previous.iter().zip(confs.iter()).all(|(a, b)| a == b);
+                if !unchanged {
+                    diff.changed.push(symbol.clone());
+                }
+            }
+        }
+        let confs = confs
+            .into_iter()
+            .map(|mut conf| {
+                if let Some(prev) =
+                    previous.and_then(|p| p.iter().find(|p| p.variant == conf.variant))
+                {
+                    conf.buff.data = prev.buff.data.clone();
+                    conf.buff.resize(conf.buff.capacity);
+                }
+                conf
+            })
+            .collect::<Vec<_>>();
+        merged.insert(symbol, confs);
+    }
+    diff.removed = old
+        .keys()
+        .filter(|s| !merged.contains_key(*s))
+        .cloned()
+        .collect();
+
+    diff.added.sort();
+    diff.removed.sort();
+    diff.changed.sort();
+    (merged, diff)
+}
+
+//changed sections besides the Stockconfig, as (reloaded, restart). Execution and
+//allocation are read from TraderConfigs when used, the rest once at startup
+pub fn section_changes(old: &Settings, new: &Settings) -> (Vec<String>, Vec<String>) {
+    let reloaded = [
+        ("execution", old.execution != new.execution),
+        ("allocation", old.allocation != new.allocation),
+    ];
+    let restart = [
+        ("grpc", old.grpc != new.grpc),
+        ("calendar", old.calendar != new.calendar),
+        ("logging", old.logging != new.logging),
+        ("metrics", old.metrics != new.metrics),
+        ("notify", old.notify != new.notify),
+        ("stream", old.stream != new.stream),
+        ("rebalance", old.rebalance != new.rebalance),
+        ("schedule", old.schedule != new.schedule),
+        ("control", old.control != new.control),
+    ];
+    let names = |sections: &[(&str, bool)]| -> Vec<String> {
+        sections
+            .iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(name, _)| name.to_string())
+            .collect()
+    };
+    (names(&reloaded), names(&restart))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use apca::data::v2::stream::Bar;
+    use chrono::Utc;
+    use num_decimal::Num;
+
+    use crate::{
+        book::QuoteConf, indicator_decision::CombinerConf, order::BracketConf,
+        portfolio::types::Buffer, strategy::RuleSet,
+    };
+
+    fn conf(symbol: &str, variant: &str, capacity: usize) -> TraderConf {
+        TraderConf {
+            variant: variant.to_string(),
+            symbol: symbol.to_string(),
+            price_label: String::from("Close"),
+            indicator: vec![],
+            shares_to_buy: 10.0,
+            buff: Buffer {
+                capacity,
+                data: VecDeque::new(),
+            },
+            rules: vec![],
+            rule_set: RuleSet::default(),
+            combine: CombinerConf::default(),
+            sessions: vec![],
+            flatten_before_close: None,
+            quote: QuoteConf::default(),
+            bracket: None,
+        }
+    }
+
+    #[test]
+    fn merge_conf_map_test() {
+        let old = HashMap::from([
+            (String::from("ORCL"), vec![conf("ORCL", "type1", 10)]),
+            (String::from("AAPL"), vec![conf("AAPL", "type1", 10)]),
+            (String::from("MSFT"), vec![conf("MSFT", "type1", 10)]),
+        ]);
+        let new = HashMap::from([
+            (String::from("ORCL"), vec![conf("ORCL", "type1", 10)]),
+            (String::from("AAPL"), vec![conf("AAPL", "type1", 5)]),
+            (String::from("TSLA"), vec![conf("TSLA", "type1", 10)]),
+        ]);
+        let (merged, diff) = merge_conf_map(&old, new);
+        assert_eq!(
+            diff,
+            ConfigDiff {
+                added: vec![String::from("TSLA")],
+                removed: vec![String::from("MSFT")],
+                changed: vec![String::from("AAPL")],
+                ..Default::default()
+            }
+        );
+        assert_eq!(merged.len(), 3);
+        assert_eq!(merged["AAPL"][0].buff.capacity, 5);
+    }
+
+    #[test]
+    fn bracket_change_test() {
+        let old = HashMap::from([(String::from("ORCL"), vec![conf("ORCL", "type1", 10)])]);
+        let mut changed = conf("ORCL", "type1", 10);
+        changed.bracket = Some(BracketConf {
+            stop_pct: Some(2.0),
+            ..Default::default()
+        });
+        let (_, diff) =
+            merge_conf_map(&old, HashMap::from([(String::from("ORCL"), vec![changed])]));
+        assert_eq!(diff.changed, vec![String::from("ORCL")]);
+
+        //buffered bars alone are no change
+        let mut buffered = conf("ORCL", "type1", 10);
+        buffered.buff.data.push_back(Bar {
+            symbol: String::from("ORCL"),
+            open_price: Num::from(1),
+            high_price: Num::from(1),
+            low_price: Num::from(1),
+            close_price: Num::from(1),
+            volume: Num::from(1),
+            timestamp: Utc::now(),
+        });
+        let (_, diff) = merge_conf_map(
+            &old,
+            HashMap::from([(String::from("ORCL"), vec![buffered])]),
+        );
+        assert!(diff.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reload_sections_test() -> Result<(), Box<dyn std::error::Error>> {
+        let mut trader = TraderConfigs::new(Settings::new()?, "", None, "").await?;
+        let mut settings = Settings::new()?;
+        settings.execution.min_qty += 1.0;
+        settings.stream.stale_secs += 1;
+        let diff = trader.reload_conf(settings.clone());
+        assert_eq!(diff.reloaded, vec![String::from("execution")]);
+        assert_eq!(diff.restart, vec![String::from("stream")]);
+        assert_eq!(trader.execution, settings.execution);
+        //the stream still runs with the old section
+        let diff = trader.reload_conf(settings);
+        assert!(diff.reloaded.is_empty());
+        assert_eq!(diff.restart, vec![String::from("stream")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reload_new_symbol_test() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("reload_{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        for name in ["default", "development"] {
+            std::fs::copy(
+                format!("config/{name}.toml"),
+                dir.join(format!("{name}.toml")),
+            )?;
+        }
+        let path = dir.to_string_lossy().to_string();
+        let settings = load_settings(&path, "development", false)?;
+        let trader = Arc::new(Mutex::new(
+            TraderConfigs::new(settings, "", None, "").await?,
+        ));
+        let (watcher, requests) = ConfigWatcher::new(&path, "development");
+        let (diffs_tx, mut diffs) = mpsc::unbounded_channel();
+        tokio::spawn(watcher.with_diffs(diffs_tx).run(trader.clone()));
+
+        std::fs::write(
+            dir.join("local.toml"),
+            "[[Stockconfig.TSLA]]\n\
+             variant = \"type1\"\n\
+             symbol = \"TSLA\"\n\
+             price_label = \"Close\"\n\
+             shares_to_buy = 10\n\
+             indicator = []\n\
+             buff = { capacity = 10, data = [] }\n",
+        )?;
+        let (reply, applied) = oneshot::channel();
+        assert!(requests
+            .send(ReloadRequest { reply: Some(reply) })
+            .await
+            .is_ok());
+        let diff = applied.await??;
+        assert_eq!(diff.added, vec![String::from("TSLA")]);
+        //the streams learn about the new symbol
+        assert_eq!(diffs.recv().await, Some(diff));
+        assert!(trader
+            .lock()
+            .unwrap()
+            .stock_symbols()
+            .contains(&String::from("TSLA")));
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
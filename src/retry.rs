@@ -0,0 +1,111 @@
+//Exponential backoff for calls that fail with a retryable CLIError
+use std::{future::Future, time::Duration};
+
+use tokio::time::sleep;
+use tracing::warn;
+
+use crate::error::CLIError;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    //attempts in total, the first one included
+    pub attempts: u32,
+    pub base: Duration,
+    pub max: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            base: Duration::from_millis(500),
+            max: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    //wait after the failed attempt n (0 based), doubling up to max
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.base
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max)
+    }
+
+    //runs op until it succeeds, fails with an error that is not retryable or runs out of attempts
+    pub async fn run<T, F, Fut>(&self, what: &str, mut op: F) -> Result<T, CLIError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, CLIError>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match op().await {
+                Err(e) if e.is_retryable() && attempt + 1 < self.attempts => {
+                    let wait = self.delay(attempt);
+                    warn!("{what} failed, retrying in {wait:?}: {e}");
+                    sleep(wait).await;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn delay_test() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.delay(0), Duration::from_millis(500));
+        assert_eq!(policy.delay(2), Duration::from_secs(2));
+        assert_eq!(policy.delay(10), Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn run_test() {
+        let policy = RetryPolicy {
+            base: Duration::ZERO,
+            ..Default::default()
+        };
+        let calls = Cell::new(0);
+        let calls = &calls;
+        let res = policy
+            .run("stream", move || async move {
+                calls.set(calls.get() + 1);
+                if calls.get() < 3 {
+                    Err(CLIError::Stream(String::from("reset")))
+                } else {
+                    Ok(calls.get())
+                }
+            })
+            .await;
+        assert_eq!(res.unwrap(), 3);
+
+        //not retryable, a single attempt
+        calls.set(0);
+        let res: Result<(), CLIError> = policy
+            .run("config", move || async move {
+                calls.set(calls.get() + 1);
+                Err(CLIError::InvalidConfig(String::from("broken")))
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(calls.get(), 1);
+
+        //gives up after the last attempt
+        calls.set(0);
+        let res: Result<(), CLIError> = policy
+            .run("stream", move || async move {
+                calls.set(calls.get() + 1);
+                Err(CLIError::Stream(String::from("reset")))
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(calls.get(), 3);
+    }
+}
@@ -0,0 +1,348 @@
+//Cron-like jobs on the trading calendar: a job fires at a time relative to the open or
+//close of every trading day, or once a week at an exchange time. The Scheduler only
+//knows when, trade_stream in cli does the work of each Job.
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, Utc, Weekday};
+use chrono_tz::America::New_York;
+use serde::Deserialize;
+use tokio::time::sleep;
+use tracing::warn;
+
+use crate::{
+    asset::position_symbol,
+    calendar::{exchange_to_utc, TradingCalendar},
+    error::{CLIError, PersistenceError},
+    portfolio::types::Portfolio,
+};
+
+//exchange time of the weekly cache refresh
+const REFRESH_HOUR: u32 = 6;
+
+//[schedule], 0 minutes or days turn a job off
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScheduleConf {
+    //minutes before the open the buffers are filled from history
+    pub warmup_mins: u32,
+    //days of minute bars the warm-up loads
+    pub warmup_days: u32,
+    //minutes before the close the stock positions of the Stockconfig are closed,
+    //no new ones until the close
+    pub flatten_mins: u32,
+    //minutes after the close of the P&L snapshot and the daily summary
+    pub report_mins: u32,
+    //the snapshots go to pnl.csv in there
+    pub report_dir: String,
+    //day of the cache refresh, 6:00 exchange time
+    pub refresh_weekday: Weekday,
+    //days of daily bars the refresh keeps in the cache and the data files
+    pub refresh_days: u32,
+}
+
+impl Default for ScheduleConf {
+    fn default() -> Self {
+        ScheduleConf {
+            warmup_mins: 30,
+            warmup_days: 5,
+            flatten_mins: 0,
+            report_mins: 5,
+            report_dir: String::from("reports"),
+            refresh_weekday: Weekday::Sat,
+            refresh_days: 365,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Job {
+    Warmup,
+    Rebalance,
+    Flatten,
+    Report,
+    RefreshCache,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum When {
+    //minutes around the regular session of trading days
+    BeforeOpen(u32),
+    AfterOpen(u32),
+    BeforeClose(u32),
+    AfterClose(u32),
+    //exchange time, trading day or not
+    Weekly(Weekday, NaiveTime),
+}
+
+impl When {
+    //time on day, None if it doesn't fire that day
+    fn on(&self, calendar: &TradingCalendar, day: NaiveDate) -> Option<DateTime<Utc>> {
+        let mins = |m: &u32| Duration::minutes(i64::from(*m));
+        match self {
+            When::BeforeOpen(m) => Some(calendar.open_at(day)? - mins(m)),
+            When::AfterOpen(m) => Some(calendar.open_at(day)? + mins(m)),
+            When::BeforeClose(m) => Some(calendar.close_at(day)? - mins(m)),
+            When::AfterClose(m) => Some(calendar.close_at(day)? + mins(m)),
+            When::Weekly(weekday, time) if day.weekday() == *weekday => {
+                exchange_to_utc(day.and_time(*time))
+            }
+            When::Weekly(..) => None,
+        }
+    }
+
+    //first time after t
+    pub fn next_after(
+        &self,
+        calendar: &TradingCalendar,
+        t: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        let day = t.with_timezone(&New_York).date_naive();
+        //two weeks cover a weekly job and any run of holidays
+        (0..15)
+            .filter_map(|i| self.on(calendar, day + Duration::days(i)))
+            .find(|at| *at > t)
+    }
+}
+
+pub struct Scheduler {
+    calendar: TradingCalendar,
+    start: DateTime<Utc>,
+    //job, when and its next time
+    jobs: Vec<(Job, When, Option<DateTime<Utc>>)>,
+}
+
+impl Scheduler {
+    //the jobs of the conf that are on, first runs after start
+    pub fn new(calendar: TradingCalendar, conf: &ScheduleConf, start: DateTime<Utc>) -> Self {
+        let mut scheduler = Scheduler {
+            calendar,
+            start,
+            jobs: vec![],
+        };
+        if conf.warmup_mins > 0 {
+            scheduler.add(Job::Warmup, When::BeforeOpen(conf.warmup_mins));
+        }
+        if conf.flatten_mins > 0 {
+            scheduler.add(Job::Flatten, When::BeforeClose(conf.flatten_mins));
+        }
+        if conf.report_mins > 0 {
+            scheduler.add(Job::Report, When::AfterClose(conf.report_mins));
+        }
+        if conf.refresh_days > 0 {
+            let at = NaiveTime::from_hms_opt(REFRESH_HOUR, 0, 0).unwrap();
+            scheduler.add(Job::RefreshCache, When::Weekly(conf.refresh_weekday, at));
+        }
+        scheduler
+    }
+
+    pub fn add(&mut self, job: Job, when: When) {
+        let next = when.next_after(&self.calendar, self.start);
+        self.jobs.push((job, when, next));
+    }
+
+    //the earliest job and its time, jobs due at the same time in the order they were added
+    pub fn pop(&mut self) -> Option<(DateTime<Utc>, Job)> {
+        let (job, when, next) = self
+            .jobs
+            .iter_mut()
+            .filter(|(_, _, next)| next.is_some())
+            .min_by_key(|(_, _, next)| *next)?;
+        let at = next.take()?;
+        *next = when.next_after(&self.calendar, at);
+        Some((at, *job))
+    }
+
+    //waits for the next job, None without any
+    pub async fn next(&mut self) -> Option<Job> {
+        let (at, job) = self.pop()?;
+        if let Ok(wait) = (at - Utc::now()).to_std() {
+            sleep(wait).await;
+        }
+        Some(job)
+    }
+}
+
+//end of day value of the account
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub date: NaiveDate,
+    pub cash: f64,
+    //market value of the positions
+    pub positions: f64,
+    pub equity: f64,
+}
+
+impl Snapshot {
+    //positions at the closes, crypto positions are held without the slash.
+    //A position without a close counts nothing
+    pub fn new(date: NaiveDate, port: &Portfolio, closes: &HashMap<String, f64>) -> Self {
+        let cash = port.cash.unwrap_or(0.0);
+        let mut positions = 0.0;
+        for (symbol, shares) in port.stocks.iter().flatten() {
+            if *shares == 0.0 {
+                continue;
+            }
+            let close = closes
+                .iter()
+                .find(|(s, _)| *s == symbol || position_symbol(s) == *symbol);
+            match close {
+                Some((_, close)) => positions += shares * close,
+                None => warn!("{symbol}: no close for the snapshot"),
+            }
+        }
+        Snapshot {
+            date,
+            cash,
+            positions,
+            equity: cash + positions,
+        }
+    }
+}
+
+//adds the row of the snapshot to the csv, the P&L of the day against the last
+//row of an earlier day
+pub fn append_snapshot(path: &Path, snap: &Snapshot) -> Result<f64, CLIError> {
+    let io = |e| PersistenceError::Io {
+        path: path.display().to_string(),
+        source: e,
+    };
+    let rows = match fs::read_to_string(path) {
+        Ok(rows) => rows,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(io(e).into()),
+    };
+    let day = snap.date.to_string();
+    let previous = rows
+        .lines()
+        .skip(1)
+        .filter(|row| !row.starts_with(&day))
+        .filter_map(|row| row.split(',').nth(3)?.parse::<f64>().ok())
+        .last();
+    let pnl = previous.map_or(0.0, |equity| snap.equity - equity);
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(io)?;
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(io)?;
+    let mut out = String::new();
+    if rows.is_empty() {
+        out.push_str("date,cash,positions,equity,pnl\n");
+    }
+    out.push_str(&format!(
+        "{day},{:.2},{:.2},{:.2},{pnl:.2}\n",
+        snap.cash, snap.positions, snap.equity
+    ));
+    file.write_all(out.as_bytes()).map_err(io)?;
+    Ok(pnl)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn next_after_test() {
+        let cal = TradingCalendar::nyse();
+        //Friday 2024-07-05 10:00 EDT: the warm-up of Monday, 9:00 EDT
+        let t = utc("2024-07-05T14:00:00Z");
+        let warmup = When::BeforeOpen(30).next_after(&cal, t);
+        assert_eq!(warmup, Some(utc("2024-07-08T13:00:00Z")));
+        //the day before Independence Day closes at 13:00, flatten 15 minutes before
+        let t = utc("2024-07-03T12:00:00Z");
+        let flatten = When::BeforeClose(15).next_after(&cal, t);
+        assert_eq!(flatten, Some(utc("2024-07-03T16:45:00Z")));
+        //July 4th is skipped
+        let report = When::AfterClose(5).next_after(&cal, utc("2024-07-03T18:00:00Z"));
+        assert_eq!(report, Some(utc("2024-07-05T20:05:00Z")));
+        //weekly jobs fire on holidays and weekends
+        let six = NaiveTime::from_hms_opt(6, 0, 0).unwrap();
+        let refresh = When::Weekly(Weekday::Sat, six).next_after(&cal, t);
+        assert_eq!(refresh, Some(utc("2024-07-06T10:00:00Z")));
+    }
+
+    #[test]
+    fn pop_test() {
+        let conf = ScheduleConf {
+            flatten_mins: 10,
+            ..Default::default()
+        };
+        //Monday 2024-03-04 before the warm-up, EST
+        let mut scheduler =
+            Scheduler::new(TradingCalendar::nyse(), &conf, utc("2024-03-04T13:00:00Z"));
+        scheduler.add(Job::Rebalance, When::AfterOpen(30));
+        let jobs: Vec<(DateTime<Utc>, Job)> = (0..5).filter_map(|_| scheduler.pop()).collect();
+        assert_eq!(
+            jobs,
+            vec![
+                (utc("2024-03-04T14:00:00Z"), Job::Warmup),
+                (utc("2024-03-04T15:00:00Z"), Job::Rebalance),
+                (utc("2024-03-04T20:50:00Z"), Job::Flatten),
+                (utc("2024-03-04T21:05:00Z"), Job::Report),
+                (utc("2024-03-05T14:00:00Z"), Job::Warmup),
+            ]
+        );
+
+        //everything off
+        let off = ScheduleConf {
+            warmup_mins: 0,
+            report_mins: 0,
+            refresh_days: 0,
+            ..Default::default()
+        };
+        let mut scheduler =
+            Scheduler::new(TradingCalendar::nyse(), &off, utc("2024-03-04T13:00:00Z"));
+        assert_eq!(scheduler.pop(), None);
+    }
+
+    #[test]
+    fn snapshot_test() -> Result<(), Box<dyn std::error::Error>> {
+        let port = Portfolio {
+            name: String::from("Alpaca"),
+            cash: Some(1000.0),
+            stocks: Some(HashMap::from([
+                (String::from("ORCL"), 10.0),
+                (String::from("BTCUSD"), 0.5),
+            ])),
+        };
+        let closes = HashMap::from([
+            (String::from("ORCL"), 20.0),
+            (String::from("BTC/USD"), 100.0),
+        ]);
+        let snap = Snapshot::new(day("2024-03-04"), &port, &closes);
+        assert_eq!((snap.positions, snap.equity), (250.0, 1250.0));
+
+        let path = std::env::temp_dir().join(format!("pnl_{}.csv", std::process::id()));
+        let _ = fs::remove_file(&path);
+        assert_eq!(append_snapshot(&path, &snap)?, 0.0);
+        let next = Snapshot {
+            date: day("2024-03-05"),
+            equity: 1300.0,
+            ..snap.clone()
+        };
+        assert_eq!(append_snapshot(&path, &next)?, 50.0);
+        //a second snapshot of the day still compares to the day before
+        assert_eq!(append_snapshot(&path, &next)?, 50.0);
+        let rows = fs::read_to_string(&path)?;
+        assert_eq!(rows.lines().count(), 4);
+        assert!(rows.starts_with("date,cash,positions,equity,pnl\n2024-03-04,1000.00,250.00"));
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,299 @@
+//Rolling bars of a symbol shared by every indicator on it. An indicator needs a
+//lookback, a number of bars or minutes, of one price field. Equal needs are kept
+//once and the buffer holds bars for the longest of them. Values come out as the
+//tail of a field, and only once the buffer covers the whole lookback.
+use std::collections::{HashMap, VecDeque};
+
+use apca::data::v2::stream::Bar;
+use chrono::{DateTime, Duration, Utc};
+
+use crate::{portfolio::types::TraderConf, strategy::Candles, types::PriceField};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Lookback {
+    Bars(usize),
+    //bars of the last minutes, however many arrived
+    Minutes(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Need {
+    pub field: PriceField,
+    pub lookback: Lookback,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SymbolBuffer {
+    bars: VecDeque<Bar>,
+    needs: Vec<Need>,
+}
+
+impl SymbolBuffer {
+    //false if an indicator needs the same already
+    pub fn require(&mut self, need: Need) -> bool {
+        if self.needs.contains(&need) {
+            return false;
+        }
+        self.needs.push(need);
+        true
+    }
+
+    pub fn needs(&self) -> &[Need] {
+        &self.needs
+    }
+
+    pub fn len(&self) -> usize {
+        self.bars.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bars.is_empty()
+    }
+
+    fn newest(&self) -> Option<DateTime<Utc>> {
+        self.bars.back().map(|b| b.timestamp)
+    }
+
+    //repeated or out of order bars are dropped
+    pub fn push(&mut self, bar: Bar) {
+        if self.newest().is_some_and(|t| t >= bar.timestamp) {
+            return;
+        }
+        self.bars.push_back(bar);
+        self.evict();
+    }
+
+    //history in front, bars that arrived after it stay
+    pub fn seed(&mut self, history: &[Bar]) {
+        let Some(last) = history.last().map(|b| b.timestamp) else {
+            return;
+        };
+        let newer: Vec<Bar> = self.bars.drain(..).filter(|b| b.timestamp > last).collect();
+        self.bars = history.iter().cloned().chain(newer).collect();
+        self.evict();
+    }
+
+    //the longest bar count stays, and for time windows one bar at or before the
+    //start of the longest window, it tells the window is covered
+    fn evict(&mut self) {
+        let keep = self
+            .needs
+            .iter()
+            .filter_map(|n| match n.lookback {
+                Lookback::Bars(n) => Some(n),
+                Lookback::Minutes(_) => None,
+            })
+            .max()
+            .unwrap_or(0);
+        let mins = self
+            .needs
+            .iter()
+            .filter_map(|n| match n.lookback {
+                Lookback::Minutes(m) => Some(m),
+                Lookback::Bars(_) => None,
+            })
+            .max()
+            .unwrap_or(0);
+        let Some(newest) = self.newest() else {
+            return;
+        };
+        let start = newest - Duration::minutes(i64::from(mins));
+        while self.bars.len() > keep && self.bars.get(1).is_some_and(|b| b.timestamp <= start) {
+            self.bars.pop_front();
+        }
+    }
+
+    pub fn ready(&self, need: &Need) -> bool {
+        match need.lookback {
+            Lookback::Bars(n) => self.bars.len() >= n,
+            Lookback::Minutes(m) => match (self.bars.front(), self.newest()) {
+                (Some(oldest), Some(newest)) => {
+                    oldest.timestamp <= newest - Duration::minutes(i64::from(m))
+                }
+                _ => false,
+            },
+        }
+    }
+
+    //the field over the lookback, oldest first, None until it is covered
+    pub fn values(&self, need: &Need) -> Option<Vec<f64>> {
+        if !self.ready(need) {
+            return None;
+        }
+        let mut candles = Candles::default();
+        match need.lookback {
+            Lookback::Bars(n) => {
+                for bar in self.bars.iter().skip(self.bars.len() - n) {
+                    candles.push_bar(bar);
+                }
+            }
+            Lookback::Minutes(m) => {
+                let start = self.newest()? - Duration::minutes(i64::from(m));
+                for bar in self.bars.iter().filter(|b| b.timestamp > start) {
+                    candles.push_bar(bar);
+                }
+            }
+        }
+        Some(candles.field(need.field).into_owned())
+    }
+}
+
+//the buffers of every symbol of the Stockconfig
+#[derive(Clone, Debug, Default)]
+pub struct Buffers {
+    symbols: HashMap<String, SymbolBuffer>,
+}
+
+impl Buffers {
+    pub fn from_confs(conf_map: &HashMap<String, Vec<TraderConf>>) -> Self {
+        let mut buffers = Buffers::default();
+        for (symbol, confs) in conf_map {
+            let buffer = buffers.symbols.entry(symbol.clone()).or_default();
+            for need in confs.iter().flat_map(TraderConf::indicator_needs) {
+                buffer.require(need);
+            }
+        }
+        buffers
+    }
+
+    //needs of the new config, symbols that stay keep their bars
+    pub fn reconfigure(&mut self, conf_map: &HashMap<String, Vec<TraderConf>>) {
+        let mut next = Buffers::from_confs(conf_map);
+        for (symbol, buffer) in next.symbols.iter_mut() {
+            if let Some(old) = self.symbols.remove(symbol) {
+                buffer.bars = old.bars;
+                buffer.evict();
+            }
+        }
+        *self = next;
+    }
+
+    //bars of symbols without a buffer are dropped
+    pub fn push(&mut self, bar: &Bar) {
+        if let Some(buffer) = self.symbols.get_mut(&bar.symbol) {
+            buffer.push(bar.clone());
+        }
+    }
+
+    pub fn seed(&mut self, symbol: &str, history: &[Bar]) {
+        if let Some(buffer) = self.symbols.get_mut(symbol) {
+            buffer.seed(history);
+        }
+    }
+
+    pub fn get(&self, symbol: &str) -> Option<&SymbolBuffer> {
+        self.symbols.get(symbol)
+    }
+
+    pub fn values(&self, symbol: &str, need: &Need) -> Option<Vec<f64>> {
+        self.symbols.get(symbol)?.values(need)
+    }
+
+    pub fn ready(&self, symbol: &str, needs: &[Need]) -> bool {
+        let buffer = self.symbols.get(symbol);
+        needs
+            .iter()
+            .all(|need| buffer.is_some_and(|b| b.ready(need)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use num_decimal::Num;
+
+    fn bar(minute: u32, close: i32) -> Bar {
+        Bar {
+            symbol: String::from("ORCL"),
+            open_price: Num::from(close - 1),
+            high_price: Num::from(close + 1),
+            low_price: Num::from(close - 1),
+            close_price: Num::from(close),
+            volume: Num::from(100 * (minute + 1)),
+            timestamp: Utc.with_ymd_and_hms(2024, 3, 4, 15, minute, 0).unwrap(),
+        }
+    }
+
+    fn need(field: PriceField, lookback: Lookback) -> Need {
+        Need { field, lookback }
+    }
+
+    #[test]
+    fn bars_test() {
+        let mut buffer = SymbolBuffer::default();
+        let close3 = need(PriceField::Close, Lookback::Bars(3));
+        assert!(buffer.require(close3));
+        //a second indicator with the same need shares it
+        assert!(!buffer.require(close3));
+        let high2 = need(PriceField::High, Lookback::Bars(2));
+        assert!(buffer.require(high2));
+
+        buffer.push(bar(0, 10));
+        buffer.push(bar(1, 11));
+        assert_eq!(buffer.values(&high2), Some(vec![11.0, 12.0]));
+        assert_eq!(buffer.values(&close3), None);
+        buffer.push(bar(2, 12));
+        buffer.push(bar(3, 13));
+        //repeated bars don't count
+        buffer.push(bar(3, 99));
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.values(&close3), Some(vec![11.0, 12.0, 13.0]));
+        let typical = need(PriceField::Typical, Lookback::Bars(1));
+        assert_eq!(buffer.values(&typical), Some(vec![13.0]));
+
+        //nothing buffered and no need never index outside
+        let empty = SymbolBuffer::default();
+        assert_eq!(empty.values(&close3), None);
+        assert_eq!(
+            buffer.values(&need(PriceField::Close, Lookback::Bars(0))),
+            Some(vec![])
+        );
+    }
+
+    #[test]
+    fn minutes_test() {
+        let mut buffer = SymbolBuffer::default();
+        let window = need(PriceField::Close, Lookback::Minutes(5));
+        buffer.require(window);
+        buffer.require(need(PriceField::Close, Lookback::Bars(2)));
+        //a bar is missing, the window still spans 5 minutes
+        for (minute, close) in [(0, 10), (1, 11), (3, 13), (4, 14)] {
+            buffer.push(bar(minute, close));
+        }
+        assert_eq!(buffer.values(&window), None);
+        buffer.push(bar(5, 15));
+        assert_eq!(buffer.values(&window), Some(vec![11.0, 13.0, 14.0, 15.0]));
+        //the last bar at or before the start of the window stays, older ones go
+        buffer.push(bar(7, 17));
+        assert_eq!(buffer.len(), 5);
+        assert_eq!(buffer.values(&window), Some(vec![13.0, 14.0, 15.0, 17.0]));
+
+        //volume weighted over the window
+        let vwap = need(PriceField::Vwap, Lookback::Bars(2));
+        let values = buffer.values(&vwap).unwrap();
+        let expected = (15.0 * 600.0 + 17.0 * 800.0) / 1400.0;
+        assert!((values[1] - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn seed_test() {
+        let mut buffers = Buffers::default();
+        buffers
+            .symbols
+            .entry(String::from("ORCL"))
+            .or_default()
+            .require(need(PriceField::Close, Lookback::Bars(4)));
+        buffers.push(&bar(9, 19));
+        let history: Vec<Bar> = (0..6).map(|m| bar(m, 10 + m as i32)).collect();
+        buffers.seed("ORCL", &history);
+        let close = need(PriceField::Close, Lookback::Bars(4));
+        assert_eq!(
+            buffers.values("ORCL", &close),
+            Some(vec![13.0, 14.0, 15.0, 19.0])
+        );
+        assert!(buffers.ready("ORCL", &[close]));
+        assert!(!buffers.ready("MSFT", &[close]));
+        assert!(buffers.ready("MSFT", &[]));
+    }
+}
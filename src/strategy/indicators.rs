@@ -0,0 +1,140 @@
+//local indicator math, one output per input value
+//None until the indicator has seen enough values
+
+pub fn sma(values: &[f64], period: usize) -> Vec<Option<f64>> {
+    let mut out = vec![None; values.len()];
+    if period == 0 {
+        return out;
+    }
+    let mut sum = 0.0;
+    for (i, v) in values.iter().enumerate() {
+        sum += v;
+        if i >= period {
+            sum -= values[i - period];
+        }
+        if i + 1 >= period {
+            out[i] = Some(sum / period as f64);
+        }
+    }
+    out
+}
+
+//seeded with the sma of the first period values
+pub fn ema(values: &[f64], period: usize) -> Vec<Option<f64>> {
+    let mut out = vec![None; values.len()];
+    if period == 0 || values.len() < period {
+        return out;
+    }
+    let k = 2.0 / (period as f64 + 1.0);
+    let mut prev = values[..period].iter().sum::<f64>() / period as f64;
+    out[period - 1] = Some(prev);
+    for i in period..values.len() {
+        prev = values[i] * k + prev * (1.0 - k);
+        out[i] = Some(prev);
+    }
+    out
+}
+
+//Wilder smoothing
+pub fn rsi(values: &[f64], period: usize) -> Vec<Option<f64>> {
+    let mut out = vec![None; values.len()];
+    if period == 0 || values.len() <= period {
+        return out;
+    }
+    let mut gain = 0.0;
+    let mut loss = 0.0;
+    for i in 1..=period {
+        let change = values[i] - values[i - 1];
+        if change > 0.0 {
+            gain += change;
+        } else {
+            loss -= change;
+        }
+    }
+    gain /= period as f64;
+    loss /= period as f64;
+    out[period] = Some(rsi_value(gain, loss));
+    for i in (period + 1)..values.len() {
+        let change = values[i] - values[i - 1];
+        gain = (gain * (period as f64 - 1.0) + change.max(0.0)) / period as f64;
+        loss = (loss * (period as f64 - 1.0) + (-change).max(0.0)) / period as f64;
+        out[i] = Some(rsi_value(gain, loss));
+    }
+    out
+}
+
+fn rsi_value(gain: f64, loss: f64) -> f64 {
+    if loss == 0.0 {
+        return 100.0;
+    }
+    100.0 - 100.0 / (1.0 + gain / loss)
+}
+
+//population standard deviation over the window
+pub fn std_dev(values: &[f64], period: usize) -> Vec<Option<f64>> {
+    let mean = sma(values, period);
+    mean.iter()
+        .enumerate()
+        .map(|(i, m)| {
+            m.map(|m| {
+                let window = &values[i + 1 - period..=i];
+                (window.iter().map(|v| (v - m).powi(2)).sum::<f64>() / period as f64).sqrt()
+            })
+        })
+        .collect()
+}
+
+//(upper, middle, lower)
+pub fn bollinger(
+    values: &[f64],
+    period: usize,
+    multiplier: f64,
+) -> (Vec<Option<f64>>, Vec<Option<f64>>, Vec<Option<f64>>) {
+    let middle = sma(values, period);
+    let sd = std_dev(values, period);
+    let upper = middle
+        .iter()
+        .zip(sd.iter())
+        .map(|(m, s)| Some((*m)? + (*s)? * multiplier))
+        .collect();
+    let lower = middle
+        .iter()
+        .zip(sd.iter())
+        .map(|(m, s)| Some((*m)? - (*s)? * multiplier))
+        .collect();
+    (upper, middle, lower)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sma_test() {
+        let res = sma(&[1.0, 2.0, 3.0, 4.0], 2);
+        assert_eq!(res, vec![None, Some(1.5), Some(2.5), Some(3.5)]);
+    }
+
+    #[test]
+    fn ema_test() {
+        let res = ema(&[1.0, 2.0, 3.0, 4.0], 3);
+        assert_eq!(res, vec![None, None, Some(2.0), Some(3.0)]);
+    }
+
+    #[test]
+    fn rsi_test() {
+        let res = rsi(&[1.0, 2.0, 3.0, 4.0], 2);
+        assert_eq!(res, vec![None, None, Some(100.0), Some(100.0)]);
+        let res = rsi(&[4.0, 3.0, 2.0], 2);
+        assert_eq!(res[2], Some(0.0));
+    }
+
+    #[test]
+    fn bollinger_test() {
+        let (upper, middle, lower) = bollinger(&[1.0, 3.0, 1.0, 3.0], 2, 2.0);
+        assert_eq!(middle[1], Some(2.0));
+        assert_eq!(upper[1], Some(4.0));
+        assert_eq!(lower[1], Some(0.0));
+        assert_eq!(upper[0], None);
+    }
+}
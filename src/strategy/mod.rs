@@ -0,0 +1,113 @@
+use std::{borrow::Cow, collections::VecDeque};
+
+use apca::data::v2::stream::Bar;
+use polars::{frame::DataFrame, prelude::DataType};
+
+use crate::{error::CLIError, types::PriceField};
+
+pub mod buffer;
+pub mod indicators;
+pub mod rule;
+
+pub use rule::{RuleConf, RuleError, RuleSet};
+
+//price history the rules are evaluated on, oldest bar first
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Candles {
+    pub open: Vec<f64>,
+    pub high: Vec<f64>,
+    pub low: Vec<f64>,
+    pub close: Vec<f64>,
+    pub volume: Vec<f64>,
+}
+
+impl Candles {
+    pub fn len(&self) -> usize {
+        self.close.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.close.is_empty()
+    }
+
+    pub fn push(&mut self, o: f64, h: f64, l: f64, c: f64, v: f64) {
+        self.open.push(o);
+        self.high.push(h);
+        self.low.push(l);
+        self.close.push(c);
+        self.volume.push(v);
+    }
+
+    pub fn push_bar(&mut self, bar: &Bar) {
+        self.push(
+            bar.open_price.to_f64().unwrap_or(f64::NAN),
+            bar.high_price.to_f64().unwrap_or(f64::NAN),
+            bar.low_price.to_f64().unwrap_or(f64::NAN),
+            bar.close_price.to_f64().unwrap_or(f64::NAN),
+            bar.volume.to_f64().unwrap_or(f64::NAN),
+        );
+    }
+
+    pub fn field(&self, field: PriceField) -> Cow<'_, [f64]> {
+        match field {
+            PriceField::Open => Cow::Borrowed(&self.open),
+            PriceField::High => Cow::Borrowed(&self.high),
+            PriceField::Low => Cow::Borrowed(&self.low),
+            PriceField::Close => Cow::Borrowed(&self.close),
+            PriceField::Volume => Cow::Borrowed(&self.volume),
+            PriceField::Typical => Cow::Owned(self.typical()),
+            PriceField::Vwap => Cow::Owned(self.vwap()),
+        }
+    }
+
+    fn typical(&self) -> Vec<f64> {
+        (0..self.len())
+            .map(|i| (self.high[i] + self.low[i] + self.close[i]) / 3.0)
+            .collect()
+    }
+
+    //the typical price while no volume has traded
+    fn vwap(&self) -> Vec<f64> {
+        let (mut value, mut volume) = (0.0, 0.0);
+        self.typical()
+            .into_iter()
+            .zip(&self.volume)
+            .map(|(price, v)| {
+                value += price * v;
+                volume += v;
+                if volume > 0.0 {
+                    value / volume
+                } else {
+                    price
+                }
+            })
+            .collect()
+    }
+
+    pub fn from_bars(bars: &VecDeque<Bar>) -> Self {
+        let mut candles = Candles::default();
+        for bar in bars {
+            candles.push_bar(bar);
+        }
+        candles
+    }
+
+    //expects the csv layout Date,Open,High,Low,Close,Adj Close,Volume
+    pub fn from_df(df: &DataFrame) -> Result<Self, CLIError> {
+        let read = |name: &str| -> Result<Vec<f64>, CLIError> {
+            let column = df.column(name)?.cast(&DataType::Float64)?;
+            Ok(column
+                .f64()?
+                .into_iter()
+                .map(|x| x.unwrap_or(f64::NAN))
+                .collect())
+        };
+        Ok(Candles {
+            open: read("Open")?,
+            high: read("High")?,
+            low: read("Low")?,
+            close: read("Close")?,
+            volume: read("Volume")?,
+        })
+    }
+}
@@ -0,0 +1,576 @@
+//Small rule language for Stockconfig, e.g.
+//  "close crosses above bb_upper(20, 2)"
+//  "rsi(14) < 30 and close > sma(200)"
+//  "ema(12) crosses ema(26)"
+use serde::Deserialize;
+
+use crate::{
+    strategy::{indicators, Candles},
+    types::{Action, PriceField},
+};
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConf {
+    pub when: String,
+    pub action: Action,
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum RuleError {
+    #[error("rule `{rule}`: unexpected `{found}` at position {pos}")]
+    Unexpected {
+        rule: String,
+        pos: usize,
+        found: String,
+    },
+    #[error("rule `{0}` ends unexpectedly")]
+    UnexpectedEnd(String),
+    #[error("rule `{rule}`: unknown series `{name}`")]
+    UnknownSeries { rule: String, name: String },
+    #[error("rule `{rule}`: invalid parameters for `{name}`: {reason}")]
+    InvalidParams {
+        rule: String,
+        name: String,
+        reason: String,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(f64),
+    Op(CmpOp),
+    LParen,
+    RParen,
+    Comma,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CmpOp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cross {
+    Above,
+    Below,
+    Any,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Band {
+    Upper,
+    Middle,
+    Lower,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Series {
+    Const(f64),
+    Price(PriceField),
+    Sma(PriceField, usize),
+    Ema(PriceField, usize),
+    Rsi(PriceField, usize),
+    Bollinger(Band, usize, f64),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cond {
+    Compare(Series, CmpOp, Series),
+    Cross(Series, Cross, Series),
+    And(Box<Cond>, Box<Cond>),
+    Or(Box<Cond>, Box<Cond>),
+}
+
+//compiled rules of one TraderConf, first matching rule wins
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RuleSet {
+    rules: Vec<(Cond, Action)>,
+}
+
+impl RuleSet {
+    pub fn compile(confs: &[RuleConf]) -> Result<Self, RuleError> {
+        let rules = confs
+            .iter()
+            .map(|c| Ok((parse(&c.when)?, c.action.clone())))
+            .collect::<Result<Vec<_>, RuleError>>()?;
+        Ok(RuleSet { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    //bars needed before every series of every rule has a value
+    pub fn lookback(&self) -> usize {
+        self.rules
+            .iter()
+            .map(|(c, _)| c.lookback())
+            .max()
+            .unwrap_or(0)
+    }
+
+    //one action per bar
+    pub fn actions(&self, candles: &Candles) -> Vec<Action> {
+        let matched: Vec<(Vec<bool>, &Action)> = self
+            .rules
+            .iter()
+            .map(|(c, a)| (c.eval(candles), a))
+            .collect();
+        (0..candles.len())
+            .map(|i| {
+                matched
+                    .iter()
+                    .find(|(m, _)| m[i])
+                    .map(|(_, a)| (*a).clone())
+                    .unwrap_or(Action::Hold)
+            })
+            .collect()
+    }
+
+    //action for the newest bar
+    pub fn decide(&self, candles: &Candles) -> Action {
+        self.actions(candles).pop().unwrap_or(Action::Hold)
+    }
+}
+
+impl Series {
+    fn values(&self, candles: &Candles) -> Vec<Option<f64>> {
+        match self {
+            Series::Const(v) => vec![Some(*v); candles.len()],
+            Series::Price(f) => candles
+                .field(*f)
+                .iter()
+                .map(|v| Some(*v).filter(|v| !v.is_nan()))
+                .collect(),
+            Series::Sma(f, p) => indicators::sma(&candles.field(*f), *p),
+            Series::Ema(f, p) => indicators::ema(&candles.field(*f), *p),
+            Series::Rsi(f, p) => indicators::rsi(&candles.field(*f), *p),
+            Series::Bollinger(band, p, k) => {
+                let (upper, middle, lower) = indicators::bollinger(&candles.close, *p, *k);
+                match band {
+                    Band::Upper => upper,
+                    Band::Middle => middle,
+                    Band::Lower => lower,
+                }
+            }
+        }
+    }
+
+    fn lookback(&self) -> usize {
+        match self {
+            Series::Const(_) | Series::Price(_) => 1,
+            Series::Sma(_, p) | Series::Ema(_, p) | Series::Bollinger(_, p, _) => *p,
+            Series::Rsi(_, p) => *p + 1,
+        }
+    }
+}
+
+impl Cond {
+    fn eval(&self, candles: &Candles) -> Vec<bool> {
+        match self {
+            Cond::Compare(a, op, b) => {
+                let (a, b) = (a.values(candles), b.values(candles));
+                a.iter()
+                    .zip(b.iter())
+                    .map(|(a, b)| match (a, b) {
+                        (Some(a), Some(b)) => match op {
+                            CmpOp::Lt => a < b,
+                            CmpOp::Le => a <= b,
+                            CmpOp::Gt => a > b,
+                            CmpOp::Ge => a >= b,
+                        },
+                        _ => false,
+                    })
+                    .collect()
+            }
+            Cond::Cross(a, dir, b) => {
+                let (a, b) = (a.values(candles), b.values(candles));
+                (0..a.len())
+                    .map(|i| {
+                        if i == 0 {
+                            return false;
+                        }
+                        match (a[i - 1], b[i - 1], a[i], b[i]) {
+                            (Some(pa), Some(pb), Some(a), Some(b)) => {
+                                let above = pa <= pb && a > b;
+                                let below = pa >= pb && a < b;
+                                match dir {
+                                    Cross::Above => above,
+                                    Cross::Below => below,
+                                    Cross::Any => above || below,
+                                }
+                            }
+                            _ => false,
+                        }
+                    })
+                    .collect()
+            }
+            Cond::And(l, r) => l
+                .eval(candles)
+                .iter()
+                .zip(r.eval(candles))
+                .map(|(l, r)| *l && r)
+                .collect(),
+            Cond::Or(l, r) => l
+                .eval(candles)
+                .iter()
+                .zip(r.eval(candles))
+                .map(|(l, r)| *l || r)
+                .collect(),
+        }
+    }
+
+    fn lookback(&self) -> usize {
+        match self {
+            Cond::Compare(a, _, b) => a.lookback().max(b.lookback()),
+            //crossing needs the previous bar too
+            Cond::Cross(a, _, b) => a.lookback().max(b.lookback()) + 1,
+            Cond::And(l, r) | Cond::Or(l, r) => l.lookback().max(r.lookback()),
+        }
+    }
+}
+
+pub fn parse(rule: &str) -> Result<Cond, RuleError> {
+    let tokens = lex(rule)?;
+    let mut parser = Parser {
+        rule,
+        tokens,
+        pos: 0,
+    };
+    let cond = parser.or()?;
+    match parser.tokens.get(parser.pos) {
+        None => Ok(cond),
+        Some((pos, t)) => Err(parser.unexpected(*pos, t)),
+    }
+}
+
+fn lex(rule: &str) -> Result<Vec<(usize, Token)>, RuleError> {
+    let chars: Vec<char> = rule.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        match c {
+            ' ' | '\t' | '\n' => {
+                i += 1;
+                continue;
+            }
+            '(' => tokens.push((start, Token::LParen)),
+            ')' => tokens.push((start, Token::RParen)),
+            ',' => tokens.push((start, Token::Comma)),
+            '<' | '>' => {
+                let eq = chars.get(i + 1) == Some(&'=');
+                let op = match (c, eq) {
+                    ('<', false) => CmpOp::Lt,
+                    ('<', true) => CmpOp::Le,
+                    ('>', false) => CmpOp::Gt,
+                    _ => CmpOp::Ge,
+                };
+                if eq {
+                    i += 1;
+                }
+                tokens.push((start, Token::Op(op)));
+            }
+            c if c.is_ascii_digit() || c == '.' => {
+                while i + 1 < chars.len() && (chars[i + 1].is_ascii_digit() || chars[i + 1] == '.')
+                {
+                    i += 1;
+                }
+                let text: String = chars[start..=i].iter().collect();
+                let number = text.parse::<f64>().map_err(|_| RuleError::Unexpected {
+                    rule: rule.to_string(),
+                    pos: start,
+                    found: text.clone(),
+                })?;
+                tokens.push((start, Token::Number(number)));
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                while i + 1 < chars.len()
+                    && (chars[i + 1].is_ascii_alphanumeric() || chars[i + 1] == '_')
+                {
+                    i += 1;
+                }
+                let text: String = chars[start..=i].iter().collect();
+                tokens.push((start, Token::Ident(text.to_lowercase())));
+            }
+            c => {
+                return Err(RuleError::Unexpected {
+                    rule: rule.to_string(),
+                    pos: start,
+                    found: c.to_string(),
+                })
+            }
+        }
+        i += 1;
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    rule: &'a str,
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, t)| t)
+    }
+
+    fn next(&mut self) -> Result<(usize, Token), RuleError> {
+        let t = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| RuleError::UnexpectedEnd(self.rule.to_string()))?;
+        self.pos += 1;
+        Ok(t)
+    }
+
+    fn keyword(&mut self, word: &str) -> bool {
+        if self.peek() == Some(&Token::Ident(word.to_string())) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn unexpected(&self, pos: usize, found: &Token) -> RuleError {
+        RuleError::Unexpected {
+            rule: self.rule.to_string(),
+            pos,
+            found: format!("{found:?}"),
+        }
+    }
+
+    fn or(&mut self) -> Result<Cond, RuleError> {
+        let mut left = self.and()?;
+        while self.keyword("or") {
+            left = Cond::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Cond, RuleError> {
+        let mut left = self.term()?;
+        while self.keyword("and") {
+            left = Cond::And(Box::new(left), Box::new(self.term()?));
+        }
+        Ok(left)
+    }
+
+    fn term(&mut self) -> Result<Cond, RuleError> {
+        //a bracket either groups conditions or starts a series argument list,
+        //series never start with a bracket so it must be a group here
+        if self.peek() == Some(&Token::LParen) {
+            self.pos += 1;
+            let cond = self.or()?;
+            match self.next()? {
+                (_, Token::RParen) => return Ok(cond),
+                (pos, t) => return Err(self.unexpected(pos, &t)),
+            }
+        }
+        let left = self.series()?;
+        if self.keyword("crosses") {
+            let dir = if self.keyword("above") {
+                Cross::Above
+            } else if self.keyword("below") {
+                Cross::Below
+            } else {
+                Cross::Any
+            };
+            return Ok(Cond::Cross(left, dir, self.series()?));
+        }
+        match self.next()? {
+            (_, Token::Op(op)) => Ok(Cond::Compare(left, op, self.series()?)),
+            (pos, t) => Err(self.unexpected(pos, &t)),
+        }
+    }
+
+    fn series(&mut self) -> Result<Series, RuleError> {
+        let name = match self.next()? {
+            (_, Token::Number(n)) => return Ok(Series::Const(n)),
+            (_, Token::Ident(name)) => name,
+            (pos, t) => return Err(self.unexpected(pos, &t)),
+        };
+        let params = if self.peek() == Some(&Token::LParen) {
+            self.pos += 1;
+            self.params()?
+        } else {
+            vec![]
+        };
+        self.build(&name, &params)
+    }
+
+    fn params(&mut self) -> Result<Vec<f64>, RuleError> {
+        let mut params = vec![];
+        loop {
+            match self.next()? {
+                (_, Token::RParen) if params.is_empty() => return Ok(params),
+                (_, Token::Number(n)) => params.push(n),
+                (pos, t) => return Err(self.unexpected(pos, &t)),
+            }
+            match self.next()? {
+                (_, Token::Comma) => continue,
+                (_, Token::RParen) => return Ok(params),
+                (pos, t) => return Err(self.unexpected(pos, &t)),
+            }
+        }
+    }
+
+    fn build(&self, name: &str, params: &[f64]) -> Result<Series, RuleError> {
+        let invalid = |reason: &str| RuleError::InvalidParams {
+            rule: self.rule.to_string(),
+            name: name.to_string(),
+            reason: reason.to_string(),
+        };
+        let period = |default: usize| -> Result<usize, RuleError> {
+            match params.first() {
+                None => Ok(default),
+                Some(p) if *p >= 1.0 && p.fract() == 0.0 => Ok(*p as usize),
+                Some(_) => Err(invalid("period must be a whole number >= 1")),
+            }
+        };
+        let price = |field| {
+            if params.is_empty() {
+                Ok(Series::Price(field))
+            } else {
+                Err(invalid("price series take no parameters"))
+            }
+        };
+        let max_params = |n: usize| {
+            if params.len() > n {
+                Err(invalid(&format!("expected at most {n} parameters")))
+            } else {
+                Ok(())
+            }
+        };
+        match name {
+            "open" => price(PriceField::Open),
+            "high" => price(PriceField::High),
+            "low" => price(PriceField::Low),
+            "close" => price(PriceField::Close),
+            "volume" => price(PriceField::Volume),
+            "typical" => price(PriceField::Typical),
+            "vwap" => price(PriceField::Vwap),
+            "sma" => max_params(1).and(Ok(Series::Sma(PriceField::Close, period(20)?))),
+            "ema" => max_params(1).and(Ok(Series::Ema(PriceField::Close, period(20)?))),
+            "rsi" => max_params(1).and(Ok(Series::Rsi(PriceField::Close, period(14)?))),
+            "bb_upper" | "bb_middle" | "bb_lower" => {
+                max_params(2)?;
+                let band = match name {
+                    "bb_upper" => Band::Upper,
+                    "bb_middle" => Band::Middle,
+                    _ => Band::Lower,
+                };
+                let multiplier = params.get(1).copied().unwrap_or(2.0);
+                if multiplier <= 0.0 {
+                    return Err(invalid("multiplier must be > 0"));
+                }
+                Ok(Series::Bollinger(band, period(20)?, multiplier))
+            }
+            _ => Err(RuleError::UnknownSeries {
+                rule: self.rule.to_string(),
+                name: name.to_string(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candles(close: &[f64]) -> Candles {
+        let mut c = Candles::default();
+        for v in close {
+            c.push(*v, *v, *v, *v, 100.0);
+        }
+        c
+    }
+
+    #[test]
+    fn parse_test() -> Result<(), Box<dyn std::error::Error>> {
+        let cond = parse("RSI(14) < 30 AND close > sma(200)")?;
+        assert_eq!(
+            cond,
+            Cond::And(
+                Box::new(Cond::Compare(
+                    Series::Rsi(PriceField::Close, 14),
+                    CmpOp::Lt,
+                    Series::Const(30.0)
+                )),
+                Box::new(Cond::Compare(
+                    Series::Price(PriceField::Close),
+                    CmpOp::Gt,
+                    Series::Sma(PriceField::Close, 200)
+                )),
+            )
+        );
+        let cond = parse("close crosses above bb_upper(20, 2)")?;
+        assert_eq!(
+            cond,
+            Cond::Cross(
+                Series::Price(PriceField::Close),
+                Cross::Above,
+                Series::Bollinger(Band::Upper, 20, 2.0)
+            )
+        );
+        assert!(matches!(
+            parse("ema(12) crosses ema(26)")?,
+            Cond::Cross(_, Cross::Any, _)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_error_test() {
+        assert!(matches!(parse("close >"), Err(RuleError::UnexpectedEnd(_))));
+        assert!(matches!(
+            parse("foo(3) > 1"),
+            Err(RuleError::UnknownSeries { .. })
+        ));
+        assert!(matches!(
+            parse("sma(0) > 1"),
+            Err(RuleError::InvalidParams { .. })
+        ));
+        assert!(matches!(
+            parse("close > 1 )"),
+            Err(RuleError::Unexpected { .. })
+        ));
+    }
+
+    #[test]
+    fn rule_set_test() -> Result<(), Box<dyn std::error::Error>> {
+        let rules = RuleSet::compile(&[
+            RuleConf {
+                when: String::from("close crosses above sma(2)"),
+                action: Action::Buy,
+            },
+            RuleConf {
+                when: String::from("close < 2"),
+                action: Action::Sell,
+            },
+        ])?;
+        let actions = rules.actions(&candles(&[3.0, 1.0, 1.0, 4.0, 5.0]));
+        assert_eq!(
+            actions,
+            vec![
+                Action::Hold,
+                Action::Sell,
+                Action::Sell,
+                Action::Buy,
+                Action::Hold
+            ]
+        );
+        assert_eq!(rules.lookback(), 3);
+        Ok(())
+    }
+}
@@ -0,0 +1,256 @@
+//Logging setup from [logging] and the Prometheus metrics served on [metrics] listen
+use std::{collections::HashMap, sync::LazyLock};
+
+use axum::{http::header::CONTENT_TYPE, routing::get, Router};
+use prometheus::{
+    Encoder, Gauge, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry,
+    TextEncoder,
+};
+use serde::Deserialize;
+use tracing::{error, info};
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
+
+use crate::{error::CLIError, portfolio::types::Portfolio};
+
+//[logging]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConf {
+    //tracing filter like "info" or "info,trader::grpc_client=debug", RUST_LOG wins if set
+    pub level: String,
+    pub format: LogFormat,
+    //log every closed span with its duration
+    pub span_events: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Compact,
+    Pretty,
+    //one object per line with the fields of the enclosing spans
+    Json,
+}
+
+impl Default for LoggingConf {
+    fn default() -> Self {
+        LoggingConf {
+            level: String::from("info"),
+            format: LogFormat::default(),
+            span_events: false,
+        }
+    }
+}
+
+//[metrics], no endpoint without listen
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConf {
+    //e.g. "127.0.0.1:9184", scraped at /metrics
+    pub listen: Option<String>,
+}
+
+pub fn init_logging(conf: &LoggingConf) -> Result<(), CLIError> {
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(&conf.level)
+            .map_err(|e| CLIError::InvalidConfig(format!("logging.level: {e}")))?,
+    };
+    let span_events = if conf.span_events {
+        FmtSpan::CLOSE
+    } else {
+        FmtSpan::NONE
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(span_events);
+    let res = match conf.format {
+        LogFormat::Compact => builder.compact().try_init(),
+        LogFormat::Pretty => builder.pretty().try_init(),
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .try_init(),
+    };
+    res.map_err(|e| CLIError::InvalidConfig(format!("logging: {e}")))
+}
+
+pub struct Metrics {
+    registry: Registry,
+    //bars that reached the traders, by symbol
+    pub bars: IntCounterVec,
+    //indicator calls to the calculate service, by method
+    pub indicator_latency: HistogramVec,
+    //signals of the variants, by symbol and action
+    pub decisions: IntCounterVec,
+    //by symbol and status: sent, filled or rejected
+    pub orders: IntCounterVec,
+    //equity minus starting cash
+    pub pnl: Gauge,
+    //market value of the position, by symbol
+    pub exposure: GaugeVec,
+    //failed indicator calls, by status code
+    pub grpc_errors: IntCounterVec,
+    //of worked orders against the arrival price, by algo
+    pub slippage: HistogramVec,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+impl Metrics {
+    //names and labels are fixed, registering can only fail on a typo
+    fn new() -> Self {
+        let registry = Registry::new();
+        let counter = |name: &str, help: &str, labels: &[&str]| {
+            let c = IntCounterVec::new(Opts::new(name, help), labels).expect("counter");
+            registry.register(Box::new(c.clone())).expect("register");
+            c
+        };
+        let bars = counter("trader_bars_total", "Bars processed", &["symbol"]);
+        let decisions = counter(
+            "trader_decisions_total",
+            "Decisions by action",
+            &["symbol", "action"],
+        );
+        let orders = counter(
+            "trader_orders_total",
+            "Orders sent, filled and rejected",
+            &["symbol", "status"],
+        );
+        let grpc_errors = counter(
+            "trader_grpc_errors_total",
+            "Failed indicator calls",
+            &["code"],
+        );
+        let indicator_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "trader_indicator_latency_seconds",
+                "Latency of indicator calls",
+            ),
+            &["method"],
+        )
+        .expect("histogram");
+        registry
+            .register(Box::new(indicator_latency.clone()))
+            .expect("register");
+        let slippage = HistogramVec::new(
+            HistogramOpts::new(
+                "trader_execution_slippage_bps",
+                "Slippage of worked orders against the arrival price",
+            )
+            .buckets(vec![-50.0, -20.0, -10.0, -5.0, 0.0, 5.0, 10.0, 20.0, 50.0]),
+            &["algo"],
+        )
+        .expect("histogram");
+        registry
+            .register(Box::new(slippage.clone()))
+            .expect("register");
+        let pnl = Gauge::new("trader_pnl", "Equity minus starting cash").expect("gauge");
+        registry.register(Box::new(pnl.clone())).expect("register");
+        let exposure = GaugeVec::new(
+            Opts::new("trader_exposure", "Market value of the position"),
+            &["symbol"],
+        )
+        .expect("gauge");
+        registry
+            .register(Box::new(exposure.clone()))
+            .expect("register");
+        Metrics {
+            registry,
+            bars,
+            indicator_latency,
+            decisions,
+            orders,
+            pnl,
+            exposure,
+            grpc_errors,
+            slippage,
+        }
+    }
+
+    pub fn order(&self, symbol: &str, status: &str) {
+        self.orders.with_label_values(&[symbol, status]).inc();
+    }
+
+    //exposure and P&L at the given prices, symbols without a price count as 0
+    pub fn portfolio(&self, port: &Portfolio, prices: &HashMap<String, f64>, start_cash: f64) {
+        let mut equity = port.cash.unwrap_or(0.0);
+        for (symbol, shares) in port.stocks.iter().flatten() {
+            let value = shares * prices.get(symbol).copied().unwrap_or(0.0);
+            self.exposure.with_label_values(&[symbol]).set(value);
+            equity += value;
+        }
+        self.pnl.set(equity - start_cash);
+    }
+
+    //Prometheus text format
+    pub fn render(&self) -> String {
+        let mut buf = vec![];
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buf) {
+            error!("metrics: {e}");
+        }
+        String::from_utf8(buf).unwrap_or_default()
+    }
+}
+
+//GET /metrics until the task is dropped, a busy port only costs the metrics
+pub async fn serve_metrics(listen: String) {
+    let app = Router::new().route(
+        "/metrics",
+        get(|| async {
+            (
+                [(CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+                metrics().render(),
+            )
+        }),
+    );
+    let listener = match tokio::net::TcpListener::bind(&listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("metrics on {listen}: {e}");
+            return;
+        }
+    };
+    info!("metrics on http://{listen}/metrics");
+    if let Err(e) = axum::serve(listener, app).await {
+        error!("metrics server: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_render_test() {
+        //not the global registry, other tests record into that one
+        let m = Metrics::new();
+        m.bars.with_label_values(&["ORCL"]).inc();
+        m.order("ORCL", "rejected");
+        let port = Portfolio {
+            name: String::from("Test Portfolio"),
+            cash: Some(500.0),
+            stocks: Some(HashMap::from([("ORCL".to_string(), 10.0)])),
+        };
+        m.portfolio(&port, &HashMap::from([("ORCL".to_string(), 60.0)]), 1000.0);
+        let text = m.render();
+        assert!(text.contains(r#"trader_bars_total{symbol="ORCL"} 1"#));
+        assert!(text.contains(r#"trader_orders_total{status="rejected",symbol="ORCL"} 1"#));
+        assert!(text.contains(r#"trader_exposure{symbol="ORCL"} 600"#));
+        assert!(text.contains("trader_pnl 100"));
+    }
+
+    #[test]
+    fn logging_conf_test() {
+        let conf: LoggingConf = toml::from_str(r#"format = "json""#).unwrap();
+        assert_eq!(conf.format, LogFormat::Json);
+        assert_eq!(conf.level, "info");
+        assert!(!conf.span_events);
+    }
+}
@@ -1,74 +1,386 @@
 use apca::{
-    api::v2::order::{self, Side, Type},
+    api::v2::{
+        account, asset,
+        order::{self, Class, StopLoss, TakeProfit, TimeInForce, Type},
+        orders, position, positions, updates,
+    },
     ApiInfo, Client,
 };
+use futures::StreamExt as _;
 use mockall::automock;
 use num_decimal::Num;
+use std::{str::FromStr, time::Duration};
+use tracing::{info, instrument, warn};
 
-use crate::{error::CLIError, trader::TraderConfigs, types::ActionValuator};
+use crate::{
+    asset::{position_symbol, round_qty},
+    error::{BrokerError, CLIError},
+    notify::{notify, Event},
+    order::{Filled, OrderClass, OrderSpec, OrderType, Side, Trail},
+    portfolio::types::Portfolio,
+    retry::RetryPolicy,
+    telemetry::metrics,
+    trader::TraderConfigs,
+    types::{Action, ActionValuator},
+};
 
 #[automock]
 pub trait StockActions {
     async fn stock_buy(&self, av: ActionValuator) -> Result<(), CLIError>;
     async fn stock_sell(&self, av: ActionValuator) -> Result<(), CLIError>;
     async fn liquidate_all(&self, av: ActionValuator) -> Result<(), CLIError>;
+    //sells the whole position in symbol
+    async fn close_position(&self, symbol: &str) -> Result<(), CLIError>;
+    //open orders of symbol, e.g. the exit legs of a bracket
+    async fn cancel_orders(&self, symbol: &str) -> Result<(), CLIError>;
+    //cash and positions of the account
+    async fn holdings(&self) -> Result<Portfolio, CLIError>;
+    //any order type and class, stock_buy and stock_sell send the order of their ActionValuator.
+    //What filled by the time it returns, all of it for IOC and FOK orders
+    async fn submit(&self, spec: OrderSpec) -> Result<Filled, CLIError>;
+    //latest price seen for the symbol, only needed by brokers that fill locally
+    fn mark_price(&self, symbol: &str, price: f64) {}
+}
+
+fn cents(price: f64) -> Option<Num> {
+    Num::from_str(&format!("{price:.2}")).ok()
+}
+
+//Alpaca request of the spec, exit legs go into take_profit and stop_loss
+fn order_request(spec: &OrderSpec) -> order::CreateReq {
+    let mut init = order::CreateReqInit {
+        time_in_force: match spec.time_in_force {
+            crate::order::TimeInForce::Day => TimeInForce::Day,
+            crate::order::TimeInForce::Gtc => TimeInForce::UntilCanceled,
+            crate::order::TimeInForce::Ioc => TimeInForce::ImmediateOrCancel,
+            crate::order::TimeInForce::Fok => TimeInForce::FillOrKill,
+            crate::order::TimeInForce::Opg => TimeInForce::UntilMarketOpen,
+            crate::order::TimeInForce::Cls => TimeInForce::UntilMarketClose,
+        },
+        ..Default::default()
+    };
+    init.type_ = match spec.type_ {
+        OrderType::Market => Type::Market,
+        OrderType::Limit(limit) => {
+            init.limit_price = cents(limit);
+            Type::Limit
+        }
+        OrderType::Stop(stop) => {
+            init.stop_price = cents(stop);
+            Type::Stop
+        }
+        OrderType::StopLimit { stop, limit } => {
+            init.stop_price = cents(stop);
+            init.limit_price = cents(limit);
+            Type::StopLimit
+        }
+        OrderType::TrailingStop(trail) => {
+            match trail {
+                Trail::Price(price) => init.trail_price = cents(price),
+                Trail::Percent(pct) => init.trail_percent = cents(pct),
+            }
+            Type::TrailingStop
+        }
+    };
+    let exit = match spec.class {
+        OrderClass::Simple => None,
+        OrderClass::Bracket(exit) => {
+            init.class = Class::Bracket;
+            Some(exit)
+        }
+        OrderClass::Oco(exit) => {
+            init.class = Class::OneCancelsOther;
+            Some(exit)
+        }
+        OrderClass::Oto(exit) => {
+            init.class = Class::OneTriggersOther;
+            Some(exit)
+        }
+    };
+    if let Some(exit) = exit {
+        init.take_profit = exit.take_profit.and_then(cents).map(TakeProfit::Limit);
+        init.stop_loss = match (
+            exit.stop_loss.and_then(cents),
+            exit.stop_limit.and_then(cents),
+        ) {
+            (Some(stop), Some(limit)) => Some(StopLoss::StopLimit(stop, limit)),
+            (Some(stop), None) => Some(StopLoss::Stop(stop)),
+            _ => None,
+        };
+    }
+    let side = match spec.side {
+        Side::Buy => order::Side::Buy,
+        Side::Sell => order::Side::Sell,
+    };
+    init.init(spec.symbol.clone(), side, quantity(spec.qty))
+}
+
+//units per order, strength 1.0 is 10, whole shares for equities
+pub fn order_qty(av: &ActionValuator) -> f64 {
+    round_qty(&av.symbol, av.strength * 10.0)
+}
+
+//order amount, crypto quantities are fractional
+fn quantity(qty: f64) -> order::Amount {
+    order::Amount::quantity(Num::from_str(&qty.to_string()).unwrap_or_default())
+}
+
+//client from APCA_API_KEY_ID and APCA_API_SECRET_KEY
+pub fn alpaca_client() -> Result<Client, CLIError> {
+    let api_info = ApiInfo::from_env().map_err(|e| BrokerError::Credentials(e.to_string()))?;
+    Ok(Client::new(api_info))
+}
+
+//sends the order of av, retrying transport errors and rate limits
+#[instrument(name = "order", skip_all, fields(symbol = %av.symbol, action = ?av.action))]
+pub async fn execute<B: StockActions>(
+    broker: &B,
+    av: ActionValuator,
+    retry: &RetryPolicy,
+) -> Result<(), CLIError> {
+    if av.action == Action::Hold {
+        return Ok(());
+    }
+    let what = format!("{:?} {}", av.action, av.symbol);
+    metrics().order(&av.symbol, "sent");
+    let res = retry
+        .run(&what, || {
+            let av = av.clone();
+            async move {
+                match av.action {
+                    Action::Buy => broker.stock_buy(av).await,
+                    Action::Sell => broker.stock_sell(av).await,
+                    Action::Hold => Ok(()),
+                }
+            }
+        })
+        .await;
+    if let Err(e) = &res {
+        warn!("rejected: {e}");
+        metrics().order(&av.symbol, "rejected");
+    }
+    res
+}
+
+//fills are counted and notified by watch_fills, even those known from the response
+fn record_order(symbol: &str, order: &order::Order) {
+    info!(%symbol, id = ?order.id, status = ?order.status, "order accepted");
+}
+
+const UPDATES: &str = "trade updates";
+
+//a filled order, or the filled part of one that ended early. Partial fills are
+//reported with the update that ends the order
+fn update_fill(update: &updates::OrderUpdate) -> Option<Event> {
+    let fill = filled(&update.order);
+    let done = match update.event {
+        updates::OrderStatus::Filled => true,
+        updates::OrderStatus::Canceled
+        | updates::OrderStatus::Expired
+        | updates::OrderStatus::DoneForDay
+        | updates::OrderStatus::Stopped => fill.qty > 0.0,
+        _ => false,
+    };
+    done.then(|| Event::Fill {
+        symbol: update.order.symbol.clone(),
+        side: format!("{:?}", update.order.side).to_lowercase(),
+        qty: fill.qty,
+        price: fill.price,
+    })
+}
+
+//Fills from Alpaca's trade updates: resting limits and the exit legs of brackets
+//fill long after their order was sent. Reconnects until the task is dropped
+pub async fn watch_fills(client: Client) {
+    let backoff = RetryPolicy {
+        base: Duration::from_secs(1),
+        max: Duration::from_secs(60),
+        ..Default::default()
+    };
+    let mut attempt = 0;
+    loop {
+        match client.subscribe::<updates::OrderUpdates>().await {
+            Ok((mut stream, _subscription)) => {
+                info!("listening to {UPDATES}");
+                attempt = 0;
+                while let Some(update) = stream.next().await {
+                    match update {
+                        Ok(Ok(update)) => {
+                            let Some(event) = update_fill(&update) else {
+                                continue;
+                            };
+                            let symbol = &update.order.symbol;
+                            info!(%symbol, id = ?update.order.id, "order filled");
+                            metrics().order(symbol, "filled");
+                            notify(event);
+                        }
+                        Ok(Err(e)) => warn!("{UPDATES}: unparseable update: {e}"),
+                        Err(e) => {
+                            warn!("{UPDATES} lost: {e}");
+                            break;
+                        }
+                    }
+                }
+                notify(Event::Disconnect {
+                    service: UPDATES.to_string(),
+                    msg: String::from("fills are reported again once it is back"),
+                });
+            }
+            Err(e) => warn!("{UPDATES}: {e}"),
+        }
+        let wait = backoff.delay(attempt);
+        attempt = attempt.saturating_add(1);
+        tokio::time::sleep(wait).await;
+    }
+}
+
+fn filled(order: &order::Order) -> Filled {
+    Filled {
+        qty: order.filled_quantity.to_f64().unwrap_or_default(),
+        price: order
+            .average_fill_price
+            .as_ref()
+            .and_then(Num::to_f64)
+            .unwrap_or_default(),
+    }
 }
+
 //check order filled, then trailing stop, atr indi
-impl StockActions for TraderConfigs {
-    async fn stock_buy(&self, av: ActionValuator) -> Result<(), CLIError> {
-        let amount = (av.strength * 10.0) as i64;
-        let api_info = ApiInfo::from_env().unwrap();
-        let client = Client::new(api_info);
-        let request = order::CreateReqInit {
-            type_: Type::Limit,
-            limit_price: Some(Num::from(100)),
+impl TraderConfigs {
+    //open orders hold the shares they would sell, e.g. the exit legs of a bracket
+    async fn cancel_open(&self, client: &Client, symbol: &str) -> Result<(), CLIError> {
+        let request = orders::ListReq {
+            symbols: vec![symbol.to_string()],
+            status: orders::Status::Open,
             ..Default::default()
+        };
+        let open = client
+            .issue::<orders::List>(&request)
+            .await
+            .map_err(|e| BrokerError::order(symbol, &e))?;
+        for order in open {
+            client
+                .issue::<order::Delete>(&order.id)
+                .await
+                .map_err(|e| BrokerError::order(symbol, &e))?;
+            info!(%symbol, id = ?order.id, "order canceled");
+        }
+        Ok(())
+    }
+
+    //IOC and FOK orders are done within moments, polled until they are
+    async fn settle(&self, client: &Client, symbol: &str, mut order: order::Order) -> order::Order {
+        for _ in 0..10 {
+            if order.status.is_terminal() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            match client.issue::<order::Get>(&order.id).await {
+                Ok(latest) => order = latest,
+                Err(e) => {
+                    warn!(%symbol, id = ?order.id, "order status: {e}");
+                    break;
+                }
+            }
         }
-        .init(av.symbol, Side::Buy, order::Amount::quantity(amount));
+        order
+    }
+}
 
-        let order = client.issue::<order::Create>(&request).await?;
-        println!("order: {:#?}", order);
+impl StockActions for TraderConfigs {
+    async fn stock_buy(&self, av: ActionValuator) -> Result<(), CLIError> {
+        self.submit(OrderSpec::from_av(&av, Side::Buy)).await?;
         Ok(())
     }
 
+    //open orders of the symbol, e.g. the exits of an earlier bracket, are canceled first
     async fn stock_sell(&self, av: ActionValuator) -> Result<(), CLIError> {
-        let amount = (av.strength * 10.0) as i64;
-        let api_info = ApiInfo::from_env().unwrap();
-        let client = Client::new(api_info);
-        let request = order::CreateReqInit {
-            type_: Type::Limit,
-            limit_price: Some(Num::from(100)),
-            ..Default::default()
+        let client = alpaca_client()?;
+        self.cancel_open(&client, &av.symbol).await?;
+        self.submit(OrderSpec::from_av(&av, Side::Sell)).await?;
+        Ok(())
+    }
+
+    async fn submit(&self, spec: OrderSpec) -> Result<Filled, CLIError> {
+        spec.check()?;
+        let client = alpaca_client()?;
+        let mut order = client
+            .issue::<order::Create>(&order_request(&spec))
+            .await
+            .map_err(|e| BrokerError::order(&spec.symbol, &e))?;
+        if matches!(
+            spec.time_in_force,
+            crate::order::TimeInForce::Ioc | crate::order::TimeInForce::Fok
+        ) {
+            order = self.settle(&client, &spec.symbol, order).await;
         }
-        .init(av.symbol, Side::Buy, order::Amount::quantity(amount));
+        record_order(&spec.symbol, &order);
+        Ok(filled(&order))
+    }
 
-        let order = client.issue::<order::Create>(&request).await?;
-        println!("order: {:#?}", order);
-        Ok(())
+    async fn cancel_orders(&self, symbol: &str) -> Result<(), CLIError> {
+        self.cancel_open(&alpaca_client()?, symbol).await
+    }
+
+    //short positions are negative
+    async fn holdings(&self) -> Result<Portfolio, CLIError> {
+        let client = alpaca_client()?;
+        let account = client
+            .issue::<account::Get>(&())
+            .await
+            .map_err(|e| CLIError::Alpaca(e.to_string()))?;
+        let held = client
+            .issue::<positions::List>(&())
+            .await
+            .map_err(|e| CLIError::Alpaca(e.to_string()))?;
+        let stocks = held
+            .iter()
+            .map(|p| {
+                let qty = p.quantity.to_f64().unwrap_or_default();
+                if p.side == position::Side::Short {
+                    return (p.symbol.clone(), -qty.abs());
+                }
+                (p.symbol.clone(), qty)
+            })
+            .collect();
+        Ok(Portfolio {
+            name: String::from("Alpaca"),
+            cash: account.cash.to_f64(),
+            stocks: Some(stocks),
+        })
     }
+
     async fn liquidate_all(&self, av: ActionValuator) -> Result<(), CLIError> {
-        todo!()
+        let client = alpaca_client()?;
+        let held = client
+            .issue::<positions::List>(&())
+            .await
+            .map_err(|e| CLIError::Alpaca(e.to_string()))?;
+        for position in held {
+            self.close_position(&position.symbol).await?;
+        }
+        Ok(())
     }
-}
 
-/* async fn data_get(date: &str) -> Result<apca::data::v2::bars::Bars, CLIError> {
-    let api_info = ApiInfo::from_env().unwrap();
-    let client = Client::new(api_info);
-    let start = DateTime::from_str(date).unwrap();
-    let end = start.checked_add_months(Months::new(2)).unwrap();
-    let request = ListReqInit {
-        limit: Some(5),
-        ..Default::default()
+    async fn close_position(&self, symbol: &str) -> Result<(), CLIError> {
+        let client = alpaca_client()?;
+        self.cancel_open(&client, symbol).await?;
+        let order = client
+            .issue::<position::Delete>(&asset::Symbol::Sym(position_symbol(symbol)))
+            .await
+            .map_err(|e| BrokerError::Position {
+                symbol: symbol.to_string(),
+                msg: e.to_string(),
+            })?;
+        record_order(symbol, &order);
+        Ok(())
     }
-    .init("AAPL", start, end, TimeFrame::OneDay);
-    let e = client.issue::<List>(&request).await?;
-    Ok(e)
-} */
+}
 
 #[cfg(test)]
 
 mod tests {
-    use crate::types::Action;
+    use crate::{order::Exit, types::Action};
 
     use super::*;
 
@@ -96,4 +408,130 @@ mod tests {
         //assert_eq!(order, ());
         Ok(())
     }
+
+    #[tokio::test]
+    async fn execute_retry_test() -> Result<(), Box<dyn std::error::Error>> {
+        let mut mock = MockStockActions::new();
+        let mut seq = mockall::Sequence::new();
+        mock.expect_stock_buy()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| Err(CLIError::Stream(String::from("connection reset"))));
+        mock.expect_stock_buy()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| Ok(()));
+        //a rejected order is not sent again
+        mock.expect_stock_sell().times(1).returning(|av| {
+            Err(BrokerError::Order {
+                symbol: av.symbol,
+                msg: String::from("insufficient qty"),
+                retryable: false,
+            }
+            .into())
+        });
+
+        let retry = RetryPolicy {
+            base: std::time::Duration::ZERO,
+            ..Default::default()
+        };
+        let av = |action| ActionValuator {
+            symbol: String::from("ORCL"),
+            strength: 1.0,
+            action,
+            limit_price: None,
+            exit: None,
+        };
+        execute(&mock, av(Action::Buy), &retry).await?;
+        let err = execute(&mock, av(Action::Sell), &retry).await.unwrap_err();
+        assert!(!err.is_retryable() && !err.is_fatal());
+        execute(&mock, av(Action::Hold), &retry).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn order_qty_test() {
+        let av = |symbol: &str| ActionValuator {
+            symbol: symbol.to_string(),
+            strength: 0.55,
+            action: Action::Buy,
+            limit_price: None,
+            exit: None,
+        };
+        assert_eq!(order_qty(&av("ORCL")), 5.0);
+        assert_eq!(order_qty(&av("BTC/USD")), 5.5);
+        let request = |symbol| order_request(&OrderSpec::from_av(&av(symbol), Side::Buy));
+        assert_eq!(request("ORCL").time_in_force, TimeInForce::Day);
+        assert_eq!(request("BTC/USD").time_in_force, TimeInForce::UntilCanceled);
+    }
+
+    #[test]
+    fn update_fill_test() -> Result<(), Box<dyn std::error::Error>> {
+        let update = |event: &str, filled_qty: &str| {
+            serde_json::from_str::<updates::OrderUpdate>(&format!(
+                r#"{{"event":"{event}","order":{{
+                "asset_class":"us_equity","asset_id":"11111111-2222-3333-4444-555555555555",
+                "canceled_at":null,"client_order_id":"11111111-2222-3333-4444-555555555555",
+                "created_at":"2021-12-09T19:48:46.176628398Z","expired_at":null,
+                "extended_hours":false,"failed_at":null,"filled_at":null,
+                "filled_avg_price":"101.5","filled_qty":"{filled_qty}","hwm":null,
+                "id":"11111111-2222-3333-4444-555555555555","legs":null,"limit_price":"102",
+                "notional":null,"order_class":"simple","order_type":"limit","qty":"10",
+                "replaced_at":null,"replaced_by":null,"replaces":null,"side":"sell",
+                "status":"new","stop_price":null,"submitted_at":"2021-12-09T19:48:46.175261379Z",
+                "symbol":"ORCL","time_in_force":"day","trail_percent":null,"trail_price":null,
+                "type":"limit","updated_at":"2021-12-09T19:48:46.185346448Z"}}}}"#
+            ))
+        };
+        //a resting limit that fills later
+        assert_eq!(
+            update_fill(&update("fill", "10")?),
+            Some(Event::Fill {
+                symbol: String::from("ORCL"),
+                side: String::from("sell"),
+                qty: 10.0,
+                price: 101.5,
+            })
+        );
+        //partial fills wait for the end of the order
+        assert_eq!(update_fill(&update("partial_fill", "4")?), None);
+        assert!(matches!(
+            update_fill(&update("canceled", "4")?),
+            Some(Event::Fill { qty, .. }) if qty == 4.0
+        ));
+        assert_eq!(update_fill(&update("canceled", "0")?), None);
+        assert_eq!(update_fill(&update("new", "0")?), None);
+        Ok(())
+    }
+
+    #[test]
+    fn order_request_test() {
+        let exit = Exit {
+            take_profit: Some(110.0),
+            stop_loss: Some(95.0),
+            stop_limit: Some(94.5),
+        };
+        let spec = OrderSpec::new("ORCL", Side::Buy, 10.0)
+            .with_type(OrderType::Limit(100.0))
+            .with_class(OrderClass::Bracket(exit));
+        let request = order_request(&spec);
+        assert_eq!(request.class, Class::Bracket);
+        assert_eq!(request.type_, Type::Limit);
+        assert_eq!(request.limit_price, Some(Num::from(100)));
+        assert_eq!(request.take_profit, Some(TakeProfit::Limit(Num::from(110))));
+        assert_eq!(
+            request.stop_loss,
+            Some(StopLoss::StopLimit(
+                Num::from(95),
+                Num::from_str("94.5").unwrap()
+            ))
+        );
+
+        let trailing = OrderSpec::new("ORCL", Side::Sell, 10.0)
+            .with_type(OrderType::TrailingStop(Trail::Percent(2.0)));
+        let request = order_request(&trailing);
+        assert_eq!(request.type_, Type::TrailingStop);
+        assert_eq!(request.trail_percent, Some(Num::from(2)));
+        assert_eq!(request.class, Class::Simple);
+    }
 }
@@ -1,21 +1,21 @@
 //#[feature(arbitrary_self_types)]
 
 use apca::data::v2::stream::{Bar, Data, Quote, Trade};
-use chrono::{DateTime, Duration, TimeZone, Utc};
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
 use mockall::automock;
 use num_decimal::Num;
 use polars::{
     frame::DataFrame,
     io::SerReader,
     prelude::{
-        col, ChunkedArray, CsvReadOptions, DatetimeType, Float64Type, Int32Type, Int64Type,
-        IntoLazy, Logical, NamedFrom,
+        col, ChunkedArray, CsvReadOptions, DataType, DatetimeType, Float64Type, Int32Type,
+        Int64Type, IntoLazy, Logical, NamedFrom,
     },
     series::Series,
 };
+use serde::Serialize;
 use tokio_util::sync::CancellationToken;
-use tokio_util::task::TaskTracker;
-use tracing::{error, info};
+use tracing::{debug, debug_span, error, info};
 
 use std::{
     collections::{HashMap, VecDeque},
@@ -25,25 +25,43 @@ use std::{
     vec,
 };
 use tokio::{task::JoinHandle, time::sleep};
-use tonic::transport::Channel;
 
 use crate::{
+    asset,
+    backtest::{load_bars, BacktestResult, START_CASH},
+    book::{TopOfBook, MAX_QUOTE_AGE_SECS},
+    calendar::{Session, TradingCalendar},
     config::AppConfig,
     config2::Settings,
-    data::csv_file::data_csv,
     dataframe::data_select_column1,
-    error::CLIError,
-    helper::desision_maker,
-    indicator_decision::action_evaluator,
-    portfolio::types::{Portfolio, TraderConf},
-    proto::{self, indicator_client::IndicatorClient, ListNumbersRequest2},
+    error::{CLIError, DataError, IndicatorError, RiskError},
+    execution::{simulate, ExecConf, ExecReport, Execution},
+    grpc_client::{self, Fallback, IndicatorService},
+    helper::{desision_maker, indicator_signals},
+    indicator_decision::{action_evaluator, combine_signals},
+    order::{BracketConf, OrderSpec, Side, SimBar, SimBook},
+    portfolio::{
+        manager::{strategy_key, AllocConf, PortfolioManager},
+        types::{IndicatorConf, Portfolio, TraderConf},
+    },
+    proto::{self, IndicatorSpec, IndicatorUpdate, ListNumbersRequest2},
+    reload::{merge_conf_map, section_changes, ConfigDiff},
+    scheduler::ScheduleConf,
+    strategy::{
+        buffer::{Buffers, Need},
+        Candles,
+    },
+    telemetry::metrics,
     trade::{self, StockActions},
-    types::{ActionConfig, ActionEval, ActionValidate, ActionValuator, Buffer, Indi, IndiValidate},
+    types::{
+        Action, ActionConfig, ActionEval, ActionValidate, ActionValuator, Buffer, Indi,
+        IndiValidate,
+    },
 };
 
 #[automock]
 trait Calc {
-    async fn grpc(&self, req: ListNumbersRequest2, symbol: String) -> Indi;
+    async fn grpc(&self, req: ListNumbersRequest2, symbol: String) -> Result<Indi, CLIError>;
 }
 
 #[automock]
@@ -69,18 +87,56 @@ pub struct TraderConfigs {
     conf_map: HashMap<String, Vec<TraderConf>>,
     //PORTFOLIO
     portfolio: Option<Portfolio>,
-    //GRPC Client
-    client: Option<IndicatorClient<Channel>>,
+    //GRPC Client, None in backtests
+    client: Option<IndicatorService>,
+    //market hours for live bars
+    calendar: TradingCalendar,
+    //latest quote per symbol
+    books: HashMap<String, TopOfBook>,
+    //latest values from the live indicator stream by the spec they were asked for
+    indis: HashMap<String, Vec<(IndicatorSpec, f64)>>,
+    //latest close per symbol
+    closes: HashMap<String, f64>,
+    //larger orders are worked over time
+    pub execution: ExecConf,
+    //backtest variants share the capital
+    pub allocation: Option<AllocConf>,
+    //jobs around the session, flatten_mins also stops new positions
+    pub schedule: ScheduleConf,
+    //bars of history each symbol was warmed up with
+    seeded: HashMap<String, usize>,
+    //rolling series the indicators of each symbol are computed over
+    buffers: Buffers,
+    //sections of the running config, its Stockconfig is conf_map
+    settings: Settings,
+}
+
+//warm-up of one variant, served by the control API
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct WarmupState {
+    pub symbol: String,
+    pub variant: String,
+    //bars in the buffer and the bars it needs
+    pub bars: usize,
+    pub lookback: usize,
+    //bars of history the symbol was warmed up with, None before a warm-up
+    pub seeded: Option<usize>,
+    //signals are accepted
+    pub ready: bool,
 }
 
+//oldest close against the newest, nothing to compare under two bars
 fn EvaluatorCompair(bar_Buffer: &VecDeque<Bar>) -> f32 {
-    //TODO dynamic buffers
-    let res = if bar_Buffer[0].close_price > bar_Buffer[4].close_price {
-        1.0
-    } else {
-        -1.0
-    };
-    res
+    match (bar_Buffer.front(), bar_Buffer.back()) {
+        (Some(oldest), Some(newest)) if bar_Buffer.len() > 1 => {
+            if oldest.close_price > newest.close_price {
+                1.0
+            } else {
+                -1.0
+            }
+        }
+        _ => 0.0,
+    }
 }
 
 //TODO ADD Portfolio
@@ -99,16 +155,24 @@ fn BufferEvaluate(
     //MATCH to IndicatorType
     //let buffer_capacity = tc.buff.capacity;
 
-    let res = if tc.buff.data.len() == tc.buff.capacity {
+    let res = if !tc.rule_set.is_empty() {
+        //rules see the buffered bars plus the new one
+        let mut candles = Candles::from_bars(&tc.buff.data);
+        candles.push_bar(&bar_new);
+        let res = tc.rule_set.decide(&candles).signal();
+        if tc.buff.data.len() >= tc.buff.capacity {
+            tc.buff.data.pop_front();
+        }
+        res
+    } else if tc.buff.data.len() == tc.buff.capacity {
         let res = EvaluatorCompair(&tc.buff.data);
         let buffer_from_self = &mut tc.buff.data;
-        let poped = buffer_from_self.pop_front();
-        let res = if poped.unwrap().close_price > bar_new.close_price {
-            1.0
-        } else {
-            -1.0
-        };
-        res
+        match buffer_from_self.pop_front() {
+            Some(poped) if poped.close_price > bar_new.close_price => 1.0,
+            Some(_) => -1.0,
+            //capacity 0
+            None => 0.0,
+        }
     } else {
         0.0
     };
@@ -117,39 +181,116 @@ fn BufferEvaluate(
     res
 }
 
+//per indicator votes of the variant merged by its combine mode, None if no indicator
+//has a threshold. A value streamed for exactly the spec of the indicator comes first,
+//the rest is computed with strategy::indicators over the series of the indicator
+fn combined_signal(
+    tc: &TraderConf,
+    symbol: &str,
+    streamed: &[(IndicatorSpec, f64)],
+    series: impl Fn(&IndicatorConf) -> Option<Vec<f64>>,
+) -> Option<ActionValuator> {
+    if !tc.has_signals() {
+        return None;
+    }
+    let mut values = Indi {
+        symbol: symbol.to_string(),
+        indicator: HashMap::new(),
+    };
+    for ind in tc.indicator.iter().filter(|ind| ind.threshold.is_some()) {
+        let Some(kind) = ind.proto_kind() else {
+            continue;
+        };
+        let spec = grpc_client::stream_spec(ind, tc.buff.capacity);
+        if let Some((_, value)) = spec.and_then(|spec| streamed.iter().find(|(s, _)| *s == spec)) {
+            values.indicator.insert(kind, *value);
+            continue;
+        }
+        let Some(list) = series(ind) else {
+            continue;
+        };
+        let req = ListNumbersRequest2 {
+            id: kind.into(),
+            opt: grpc_client::spec(ind).opt,
+            list,
+        };
+        if let Some(value) = grpc_client::local_indicator(&req).and_then(|v| v.last().copied()) {
+            values.indicator.insert(kind, value);
+        }
+    }
+    let signals = indicator_signals(&values, &tc.thresholds());
+    Some(combine_signals(
+        symbol.to_string(),
+        &signals,
+        &tc.weights(),
+        &tc.combine,
+    ))
+}
+
+//the mid replaces the close, high and low still span it
+fn with_mid(bar: &mut Bar, book: &TopOfBook) {
+    let Ok(mid) = Num::from_str(&book.mid().to_string()) else {
+        return;
+    };
+    if mid > bar.high_price {
+        bar.high_price = mid.clone();
+    }
+    if mid < bar.low_price {
+        bar.low_price = mid.clone();
+    }
+    bar.close_price = mid;
+}
+
+fn held(port: &Portfolio, symbol: &str) -> f64 {
+    port.stocks
+        .as_ref()
+        .and_then(|s| s.get(symbol))
+        .copied()
+        .unwrap_or(0.0)
+}
+
+//NaN and infinite prices can't be a Num
+fn price(symbol: &str, what: &str, v: f64) -> Result<Num, CLIError> {
+    Num::from_str(&v.to_string()).map_err(|_| {
+        DataError::Invalid {
+            symbol: symbol.to_string(),
+            what: what.to_string(),
+            value: v.to_string(),
+        }
+        .into()
+    })
+}
+
+//crypto pairs trade around the clock
+fn trades_in(tc: &TraderConf, symbol: &str, session: Session) -> bool {
+    if asset::is_crypto(symbol) {
+        true
+    } else if tc.sessions.is_empty() {
+        session == Session::Regular
+    } else {
+        tc.sessions.contains(&session)
+    }
+}
+
 impl Calc for TraderConfigs {
     async fn grpc(
         &self,
         req: ListNumbersRequest2,
         //ii: IndicatorClient<Channel>,
         symbol: String,
-    ) -> Indi {
-        let tracker = TaskTracker::new();
-        let mut treads: Vec<JoinHandle<()>> = vec![];
-
-        let mut c = self.client.clone().unwrap();
-
-        let handle = tokio::spawn(async move {
-            println!("now running on a worker thread");
-
-            let test = c.gen_liste(req).await.unwrap();
-            //println!("test: {:#?}", test);
-        });
-        treads.push(handle);
-
-        for i in treads {
-            i.await.unwrap();
-        }
-        // Once we spawned everything, we close the tracker.
-        tracker.close();
-
-        // Wait for everything to finish.
-        tracker.wait().await;
+    ) -> Result<Indi, CLIError> {
+        let service = self.client.as_ref().ok_or(IndicatorError::NotConnected)?;
+        let kind = proto::IndicatorType::try_from(req.id).ok();
+        //latest value, nothing for Hold
+        let last = service
+            .values(&symbol, req)
+            .await
+            .and_then(|v| v.last().copied());
 
-        Indi {
+        Ok(Indi {
             symbol, //String::from("ORCL"),
-            indicator: HashMap::new(),
-        }
+            indicator: kind.zip(last).into_iter().collect(),
+        })
     }
     //TODO udjust to new structure
 }
@@ -178,33 +319,43 @@ pub fn actionTest(d: DateTime<Utc>, a: i32, c: f64) -> (f64, f64) {
 
 impl TraderConfigs {
     pub async fn new(
-        settings: Settings,
+        mut settings: Settings,
         path: &str,
-        client: Option<IndicatorClient<Channel>>,
+        client: Option<IndicatorService>,
         sym: &str,
     ) -> Result<Self, CLIError> {
-        let settings = Settings::new().unwrap();
-        let kk = settings.Stockconfig;
+        let calendar = TradingCalendar::from_conf(&settings.calendar)?;
+        let execution = settings.execution.clone();
+        let allocation = settings.allocation.clone();
+        let schedule = settings.schedule.clone();
+        let kk = std::mem::take(&mut settings.Stockconfig);
+        let stocks = kk.keys().map(|s| (s.clone(), 0.0)).collect();
+        let buffers = Buffers::from_confs(&kk);
 
         let _port = settings.grpc.grpcport.clone();
         tracing::info!("Port: {}", _port);
 
-        if let client = client {
-            Ok(TraderConfigs {
-                //Stockconfig: settings.Stockconfig,
-                conf_map: kk,
-                portfolio: Some(Portfolio {
-                    name: String::from("Default Portfolio"),
-                    cash: Some(1000.0),
-                    stocks: Some(HashMap::from([("ORCL".to_string(), 0.0)])),
-                }),
-                client: None,
-                //stock_indicators: Some(ac),
-            })
-            //todo!()
-        } else {
-            Err(CLIError::Converting)
-        }
+        Ok(TraderConfigs {
+            //Stockconfig: settings.Stockconfig,
+            conf_map: kk,
+            portfolio: Some(Portfolio {
+                name: String::from("Default Portfolio"),
+                cash: Some(START_CASH),
+                stocks: Some(stocks),
+            }),
+            client,
+            calendar,
+            books: HashMap::new(),
+            indis: HashMap::new(),
+            closes: HashMap::new(),
+            execution,
+            allocation,
+            schedule,
+            seeded: HashMap::new(),
+            buffers,
+            settings,
+            //stock_indicators: Some(ac),
+        })
     }
 
     //TODO holding shares
@@ -310,7 +461,7 @@ impl TraderConfigs {
         c: f64,
         h: f64,
         l: f64,
-    ) -> (f64, f64) {
+    ) -> Result<(f64, f64), CLIError> {
         /* let data = Data::Bar(Bar {
             symbol: sym.to_string(),
             open_price: Num::from_str(&o.to_string()).unwrap(),
@@ -327,33 +478,367 @@ impl TraderConfigs {
         //let buffer_from_self = &mut self.conf_map.get_mut(sym).unwrap().buff.data;
         let bar_new = Bar {
             symbol: sym.to_string(),
-            open_price: Num::from_str(&o.to_string()).unwrap(),
-            high_price: Num::from_str(&h.to_string()).unwrap(),
-            low_price: Num::from_str(&l.to_string()).unwrap(),
-            close_price: Num::from_str(&c.to_string()).unwrap(),
+            open_price: price(sym, "open", o)?,
+            high_price: price(sym, "high", h)?,
+            low_price: price(sym, "low", l)?,
+            close_price: price(sym, "close", c)?,
             volume: Num::from(100),
             timestamp: d,
         };
-        let port_ref = self.portfolio.as_mut().unwrap();
-        let shares_to_buy = 22.0;
-        let mut cash = port_ref.cash.unwrap();
-        let mut shares_owned = port_ref.stocks.clone().unwrap().get(sym).unwrap().clone();
+        let port_ref = self.portfolio.as_mut().ok_or(RiskError::NoPortfolio)?;
+        let shares_to_buy = tc.shares_to_buy;
+        let cash = port_ref.cash.unwrap_or(0.0);
+        let shares_owned = held(port_ref, sym);
 
         /* let oo = self.conf_map.get_mut(sym).unwrap();
         let oo = oo.first_mut().unwrap(); */
 
         //TODO
         let action = BufferEvaluate(tc, port_ref, shares_owned, shares_to_buy, cash, bar_new);
-        port_ref
+        Ok(port_ref
             .clone()
-            .evaluator(action, port_ref, shares_owned, shares_to_buy, cash, c)
+            .evaluator(sym, action, port_ref, shares_owned, shares_to_buy, cash, c))
     }
 
-    #[allow(dead_code)]
-    async fn reconnect_client(mut self, port: &str) {
-        let mut addr = String::from("http://[::1]:");
-        addr.push_str(port);
-        self.client = Some(IndicatorClient::connect(addr).await.unwrap());
+    fn held(&self, symbol: &str) -> Result<f64, CLIError> {
+        let port = self.portfolio.as_ref().ok_or(RiskError::NoPortfolio)?;
+        Ok(held(port, symbol))
+    }
+
+    //backtest exits resting from earlier bars fill before the variant decides
+    fn sim_exits(
+        &mut self,
+        book: &mut SimBook,
+        symbol: &str,
+        bar: &SimBar,
+    ) -> Result<(), CLIError> {
+        let port = self.portfolio.as_mut().ok_or(RiskError::NoPortfolio)?;
+        let fills = book.on_bar(symbol, bar, |fill| {
+            port.fill(fill).is_ok_and(|qty| qty > 0.0)
+        });
+        for fill in fills {
+            debug!(
+                "{symbol}: exit {:?} {} @ {}",
+                fill.side, fill.qty, fill.price
+            );
+        }
+        Ok(())
+    }
+
+    //a bought position gets the exits of the bracket at the close, a sale cancels them
+    fn sim_bracket(
+        &mut self,
+        book: &mut SimBook,
+        symbol: &str,
+        bracket: Option<BracketConf>,
+        before: f64,
+        bar: &SimBar,
+    ) -> Result<(), CLIError> {
+        let Some(bracket) = bracket else {
+            return Ok(());
+        };
+        let after = self.held(symbol)?;
+        if after == before {
+            return Ok(());
+        }
+        book.cancel(symbol);
+        if let (true, Some(exit)) = (after > before, bracket.exit(bar.close)) {
+            book.submit(&OrderSpec::protect(symbol, after, exit), bar.time)?;
+        }
+        Ok(())
+    }
+
+    //a trade of the bar big enough to be worked starts an execution at the close
+    fn sim_order(
+        &self,
+        symbol: &str,
+        before: f64,
+        bar: &SimBar,
+    ) -> Result<Option<Execution>, CLIError> {
+        let after = self.held(symbol)?;
+        let side = if after > before {
+            Side::Buy
+        } else {
+            Side::Sell
+        };
+        let qty = (after - before).abs();
+        Ok(self.execution.works(qty).then(|| {
+            Execution::new(
+                symbol,
+                side,
+                qty,
+                bar.close,
+                bar.time,
+                &self.execution,
+                None,
+            )
+        }))
+    }
+
+    //the portfolio booked the trade at the arrival price, the execution's cost is added
+    fn sim_settle(&mut self, exec: &Execution, last: f64) -> Result<ExecReport, CLIError> {
+        let port = self.portfolio.as_mut().ok_or(RiskError::NoPortfolio)?;
+        port.cash = Some(port.cash.unwrap_or(0.0) - exec.cost(last));
+        Ok(exec.report())
+    }
+
+    //action already decided by the rules of the TraderConf
+    pub fn traders_ruled(
+        &mut self,
+        sym: &str,
+        shares_to_buy: f64,
+        action: &Action,
+        c: f64,
+    ) -> Result<(f64, f64), CLIError> {
+        let port_ref = self.portfolio.as_mut().ok_or(RiskError::NoPortfolio)?;
+        let cash = port_ref.cash.unwrap_or(0.0);
+        let shares_owned = held(port_ref, sym);
+        Ok(port_ref.clone().evaluator(
+            sym,
+            action.signal(),
+            port_ref,
+            shares_owned,
+            shares_to_buy,
+            cash,
+            c,
+        ))
+    }
+
+    //live bar from the stream, one ActionValuator per variant that wants to trade
+    pub fn on_bar(&mut self, bar: Bar) -> Vec<ActionValuator> {
+        if let Some(close) = bar.close_price.to_f64() {
+            self.closes.insert(bar.symbol.clone(), close);
+        }
+        self.buffers.push(&bar);
+        let buffers = &self.buffers;
+        //streamed values are stale while the indicator service is down, the
+        //fallback of the client decides between local values and Hold
+        let down = self.client.as_ref().filter(|c| c.is_open());
+        let hold = down.is_some_and(|c| c.conf().fallback == Fallback::Hold);
+        let streamed = match down {
+            Some(_) => &[][..],
+            None => self.indis.get(&bar.symbol).map_or(&[][..], Vec::as_slice),
+        };
+        let Some(confs) = self.conf_map.get_mut(&bar.symbol) else {
+            return vec![];
+        };
+        let Some(port_ref) = self.portfolio.as_mut() else {
+            return vec![];
+        };
+        let session = self.calendar.session_at(bar.timestamp);
+        let book = self.books.get(&bar.symbol).copied();
+        let cash = port_ref.cash.unwrap_or(0.0);
+        let shares_owned = held(port_ref, &bar.symbol);
+        confs
+            .iter_mut()
+            .filter_map(|tc| {
+                let shares_to_buy = tc.shares_to_buy;
+                let mut bar = bar.clone();
+                let book = tc.quote.fresh(book, bar.timestamp);
+                if let (true, Some(book)) = (tc.quote.use_mid, book) {
+                    with_mid(&mut bar, &book);
+                }
+                let _decision = debug_span!("decision", variant = %tc.variant).entered();
+                //a buffer short of its lookback only collects bars
+                let warm = tc.buff.data.len() >= tc.lookback()
+                    && buffers.ready(&bar.symbol, &tc.indicator_needs());
+                let signal =
+                    BufferEvaluate(tc, port_ref, shares_owned, shares_to_buy, cash, bar.clone());
+                let capacity = tc.buff.capacity;
+                let combined = if hold {
+                    tc.has_signals().then_some((Action::Hold, 0.0))
+                } else {
+                    combined_signal(tc, &bar.symbol, streamed, |ind| {
+                        buffers.values(&bar.symbol, &ind.need(capacity))
+                    })
+                    .map(|av| (av.action, av.strength))
+                };
+                let (action, strength) = match combined {
+                    Some(combined) => combined,
+                    None if signal >= 1.0 => (Action::Buy, 1.0),
+                    None if signal <= -1.0 => (Action::Sell, 1.0),
+                    None => (Action::Hold, 0.0),
+                };
+                let action = if warm { action } else { Action::Hold };
+                debug!(signal, strength, ?action);
+                metrics()
+                    .decisions
+                    .with_label_values(&[&bar.symbol, &format!("{action:?}")])
+                    .inc();
+                //the buffer sees every bar, orders only go out in the chosen sessions
+                if action == Action::Hold
+                    || !trades_in(tc, &bar.symbol, session)
+                    || !tc.quote.spread_ok(book.as_ref())
+                {
+                    return None;
+                }
+                let limit_price =
+                    book.and_then(|b| b.marketable_limit(&action, tc.quote.limit_offset_bps));
+                //exits priced off the limit, or the close for a market order
+                let entry = limit_price.or_else(|| bar.close_price.to_f64());
+                let exit = match (&action, tc.bracket, entry) {
+                    (Action::Buy, Some(bracket), Some(entry)) => bracket.exit(entry),
+                    _ => None,
+                };
+                Some(ActionValuator {
+                    symbol: bar.symbol.clone(),
+                    strength,
+                    action,
+                    limit_price,
+                    exit,
+                })
+            })
+            .collect()
+    }
+
+    //keeps the top of book, one-sided quotes are ignored
+    pub fn on_quote(&mut self, quote: &Quote) {
+        if let Some(book) = TopOfBook::from_quote(quote) {
+            self.books.insert(quote.symbol.clone(), book);
+        }
+    }
+
+    //the book unless it is older than MAX_QUOTE_AGE_SECS at now
+    pub fn book(&self, symbol: &str, now: DateTime<Utc>) -> Option<&TopOfBook> {
+        self.books
+            .get(symbol)
+            .filter(|b| b.is_fresh(now, MAX_QUOTE_AGE_SECS))
+    }
+
+    pub fn closes(&self) -> &HashMap<String, f64> {
+        &self.closes
+    }
+
+    //latest streamed values, on_bar prefers them over the local ones. Values
+    //without their spec can't be told apart and are dropped
+    pub fn on_indicators(&mut self, update: &IndicatorUpdate) {
+        let values = self.indis.entry(update.symbol.clone()).or_default();
+        for v in &update.values {
+            let Some(spec) = v.spec.clone() else {
+                continue;
+            };
+            match values.iter_mut().find(|(s, _)| *s == spec) {
+                Some((_, value)) => *value = v.value,
+                None => values.push((spec, v.value)),
+            }
+        }
+    }
+
+    pub fn indicators(&self, symbol: &str) -> Option<&[(IndicatorSpec, f64)]> {
+        self.indis.get(symbol).map(Vec::as_slice)
+    }
+
+    //the stream is gone, its last values would vote forever
+    pub fn clear_indicators(&mut self) {
+        self.indis.clear();
+    }
+
+    //indicators of every variant of the symbol the live stream can compute
+    pub fn indicator_specs(&self, symbol: &str) -> Vec<IndicatorSpec> {
+        let mut specs: Vec<IndicatorSpec> = vec![];
+        for tc in self.conf_map.get(symbol).into_iter().flatten() {
+            for spec in grpc_client::stream_specs(tc) {
+                if !specs.contains(&spec) {
+                    specs.push(spec);
+                }
+            }
+        }
+        specs
+    }
+
+    pub fn set_calendar(&mut self, calendar: TradingCalendar) {
+        self.calendar = calendar;
+    }
+
+    pub fn calendar(&self) -> &TradingCalendar {
+        &self.calendar
+    }
+
+    //true once a variant of the symbol wants to be flat for the close
+    pub fn flatten_due(&self, symbol: &str, now: DateTime<Utc>) -> bool {
+        //no close to flatten before
+        if asset::is_crypto(symbol) {
+            return false;
+        }
+        let Some(left) = self.calendar.minutes_to_close(now) else {
+            return false;
+        };
+        let scheduled =
+            self.schedule.flatten_mins > 0 && left <= i64::from(self.schedule.flatten_mins);
+        self.conf_map.get(symbol).is_some_and(|confs| {
+            scheduled
+                || confs
+                    .iter()
+                    .filter_map(|tc| tc.flatten_before_close)
+                    .any(|minutes| left <= minutes as i64)
+        })
+    }
+
+    //every symbol of the Stockconfig, stocks and crypto pairs
+    pub fn symbols(&self) -> Vec<String> {
+        self.conf_map.keys().cloned().collect()
+    }
+
+    //stock symbols of the Stockconfig, the ones the flatten job closes
+    pub fn stock_symbols(&self) -> Vec<String> {
+        self.conf_map
+            .keys()
+            .filter(|s| !asset::is_crypto(s))
+            .cloned()
+            .collect()
+    }
+
+    //every variant, by symbol and variant
+    pub fn warmup_state(&self) -> Vec<WarmupState> {
+        let mut states: Vec<WarmupState> = self
+            .conf_map
+            .iter()
+            .flat_map(|(symbol, confs)| {
+                confs.iter().map(|tc| WarmupState {
+                    symbol: symbol.clone(),
+                    variant: tc.variant.clone(),
+                    bars: tc.buff.data.len(),
+                    lookback: tc.lookback(),
+                    seeded: self.seeded.get(symbol).copied(),
+                    ready: tc.buff.data.len() >= tc.lookback()
+                        && self.buffers.ready(symbol, &tc.indicator_needs()),
+                })
+            })
+            .collect();
+        states.sort_by(|a, b| (&a.symbol, &a.variant).cmp(&(&b.symbol, &b.variant)));
+        states
+    }
+
+    //history in front of the buffers of the symbol, bars that arrived after it stay.
+    //Every buffer keeps its capacity, the most bars seeded into one of them
+    pub fn warm_up(&mut self, symbol: &str, history: &[Bar]) -> usize {
+        let Some(confs) = self.conf_map.get_mut(symbol) else {
+            return 0;
+        };
+        let Some(last) = history.last() else {
+            return 0;
+        };
+        self.seeded.insert(symbol.to_string(), history.len());
+        self.buffers.seed(symbol, history);
+        if let Some(close) = last.close_price.to_f64() {
+            self.closes.entry(symbol.to_string()).or_insert(close);
+        }
+        let mut seeded = 0;
+        for tc in confs.iter_mut() {
+            let newer = tc
+                .buff
+                .data
+                .iter()
+                .filter(|b| b.timestamp > last.timestamp)
+                .cloned();
+            let mut data: VecDeque<Bar> = history.iter().cloned().chain(newer).collect();
+            while data.len() > tc.buff.capacity {
+                data.pop_front();
+            }
+            seeded = seeded.max(data.len());
+            tc.buff.data = data;
+        }
+        seeded
     }
 
     #[allow(dead_code)]
@@ -387,14 +872,20 @@ impl TraderConfigs {
         let conf = Arc::new(self);
         let mut threads: Vec<JoinHandle<()>> = vec![];
 
-        let conf_map = {
-            let self_lock = trader_conf.lock().unwrap();
-            self_lock.conf_map.clone()
+        let conf_map = match trader_conf.lock() {
+            Ok(self_lock) => self_lock.conf_map.clone(),
+            Err(_) => {
+                error!("{}", CLIError::Lock("trader"));
+                return threads;
+            }
         };
 
         //spawn trader for every symbol
-        for (_symbol, trader_conf) in conf_map {
-            let trader_conf = trader_conf.first().unwrap().clone();
+        for (symbol, trader_conf) in conf_map {
+            let Some(trader_conf) = trader_conf.first().cloned() else {
+                error!("{symbol}: no TraderConf");
+                continue;
+            };
             // Token for shutdown cloned
             let cloned_shutdown_token = shutdown_token.clone();
             let self_clone = Arc::clone(&conf);
@@ -404,7 +895,9 @@ impl TraderConfigs {
                     // Step 3: Using cloned token to listen to cancellation requests
                     _ = cloned_shutdown_token.cancelled() => {
                         // The token was cancelled, task can shut down
-                        let _res = self_clone.trader(&trader_conf, "Close").await;
+                        if let Err(e) = self_clone.trader(&trader_conf, "Close").await {
+                            error!("{}: {e}", trader_conf.variant);
+                        }
                     }
                 }
             });
@@ -415,95 +908,421 @@ impl TraderConfigs {
         threads
     }
 
-    /* async fn reload_conf(
+    //swaps in the Stockconfig, execution and allocation of new settings, positions
+    //stay in the portfolio. Other changed sections are reported in restart
+    pub fn reload_conf(&mut self, mut settings: Settings) -> ConfigDiff {
+        let stockconfig = std::mem::take(&mut settings.Stockconfig);
+        let (conf_map, mut diff) = merge_conf_map(&self.conf_map, stockconfig);
+        (diff.reloaded, diff.restart) = section_changes(&self.settings, &settings);
+        //sections read at startup keep their running values, a later reload
+        //reports them again
+        self.execution = settings.execution.clone();
+        self.allocation = settings.allocation.clone();
+        self.settings.execution = settings.execution;
+        self.settings.allocation = settings.allocation;
+        if let Some(stocks) = self.portfolio.as_mut().and_then(|p| p.stocks.as_mut()) {
+            for symbol in &diff.added {
+                stocks.entry(symbol.clone()).or_insert(0.0);
+            }
+        }
+        self.conf_map = conf_map;
+        self.buffers.reconfigure(&self.conf_map);
+        diff
+    }
+
+    async fn data_indicator_get(
         self: Arc<Self>,
-        data: DataFrame,
-        av: (String, Vec<f64>),
+        symbol: &str,
+        req: proto::ListNumbersRequest2,
     ) -> Result<Vec<f64>, CLIError> {
-        todo!()
-    } */
+        let service = self.client.as_ref().ok_or(IndicatorError::NotConnected)?;
+        service.gen_liste(symbol, req).await
+    }
+
+    //indicators of the TraderConf keyed by IndicatorType id, one request per buffered
+    //series. Indicators whose buffer is short of the lookback are left out
+    pub async fn data_indicators_get(
+        &self,
+        tc: &TraderConf,
+    ) -> Result<HashMap<i32, Vec<f64>>, CLIError> {
+        let service = self.client.as_ref().ok_or(IndicatorError::NotConnected)?;
+        let mut series: Vec<(Need, Vec<IndicatorSpec>)> = vec![];
+        for ind in &tc.indicator {
+            let need = ind.need(tc.buff.capacity);
+            match series.iter_mut().find(|(n, _)| *n == need) {
+                Some((_, specs)) => specs.push(grpc_client::spec(ind)),
+                None => series.push((need, vec![grpc_client::spec(ind)])),
+            }
+        }
+        let mut values = HashMap::new();
+        for (need, specs) in series {
+            let Some(list) = self.buffers.values(&tc.symbol, &need) else {
+                continue;
+            };
+            values.extend(service.values_batch(&tc.symbol, &specs, list).await);
+        }
+        Ok(values)
+    }
 
-    async fn data_indicator_get(self: Arc<Self>, req: proto::ListNumbersRequest2) -> Vec<f64> {
-        let mut c = self.client.clone().unwrap();
-        let request = tonic::Request::new(req);
-        c.gen_liste(request).await.unwrap().into_inner().result
+    pub fn buffers(&self) -> &Buffers {
+        &self.buffers
     }
 
     //DATA FAKE
-    async fn data_from_csv(&mut self) -> DataFrame {
+    //runs every variant over {data_dir}/{symbol}.csv, each variant starts with a fresh portfolio
+    pub async fn data_from_csv(
+        &mut self,
+        data_dir: &str,
+        start: Option<NaiveDate>,
+        end: Option<NaiveDate>,
+    ) -> Result<Vec<BacktestResult>, CLIError> {
+        if let Some(conf) = self.allocation.clone() {
+            return self.data_from_csv_allocated(&conf, data_dir, start, end);
+        }
+        let mut results = vec![];
         let trader_conf = &self.conf_map.clone();
         for (symbol, trader_conf) in trader_conf {
-            let mut df = data_csv(String::from("files/orcl.csv")).unwrap();
+            let df = load_bars(data_dir, symbol, start, end)?;
 
             //&TraderConf
             for i in trader_conf.clone().iter_mut() {
                 //RESET Portfolio
                 self.portfolio = Some(Portfolio {
                     name: String::from("Default Portfolio"),
-                    cash: Some(1000.0),
-                    stocks: Some(HashMap::from([("ORCL".to_string(), 0.0)])),
+                    cash: Some(START_CASH),
+                    stocks: Some(HashMap::from([(symbol.clone(), 0.0)])),
                 });
-                println!("{:?}", i);
-                //COPY for loop
-                let mut df = df.clone();
-                //timestamp: DateTime<Utc>
-                // Parse the "date" column as Utf8 (string), then convert to DateTime<Utc>
-                let sym = i.symbol.clone();
-                let co = self.conf_map.get(&sym).unwrap().clone();
-
-                let date = df.column("Date").unwrap().datetime().unwrap();
-                let open = df.column("Open").unwrap().f64().unwrap();
-                let close = df.column("Close").unwrap().f64().unwrap();
-                let high = df.column("High").unwrap().f64().unwrap();
-                let low = df.column("Low").unwrap().f64().unwrap();
-                //let Volume = df.column("Volume").unwrap().f64().unwrap();
-
-                //TODO Each SYML gets a Config with inducators
-                //TODO data from csv in config or stream
-                // Perform the operation
-
-                //TODO LOOP with ZIP data
+
+                let date = df.column("Date")?.datetime()?;
+                let open = df.column("Open")?.f64()?;
+                let close = df.column("Close")?.f64()?;
+                let high = df.column("High")?.f64()?;
+                let low = df.column("Low")?.f64()?;
+                let volume = match df.column("Volume") {
+                    Ok(v) => Some(v.cast(&DataType::Float64)?),
+                    Err(_) => None,
+                };
+                let volume = volume.as_ref().map(|v| v.f64()).transpose()?;
+
+                //indicator votes and rules are evaluated on the whole series up front
+                let rule_actions = if i.has_signals() {
+                    Some(Self::signal_actions(symbol, i, &df)?)
+                } else if i.rule_set.is_empty() {
+                    None
+                } else {
+                    Some(i.rule_set.actions(&Candles::from_df(&df)?))
+                };
+
+                let shares_to_buy = i.shares_to_buy;
+                let bracket = i.bracket;
+                //resting exits of the variant
+                let mut exits = SimBook::default();
+                //order being worked and the finished ones
+                let mut working: Option<Execution> = None;
+                let mut worked: Vec<ExecReport> = vec![];
                 let values: Vec<(f64, f64)> = close
                     .into_iter()
                     .zip(open.into_iter())
                     .zip(high.into_iter())
                     .zip(low.into_iter())
                     .zip(date.into_iter())
-                    .map(|((((opt_c, opt_l), opt_h), opt_o), opt_d)| {
-                        match (opt_d, opt_l, opt_h, opt_o, opt_c) {
-                            (Some(d), Some(o), Some(h), Some(l), Some(c)) => self.traders(
-                                symbol,
-                                i,
-                                Utc.timestamp_opt(d, 0).unwrap(),
-                                o,
-                                c,
-                                h,
-                                l,
-                            ),
-                            _ => (0.0, 0.0),
+                    .enumerate()
+                    .map(
+                        |(idx, ((((opt_c, opt_l), opt_h), opt_o), opt_d))| -> Result<_, CLIError> {
+                            match (opt_d, opt_l, opt_h, opt_o, opt_c) {
+                                (Some(d), Some(o), Some(h), Some(l), Some(c)) => {
+                                    let d =
+                                        Utc.timestamp_millis_opt(d).single().ok_or_else(|| {
+                                            DataError::Invalid {
+                                                symbol: symbol.clone(),
+                                                what: String::from("Date"),
+                                                value: d.to_string(),
+                                            }
+                                        })?;
+                                    let bar = SimBar {
+                                        time: d,
+                                        open: o,
+                                        high: h,
+                                        low: l,
+                                        close: c,
+                                    };
+                                    self.sim_exits(&mut exits, symbol, &bar)?;
+                                    if let Some(exec) = working.as_mut() {
+                                        let v = volume.and_then(|v| v.get(idx)).unwrap_or(0.0);
+                                        simulate(exec, &[(bar, v)]);
+                                    }
+                                    let before = self.held(symbol)?;
+                                    let res = match &rule_actions {
+                                        Some(actions) => self.traders_ruled(
+                                            symbol,
+                                            shares_to_buy,
+                                            &actions[idx],
+                                            c,
+                                        ),
+                                        None => self.traders(symbol, i, d, o, c, h, l),
+                                    };
+                                    self.sim_bracket(&mut exits, symbol, bracket, before, &bar)?;
+                                    //a new trade ends the one being worked
+                                    let next = self.sim_order(symbol, before, &bar)?;
+                                    if let Some(exec) = working.take() {
+                                        if next.is_some() || exec.is_done(d) {
+                                            worked.push(self.sim_settle(&exec, c)?);
+                                        } else {
+                                            working = Some(exec);
+                                        }
+                                    }
+                                    working = next.or(working);
+                                    res
+                                }
+                                _ => Ok((0.0, 0.0)),
+                            }
+                        },
+                    )
+                    .collect::<Result<_, _>>()?;
+
+                if let Some(exec) = working {
+                    let last = close.into_iter().flatten().last().unwrap_or(0.0);
+                    worked.push(self.sim_settle(&exec, last)?);
+                }
+                if !worked.is_empty() {
+                    let slippage = worked.iter().map(|r| r.slippage_bps).sum::<f64>();
+                    info!(
+                        "{} {}: {} worked orders, {:.1} bps mean slippage",
+                        symbol,
+                        i.variant,
+                        worked.len(),
+                        slippage / worked.len() as f64
+                    );
+                    worked.iter().for_each(|r| debug!("{r}"));
+                }
+
+                //evaluator reports the shares before the bar, a change means a trade
+                let trades = values.windows(2).filter(|w| w[0].1 != w[1].1).count();
+                let last_close = close.into_iter().flatten().last().unwrap_or(0.0);
+                let port = self.portfolio.as_ref().ok_or(RiskError::NoPortfolio)?;
+                let result = BacktestResult {
+                    symbol: symbol.clone(),
+                    variant: i.variant.clone(),
+                    bars: df.height(),
+                    trades,
+                    start_cash: START_CASH,
+                    end_cash: port.cash.unwrap_or(0.0),
+                    end_shares: port
+                        .stocks
+                        .as_ref()
+                        .and_then(|s| s.get(symbol))
+                        .copied()
+                        .unwrap_or(0.0),
+                    last_close,
+                };
+                info!(
+                    "{} {}: equity {:.2} ({:.2}%)",
+                    result.symbol,
+                    result.variant,
+                    result.equity(),
+                    result.return_pct()
+                );
+                results.push(result);
+            }
+        }
+        Ok(results)
+    }
+
+    //one action per row of df from the combined indicator votes of tc. The rows go
+    //through a buffer like live bars, so the indicators see the same series
+    fn signal_actions(
+        symbol: &str,
+        tc: &TraderConf,
+        df: &DataFrame,
+    ) -> Result<Vec<Action>, CLIError> {
+        let candles = Candles::from_df(df)?;
+        let date = df.column("Date")?.datetime()?;
+        let mut buffers =
+            Buffers::from_confs(&HashMap::from([(symbol.to_string(), vec![tc.clone()])]));
+        let needs = tc.indicator_needs();
+        let mut actions = vec![Action::Hold; df.height()];
+        let mut pushed = 0;
+        for (idx, action) in actions.iter_mut().enumerate() {
+            let Some(time) = date.get(idx).and_then(DateTime::from_timestamp_millis) else {
+                continue;
+            };
+            buffers.push(&Bar {
+                symbol: symbol.to_string(),
+                open_price: price(symbol, "open", candles.open[idx])?,
+                high_price: price(symbol, "high", candles.high[idx])?,
+                low_price: price(symbol, "low", candles.low[idx])?,
+                close_price: price(symbol, "close", candles.close[idx])?,
+                volume: price(symbol, "volume", candles.volume[idx])?,
+                timestamp: time,
+            });
+            pushed += 1;
+            if pushed < tc.lookback() || !buffers.ready(symbol, &needs) {
+                continue;
+            }
+            let combined = combined_signal(tc, symbol, &[], |ind| {
+                buffers.values(symbol, &ind.need(tc.buff.capacity))
+            });
+            if let Some(av) = combined {
+                *action = av.action;
+            }
+        }
+        Ok(actions)
+    }
+
+    //rows of the csv without gaps, with their index in the frame
+    fn sim_rows(symbol: &str, df: &DataFrame) -> Result<Vec<(usize, SimBar)>, CLIError> {
+        let date = df.column("Date")?.datetime()?;
+        let open = df.column("Open")?.f64()?;
+        let high = df.column("High")?.f64()?;
+        let low = df.column("Low")?.f64()?;
+        let close = df.column("Close")?.f64()?;
+        let mut rows = vec![];
+        for idx in 0..df.height() {
+            let (Some(d), Some(o), Some(h), Some(l), Some(c)) = (
+                date.get(idx),
+                open.get(idx),
+                high.get(idx),
+                low.get(idx),
+                close.get(idx),
+            ) else {
+                continue;
+            };
+            let time = Utc
+                .timestamp_millis_opt(d)
+                .single()
+                .ok_or_else(|| DataError::Invalid {
+                    symbol: symbol.to_string(),
+                    what: String::from("Date"),
+                    value: d.to_string(),
+                })?;
+            let bar = SimBar {
+                time,
+                open: o,
+                high: h,
+                low: l,
+                close: c,
+            };
+            rows.push((idx, bar));
+        }
+        Ok(rows)
+    }
+
+    //every variant of every symbol on one timeline, each trading out of its sleeve
+    //of the shared capital. The result of a variant is its sleeve, start_cash is
+    //what it was funded with.
+    fn data_from_csv_allocated(
+        &mut self,
+        conf: &AllocConf,
+        data_dir: &str,
+        start: Option<NaiveDate>,
+        end: Option<NaiveDate>,
+    ) -> Result<Vec<BacktestResult>, CLIError> {
+        let mut confs: Vec<(String, Vec<TraderConf>)> = self.conf_map.clone().into_iter().collect();
+        confs.sort_by(|a, b| a.0.cmp(&b.0));
+        let strategies: Vec<(String, String)> = confs
+            .iter()
+            .flat_map(|(symbol, tcs)| tcs.iter().map(|tc| (symbol.clone(), tc.variant.clone())))
+            .collect();
+        let mut manager = PortfolioManager::new(conf, &strategies);
+
+        //bars by time and the rule actions of every variant per symbol
+        let mut series = HashMap::new();
+        let mut timeline = std::collections::BTreeSet::new();
+        for (symbol, tcs) in &confs {
+            let df = load_bars(data_dir, symbol, start, end)?;
+            let rows = Self::sim_rows(symbol, &df)?;
+            let candles = Candles::from_df(&df)?;
+            let actions = tcs
+                .iter()
+                .map(|tc| -> Result<_, CLIError> {
+                    if tc.has_signals() {
+                        return Ok(Some(Self::signal_actions(symbol, tc, &df)?));
+                    }
+                    Ok((!tc.rule_set.is_empty()).then(|| tc.rule_set.actions(&candles)))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            timeline.extend(rows.iter().map(|(_, bar)| bar.time));
+            let rows: HashMap<DateTime<Utc>, (usize, SimBar)> = rows
+                .into_iter()
+                .map(|(idx, bar)| (bar.time, (idx, bar)))
+                .collect();
+            series.insert(symbol.clone(), (rows, actions));
+        }
+
+        let saved = self.portfolio.take();
+        let mut exits: HashMap<String, SimBook> = HashMap::new();
+        let mut prices: HashMap<String, f64> = HashMap::new();
+        for (step, time) in timeline.iter().enumerate() {
+            for (symbol, tcs) in confs.iter_mut() {
+                let (rows, actions) = &series[symbol.as_str()];
+                let Some((idx, bar)) = rows.get(time).copied() else {
+                    continue;
+                };
+                prices.insert(symbol.clone(), bar.close);
+                let mut changes = vec![];
+                for (tc, actions) in tcs.iter_mut().zip(actions) {
+                    let key = strategy_key(symbol, &tc.variant);
+                    let Some(sleeve) = manager.sleeve_mut(&key) else {
+                        continue;
+                    };
+                    self.portfolio = Some(sleeve.portfolio.clone());
+                    let book = exits.entry(key).or_default();
+                    self.sim_exits(book, symbol, &bar)?;
+                    let before = self.held(symbol)?;
+                    let res = match actions {
+                        Some(actions) => {
+                            self.traders_ruled(symbol, tc.shares_to_buy, &actions[idx], bar.close)
                         }
-                    })
-                    .collect();
-
-                let cash = format!("{} Cash", i.variant);
-                let shares = format!("{} Shares", i.variant);
-                let (vec1, vec2): (Vec<f64>, Vec<f64>) = values.into_iter().unzip();
-                let new_series1 = Series::new(cash.into(), vec1);
-                let new_series2 = Series::new(shares.into(), vec2);
-                df.with_column(new_series1).unwrap();
-                df.with_column(new_series2).unwrap();
-
-                df = df.drop("Open").unwrap();
-                df = df.drop("High").unwrap();
-                df = df.drop("Low").unwrap();
-                df = df.drop("Adj Close").unwrap();
-                df = df.drop("Volume").unwrap();
-
-                println!("{:?}", df);
-                df;
+                        None => self
+                            .traders(symbol, tc, bar.time, bar.open, bar.close, bar.high, bar.low),
+                    };
+                    res?;
+                    self.sim_bracket(book, symbol, tc.bracket, before, &bar)?;
+                    let after = self.held(symbol)?;
+                    if after != before {
+                        sleeve.trades += 1;
+                        changes.push(after - before);
+                    }
+                    sleeve.portfolio = self.portfolio.take().ok_or(RiskError::NoPortfolio)?;
+                }
+                manager.net(&changes);
+            }
+            manager.mark(&prices);
+            if manager.rebalance_due(step) {
+                manager.rebalance(&prices);
             }
         }
-        todo!();
+        self.portfolio = saved;
+
+        info!(
+            "{:?} allocation: {:.2} shares netted between variants",
+            conf.mode,
+            manager.netted()
+        );
+        let results = manager
+            .sleeves()
+            .map(|sleeve| {
+                let last_close = prices.get(&sleeve.symbol).copied().unwrap_or(0.0);
+                info!(
+                    "{}:{}: P&L {:.2} on {:.2}",
+                    sleeve.symbol,
+                    sleeve.variant,
+                    sleeve.pnl(last_close),
+                    sleeve.funded
+                );
+                BacktestResult {
+                    symbol: sleeve.symbol.clone(),
+                    variant: sleeve.variant.clone(),
+                    bars: series[sleeve.symbol.as_str()].0.len(),
+                    trades: sleeve.trades,
+                    start_cash: sleeve.funded,
+                    end_cash: sleeve.cash(),
+                    end_shares: sleeve.shares(),
+                    last_close,
+                }
+            })
+            .collect();
+        Ok(results)
     }
 
     //trader for every symbol
@@ -511,8 +1330,7 @@ impl TraderConfigs {
         let self_clone = Arc::clone(&self);
         //get data from csv or grpc
         let df = CsvReadOptions::default()
-            .try_into_reader_with_file_path(Some("files/orcl.csv".into()))
-            .unwrap()
+            .try_into_reader_with_file_path(Some("files/orcl.csv".into()))?
             .finish()?;
 
         let close = data_select_column1(df.clone(), col)?;
@@ -560,6 +1378,19 @@ mod tests {
     use proto::{indicator_client::IndicatorClient, ListNumbersRequest2, ListNumbersResponse};
     use tonic::transport::Channel;
 
+    //ORCL minute bar of 2024-03-04, UTC
+    fn bar(hour: u32, minute: u32, close: i32) -> Bar {
+        Bar {
+            symbol: String::from("ORCL"),
+            open_price: Num::from(close),
+            high_price: Num::from(close),
+            low_price: Num::from(close),
+            close_price: Num::from(close),
+            volume: Num::from(100),
+            timestamp: Utc.with_ymd_and_hms(2024, 3, 4, hour, minute, 0).unwrap(),
+        }
+    }
+
     #[tokio::test]
     async fn new_test() -> Result<(), Box<dyn std::error::Error>> {
         Ok(())
@@ -576,26 +1407,341 @@ mod tests {
             .await
             .unwrap();
 
-        let action_vec = tr.data_from_csv().await;
+        let action_vec = tr.data_from_csv("files", None, None).await;
         //tr.actionEval(action_vec);
 
         panic!("Test not implemented yet");
     }
 
+    #[tokio::test]
+    async fn on_indicators_test() -> Result<(), Box<dyn std::error::Error>> {
+        let settings = Settings::new()?;
+        let mut tr = TraderConfigs::new(settings, "", None, "").await?;
+        let sma = |period| IndicatorSpec {
+            id: IndicatorType::SimpleMovingAverage.into(),
+            opt: Some(proto::Opt {
+                multiplier: 2.0,
+                period,
+            }),
+            lookback: 10,
+        };
+        let value = |spec: Option<IndicatorSpec>, value| IndicatorValue {
+            id: IndicatorType::SimpleMovingAverage.into(),
+            value,
+            spec,
+        };
+        let update = |v| IndicatorUpdate {
+            symbol: String::from("ORCL"),
+            timestamp: 0,
+            values: vec![
+                value(Some(sma(5)), v),
+                //another period is another value
+                value(Some(sma(10)), v + 1.0),
+                //no spec, no telling which indicator it is
+                value(None, v),
+            ],
+        };
+        tr.on_indicators(&update(1.5));
+        tr.on_indicators(&update(2.5));
+        assert_eq!(
+            tr.indicators("ORCL").unwrap(),
+            &[(sma(5), 2.5), (sma(10), 3.5)][..]
+        );
+        assert!(tr.indicators("MSFT").is_none());
+        tr.clear_indicators();
+        assert!(tr.indicators("ORCL").is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn allocated_backtest_test() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::portfolio::manager::AllocMode;
+        let mut tr = TraderConfigs::new(Settings::new()?, "", None, "").await?;
+        tr.allocation = Some(AllocConf {
+            mode: AllocMode::RiskParity,
+            rebalance_bars: 5,
+            ..Default::default()
+        });
+        let start = NaiveDate::from_ymd_opt(1995, 1, 1);
+        let end = NaiveDate::from_ymd_opt(1995, 6, 30);
+        let results = tr.data_from_csv("files", start, end).await?;
+        assert_eq!(results.len(), tr.conf_map["ORCL"].len());
+        //rebalancing only moves cash between the variants
+        let funded: f64 = results.iter().map(|r| r.start_cash).sum();
+        assert!((funded - START_CASH).abs() < 1e-6);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn warm_up_test() -> Result<(), Box<dyn std::error::Error>> {
+        let mut tr = TraderConfigs::new(Settings::new()?, "", None, "").await?;
+        //a bar of the stream newer than the history stays at the end
+        for tc in tr.conf_map.get_mut("ORCL").unwrap() {
+            tc.buff.data = VecDeque::from([bar(14, 10, 99), bar(14, 50, 7)]);
+        }
+        let history: Vec<Bar> = (0..40).map(|m| bar(14, m, m as i32)).collect();
+        let seeded = tr.warm_up("ORCL", &history);
+        let capacities: Vec<usize> = tr.conf_map["ORCL"]
+            .iter()
+            .map(|tc| tc.buff.capacity)
+            .collect();
+        assert_eq!(seeded, capacities.iter().copied().max().unwrap().min(41));
+        for tc in &tr.conf_map["ORCL"] {
+            assert_eq!(tc.buff.data.len(), tc.buff.capacity.min(41));
+            assert_eq!(
+                tc.buff.data.back().map(|b| b.close_price.clone()),
+                Some(Num::from(7))
+            );
+        }
+        assert_eq!(tr.closes()["ORCL"], 39.0);
+        assert_eq!(tr.warm_up("MSFT", &history), 0);
+        let states: Vec<WarmupState> = tr
+            .warmup_state()
+            .into_iter()
+            .filter(|s| s.symbol == "ORCL")
+            .collect();
+        assert!(states.iter().all(|s| s.seeded == Some(40)));
+        assert!(states.iter().all(|s| s.ready == (s.lookback <= 41)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn warmup_gate_test() -> Result<(), Box<dyn std::error::Error>> {
+        let mut tr = TraderConfigs::new(Settings::new()?, "", None, "").await?;
+        //falling closes, a full buffer of 10 buys
+        let mut actions = 0;
+        for minute in 0..10 {
+            actions += tr.on_bar(bar(15, minute, 1000 - minute as i32)).len();
+        }
+        assert_eq!(actions, 0);
+        assert!(!tr.on_bar(bar(15, 10, 990)).is_empty());
+        //the rule variant needs 30 bars
+        let states: Vec<WarmupState> = tr
+            .warmup_state()
+            .into_iter()
+            .filter(|s| s.symbol == "ORCL")
+            .collect();
+        assert!(states.iter().all(|s| s.ready == (s.lookback <= 10)));
+        assert!(states.iter().any(|s| !s.ready));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn combine_mode_test() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::{indicator_decision::CombineMode, portfolio::types::IndicatorType as Kind};
+        let ind = |kind, threshold, weight| IndicatorConf {
+            kind,
+            period: Some(2),
+            lookback: None,
+            window_mins: None,
+            field: crate::types::PriceField::Close,
+            threshold: Some(threshold),
+            weight: Some(weight),
+        };
+        let mut tr = TraderConfigs::new(Settings::new()?, "", None, "").await?;
+        let confs = tr.conf_map.get_mut("ORCL").unwrap();
+        confs.retain(|tc| tc.variant == "type1");
+        //flat closes of 100: the sma votes Buy with weight 3, the ema and the
+        //deviation vote Sell
+        confs[0].indicator = vec![
+            ind(Kind::SimpleMovingAverage, 50.0, 3.0),
+            ind(Kind::ExponentialMovingAverage, 150.0, 1.0),
+            ind(Kind::StandardDeviation, 1.0, 1.0),
+        ];
+        confs[0].combine.threshold = 0.0;
+        tr.buffers.reconfigure(&tr.conf_map);
+        for minute in 0..10 {
+            tr.on_bar(bar(15, minute, 100));
+        }
+        let mut decide = |mode, minute| {
+            tr.conf_map.get_mut("ORCL").unwrap()[0].combine.mode = mode;
+            tr.on_bar(bar(15, minute, 100))
+                .first()
+                .map(|av| (av.action.clone(), av.strength))
+        };
+        assert_eq!(
+            decide(CombineMode::Majority, 10),
+            Some((Action::Sell, 2.0 / 3.0))
+        );
+        assert_eq!(
+            decide(CombineMode::WeightedSum, 11),
+            Some((Action::Buy, 0.2))
+        );
+        assert_eq!(decide(CombineMode::Unanimous, 12), None);
+
+        //a streamed ema above its threshold turns the vote around, but only if it
+        //was computed for the spec of the variant
+        let ema = tr.conf_map["ORCL"][0].indicator[1].clone();
+        let spec = grpc_client::stream_spec(&ema, 10).unwrap();
+        let streamed = |spec: IndicatorSpec| IndicatorUpdate {
+            symbol: String::from("ORCL"),
+            timestamp: 0,
+            values: vec![IndicatorValue {
+                id: spec.id,
+                value: 200.0,
+                spec: Some(spec),
+            }],
+        };
+        tr.conf_map.get_mut("ORCL").unwrap()[0].combine.mode = CombineMode::Majority;
+        tr.on_indicators(&streamed(IndicatorSpec {
+            lookback: 20,
+            ..spec.clone()
+        }));
+        let av = tr.on_bar(bar(15, 13, 100));
+        assert_eq!(av[0].action, Action::Sell);
+        tr.on_indicators(&streamed(spec));
+        let av = tr.on_bar(bar(15, 14, 100));
+        assert_eq!(av[0].action, Action::Buy);
+
+        //with the service down the streamed ema is ignored, nothing listens on port 1
+        let down = |fallback| async move {
+            let service = IndicatorService::new(
+                "http://127.0.0.1:1",
+                grpc_client::ClientConf {
+                    connect_timeout_ms: 200,
+                    retries: 0,
+                    breaker_failures: 1,
+                    fallback,
+                    ..Default::default()
+                },
+            );
+            let req = ListNumbersRequest2 {
+                id: IndicatorType::SimpleMovingAverage.into(),
+                opt: None,
+                list: vec![1.0, 2.0],
+            };
+            assert!(service.gen_liste("ORCL", req).await.is_err());
+            service
+        };
+        tr.client = Some(down(Fallback::Local).await);
+        let av = tr.on_bar(bar(15, 15, 100));
+        assert_eq!(av[0].action, Action::Sell);
+        tr.client = Some(down(Fallback::Hold).await);
+        assert!(tr.on_bar(bar(15, 16, 100)).is_empty());
+
+        //a closed stream leaves no values behind
+        tr.client = None;
+        assert_eq!(tr.on_bar(bar(15, 17, 100))[0].action, Action::Buy);
+        tr.clear_indicators();
+        assert_eq!(tr.on_bar(bar(15, 18, 100))[0].action, Action::Sell);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn quote_test() -> Result<(), Box<dyn std::error::Error>> {
+        let mut tr = TraderConfigs::new(Settings::new()?, "", None, "").await?;
+        let book = TopOfBook {
+            bid: 100.0,
+            ask: 102.0,
+            bid_size: 100.0,
+            ask_size: 100.0,
+            time: Utc.with_ymd_and_hms(2024, 3, 4, 15, 0, 30).unwrap(),
+        };
+        tr.books.insert(String::from("ORCL"), book);
+        let minute = |m| Utc.with_ymd_and_hms(2024, 3, 4, 15, m, 0).unwrap();
+        assert!(tr.book("ORCL", minute(1)).is_some());
+        //hours later the book is gone
+        assert!(tr.book("ORCL", minute(1) + Duration::hours(2)).is_none());
+
+        //a mid above the bar raises its high
+        let mut bar = bar(15, 1, 100);
+        with_mid(&mut bar, &book);
+        assert_eq!(bar.close_price, Num::from(101));
+        assert_eq!(bar.high_price, Num::from(101));
+        assert_eq!(bar.low_price, Num::from(100));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn small_buffer_test() -> Result<(), Box<dyn std::error::Error>> {
+        let mut tr = TraderConfigs::new(Settings::new()?, "", None, "").await?;
+        //buffers shorter than the old fixed compare of 5 bars
+        for capacity in 0..5 {
+            let confs = tr.conf_map.get_mut("ORCL").unwrap();
+            for tc in confs.iter_mut() {
+                tc.buff.capacity = capacity;
+                tc.buff.data.clear();
+                tc.rules.clear();
+                tc.compile_rules()?;
+            }
+            tr.buffers.reconfigure(&tr.conf_map);
+            for minute in 0..6 {
+                tr.on_bar(bar(15, minute + 10 * capacity as u32, 100 - minute as i32));
+            }
+        }
+        //one series per distinct need: the close over the capacity, shared by the
+        //indicators of type1 and type3, and the typical price of type2
+        let needs = tr.buffers().get("ORCL").unwrap().needs().len();
+        assert_eq!(needs, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sim_bracket_test() -> Result<(), Box<dyn std::error::Error>> {
+        let mut tr = TraderConfigs::new(Settings::new()?, "", None, "").await?;
+        tr.portfolio = Some(Portfolio {
+            name: String::from("Test Portfolio"),
+            cash: Some(1000.0),
+            stocks: Some(HashMap::new()),
+        });
+        let bar = |day, open, high, low, close| SimBar {
+            time: Utc.with_ymd_and_hms(2024, 3, day, 21, 0, 0).unwrap(),
+            open,
+            high,
+            low,
+            close,
+        };
+        let bracket = BracketConf {
+            take_profit_pct: Some(10.0),
+            stop_pct: Some(5.0),
+            stop_limit_pct: None,
+        };
+        let mut book = SimBook::default();
+        tr.portfolio.as_mut().unwrap().buy("ORCL", 10.0, 100.0)?;
+        tr.sim_bracket(
+            &mut book,
+            "ORCL",
+            Some(bracket),
+            0.0,
+            &bar(4, 100.0, 100.0, 100.0, 100.0),
+        )?;
+        assert_eq!(book.open("ORCL"), 2);
+
+        tr.sim_exits(&mut book, "ORCL", &bar(5, 101.0, 104.0, 99.0, 103.0))?;
+        assert_eq!(tr.held("ORCL")?, 10.0);
+        tr.sim_exits(&mut book, "ORCL", &bar(6, 104.0, 112.0, 103.0, 111.0))?;
+        assert_eq!(tr.held("ORCL")?, 0.0);
+        let cash = tr.portfolio.as_ref().unwrap().cash.unwrap();
+        assert!((cash - 1100.0).abs() < 1e-9);
+        assert_eq!(book.open("ORCL"), 0);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn portfolio_read_test() -> Result<(), Box<dyn std::error::Error>> {
-        /* let mut portfolio = Portfolio {
+        let mut portfolio = Portfolio {
             name: String::from("Test Portfolio"),
             cash: Some(1000.0),
             stocks: Some(HashMap::from([("ORCL".to_string(), 0.0)])),
         };
-        portfolio.buy("ORCL", 10.0, 50.0); //500 10
+        portfolio.buy("ORCL", 10.0, 50.0)?; //500 10
         assert_eq!(portfolio.cash.unwrap(), 500.0);
-        portfolio.sell("ORCL", 5.0, 55.0); //775 5
-        println!("Portfolio: {:?}", portfolio);
+        portfolio.sell("ORCL", 5.0, 55.0)?; //775 5
+        assert_eq!(portfolio.cash.unwrap(), 775.0);
+
+        //rejected, nothing changes
+        assert!(portfolio.buy("ORCL", 100.0, 50.0).is_err());
+        assert_eq!(
+            portfolio.sell("ORCL", 10.0, 55.0),
+            Err(RiskError::InsufficientShares {
+                symbol: String::from("ORCL"),
+                wanted: 10.0,
+                owned: 5.0,
+            })
+        );
         assert_eq!(portfolio.cash.unwrap(), 775.0);
         assert_eq!(portfolio.stocks.unwrap().get("ORCL").unwrap(), &5.0);
-        assert_eq!(portfolio.name, "Test Portfolio"); */
+        assert_eq!(portfolio.name, "Test Portfolio");
 
         Ok(())
     }
@@ -678,8 +1824,7 @@ mod tests {
     #[tokio::test]
     async fn data_read() -> Result<(), Box<dyn std::error::Error>> {
         let df = CsvReadOptions::default()
-            .try_into_reader_with_file_path(Some("files/orcl.csv".into()))
-            .unwrap()
+            .try_into_reader_with_file_path(Some("files/orcl.csv".into()))?
             .finish()?;
         println!("symbol: {:?}", df);
         Ok(())
@@ -1,6 +1,10 @@
 use apca::data::v2::stream::Bar;
+use serde::Deserialize;
 
-use crate::proto::{self};
+use crate::{
+    order::Exit,
+    proto::{self},
+};
 use std::collections::{HashMap, VecDeque};
 
 #[derive(Clone, Debug)]
@@ -39,18 +43,48 @@ pub struct ActionConfig {
     pub indi_validate: Option<IndiValidate>,
 }
 
-#[derive(Clone, PartialEq, Debug)]
+#[derive(Clone, PartialEq, Debug, Deserialize)]
 pub enum Action {
     Buy,
     Sell,
     Hold,
 }
 
-#[derive(Clone, Debug)]
+impl Action {
+    //signal as used by Portfolio::evaluator
+    pub fn signal(&self) -> f32 {
+        match self {
+            Action::Buy => 1.0,
+            Action::Sell => -1.0,
+            Action::Hold => 0.0,
+        }
+    }
+}
+
+//price column of a bar, typical is (high + low + close) / 3 and vwap the
+//volume weighted typical price since the first bar of the series
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PriceField {
+    Open,
+    High,
+    Low,
+    #[default]
+    Close,
+    Volume,
+    Typical,
+    Vwap,
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct ActionValuator {
     pub symbol: String,
     pub strength: f64,
     pub action: Action,
+    //limit order price, a market order if None
+    pub limit_price: Option<f64>,
+    //take-profit and stop-loss sent with a buy
+    pub exit: Option<Exit>,
 }
 
 #[derive(Clone, Debug)]
@@ -0,0 +1,726 @@
+//Validation of the layered config files before they are turned into Settings.
+//Unknown keys and values of the wrong type come from the config types: each section
+//of the merged files is deserialized on its own, the issue points at the file that
+//set the key. What the types can't tell (symbol mismatches, zero capacities, ranges,
+//credentials) is checked by hand per file.
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    net::SocketAddr,
+    path::Path,
+};
+
+use serde::de::DeserializeOwned;
+use serde_path_to_error::Segment;
+use toml::{Table, Value};
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    asset,
+    calendar::CalendarConf,
+    config2::{AppConfig, Settings},
+    control::ControlConf,
+    data::supervisor::StreamConf,
+    error::CLIError,
+    execution::ExecConf,
+    notify::NotifyConf,
+    portfolio::{manager::AllocConf, rebalance::RebalanceConf, types::TraderConf},
+    scheduler::ScheduleConf,
+    strategy::rule,
+    telemetry::{LoggingConf, MetricsConf},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationIssue {
+    pub file: String,
+    pub key: String,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.key.is_empty() {
+            write!(f, "{}: {}", self.file, self.message)
+        } else {
+            write!(f, "{}: {}: {}", self.file, self.key, self.message)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ValidationReport {
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for issue in &self.issues {
+            writeln!(f, "{issue}")?;
+        }
+        Ok(())
+    }
+}
+
+impl ValidationReport {
+    pub fn is_empty(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    fn push(&mut self, file: &str, key: &str, message: impl Into<String>) {
+        self.issues.push(ValidationIssue {
+            file: file.to_string(),
+            key: key.to_string(),
+            message: message.into(),
+        });
+    }
+}
+
+type Check = fn(Value) -> Result<(), serde_path_to_error::Error<toml::de::Error>>;
+
+fn check<T: DeserializeOwned>(
+    value: Value,
+) -> Result<(), serde_path_to_error::Error<toml::de::Error>> {
+    serde_path_to_error::deserialize::<_, T>(value).map(|_| ())
+}
+
+//sections of Settings and the type each one is read as, Stockconfig is checked per variant
+const SECTIONS: &[(&str, Check)] = &[
+    ("grpc", check::<AppConfig>),
+    ("database", check::<Table>),
+    ("calendar", check::<CalendarConf>),
+    ("logging", check::<LoggingConf>),
+    ("metrics", check::<MetricsConf>),
+    ("notify", check::<NotifyConf>),
+    ("stream", check::<StreamConf>),
+    ("execution", check::<ExecConf>),
+    ("allocation", check::<AllocConf>),
+    ("rebalance", check::<RebalanceConf>),
+    ("schedule", check::<ScheduleConf>),
+    ("control", check::<ControlConf>),
+];
+const PRICE_LABELS: &[&str] = &["Open", "High", "Low", "Close", "Adj Close"];
+
+//Loads Settings from dir, returns every problem found instead of the first one.
+//Credentials are only required for modes that talk to the broker.
+pub fn load_settings(
+    dir: &str,
+    run_mode: &str,
+    require_credentials: bool,
+) -> Result<Settings, CLIError> {
+    let mut report = ValidationReport::default();
+    let files = ["default", run_mode, "local"].map(|name| format!("{dir}/{name}.toml"));
+    if !Path::new(&files[0]).exists() {
+        report.push(&files[0], "", "required file is missing");
+    }
+    let mut layers = vec![];
+    for file in files.iter().filter(|f| Path::new(f).exists()) {
+        match std::fs::read_to_string(file) {
+            Ok(content) => {
+                if let Some(table) = parse(file, &content, &mut report) {
+                    check_values(file, &table, &mut report);
+                    layers.push((file.clone(), table));
+                }
+            }
+            Err(e) => report.push(file, "", format!("cannot be read: {e}")),
+        }
+    }
+    //a section may be split over the files, e.g. the grpc password in local.toml
+    if !layers.is_empty() {
+        check_types(&layers, &mut report);
+    }
+    if require_credentials {
+        for var in ["APCA_API_KEY_ID", "APCA_API_SECRET_KEY"] {
+            if std::env::var(var).map(|v| v.is_empty()).unwrap_or(true) {
+                report.push("environment", var, "missing Alpaca credential");
+            }
+        }
+    }
+    if !report.is_empty() {
+        return Err(CLIError::Validation(report));
+    }
+
+    Settings::from_dir(dir, run_mode).map_err(|e| {
+        report.push(&format!("{dir}/*"), "", e.to_string());
+        CLIError::Validation(report)
+    })
+}
+
+//a single file that holds the whole config
+pub fn validate_str(file: &str, content: &str, report: &mut ValidationReport) {
+    if let Some(table) = parse(file, content, report) {
+        check_values(file, &table, report);
+        check_types(&[(file.to_string(), table)], report);
+    }
+}
+
+fn parse(file: &str, content: &str, report: &mut ValidationReport) -> Option<Table> {
+    content
+        .parse::<Table>()
+        .map_err(|e| report.push(file, "", format!("invalid toml: {e}")))
+        .ok()
+}
+
+//later files win, tables are merged key by key like the config crate does
+fn merge(into: &mut Table, layer: &Table) {
+    for (k, v) in layer {
+        match (into.get_mut(k), v) {
+            (Some(Value::Table(into)), Value::Table(v)) => merge(into, v),
+            _ => {
+                into.insert(k.clone(), v.clone());
+            }
+        }
+    }
+}
+
+//one issue per section or variant, serde stops at the first error of a value
+fn check_types(layers: &[(String, Table)], report: &mut ValidationReport) {
+    let mut merged = Table::new();
+    for (_, table) in layers {
+        merge(&mut merged, table);
+    }
+    let issues = report.issues.len();
+    let map = |key: &str| Segment::Map {
+        key: key.to_string(),
+    };
+    for (section, value) in &merged {
+        let path = vec![map(section)];
+        if section == "Stockconfig" {
+            let symbols = value.as_table().filter(|t| t.values().all(Value::is_array));
+            let Some(symbols) = symbols else {
+                let res = check::<HashMap<String, Vec<TraderConf>>>(value.clone());
+                type_issue(layers, path, res, report);
+                continue;
+            };
+            for (symbol, confs) in symbols {
+                for (i, conf) in confs.as_array().into_iter().flatten().enumerate() {
+                    let path = vec![map(section), map(symbol), Segment::Seq { index: i }];
+                    type_issue(layers, path, check::<TraderConf>(conf.clone()), report);
+                }
+            }
+            continue;
+        }
+        match SECTIONS.iter().find(|(s, _)| *s == section.as_str()) {
+            Some((_, check)) => type_issue(layers, path, check(value.clone()), report),
+            None => {
+                let sections: Vec<&str> = SECTIONS.iter().map(|(s, _)| *s).collect();
+                report.push(
+                    origin(layers, &path),
+                    section,
+                    format!(
+                        "unknown section, expected Stockconfig or {}",
+                        sections.join(", ")
+                    ),
+                );
+            }
+        }
+    }
+    //sections that are missing, once the ones that are there are fine
+    if report.issues.len() == issues {
+        type_issue(
+            layers,
+            vec![],
+            check::<Settings>(Value::Table(merged)),
+            report,
+        );
+    }
+}
+
+fn type_issue(
+    layers: &[(String, Table)],
+    mut path: Vec<Segment>,
+    res: Result<(), serde_path_to_error::Error<toml::de::Error>>,
+    report: &mut ValidationReport,
+) {
+    let Err(e) = res else {
+        return;
+    };
+    path.extend(e.path().iter().cloned());
+    let mut key = String::new();
+    for segment in &path {
+        if !key.is_empty() && !matches!(segment, Segment::Seq { .. }) {
+            key.push('.');
+        }
+        key.push_str(&segment.to_string());
+    }
+    report.push(origin(layers, &path), &key, e.inner().message());
+}
+
+//file of the last layer that has most of path, the one whose value won the merge
+fn origin<'a>(layers: &'a [(String, Table)], path: &[Segment]) -> &'a str {
+    let depth = |table: &Table| {
+        let mut value: Option<&Value> = None;
+        for (i, segment) in path.iter().enumerate() {
+            let next = match (segment, value) {
+                (Segment::Map { key }, None) => table.get(key),
+                (Segment::Map { key }, Some(v)) => v.get(key.as_str()),
+                (Segment::Seq { index }, Some(v)) => v.get(*index),
+                _ => None,
+            };
+            match next {
+                Some(next) => value = Some(next),
+                None => return i,
+            }
+        }
+        path.len()
+    };
+    layers
+        .iter()
+        .max_by_key(|(_, table)| depth(table))
+        .map_or("", |(file, _)| file.as_str())
+}
+
+fn number(v: &Value) -> Option<f64> {
+    v.as_float().or_else(|| v.as_integer().map(|i| i as f64))
+}
+
+//a number that fails valid, values of the wrong type are left to check_types
+fn invalid(v: Option<&Value>, valid: impl Fn(f64) -> bool) -> bool {
+    v.and_then(number).is_some_and(|v| !valid(v))
+}
+
+//what the types can't tell, the values are only looked at if their type fits
+fn check_values(file: &str, table: &Table, report: &mut ValidationReport) {
+    let section = |name: &str| table.get(name).and_then(Value::as_table);
+
+    if let Some(grpc) = section("grpc") {
+        for key in ["username", "password", "grpcport"] {
+            if grpc.get(key).and_then(Value::as_str) == Some("") {
+                report.push(file, &format!("grpc.{key}"), "must not be empty");
+            }
+        }
+    }
+    if let Some(path) = section("calendar").and_then(|c| c.get("file")?.as_str()) {
+        if !Path::new(path).exists() {
+            report.push(file, "calendar.file", format!("`{path}` does not exist"));
+        }
+    }
+    if let Some(level) = section("logging").and_then(|l| l.get("level")?.as_str()) {
+        if EnvFilter::builder().parse(level).is_err() {
+            report.push(
+                file,
+                "logging.level",
+                "must be a tracing filter like \"info\"",
+            );
+        }
+    }
+    for (name, example) in [("metrics", "127.0.0.1:9184"), ("control", "127.0.0.1:9185")] {
+        if let Some(listen) = section(name).and_then(|s| s.get("listen")?.as_str()) {
+            if listen.parse::<SocketAddr>().is_err() {
+                report.push(
+                    file,
+                    &format!("{name}.listen"),
+                    format!("must be an address like {example}"),
+                );
+            }
+        }
+    }
+
+    if let Some(stream) = section("stream") {
+        if stream.get("buffer").and_then(Value::as_integer) == Some(0) {
+            report.push(file, "stream.buffer", "must be >= 1");
+        }
+        if stream.get("feed").and_then(Value::as_str) == Some("custom")
+            && !stream.contains_key("url")
+        {
+            report.push(file, "stream.url", "is required for feed = \"custom\"");
+        }
+    }
+    if let Some(execution) = section("execution") {
+        let checks: [(&str, fn(f64) -> bool, &str); 4] = [
+            ("min_qty", |v| v >= 0.0, "must be >= 0"),
+            ("limit_offset_bps", |v| v >= 0.0, "must be >= 0"),
+            (
+                "participation",
+                |v| (0.0..=1.0).contains(&v),
+                "must be between 0 and 1, 0 for no cap",
+            ),
+            ("duration_secs", |v| v >= 60.0, "must be >= 60"),
+        ];
+        for (k, valid, message) in checks {
+            if invalid(execution.get(k), valid) {
+                report.push(file, &format!("execution.{k}"), message);
+            }
+        }
+        for k in ["chase_steps", "profile_days"] {
+            if invalid(execution.get(k), |v| v >= 1.0) {
+                report.push(file, &format!("execution.{k}"), "must be >= 1");
+            }
+        }
+    }
+    if let Some(allocation) = section("allocation") {
+        if invalid(allocation.get("capital"), |c| c > 0.0) {
+            report.push(file, "allocation.capital", "must be > 0");
+        }
+        if invalid(allocation.get("lookback"), |l| l >= 2.0) {
+            report.push(file, "allocation.lookback", "must be >= 2");
+        }
+        for (k, w) in allocation
+            .get("weights")
+            .and_then(Value::as_table)
+            .into_iter()
+            .flatten()
+        {
+            if invalid(Some(w), |w| w >= 0.0) {
+                report.push(file, &format!("allocation.weights.{k}"), "must be >= 0");
+            }
+        }
+    }
+    if let Some(rebalance) = section("rebalance") {
+        validate_rebalance(file, rebalance, report);
+    }
+    if let Some(dir) = section("schedule").and_then(|s| s.get("report_dir")?.as_str()) {
+        if dir.is_empty() {
+            report.push(file, "schedule.report_dir", "must be a directory");
+        }
+    }
+
+    let Some(stockconfig) = section("Stockconfig") else {
+        return;
+    };
+    for (symbol, confs) in stockconfig {
+        let key = format!("Stockconfig.{symbol}");
+        if !asset::valid_symbol(symbol) {
+            report.push(
+                file,
+                &key,
+                "must be a ticker like ORCL or a crypto pair like BTC/USD",
+            );
+        }
+        let mut variants = HashSet::new();
+        for (i, conf) in confs.as_array().into_iter().flatten().enumerate() {
+            let Some(conf) = conf.as_table() else {
+                continue;
+            };
+            let key = format!("{key}[{i}]");
+            validate_trader_conf(file, &key, symbol, conf, report);
+            if let Some(variant) = conf.get("variant").and_then(Value::as_str) {
+                if !variants.insert(variant.to_string()) {
+                    report.push(
+                        file,
+                        &format!("{key}.variant"),
+                        format!("variant `{variant}` is used twice for {symbol}"),
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn validate_trader_conf(
+    file: &str,
+    key: &str,
+    symbol: &str,
+    conf: &Table,
+    report: &mut ValidationReport,
+) {
+    let sub = |name: &str| conf.get(name).and_then(Value::as_table);
+
+    if let Some(s) = conf.get("symbol").and_then(Value::as_str) {
+        if s != symbol {
+            report.push(
+                file,
+                &format!("{key}.symbol"),
+                format!("`{s}` does not match the Stockconfig key `{symbol}`"),
+            );
+        }
+    }
+    if let Some(label) = conf.get("price_label").and_then(Value::as_str) {
+        if !PRICE_LABELS.contains(&label) {
+            report.push(
+                file,
+                &format!("{key}.price_label"),
+                format!("must be one of {}", PRICE_LABELS.join(", ")),
+            );
+        }
+    }
+    if invalid(conf.get("shares_to_buy"), |s| s > 0.0) {
+        report.push(file, &format!("{key}.shares_to_buy"), "must be > 0");
+    }
+    if sub("buff").and_then(|b| b.get("capacity")?.as_integer()) == Some(0) {
+        report.push(file, &format!("{key}.buff.capacity"), "must be > 0");
+    }
+    if invalid(conf.get("flatten_before_close"), |m| m > 0.0) {
+        report.push(
+            file,
+            &format!("{key}.flatten_before_close"),
+            "must be a number of minutes > 0",
+        );
+    }
+
+    let indicators = conf.get("indicator").and_then(Value::as_array);
+    for (i, indicator) in indicators.into_iter().flatten().enumerate() {
+        let Some(indicator) = indicator.as_table() else {
+            continue;
+        };
+        let key = format!("{key}.indicator[{i}]");
+        for k in ["period", "lookback", "window_mins"] {
+            if invalid(indicator.get(k), |v| v >= 1.0) {
+                report.push(file, &format!("{key}.{k}"), "must be >= 1");
+            }
+        }
+        if indicator.contains_key("lookback") && indicator.contains_key("window_mins") {
+            report.push(
+                file,
+                &format!("{key}.window_mins"),
+                "replaces lookback, set only one",
+            );
+        }
+        if invalid(indicator.get("weight"), |w| w >= 0.0) {
+            report.push(file, &format!("{key}.weight"), "must be >= 0");
+        }
+    }
+
+    let rules = conf.get("rules").and_then(Value::as_array);
+    for (i, r) in rules.into_iter().flatten().enumerate() {
+        if let Some(when) = r.get("when").and_then(Value::as_str) {
+            if let Err(e) = rule::parse(when) {
+                report.push(file, &format!("{key}.rules[{i}].when"), e.to_string());
+            }
+        }
+    }
+
+    if let Some(quote) = sub("quote") {
+        for k in ["max_spread_bps", "limit_offset_bps"] {
+            if invalid(quote.get(k), |v| v >= 0.0) {
+                report.push(file, &format!("{key}.quote.{k}"), "must be >= 0");
+            }
+        }
+        if invalid(quote.get("max_age_secs"), |v| v >= 1.0) {
+            report.push(
+                file,
+                &format!("{key}.quote.max_age_secs"),
+                "must be a whole number of seconds >= 1",
+            );
+        }
+    }
+
+    if let Some(bracket) = sub("bracket") {
+        if invalid(bracket.get("take_profit_pct"), |v| v > 0.0) {
+            report.push(
+                file,
+                &format!("{key}.bracket.take_profit_pct"),
+                "must be a percentage > 0",
+            );
+        }
+        //a stop 100% below the entry is a price of 0
+        for k in ["stop_pct", "stop_limit_pct"] {
+            if invalid(bracket.get(k), |v| v > 0.0 && v < 100.0) {
+                report.push(
+                    file,
+                    &format!("{key}.bracket.{k}"),
+                    "must be a percentage between 0 and 100",
+                );
+            }
+        }
+        if bracket.contains_key("stop_limit_pct") && !bracket.contains_key("stop_pct") {
+            report.push(
+                file,
+                &format!("{key}.bracket.stop_limit_pct"),
+                "needs stop_pct, it is the limit below the stop",
+            );
+        }
+    }
+
+    if invalid(sub("combine").and_then(|c| c.get("threshold")), |t| {
+        (0.0..=1.0).contains(&t)
+    }) {
+        report.push(
+            file,
+            &format!("{key}.combine.threshold"),
+            "must be between 0 and 1",
+        );
+    }
+}
+
+fn validate_rebalance(file: &str, rebalance: &Table, report: &mut ValidationReport) {
+    for k in ["drift_pct", "min_trade"] {
+        if invalid(rebalance.get(k), |v| v >= 0.0) {
+            report.push(file, &format!("rebalance.{k}"), "must be >= 0");
+        }
+    }
+    if invalid(rebalance.get("cash_buffer_pct"), |v| {
+        (0.0..100.0).contains(&v)
+    }) {
+        report.push(
+            file,
+            "rebalance.cash_buffer_pct",
+            "must be a number from 0 to below 100",
+        );
+    }
+    if invalid(rebalance.get("capital"), |v| v > 0.0) {
+        report.push(file, "rebalance.capital", "must be > 0");
+    }
+    let targets = rebalance.get("targets").and_then(Value::as_table);
+    for (symbol, w) in targets.into_iter().flatten() {
+        let key = format!("rebalance.targets.{symbol}");
+        if !asset::valid_symbol(symbol) {
+            report.push(
+                file,
+                &key,
+                "must be a ticker like ORCL or a crypto pair like BTC/USD",
+            );
+        } else if invalid(Some(w), |w| (0.0..=1.0).contains(&w)) {
+            report.push(file, &key, "must be a weight from 0 to 1");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_str_test() {
+        let content = r#"
+[grpc]
+grpcport = "http://[::1]:50051"
+username = ""
+password = "secret"
+client = { retries = -1 }
+
+[logging]
+format = "xml"
+
+[metrics]
+listen = "localhost"
+
+[control]
+listen = "nowhere"
+
+[stream]
+buffer = 0
+feed = "custom"
+
+[notify]
+sinks = [
+    { type = "webhook", url = "http://localhost:9000/hook", events = ["fill"] },
+    { type = "pager", number = "123" },
+]
+
+[execution]
+algo = "iceberg"
+participation = 2
+
+[allocation]
+mode = "equal"
+weights = { type1 = -1 }
+
+[rebalance]
+targets = { SPY = 0.6, TLT = 1.5 }
+cash_buffer_pct = 100
+
+[schedule]
+warmup_mins = -5
+
+[[Stockconfig.ORCL]]
+variant = "type1"
+symbol = "AAPL"
+price_label = "Close"
+shares_to_buy = 10
+indicator = [{ type = "BollingerBands", period = 0, field = "median" }]
+buffersize = 10
+buff = { capacity = 0, data = [] }
+rules = [{ when = "sma(0) > 1", action = "Buy" }]
+sessions = ["regular", "lunch"]
+quote = { max_spread_bps = -1, use_mid = true }
+bracket = { take_profit_pct = 5, stop_pct = 0 }
+
+[[Stockconfig."btc-usd"]]
+variant = "type1"
+"#;
+        let mut report = ValidationReport::default();
+        validate_str("development.toml", content, &mut report);
+        let keys: Vec<&str> = report.issues.iter().map(|i| i.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                //checked by hand
+                "grpc.username",
+                "metrics.listen",
+                "control.listen",
+                "stream.buffer",
+                "stream.url",
+                "execution.participation",
+                "allocation.weights.type1",
+                "rebalance.cash_buffer_pct",
+                "rebalance.targets.TLT",
+                "Stockconfig.ORCL[0].symbol",
+                "Stockconfig.ORCL[0].buff.capacity",
+                "Stockconfig.ORCL[0].indicator[0].period",
+                "Stockconfig.ORCL[0].rules[0].when",
+                "Stockconfig.ORCL[0].quote.max_spread_bps",
+                "Stockconfig.ORCL[0].bracket.stop_pct",
+                "Stockconfig.btc-usd",
+                //from the types, the first problem of each section or variant
+                "Stockconfig.ORCL[0].buffersize",
+                "Stockconfig.btc-usd[0]",
+                "allocation.mode",
+                "execution.algo",
+                "grpc.client.retries",
+                "logging.format",
+                "notify.sinks[1]",
+                "schedule.warmup_mins",
+            ]
+        );
+        assert!(report.issues.iter().all(|i| i.file == "development.toml"));
+        assert!(report.issues[16]
+            .message
+            .contains("unknown field `buffersize`"));
+        assert!(report.issues[17].message.contains("missing field"));
+    }
+
+    #[test]
+    fn layers_test() {
+        let layer =
+            |file: &str, content: &str| (file.to_string(), content.parse::<Table>().unwrap());
+        let default = layer(
+            "default.toml",
+            r#"
+[grpc]
+grpcport = "http://[::1]:50051"
+username = "user"
+
+[[Stockconfig.ORCL]]
+variant = "type1"
+symbol = "ORCL"
+price_label = "Close"
+indicator = [{ type = "SimpleMovingAverage" }]
+shares_to_buy = 1
+buff = { capacity = 2, data = [] }
+"#,
+        );
+        //the password is in another file, the unknown key is blamed on the file that has it
+        let local = layer("local.toml", "[grpc]\npassword = \"secret\"\nport = 1\n");
+        let mut report = ValidationReport::default();
+        check_types(&[default.clone(), local], &mut report);
+        let issues: Vec<(&str, &str)> = report
+            .issues
+            .iter()
+            .map(|i| (i.file.as_str(), i.key.as_str()))
+            .collect();
+        assert_eq!(issues, vec![("local.toml", "grpc.port")]);
+
+        let local = layer("local.toml", "[grpc]\npassword = \"secret\"\n");
+        let mut report = ValidationReport::default();
+        check_types(&[default, local], &mut report);
+        assert!(report.is_empty(), "{report}");
+
+        //a section that is missing altogether
+        let grpc = layer(
+            "default.toml",
+            "[grpc]\ngrpcport = \"x\"\nusername = \"user\"\npassword = \"secret\"\n",
+        );
+        let mut report = ValidationReport::default();
+        check_types(&[grpc], &mut report);
+        assert_eq!(report.issues.len(), 1);
+        assert!(report.issues[0].message.contains("Stockconfig"));
+    }
+
+    #[test]
+    fn validate_dev_config_test() {
+        let mut report = ValidationReport::default();
+        let content = std::fs::read_to_string("config/development.toml").unwrap();
+        validate_str("config/development.toml", &content, &mut report);
+        assert!(report.is_empty(), "{report}");
+    }
+}